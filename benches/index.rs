@@ -0,0 +1,149 @@
+// Indexing and search over a small generated crate fixture, or a real index exported with `reeves export` if
+// REEVES_BENCH_EXPORT names one. Search uses the embedded tantivy text search, in a directory next to the db
+
+use criterion::{BatchSize, BenchmarkId, Criterion, criterion_group, criterion_main};
+use std::env;
+use std::fs;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use reeves_types::*;
+
+#[path = "../src/test_util.rs"]
+mod test_util;
+
+const FIXTURE_CRATES: usize = 50;
+
+// A JSONL export to populate dbs with instead of the generated fixture
+const EXPORT_VAR: &str = "REEVES_BENCH_EXPORT";
+
+// Common shapes, so type keys are shared between crates like in a real index
+const FIXTURE_SIGNATURES: &[(&[&str], &str)] = &[
+    (&["&str"], "String"),
+    (&["&str"], "usize"),
+    (&["&str"], "Result<u64, ParseIntError>"),
+    (&["&[u8]"], "String"),
+    (&["&[u8]"], "Vec<u8>"),
+    (&["Vec<u8>"], "String"),
+    (&["&Path"], "bool"),
+    (&["&Path"], "Result<File, Error>"),
+    (&["&self"], "usize"),
+    (&["&self"], "&str"),
+    (&["&mut self", "u64"], "()"),
+    (&["&self", "&str"], "Option<&str>"),
+    (&["u64", "u64"], "u64"),
+    (&[], "Instant"),
+    (&[], "Self"),
+    (&["Duration"], "Self"),
+];
+
+fn fixture_crate(i: usize) -> (String, String, Vec<FnDetail>) {
+    let name = format!("fixture{}", i);
+    let version = "1.0.0".to_owned();
+    // Vary which signatures each crate has, so candidate sets differ in size
+    let fndetails = FIXTURE_SIGNATURES.iter().enumerate()
+        .filter(|(j, _)| (i + j) % 3 != 0)
+        .map(|(j, (params, ret))| {
+            let path = format!("{}::m{}::f{}", name, i % 5, j);
+            let kind = if params.first().map_or(false, |p| p.contains("self")) { ItemKind::Method } else { ItemKind::Function };
+            test_util::fndetail(&version, &path, kind, params, ret)
+        })
+        .collect();
+    (name, version, fndetails)
+}
+
+// A fresh db for each use, removed (with its text search) on drop
+struct BenchDb {
+    path: PathBuf,
+    db: sled::Db,
+}
+
+fn text_search_dir(db_path: &Path) -> PathBuf {
+    let mut dir = db_path.as_os_str().to_owned();
+    dir.push(".text-search");
+    PathBuf::from(dir)
+}
+
+impl BenchDb {
+    fn new(populated: bool) -> Self {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let path = env::temp_dir().join(format!("reeves-bench-{}-{}", std::process::id(), NEXT.fetch_add(1, Ordering::SeqCst)));
+        let db = reeves::open_db(&path).unwrap();
+        if populated {
+            if let Some(export) = env::var_os(EXPORT_VAR) {
+                let mut input = BufReader::new(fs::File::open(&export).unwrap());
+                reeves::import_jsonl(&db, &mut input).unwrap();
+                return BenchDb { path, db }
+            }
+            for i in 0..FIXTURE_CRATES {
+                let (name, version, fndetails) = fixture_crate(i);
+                reeves::save_analysis(&db, &name, &version, fndetails).unwrap();
+            }
+        }
+        BenchDb { path, db }
+    }
+}
+
+impl Drop for BenchDb {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+        let _ = fs::remove_dir_all(text_search_dir(&self.path));
+    }
+}
+
+fn bench_save_analysis(c: &mut Criterion) {
+    let bench_db = BenchDb::new(true);
+    c.bench_function("save_analysis/new", |b| b.iter_batched(
+        || fixture_crate(FIXTURE_CRATES),
+        |(name, version, fndetails)| {
+            reeves::save_analysis(&bench_db.db, &name, &version, fndetails).unwrap();
+            reeves::remove_crate(&bench_db.db, &name).unwrap();
+        },
+        BatchSize::SmallInput,
+    ));
+    // Replacing an existing analysis has to purge the old one first
+    c.bench_function("save_analysis/replace", |b| b.iter_batched(
+        || fixture_crate(0),
+        |(name, version, fndetails)| reeves::save_analysis(&bench_db.db, &name, &version, fndetails).unwrap(),
+        BatchSize::SmallInput,
+    ));
+}
+
+fn bench_remove_crate(c: &mut Criterion) {
+    let bench_db = BenchDb::new(true);
+    c.bench_function("remove_crate", |b| b.iter_batched(
+        || {
+            let (name, version, fndetails) = fixture_crate(FIXTURE_CRATES);
+            reeves::save_analysis(&bench_db.db, &name, &version, fndetails).unwrap();
+            name
+        },
+        |name| reeves::remove_crate(&bench_db.db, &name).unwrap(),
+        BatchSize::SmallInput,
+    ));
+}
+
+fn bench_search(c: &mut Criterion) {
+    let bench_db = BenchDb::new(true);
+    reeves::set_text_search_url(text_search_dir(&bench_db.path).to_string_lossy().into_owned()).unwrap();
+    reeves::load_text_search(&bench_db.db).unwrap();
+
+    let queries: &[(&str, Option<&[&str]>, Option<&str>)] = &[
+        ("ret", None, Some("String")),
+        ("param", Some(&["&str"]), None),
+        ("params", Some(&["&self", "&str"]), None),
+        ("params_ret", Some(&["&[u8]"]), Some("String")),
+        ("no_params", Some(&[]), Some("Instant")),
+    ];
+    let mut group = c.benchmark_group("search");
+    for (shape, params, ret) in queries {
+        group.bench_with_input(BenchmarkId::from_parameter(shape), &(params, ret), |b, (params, ret)| b.iter(|| {
+            let params = params.map(|params| params.iter().map(|p| p.to_string()).collect());
+            reeves::search(&bench_db.db, params, ret.map(str::to_owned)).unwrap()
+        }));
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_save_analysis, bench_remove_crate, bench_search);
+criterion_main!(benches);
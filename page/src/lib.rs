@@ -3,15 +3,17 @@
 #[macro_use]
 extern crate log;
 
+extern crate reeves_client;
 extern crate reeves_types;
 
-use std::collections::BTreeMap;
-use std::rc::Rc;
-use std::sync::Mutex;
+use std::collections::HashSet;
+use std::future::Future;
 use wasm_bindgen::prelude::*;
+use wasm_bindgen_futures::spawn_local;
 use yew::prelude::*;
-use yew::format::Binary;
-use yew::services::fetch::{FetchService, FetchTask, Request, Response};
+use serde::{Serialize, Deserialize};
+use yew::format::Json;
+use yew::services::storage::{Area, StorageService};
 
 use reeves_types::*;
 
@@ -30,8 +32,18 @@ pub fn main() {
     let env = app.mount(elt);
     info!("Mounted app...");
 
-    env.send_message(ReevesMsg::ParamsChange("entry".into()));
-    env.send_message(ReevesMsg::RetChange("bool".into()));
+    // Restore a search from the URL if there was one, otherwise pick up where the user left off
+    match (get_url_query_param("params"), get_url_query_param("ret")) {
+        (None, None) => env.send_message(ReevesMsg::SeedInputs),
+        (params, ret) => {
+            env.send_message(ReevesMsg::ParamsChange(params.unwrap_or_else(|| "*".into())));
+            env.send_message(ReevesMsg::RetChange(ret.unwrap_or_else(|| "*".into())));
+            env.send_message(ReevesMsg::CrateFilter(get_url_query_param("crate")));
+            env.send_message(ReevesMsg::SearchRequest);
+        },
+    }
+
+    env.send_message(ReevesMsg::Route(get_location_hash()));
 
     yew::run_loop();
 }
@@ -51,94 +63,389 @@ fn href<M>(e: yew::events::MouseEvent, msg: M) -> M {
     msg
 }
 
-fn error_div(e: &str) -> Html {
-    html!{ <div class="error">{ format!("ERROR: {}", e) }</div> }
+// Arrow keys would otherwise move the cursor in inputs or scroll the page
+fn keydown(e: yew::events::KeyboardEvent) -> ReevesMsg {
+    let key = e.key();
+    if key == "ArrowUp" || key == "ArrowDown" {
+        e.prevent_default();
+    }
+    ReevesMsg::KeyDown(key)
 }
 
 #[wasm_bindgen(inline_js = r#"
 export function get_base_fetch_path(has_dirty_issues) {
     return window.location.pathname.replace(RegExp("^\\/$"), "");
 }
+export function on_scroll_near_bottom(cb) {
+    window.addEventListener("scroll", () => {
+        if (window.innerHeight + window.scrollY >= document.body.offsetHeight - 500) {
+            cb();
+        }
+    }, { passive: true });
+}
+export function get_location_hash() {
+    return window.location.hash;
+}
+export function on_hash_change(cb) {
+    window.addEventListener("hashchange", () => cb());
+}
+export function now_ms() {
+    return Date.now();
+}
+export function copy_to_clipboard(text) {
+    navigator.clipboard.writeText(text);
+}
+export function get_url_query_param(name) {
+    return new URLSearchParams(window.location.search).get(name);
+}
+export function open_url(url) {
+    window.location.assign(url);
+}
+export function scroll_into_view(id) {
+    const elt = document.getElementById(id);
+    if (elt) { elt.scrollIntoView({ block: "nearest" }); }
+}
+export function set_url_query_params(names, values) {
+    const url = new URL(window.location);
+    url.search = "";
+    names.forEach((name, i) => url.searchParams.set(name, values[i]));
+    window.history.replaceState(null, "", url);
+}
 "#)]
 extern "C" {
     fn get_base_fetch_path() -> String;
+    fn on_scroll_near_bottom(cb: &Closure<dyn FnMut()>);
+    fn get_location_hash() -> String;
+    fn on_hash_change(cb: &Closure<dyn FnMut()>);
+    fn now_ms() -> f64;
+    fn copy_to_clipboard(text: &str);
+    fn get_url_query_param(name: &str) -> Option<String>;
+    fn set_url_query_params(names: Box<[JsValue]>, values: Box<[JsValue]>);
+    fn open_url(url: &str);
+    fn scroll_into_view(id: &str);
+}
+
+// What you'd need to write to call an item: methods and associated functions need their type in scope, so import
+// that rather than the function
+fn use_statement(path: &str) -> String {
+    let segments: Vec<_> = path.split("::").collect();
+    let is_assoc = segments.len() > 2 && segments[segments.len() - 2].starts_with(char::is_uppercase);
+    let import = if is_assoc { &segments[..segments.len() - 1] } else { &segments[..] };
+    format!("use {};", import.join("::"))
+}
+
+// Permalinks are client-side routes, so they work wherever the page is hosted
+// The crate is encoded, as git crates have slashes in them
+fn fn_permalink(fndetail: &FnDetail) -> String {
+    format!("#/fn/{}/{}", encode_component(&fndetail.krate), path_hash(&fndetail.path))
+}
+
+// Git crates link to their repo rather than crates.io, and standard library crates (saved as <crate>@<rustc version>)
+// to their docs for that release
+fn crate_url(krate: &str, version: &str) -> String {
+    if is_sysroot_crate(krate) {
+        let (name, _) = krate.split_once('@').unwrap();
+        return format!("https://doc.rust-lang.org/{}/{}/", version, name)
+    }
+    match git_crate_source(krate) {
+        Some((url, _commit, _package)) => url.to_owned(),
+        None => format!("https://crates.io/crates/{}/{}", krate, version),
+    }
+}
+
+// The fn's lines on docs.rs, or the crate's source root if it wasn't recorded. Re-exported fns are in another crate,
+// whose version isn't known. Git crates only link to their repo, as where the package is in it isn't recorded.
+// Standard library crates are in the rust repo, tagged with the rustc version
+fn source_url(fndetail: &FnDetail) -> String {
+    if let Some((url, _commit, _package)) = git_crate_source(&fndetail.krate) {
+        return url.to_owned()
+    }
+    let span = fndetail.source.as_ref().filter(|_| fndetail.reexported_from.is_none());
+    if is_sysroot_crate(&fndetail.krate) {
+        let (name, _) = fndetail.krate.split_once('@').unwrap();
+        return match span {
+            Some(span) => format!("https://github.com/rust-lang/rust/blob/{}/library/{}/{}#L{}-L{}",
+                                  fndetail.version, name, span.file, span.start_line, span.end_line),
+            None => format!("https://github.com/rust-lang/rust/tree/{}/library/{}/", fndetail.version, name),
+        }
+    }
+    let root = format!("https://docs.rs/crate/{}/{}/source/", fndetail.krate, fndetail.version);
+    match span {
+        Some(span) => format!("{}{}#{}-{}", root, span.file, span.start_line, span.end_line),
+        None => root,
+    }
+}
+
+// (crate, path hash) for a function permalink
+fn parse_fn_route(hash: &str) -> Option<(String, String)> {
+    let mut parts = hash.strip_prefix("#/fn/")?.splitn(2, '/');
+    let krate = decode_component(parts.next()?)?;
+    let hash = parts.next()?;
+    if krate.is_empty() || hash.is_empty() { None } else { Some((krate, hash.to_owned())) }
+}
+
+fn view_badges(fndetail: &FnDetail) -> Html {
+    html!{ <>
+        { ifnode(fndetail.is_unsafe, || html!{ <span class="badge badge-unsafe">{ "unsafe" }</span> }) }
+        { ifnode(fndetail.is_async, || html!{ <span class="badge">{ "async" }</span> }) }
+        { ifnode(fndetail.is_const, || html!{ <span class="badge">{ "const" }</span> }) }
+        { ifnode(fndetail.is_deprecated, || html!{ <span class="badge badge-deprecated">{ "deprecated" }</span> }) }
+        { ifnode(fndetail.kind == ItemKind::BuilderChain, || html!{
+            <span class="badge" title="A constructor followed by a build method, with setters called in between">{ "builder" }</span>
+        }) }
+    </> }
+}
+
+fn result_id(i: usize) -> String {
+    format!("result-{}", i)
+}
+
+// Keep the current search in the URL, so it can be shared or bookmarked
+fn set_url_query(query: &[(&str, &str)]) {
+    let names = query.iter().map(|(name, _)| JsValue::from_str(name)).collect();
+    let values = query.iter().map(|(_, value)| JsValue::from_str(value)).collect();
+    set_url_query_params(names, values)
+}
+
+// A request in the background, which can be aborted if it's superseded
+struct InFlight {
+    fetch_id: u64,
+    abort: web_sys::AbortController,
+}
+
+impl InFlight {
+    // The response will never be emitted
+    fn cancel(self) {
+        self.abort.abort()
+    }
 }
 
 struct ReevesApi {
-    base_fetch_path: String,
-    fetch: FetchService,
-    fetches: Rc<Mutex<BTreeMap<u64, FetchTask>>>, // arbitrary id -> request callback
+    client: reeves_client::Client,
     next_fetch_id: u64,
 }
 
 impl ReevesApi {
     fn new(base_fetch_path: String) -> Self {
         Self {
-            base_fetch_path,
-            fetch: FetchService::new(),
-            fetches: Rc::new(Mutex::new(BTreeMap::new())),
+            client: reeves_client::Client::new(&base_fetch_path),
             next_fetch_id: 0,
         }
     }
 
-    fn post_search(&mut self, cb: Callback<ReevesMsg>, search_request: proto::SearchRequest) {
-        let request = Request::post(format!("{}/reeves/search", self.base_fetch_path))
-            .header("Content-Type", "application/octet-stream")
-            .body(Ok(bincode::serialize(&search_request).unwrap()))
-            .expect("failed to build request");
+    fn post_search(&mut self, cb: Callback<ReevesMsg>, search_request: &proto::SearchRequest) -> InFlight {
+        let search_request = search_request.clone();
+        self.spawn(cb, |client| async move { client.search(&search_request).await }, |fetch_id, res| match res {
+            Ok(res) => ReevesMsg::SearchResult(fetch_id, res),
+            Err(e) => ReevesMsg::SearchFailed(fetch_id, e),
+        })
+    }
+
+    fn get_stats(&mut self, cb: Callback<ReevesMsg>) -> InFlight {
+        self.spawn(cb, |client| async move { client.stats().await }, |_, res| match res {
+            Ok(res) => ReevesMsg::StatsResult(res),
+            Err(e) => ReevesMsg::Error(format!("failed to load index stats: {}", e)),
+        })
+    }
+
+    fn get_suggest(&mut self, cb: Callback<ReevesMsg>, q: &str) -> InFlight {
+        let q = q.to_owned();
+        self.spawn(cb, |client| async move { client.suggest(&q).await }, |fetch_id, res| match res {
+            Ok(res) => ReevesMsg::SuggestResult(fetch_id, res.types),
+            // Not worth bothering the user about, they can carry on typing
+            Err(e) => {
+                warn!("Failed to get suggestions: {}", e);
+                ReevesMsg::SuggestResult(fetch_id, vec![])
+            },
+        })
+    }
 
+    fn get_fn(&mut self, cb: Callback<ReevesMsg>, krate: &str, hash: &str) -> InFlight {
+        let (krate, hash) = (krate.to_owned(), hash.to_owned());
+        self.spawn(cb, |client| async move { client.fn_detail(&krate, &hash).await }, |fetch_id, res| match res {
+            Ok(res) => ReevesMsg::FnDetailResult(fetch_id, res),
+            Err(e) => ReevesMsg::FnDetailFailed(fetch_id, e),
+        })
+    }
+
+    fn post_feedback(&mut self, cb: Callback<ReevesMsg>, feedback: &proto::FeedbackRequest, result: usize) -> InFlight {
+        let feedback = feedback.clone();
+        let kind = feedback.kind;
+        self.spawn(cb, |client| async move { client.feedback(&feedback).await }, move |_, res| match (kind, res) {
+            (proto::FeedbackKind::BadResult, Ok(())) => ReevesMsg::ReportSent(result),
+            (proto::FeedbackKind::BadResult, Err(e)) => ReevesMsg::Error(format!("failed to report result: {}", e)),
+            // Not worth bothering the user about, they've gone to the docs
+            (proto::FeedbackKind::ClickThrough, res) => {
+                if let Err(e) = res {
+                    warn!("Failed to record click on result: {}", e);
+                }
+                ReevesMsg::ClickSent
+            },
+        })
+    }
+
+    // Runs a request in the background, emitting the message made from its result unless it gets cancelled
+    fn spawn<T: 'static, F: Future<Output = Result<T, reeves_client::Error>> + 'static>(
+        &mut self, cb: Callback<ReevesMsg>, request: impl FnOnce(reeves_client::Client) -> F,
+        to_msg: impl FnOnce(u64, Result<T, String>) -> ReevesMsg + 'static,
+    ) -> InFlight {
         let fetch_id = self.next_fetch_id;
         self.next_fetch_id += 1;
-        let fetches = self.fetches.clone();
-        let handler = move |response: Response<Binary>| {
-            assert!(fetches.lock().expect("fetch lock fail for remove").remove(&fetch_id).is_some());
-            let (meta, body) = response.into_parts();
-            cb.emit(if meta.status.is_success() {
-                let body = body.expect("no body present for success");
-                let res = bincode::deserialize(&body).expect("success body invalid bincode");
-                ReevesMsg::SearchResult(res)
-            } else {
-                match body {
-                    Ok(body) => {
-                        let err = String::from_utf8(body).expect("fail body invalid utf8");
-                        ReevesMsg::Error(err)
-                    },
-                    Err(e) => {
-                        ReevesMsg::Error(format!("error on fetch: {} (body error: {})", meta.status, e))
-                    }
-                }
-            })
-        };
-        let task = self.fetch.fetch_binary(request, handler.into()).unwrap();
-        assert!(self.fetches.lock().expect("fetch lock fail for insert").insert(fetch_id, task).is_none());
+        let abort = web_sys::AbortController::new().expect("failed to create abort controller");
+        let signal = abort.signal();
+        let res = request(self.client.clone().with_abort_signal(signal.clone()));
+        spawn_local(async move {
+            let res = res.await.map_err(|e| e.to_string());
+            if signal.aborted() {
+                // Cancelled, but the response was already on its way
+                return
+            }
+            cb.emit(to_msg(fetch_id, res))
+        });
+        InFlight { fetch_id, abort }
+    }
+}
+
+// Enough for a whole search, rendering is limited separately
+const PAGE_SIZE: usize = 500;
+// Results are mounted this many at a time as the user scrolls, so a big page doesn't make the page janky
+const RENDER_CHUNK: usize = 50;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum SuggestTarget {
+    Params, // the last param being typed
+    Ret,
+}
+
+const RECENT_SEARCHES_KEY: &str = "reeves.recent-searches";
+const MAX_RECENT_SEARCHES: usize = 10;
+
+// Shown before there are any results, as (params, ret) in the same syntax as the inputs
+const EXAMPLE_SEARCHES: &[(&str, &str)] = &[
+    ("&str", "u64"),
+    ("Vec<u8>", "String"),
+    ("&str", "*"),
+    ("&Path", "bool"),
+    ("*", "Duration"),
+    ("", "Instant"),
+];
+
+// As typed, rather than parsed, so they can be put back in the inputs
+#[derive(Serialize, Deserialize)]
+#[derive(Clone, PartialEq)]
+struct RecentSearch {
+    params: String,
+    ret: String,
+    krate: Option<String>,
+}
+
+impl RecentSearch {
+    fn label(&self) -> String {
+        let label = format!("({}) -> {}", self.params, self.ret);
+        match self.krate.as_ref() {
+            Some(krate) => format!("{} in {}", label, krate),
+            None => label,
+        }
     }
 }
 
+struct ResultGroup {
+    krate: String,
+    version: String,
+    results: Vec<usize>, // indexes into search_results
+}
+
 pub enum ReevesMsg {
     SearchRequest,
-    SearchResult(proto::SearchResult),
+    PageRequest(usize), // offset
+    SearchResult(u64, proto::SearchResult), // fetch id, result
+    SearchFailed(u64, String), // fetch id, error
+    StatsResult(proto::StatsResult),
+    Route(String), // location hash
+    FnDetailResult(u64, proto::FnDetailResult), // fetch id, result
+    FnDetailFailed(u64, String), // fetch id, error
+    SuggestRequest(SuggestTarget),
+    SuggestResult(u64, Vec<String>), // fetch id, types
+    AcceptSuggestion(usize), // index into suggestions
 
     ParamsChange(String),
     RetChange(String),
+    SignatureChange(String),
+    ToggleSignatureMode,
+    CrateFilter(Option<String>),
+    SortChange(proto::SortOrder),
+    ToggleExactParams,
+    CopyToClipboard(String),
+    ToggleDocs(usize), // index into search_results
+    ToggleCrate(String),
+    RenderMore,
+    ToggleHideUnsafe,
+    ToggleHideDeprecated,
+    ReportResult(usize), // index into search_results
+    ReportSent(usize), // index into search_results
+    OpenResult(usize), // index into search_results, only recorded as the link does the opening
+    ClickSent,
+    KeyDown(String), // key name, e.g. "Enter"
+
+    SeedInputs,
+    RecentSearch(usize), // index into recent_searches
+    ExampleSearch(usize), // index into EXAMPLE_SEARCHES
 
     Error(String),
+    DismissError(usize), // index into errors
+    RetrySearch,
 }
 
 pub struct ReevesComponent {
     // State from server
-    search_results: Vec<FnDetail>,
+    search_results: Vec<proto::SearchHit>,
+    search_offset: usize,
+    search_total: usize,
+    search_truncated: bool,
+    search_time_ms: u64,
+    search_facets: Vec<proto::CrateFacet>,
+    crate_status: Option<proto::IndexStatus>, // of the filtered crate, when it isn't indexed
+    stats: Option<proto::StatsResult>,
+    detail: Option<proto::FnDetailResult>,
 
     // User state
     params: String,
     parsed_params: Option<Vec<String>>,
     ret: String,
     parsed_ret: Option<String>,
+    signature_mode: bool, // a single `fn(..) -> ..` input rather than separate params and ret
+    signature: String,
+    parse_error: Option<String>,
+    crate_filter: Option<String>,
+    sort: proto::SortOrder,
+    exact_params: bool, // only fns with exactly the params searched for
+    selected_result: Option<usize>, // index into search_results
+    rendered_limit: usize, // how many visible results are mounted
+    expanded_docs: HashSet<usize>, // indexes into search_results
+    collapsed_crates: HashSet<String>,
+    hide_unsafe: bool,
+    hide_deprecated: bool,
+    reported_results: HashSet<usize>, // indexes into search_results
+
+    // Showing a single function rather than search results
+    detail_route: Option<(String, String)>, // crate, path hash
+    active_detail: Option<InFlight>,
+
+    // Autocomplete for whichever input is being typed in
+    suggestions: Vec<String>,
+    suggest_target: Option<SuggestTarget>,
+    selected_suggestion: Option<usize>,
+    active_suggest: Option<InFlight>, // the suggest we're waiting on
 
     // Maintained state
-    last_error: Option<String>,
+    errors: Vec<String>, // shown as toasts until dismissed
+    recent_searches: Vec<RecentSearch>, // most recent first
+    last_search: Option<proto::SearchRequest>,
+    active_search: Option<InFlight>, // the search we're waiting on
 
     // Internal guts
     api: ReevesApi,
+    storage: Option<StorageService>, // None if the browser won't give us local storage
     msg_callback: Callback<ReevesMsg>,
     link: ComponentLink<Self>,
 }
@@ -150,21 +457,73 @@ impl Component for ReevesComponent {
     fn create(_: Self::Properties, link: ComponentLink<Self>) -> Self {
         let base_fetch_path = get_base_fetch_path();
         let api = ReevesApi::new(base_fetch_path);
+        let storage = StorageService::new(Area::Local)
+            .map_err(|e| warn!("Recent searches won't be saved: {}", e))
+            .ok();
+        let recent_searches = storage.as_ref()
+            .and_then(|storage| {
+                let Json(recent) = storage.restore(RECENT_SEARCHES_KEY);
+                recent.map_err(|e| info!("No recent searches restored: {}", e)).ok()
+            })
+            .unwrap_or_default();
 
-        let ret = Self {
+        let mut ret = Self {
             search_results: vec![],
+            search_offset: 0,
+            search_total: 0,
+            search_truncated: false,
+            search_time_ms: 0,
+            crate_status: None,
+            search_facets: vec![],
+            stats: None,
+            detail: None,
 
             params: String::from("*"),
             parsed_params: None,
             ret: String::from("*"),
             parsed_ret: None,
+            signature_mode: false,
+            signature: String::new(),
+            parse_error: None,
+            crate_filter: None,
+            sort: proto::SortOrder::Relevance,
+            exact_params: false,
+            selected_result: None,
+            rendered_limit: RENDER_CHUNK,
+            expanded_docs: HashSet::new(),
+            collapsed_crates: HashSet::new(),
+            hide_unsafe: false,
+            hide_deprecated: false,
+            reported_results: HashSet::new(),
+
+            detail_route: None,
+            active_detail: None,
 
-            last_error: None,
+            suggestions: vec![],
+            suggest_target: None,
+            selected_suggestion: None,
+            active_suggest: None,
+
+            errors: vec![],
+            recent_searches,
+            last_search: None,
+            active_search: None,
 
             api,
+            storage,
             msg_callback: link.callback(|msg| msg),
             link,
         };
+        ret.api.get_stats(ret.msg_callback.clone());
+        // Lives as long as the page
+        let scroll_link = ret.link.clone();
+        let on_scroll = Closure::wrap(Box::new(move || scroll_link.send_message(ReevesMsg::RenderMore)) as Box<dyn FnMut()>);
+        on_scroll_near_bottom(&on_scroll);
+        on_scroll.forget();
+        let hash_link = ret.link.clone();
+        let on_hash = Closure::wrap(Box::new(move || hash_link.send_message(ReevesMsg::Route(get_location_hash()))) as Box<dyn FnMut()>);
+        on_hash_change(&on_hash);
+        on_hash.forget();
 
         ret
     }
@@ -172,30 +531,151 @@ impl Component for ReevesComponent {
     fn update(&mut self, msg: Self::Message) -> ShouldRender {
         match msg {
             ReevesMsg::SearchRequest => {
+                if let Some(e) = self.parse_error.as_ref() {
+                    info!("Not searching with unparseable query: {}", e);
+                    return false
+                }
                 info!("Doing search for {:?} {:?}", self.params, self.ret);
+                self.clear_suggestions();
+                self.update_url();
+                self.add_recent_search();
 
                 let params = self.parsed_params.clone();
                 let ret = self.parsed_ret.clone();
-                let sr = proto::SearchRequest { params, ret };
-                self.api.post_search(self.msg_callback.clone(), sr);
+                let krate = self.crate_filter.clone();
+                let sr = proto::SearchRequest { params, ret, krate, offset: 0, limit: Some(PAGE_SIZE), sort: self.sort, exact_params: self.exact_params };
+                self.send_search(sr);
+
+                true
+            },
+            ReevesMsg::PageRequest(offset) => {
+                // Page through the previous search, not whatever is currently in the inputs
+                if let Some(mut sr) = self.last_search.clone() {
+                    info!("Loading results from offset {}", offset);
+                    sr.offset = offset;
+                    self.send_search(sr);
+                }
+
+                true
+            },
+            ReevesMsg::SearchResult(fetch_id, _) | ReevesMsg::SearchFailed(fetch_id, _) if self.active_search.as_ref().map(|f| f.fetch_id) != Some(fetch_id) => {
+                info!("Dropping response from superseded search {}", fetch_id);
 
                 false
             },
-            ReevesMsg::SearchResult(sr) => {
-                info!("Loaded {} search results", sr.fndetails.len());
+            ReevesMsg::SearchFailed(_, e) => {
+                self.active_search = None;
+                self.update(ReevesMsg::Error(e))
+            },
+            ReevesMsg::SearchResult(_, sr) => {
+                self.active_search = None;
+                info!("Loaded {} search results (offset {}, total {}) in {}ms, query interpreted as {:?}",
+                    sr.hits.len(), sr.offset, sr.total, sr.server_time_ms, sr.query);
 
-                self.search_results = sr.fndetails;
+                self.search_results = sr.hits;
+                self.search_offset = sr.offset;
+                self.search_total = sr.total;
+                // Either way there are matches that weren't returned
+                self.search_truncated = sr.truncated || sr.timed_out;
+                self.search_time_ms = sr.server_time_ms;
+                self.crate_status = sr.crate_status;
+                self.search_facets = sr.facets;
+                self.selected_result = None;
+                self.expanded_docs.clear();
+                self.reported_results.clear();
+                self.rendered_limit = RENDER_CHUNK;
 
                 true
             },
 
+            ReevesMsg::StatsResult(stats) => {
+                info!("Index has {} crates, last updated at {:?}", stats.num_crates, stats.last_updated_ms);
+                self.stats = Some(stats);
+
+                true
+            },
+
+            ReevesMsg::SuggestRequest(target) => {
+                let fragment = self.suggest_fragment(target).trim().to_owned();
+                if let Some(suggest) = self.active_suggest.take() {
+                    suggest.cancel()
+                }
+                if fragment.is_empty() || fragment == "*" {
+                    self.clear_suggestions();
+                } else {
+                    self.suggest_target = Some(target);
+                    self.active_suggest = Some(self.api.get_suggest(self.msg_callback.clone(), &fragment));
+                }
+
+                true
+            },
+            ReevesMsg::SuggestResult(fetch_id, _) if self.active_suggest.as_ref().map(|f| f.fetch_id) != Some(fetch_id) => false,
+            ReevesMsg::SuggestResult(_, types) => {
+                self.active_suggest = None;
+                self.suggestions = types;
+                self.selected_suggestion = None;
+
+                true
+            },
+            ReevesMsg::AcceptSuggestion(i) => {
+                let suggestion = self.suggestions[i].clone();
+                match self.suggest_target {
+                    Some(SuggestTarget::Params) => {
+                        // Keep the params before the one being typed
+                        let params = match self.params.rfind(',') {
+                            Some(comma) => format!("{}, {}", &self.params[..comma], suggestion),
+                            None => suggestion,
+                        };
+                        self.update(ReevesMsg::ParamsChange(params));
+                    },
+                    Some(SuggestTarget::Ret) => {
+                        self.update(ReevesMsg::RetChange(suggestion));
+                    },
+                    None => (),
+                }
+                self.clear_suggestions();
+
+                true
+            },
+
+            ReevesMsg::Route(hash) => {
+                if let Some(detail) = self.active_detail.take() {
+                    detail.cancel()
+                }
+                self.detail = None;
+                self.detail_route = parse_fn_route(&hash);
+                if let Some((krate, hash)) = self.detail_route.as_ref() {
+                    info!("Loading function {} in {}", hash, krate);
+                    self.active_detail = Some(self.api.get_fn(self.msg_callback.clone(), krate, hash));
+                }
+
+                true
+            },
+            ReevesMsg::FnDetailResult(fetch_id, _) | ReevesMsg::FnDetailFailed(fetch_id, _)
+                if self.active_detail.as_ref().map(|f| f.fetch_id) != Some(fetch_id) => false,
+            ReevesMsg::FnDetailResult(_, detail) => {
+                self.active_detail = None;
+                self.detail = Some(detail);
+
+                true
+            },
+            ReevesMsg::FnDetailFailed(_, e) => {
+                self.active_detail = None;
+                self.update(ReevesMsg::Error(e))
+            },
+
             ReevesMsg::ParamsChange(val) => {
                 self.params = val;
+                self.selected_result = None;
+                self.parse_error = None;
                 self.parsed_params = if self.params.trim() != "*" {
-                    Some(self.params.trim().split(',')
-                        .map(|s| s.trim().to_owned())
-                        .filter(|s| !s.is_empty())
-                        .collect())
+                    match query::split_params(&self.params) {
+                        Ok(params) => Some(params),
+                        Err(e) => {
+                            self.parse_error = Some(e);
+                            None
+                        },
+                    }
                 } else {
                     None
                 };
@@ -203,6 +683,7 @@ impl Component for ReevesComponent {
             },
             ReevesMsg::RetChange(val) => {
                 self.ret = val;
+                self.selected_result = None;
                 self.parsed_ret = match self.ret.trim() {
                     "" => None,
                     "*" => None,
@@ -210,10 +691,255 @@ impl Component for ReevesComponent {
                 };
                 true
             },
+            ReevesMsg::SignatureChange(val) => {
+                self.signature = val;
+                self.selected_result = None;
+                match query::parse_signature(&self.signature) {
+                    Ok(query::Signature { params, ret }) => {
+                        // Keep the separate inputs in sync, so the URL and switching modes both work
+                        self.params = params.as_ref().map_or_else(|| "*".into(), |params| params.join(", "));
+                        self.ret = ret.clone().unwrap_or_else(|| "*".into());
+                        self.parsed_params = params;
+                        self.parsed_ret = ret;
+                        self.parse_error = None;
+                    },
+                    Err(e) => self.parse_error = Some(e),
+                }
+                true
+            },
+            ReevesMsg::ToggleSignatureMode => {
+                self.signature_mode = !self.signature_mode;
+                if self.signature_mode {
+                    let sig = query::Signature { params: self.parsed_params.clone(), ret: self.parsed_ret.clone() };
+                    self.update(ReevesMsg::SignatureChange(sig.to_string()))
+                } else {
+                    self.update(ReevesMsg::ParamsChange(self.params.clone()))
+                }
+            },
+
+            ReevesMsg::CrateFilter(krate) => {
+                self.crate_filter = krate;
+                // Refilter the previous search from the start
+                if let Some(mut sr) = self.last_search.clone() {
+                    info!("Filtering results to crate {:?}", self.crate_filter);
+                    self.update_url();
+                    sr.krate = self.crate_filter.clone();
+                    sr.offset = 0;
+                    self.send_search(sr);
+                }
+
+                true
+            },
+
+            ReevesMsg::SortChange(sort) => {
+                self.sort = sort;
+                self.selected_result = None;
+                self.expanded_docs.clear();
+                self.reported_results.clear();
+                if let Some(mut sr) = self.last_search.clone() {
+                    sr.sort = sort;
+                    if sort == proto::SortOrder::Relevance {
+                        // We don't keep the order results arrived in, so ask again
+                        self.send_search(sr);
+                    } else {
+                        // Reorder what we have, later pages will come back from the server in this order
+                        info!("Sorting loaded results by {}", sort.name());
+                        sort.sort(&mut self.search_results, |hit| (&hit.fndetail.krate, &hit.fndetail.path), &self.search_facets);
+                        self.last_search = Some(sr);
+                    }
+                }
+
+                true
+            },
+            ReevesMsg::ToggleExactParams => {
+                self.exact_params = !self.exact_params;
+                // Redo the previous search from the start, as results can be dropped as well as added
+                if let Some(mut sr) = self.last_search.clone() {
+                    sr.exact_params = self.exact_params;
+                    sr.offset = 0;
+                    self.send_search(sr);
+                }
+
+                true
+            },
+
+            ReevesMsg::CopyToClipboard(text) => {
+                info!("Copying {:?} to clipboard", text);
+                copy_to_clipboard(&text);
+
+                false
+            },
+
+            ReevesMsg::ToggleDocs(i) => {
+                if !self.expanded_docs.remove(&i) {
+                    self.expanded_docs.insert(i);
+                }
+
+                true
+            },
+
+            ReevesMsg::ToggleCrate(krate) => {
+                if !self.collapsed_crates.remove(&krate) {
+                    // Don't leave the selection on a result that's no longer visible
+                    if self.selected_result.map_or(false, |i| self.search_results[i].fndetail.krate == krate) {
+                        self.selected_result = None;
+                    }
+                    self.collapsed_crates.insert(krate);
+                }
+
+                true
+            },
+
+            // While suggestions are showing, the keyboard drives them rather than the results
+            ReevesMsg::RenderMore => {
+                if self.rendered_limit >= self.visible_results().len() {
+                    return false
+                }
+                self.rendered_limit += RENDER_CHUNK;
+
+                true
+            },
+
+            ReevesMsg::ToggleHideUnsafe => {
+                self.hide_unsafe = !self.hide_unsafe;
+                self.deselect_hidden();
+
+                true
+            },
+            ReevesMsg::ToggleHideDeprecated => {
+                self.hide_deprecated = !self.hide_deprecated;
+                self.deselect_hidden();
+
+                true
+            },
+
+            ReevesMsg::ReportResult(i) => {
+                let feedback = self.feedback(i, proto::FeedbackKind::BadResult);
+                info!("Reporting bad result {}", feedback.s);
+                self.api.post_feedback(self.msg_callback.clone(), &feedback, i);
+
+                false
+            },
+            ReevesMsg::ReportSent(i) => {
+                self.reported_results.insert(i);
+
+                true
+            },
+            ReevesMsg::OpenResult(i) => {
+                let feedback = self.feedback(i, proto::FeedbackKind::ClickThrough);
+                self.api.post_feedback(self.msg_callback.clone(), &feedback, i);
+
+                false
+            },
+            ReevesMsg::ClickSent => false,
+
+            ReevesMsg::KeyDown(key) if !self.suggestions.is_empty() => match key.as_str() {
+                "ArrowDown" => {
+                    let last = self.suggestions.len() - 1;
+                    self.selected_suggestion = Some(self.selected_suggestion.map_or(0, |i| (i + 1).min(last)));
+                    true
+                },
+                "ArrowUp" => {
+                    self.selected_suggestion = self.selected_suggestion.and_then(|i| i.checked_sub(1));
+                    true
+                },
+                "Enter" => match self.selected_suggestion {
+                    Some(i) => self.update(ReevesMsg::AcceptSuggestion(i)),
+                    None => {
+                        self.clear_suggestions();
+                        self.update(ReevesMsg::KeyDown(key))
+                    },
+                },
+                "Escape" => {
+                    self.clear_suggestions();
+                    true
+                },
+                _ => false,
+            },
+            ReevesMsg::KeyDown(key) => match key.as_str() {
+                "Enter" => match self.selected_result {
+                    Some(i) => {
+                        let docs_url = &self.search_results[i].docs_url;
+                        info!("Opening docs {}", docs_url);
+                        open_url(docs_url);
+                        self.update(ReevesMsg::OpenResult(i))
+                    },
+                    None => self.update(ReevesMsg::SearchRequest),
+                },
+                // Selection moves through results in display order, skipping collapsed crates
+                "ArrowDown" => {
+                    let visible = self.visible_results();
+                    let pos = self.selected_result.and_then(|i| visible.iter().position(|&v| v == i));
+                    let next = pos.map_or(0, |pos| (pos + 1).min(visible.len().saturating_sub(1)));
+                    if let Some(&i) = visible.get(next) {
+                        self.selected_result = Some(i);
+                    }
+                    // Mount the selection if it's gone past what's rendered
+                    while self.rendered_limit <= next {
+                        self.rendered_limit += RENDER_CHUNK;
+                    }
+                    true
+                },
+                // Moving up off the top returns to the inputs, so Enter searches again
+                "ArrowUp" => {
+                    let visible = self.visible_results();
+                    let pos = self.selected_result.and_then(|i| visible.iter().position(|&v| v == i));
+                    self.selected_result = pos.and_then(|pos| pos.checked_sub(1)).map(|pos| visible[pos]);
+                    true
+                },
+                _ => false,
+            },
+
+            ReevesMsg::SeedInputs => {
+                match self.recent_searches.first().cloned() {
+                    Some(recent) => {
+                        self.update(ReevesMsg::ParamsChange(recent.params));
+                        self.update(ReevesMsg::RetChange(recent.ret));
+                        self.crate_filter = recent.krate;
+                    },
+                    // Show off a demo search
+                    None => {
+                        self.update(ReevesMsg::ParamsChange("entry".into()));
+                        self.update(ReevesMsg::RetChange("bool".into()));
+                    },
+                }
+
+                true
+            },
+            ReevesMsg::RecentSearch(i) => {
+                let recent = self.recent_searches[i].clone();
+                self.update(ReevesMsg::ParamsChange(recent.params));
+                self.update(ReevesMsg::RetChange(recent.ret));
+                self.crate_filter = recent.krate;
+                self.update(ReevesMsg::SearchRequest)
+            },
+
+            ReevesMsg::ExampleSearch(i) => {
+                let (params, ret) = EXAMPLE_SEARCHES[i];
+                self.update(ReevesMsg::ParamsChange(params.into()));
+                self.update(ReevesMsg::RetChange(ret.into()));
+                self.crate_filter = None;
+                self.update(ReevesMsg::SearchRequest)
+            },
 
             ReevesMsg::Error(e) => {
                 error!("Nooo: {}", e);
-                self.last_error = Some(e);
+                self.errors.push(e);
+
+                true
+            },
+            ReevesMsg::DismissError(i) => {
+                self.errors.remove(i);
+
+                true
+            },
+            ReevesMsg::RetrySearch => {
+                // Previous results stay visible until the retry comes back
+                if let Some(sr) = self.last_search.clone() {
+                    info!("Retrying search {:?}", sr);
+                    self.errors.clear();
+                    self.send_search(sr);
+                }
 
                 true
             },
@@ -224,6 +950,12 @@ impl Component for ReevesComponent {
         false
     }
 
+    fn rendered(&mut self, _first_render: bool) {
+        if let Some(i) = self.selected_result {
+            scroll_into_view(&result_id(i))
+        }
+    }
+
     fn view(&self) -> Html {
         macro_rules! cb { ($x:expr) => { self.link.callback($x) } }
 
@@ -242,24 +974,50 @@ impl Component for ReevesComponent {
                     </a>
                 </div>
                 <br />
-                { maybenode(self.last_error.as_ref().map(String::as_str), error_div) }
+                { self.view_errors() }
+                { self.view_stats() }
                 <div id="search-pane"><code>
-                    { "fn ???(" }
-                    <input
-                        placeholder="[no params]"
-                        oninput=cb!(|data: InputData| ReevesMsg::ParamsChange(data.value))
-                        value={ &self.params }
-                        ></input>
-                    { ") -> "}
-                    <input
-                        placeholder="[any return type]"
-                        oninput=cb!(|data: InputData| ReevesMsg::RetChange(data.value))
-                        value={ &self.ret }
-                        ></input>
+                    { if self.signature_mode { html!{
+                        <input
+                            class="signature-input"
+                            placeholder="fn(&str, usize) -> Result<_, Error>"
+                            oninput=cb!(|data: InputData| ReevesMsg::SignatureChange(data.value))
+                            onkeydown=cb!(keydown)
+                            value={ &self.signature }
+                            ></input>
+                    } } else { html!{ <>
+                        { "fn ???(" }
+                        <input
+                            placeholder="[no params]"
+                            oninput=self.link.batch_callback(|data: InputData| vec![
+                                ReevesMsg::ParamsChange(data.value),
+                                ReevesMsg::SuggestRequest(SuggestTarget::Params),
+                            ])
+                            onkeydown=cb!(keydown)
+                            value={ &self.params }
+                            ></input>
+                        { ") -> "}
+                        <input
+                            placeholder="[any return type]"
+                            oninput=self.link.batch_callback(|data: InputData| vec![
+                                ReevesMsg::RetChange(data.value),
+                                ReevesMsg::SuggestRequest(SuggestTarget::Ret),
+                            ])
+                            onkeydown=cb!(keydown)
+                            value={ &self.ret }
+                            ></input>
+                    </> } } }
                 </code></div>
-                <small>{ "Use * to indicate '<any>'" }</small>
+                { self.view_suggestions() }
+                <small>
+                    { "Use * to indicate '<any>' " }
+                    <a href="#" onclick=cb!(|e| href(e, ReevesMsg::ToggleSignatureMode))>
+                        { if self.signature_mode { "[separate inputs]" } else { "[signature input]" } }
+                    </a>
+                </small>
                 <div id="parsed-pane">
                     <h2>{ "Parsed search" }</h2>
+                    { maybenode(self.parse_error.as_ref(), |e| html!{ <div class="parse-error">{ e }</div> }) }
                     <div>
                         { "Params (any order): " }
                         { match self.parsed_params.as_ref() {
@@ -277,23 +1035,474 @@ impl Component for ReevesComponent {
                         } }
                     </div>
                 </div>
+                { self.view_recent_searches() }
                 <button onclick=cb!(|_| ReevesMsg::SearchRequest)>{ "Search" }</button>
+                { " Sort by " }
+                <select onchange=cb!(|data: ChangeData| match data {
+                    ChangeData::Select(select) => match proto::SortOrder::ALL.get(select.selected_index() as usize) {
+                        Some(&sort) => ReevesMsg::SortChange(sort),
+                        None => ReevesMsg::Error(format!("unknown sort option {}", select.selected_index())),
+                    },
+                    _ => ReevesMsg::Error("unexpected change event for sort".into()),
+                })>
+                    { for proto::SortOrder::ALL.iter().map(|&sort| html!{
+                        <option selected={ sort == self.sort }>{ sort.name() }</option>
+                    }) }
+                </select>
+                <label>
+                    <input type="checkbox" checked={ self.exact_params } onclick=cb!(|_| ReevesMsg::ToggleExactParams) />
+                    { "exact param count" }
+                </label>
+                { ifnode(self.active_search.is_some(), || html!{ <span class="spinner" title="Searching..."></span> }) }
+            </div>
+            { if self.detail_route.is_some() { self.view_detail() } else { html!{
+                <div id="results-area">
+                { self.view_facets() }
+                <div id="results-pane" tabindex="0" onkeydown=cb!(keydown)>
+                    { self.view_crate_status() }
+                    { self.view_examples() }
+                    { self.view_pagination() }
+                    { self.view_result_filters() }
+                    { self.view_results() }
+                    { self.view_pagination() }
+                </div>
+                </div>
+            } } }
+        </> }
+    }
+}
+
+impl ReevesComponent {
+    fn send_search(&mut self, sr: proto::SearchRequest) {
+        if let Some(search) = self.active_search.take() {
+            search.cancel()
+        }
+        self.active_search = Some(self.api.post_search(self.msg_callback.clone(), &sr));
+        self.last_search = Some(sr);
+    }
+
+    fn add_recent_search(&mut self) {
+        let recent = RecentSearch { params: self.params.clone(), ret: self.ret.clone(), krate: self.crate_filter.clone() };
+        self.recent_searches.retain(|r| r != &recent);
+        self.recent_searches.insert(0, recent);
+        self.recent_searches.truncate(MAX_RECENT_SEARCHES);
+        if let Some(storage) = self.storage.as_mut() {
+            storage.store(RECENT_SEARCHES_KEY, Json(&self.recent_searches))
+        }
+    }
+
+    fn clear_suggestions(&mut self) {
+        if let Some(suggest) = self.active_suggest.take() {
+            suggest.cancel()
+        }
+        self.suggestions.clear();
+        self.suggest_target = None;
+        self.selected_suggestion = None;
+    }
+
+    // The part of an input that suggestions are for
+    fn suggest_fragment(&self, target: SuggestTarget) -> &str {
+        match target {
+            SuggestTarget::Params => self.params.rsplit(',').next().unwrap_or(""),
+            SuggestTarget::Ret => &self.ret,
+        }
+    }
+
+    fn update_url(&self) {
+        let mut query = vec![("params", self.params.as_str()), ("ret", self.ret.as_str())];
+        if let Some(krate) = self.crate_filter.as_ref() {
+            query.push(("crate", krate))
+        }
+        set_url_query(&query)
+    }
+
+    // Feedback on a result of the last search
+    fn feedback(&self, i: usize, kind: proto::FeedbackKind) -> proto::FeedbackRequest {
+        let fndetail = &self.search_results[i].fndetail;
+        let (params, ret) = match self.last_search.as_ref() {
+            Some(sr) => (sr.params.clone(), sr.ret.clone()),
+            None => (None, None),
+        };
+        proto::FeedbackRequest {
+            params,
+            ret,
+            krate: fndetail.krate.clone(),
+            version: fndetail.version.clone(),
+            path: fndetail.path.clone(),
+            s: fndetail.s.clone(),
+            kind,
+        }
+    }
+
+    fn is_hidden(&self, fndetail: &FnDetail) -> bool {
+        (self.hide_unsafe && fndetail.is_unsafe) || (self.hide_deprecated && fndetail.is_deprecated)
+    }
+
+    fn deselect_hidden(&mut self) {
+        if self.selected_result.map_or(false, |i| self.is_hidden(&self.search_results[i].fndetail)) {
+            self.selected_result = None;
+        }
+    }
+
+    // Results from the same crate are shown together, in order of the crate's first result
+    fn result_groups(&self) -> Vec<ResultGroup> {
+        let mut groups: Vec<ResultGroup> = vec![];
+        for (i, hit) in self.search_results.iter().enumerate() {
+            if self.is_hidden(&hit.fndetail) {
+                continue
+            }
+            match groups.iter_mut().find(|g| g.krate == hit.fndetail.krate) {
+                Some(group) => group.results.push(i),
+                None => groups.push(ResultGroup {
+                    krate: hit.fndetail.krate.clone(),
+                    version: hit.fndetail.version.clone(),
+                    results: vec![i],
+                }),
+            }
+        }
+        groups
+    }
+
+    // Indexes of results the user can see, in the order they're displayed
+    fn visible_results(&self) -> Vec<usize> {
+        self.result_groups().into_iter()
+            .filter(|g| !self.collapsed_crates.contains(&g.krate))
+            .flat_map(|g| g.results)
+            .collect()
+    }
+
+    fn view_results(&self) -> Html {
+        let mut budget = self.rendered_limit;
+        let mut nodes = vec![];
+        for group in self.result_groups() {
+            if budget == 0 {
+                break
+            }
+            let shown = if self.collapsed_crates.contains(&group.krate) { 0 } else { group.results.len().min(budget) };
+            budget -= shown;
+            nodes.push(self.view_group(group, shown));
+        }
+        html!{ <>{ for nodes }</> }
+    }
+
+    fn view_crate_status(&self) -> Html {
+        let krate = self.crate_filter.as_deref().unwrap_or("");
+        let message = match self.crate_status.as_ref() {
+            None | Some(proto::IndexStatus::Indexed { .. }) => return nilnode(),
+            Some(proto::IndexStatus::NotIndexed) => format!("{} isn't indexed", krate),
+            Some(proto::IndexStatus::QueueFull) => format!("{} isn't indexed, and too many crates are waiting to be - try again later", krate),
+            Some(proto::IndexStatus::Queued { version }) => format!("{} {} isn't indexed yet, it's queued for analysis - search again in a few minutes", krate, version),
+            Some(proto::IndexStatus::Analyzing { version }) => format!("{} {} is being analyzed - search again in a few minutes", krate, version),
+            Some(proto::IndexStatus::Failed { version, err: _ }) => format!("{} {} couldn't be analyzed", krate, version),
+        };
+        html!{ <p class="crate-status">{ message }</p> }
+    }
+
+    // Only the first `shown` results of the group are mounted
+    fn view_group(&self, group: ResultGroup, shown: usize) -> Html {
+        let collapsed = self.collapsed_crates.contains(&group.krate);
+        // The facet covers all pages, rather than just the results we have loaded
+        let count = self.search_facets.iter().find(|f| f.krate == group.krate)
+            .map_or(group.results.len(), |f| f.count);
+        let krate = group.krate.clone();
+        html!{
+            <div class="result-group">
+                <h2 class="result-group-header">
+                    <a href="#" class="group-toggle" onclick=self.link.callback(move |e| href(e, ReevesMsg::ToggleCrate(krate.clone())))>
+                        { if collapsed { "▶" } else { "▼" } }
+                    </a>
+                    { " " }
+                    <a href={ crate_url(&group.krate, &group.version) } title="Version analysed for the index">
+                        { format!("{} {}", group.krate, group.version) }
+                    </a>
+                    { format!(" ({} {})", count, if count == 1 { "hit" } else { "hits" }) }
+                </h2>
+                { ifnode(!collapsed, || html!{
+                    <>{ for group.results[..shown].iter().map(|&i| self.view_result(i)) }</>
+                }) }
             </div>
-            <div id="results-pane">
-                {
-                    for self.search_results.iter().map(|fndetail| {
-                        html!{
-                            <div>
-                                <a href={ format!("https://crates.io/crates/{}", fndetail.krate) }>
-                                    { &fndetail.krate }
-                                </a>
+        }
+    }
+
+    fn view_result(&self, i: usize) -> Html {
+        let proto::SearchHit { fndetail, docs_url, partial, ownership_variant } = &self.search_results[i];
+        let selected = self.selected_result == Some(i);
+        let path = fndetail.path.clone();
+        let use_stmt = use_statement(&fndetail.path);
+        let use_title = use_stmt.clone();
+        html!{
+            <div id={ result_id(i) } class={ if selected { "result selected" } else { "result" } }>
+                { view_badges(fndetail) }
+                { ifnode(*partial, || html!{
+                    <span class="badge badge-partial" title="Matched an element of a tuple or array">{ "partial" }</span>
+                }) }
+                { ifnode(*ownership_variant, || html!{
+                    <span class="badge badge-partial" title="Returns the searched for type behind a different reference or smart pointer">{ "ownership" }</span>
+                }) }
+                <code class={ if fndetail.is_deprecated { "deprecated" } else { "" } }>{ &fndetail.s }</code>
+                { " " }
+                <a class="result-link" href={ docs_url } title={ format!("Documentation for {}", fndetail.path) }
+                    onclick=self.link.callback(move |_| ReevesMsg::OpenResult(i))>
+                    { "[docs]" }
+                </a>
+                { " " }
+                <a class="result-link" href={ fn_permalink(fndetail) } title="Permalink to this function">
+                    { "[link]" }
+                </a>
+                { " " }
+                <button class="copy-button" title="Copy path"
+                    onclick=self.link.callback(move |_| ReevesMsg::CopyToClipboard(path.clone()))
+                    >{ "copy path" }</button>
+                <button class="copy-button" title={ use_title }
+                    onclick=self.link.callback(move |_| ReevesMsg::CopyToClipboard(use_stmt.clone()))
+                    >{ "copy use" }</button>
+                { if self.reported_results.contains(&i) { html!{
+                    <span class="report-button reported" title="Thanks for the report">{ "⚑" }</span>
+                } } else { html!{
+                    <button class="report-button" title="Report bad result"
+                        onclick=self.link.callback(move |_| ReevesMsg::ReportResult(i))
+                        >{ "⚑" }</button>
+                } } }
+                { maybenode(fndetail.doc_summary.as_ref(), |summary| {
+                    let expanded = self.expanded_docs.contains(&i);
+                    html!{
+                        <div
+                            class={ if expanded { "doc-summary expanded" } else { "doc-summary" } }
+                            title={ if expanded { "Click to collapse" } else { "Click to expand" } }
+                            onclick=self.link.callback(move |_| ReevesMsg::ToggleDocs(i))
+                            >{ summary }</div>
+                    }
+                }) }
+            </div>
+        }
+    }
+
+    fn view_detail(&self) -> Html {
+        let detail = match self.detail.as_ref() {
+            Some(detail) => detail,
+            None => return html!{
+                <div id="detail-pane">
+                    <a href="#">{ "← back to results" }</a>
+                    { ifnode(self.active_detail.is_some(), || html!{ <span class="spinner" title="Loading..."></span> }) }
+                </div>
+            },
+        };
+        let proto::SearchHit { fndetail, docs_url, .. } = &detail.hit;
+        let use_stmt = use_statement(&fndetail.path);
+        html!{
+            <div id="detail-pane">
+                <a href="#">{ "← back to results" }</a>
+                <h2>
+                    <a href={ crate_url(&fndetail.krate, &fndetail.version) }>
+                        { format!("{} {}", fndetail.krate, fndetail.version) }
+                    </a>
+                </h2>
+                <div>{ view_badges(fndetail) }</div>
+                <pre class="detail-signature"><code>{ &fndetail.s }</code></pre>
+                <pre><code>{ &use_stmt }</code></pre>
+                { maybenode(fndetail.doc_summary.as_ref(), |summary| html!{ <p class="detail-docs">{ summary }</p> }) }
+                { maybenode(fndetail.reexported_from.as_ref(), |from| html!{
+                    <p class="detail-reexport">{ format!("Re-exported from {}", from) }</p>
+                }) }
+                { ifnode(!fndetail.module.is_empty(), || html!{
+                    <p class="detail-module">{ "Defined in " }<code>{ &fndetail.module }</code></p>
+                }) }
+                { maybenode(fndetail.example.as_ref(), |example| html!{
+                    <>
+                        <h3>{ "Example usage" }</h3>
+                        <pre class="detail-example"><code>{ example }</code></pre>
+                    </>
+                }) }
+                <div>
+                    <a href={ docs_url }>{ "Documentation" }</a>
+                    { " | " }
+                    <a href={ source_url(fndetail) }>{ "Browse source" }</a>
+                </div>
+                <h2>{ "Similar functions" }</h2>
+                { if detail.similar.is_empty() { html!{ <p>{ "None found" }</p> } } else { html!{
+                    <ul id="similar-fns">
+                        { for detail.similar.iter().map(|proto::SearchHit { fndetail, .. }| html!{
+                            <li>
+                                <a href={ fn_permalink(fndetail) }>{ &fndetail.krate }</a>
                                 { " " }
                                 <code>{ &fndetail.s }</code>
-                            </div>
-                        }
-                    })
-                }
+                            </li>
+                        }) }
+                    </ul>
+                } } }
             </div>
-        </> }
+        }
+    }
+
+    fn view_result_filters(&self) -> Html {
+        if self.search_results.is_empty() {
+            return nilnode()
+        }
+        html!{
+            <div id="result-filters">
+                <label>
+                    <input type="checkbox" checked={ self.hide_unsafe } onclick=self.link.callback(|_| ReevesMsg::ToggleHideUnsafe) />
+                    { "hide unsafe" }
+                </label>
+                <label>
+                    <input type="checkbox" checked={ self.hide_deprecated } onclick=self.link.callback(|_| ReevesMsg::ToggleHideDeprecated) />
+                    { "hide deprecated" }
+                </label>
+            </div>
+        }
+    }
+
+    fn view_suggestions(&self) -> Html {
+        if self.suggestions.is_empty() {
+            return nilnode()
+        }
+        html!{
+            <ul id="suggestions">
+                { for self.suggestions.iter().enumerate().map(|(i, ty)| html!{
+                    <li
+                        class={ if self.selected_suggestion == Some(i) { "selected" } else { "" } }
+                        onclick=self.link.callback(move |_| ReevesMsg::AcceptSuggestion(i))
+                        ><code>{ ty }</code></li>
+                }) }
+            </ul>
+        }
+    }
+
+    fn view_stats(&self) -> Html {
+        maybenode(self.stats.as_ref(), |stats| {
+            let updated = match stats.last_updated_ms {
+                Some(last_updated_ms) => {
+                    let days = (now_ms() as u64).saturating_sub(last_updated_ms) / (24 * 60 * 60 * 1000);
+                    match days {
+                        0 => "index updated today".to_owned(),
+                        1 => "index updated 1 day ago".to_owned(),
+                        days => format!("index updated {} days ago", days),
+                    }
+                },
+                // Nothing saved since the db started recording update times
+                None => "index update time unknown".to_owned(),
+            };
+            html!{
+                <div id="stats-banner">
+                    { format!("{} crates, {} functions; {}", stats.num_crates, stats.num_fns, updated) }
+                </div>
+            }
+        })
+    }
+
+    fn view_recent_searches(&self) -> Html {
+        if self.recent_searches.is_empty() {
+            return nilnode()
+        }
+        html!{
+            <div id="recent-pane">
+                { "Recent: " }
+                { for self.recent_searches.iter().enumerate().map(|(i, recent)| html!{
+                    <button class="chip" onclick=self.link.callback(move |_| ReevesMsg::RecentSearch(i))>
+                        <code>{ recent.label() }</code>
+                    </button>
+                }) }
+            </div>
+        }
+    }
+
+    fn view_examples(&self) -> Html {
+        if !self.search_results.is_empty() || self.active_search.is_some() {
+            return nilnode()
+        }
+        html!{
+            <div id="examples-pane">
+                <h2>{ "Try an example" }</h2>
+                { for EXAMPLE_SEARCHES.iter().enumerate().map(|(i, (params, ret))| html!{
+                    <button class="chip" onclick=self.link.callback(move |_| ReevesMsg::ExampleSearch(i))>
+                        <code>{ format!("fn ???({}) -> {}", params, ret) }</code>
+                    </button>
+                }) }
+            </div>
+        }
+    }
+
+    fn view_errors(&self) -> Html {
+        if self.errors.is_empty() {
+            return nilnode()
+        }
+        html!{
+            <div id="toasts">
+                { for self.errors.iter().enumerate().map(|(i, e)| html!{
+                    <div class="error toast">
+                        { format!("ERROR: {}", e) }
+                        { ifnode(self.last_search.is_some(), || html!{
+                            <button onclick=self.link.callback(|_| ReevesMsg::RetrySearch)>{ "Retry" }</button>
+                        }) }
+                        <button title="Dismiss" onclick=self.link.callback(move |_| ReevesMsg::DismissError(i))>{ "×" }</button>
+                    </div>
+                }) }
+            </div>
+        }
+    }
+
+    fn view_facets(&self) -> Html {
+        if self.search_facets.is_empty() {
+            return nilnode()
+        }
+        let facet = |krate: Option<&str>, label: String| {
+            let selected = self.crate_filter.as_deref() == krate;
+            let krate = krate.map(str::to_owned);
+            html!{
+                <li class={ if selected { "selected" } else { "" } }>
+                    <a href="#" onclick=self.link.callback(move |e| href(e, ReevesMsg::CrateFilter(krate.clone())))>{ label }</a>
+                </li>
+            }
+        };
+        let num_hits: usize = self.search_facets.iter().map(|f| f.count).sum();
+        // Std first, as it's always available, then the ecosystem crates most used first
+        let (std_facets, mut ecosystem_facets): (Vec<_>, Vec<_>) = self.search_facets.iter().partition(|f| f.is_std);
+        ecosystem_facets.sort_by(|f1, f2| f2.recent_downloads.cmp(&f1.recent_downloads));
+        let section = |title: &str, facets: Vec<&proto::CrateFacet>| {
+            if facets.is_empty() {
+                return nilnode()
+            }
+            html!{
+                <>
+                    <h3>{ title }</h3>
+                    <ul>
+                        { for facets.into_iter().map(|f| facet(Some(&f.krate), format!("{} ({})", f.krate, f.count))) }
+                    </ul>
+                </>
+            }
+        };
+        html!{
+            <div id="facet-pane">
+                <h2>{ "Crates" }</h2>
+                <ul>
+                    { facet(None, format!("all crates ({})", num_hits)) }
+                </ul>
+                { section("Standard library", std_facets) }
+                { section("Ecosystem", ecosystem_facets) }
+            </div>
+        }
+    }
+
+    fn view_pagination(&self) -> Html {
+        if self.search_total == 0 {
+            return nilnode()
+        }
+        let start = self.search_offset;
+        let end = start + self.search_results.len();
+        let prev_offset = start.saturating_sub(PAGE_SIZE);
+        html!{
+            <div class="pagination">
+                <button
+                    disabled={ start == 0 }
+                    onclick=self.link.callback(move |_| ReevesMsg::PageRequest(prev_offset))
+                    >{ "Previous" }</button>
+                { format!(" Showing {}–{} of {}{} ({}ms) ", start + 1, end, self.search_total,
+                    if self.search_truncated { "+" } else { "" }, self.search_time_ms) }
+                <button
+                    disabled={ end >= self.search_total }
+                    onclick=self.link.callback(move |_| ReevesMsg::PageRequest(end))
+                    >{ "Next" }</button>
+            </div>
+        }
     }
 }
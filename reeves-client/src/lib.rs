@@ -0,0 +1,178 @@
+// A typed client for the reeves API, for the page and any other tools wanting to search. Requests are made with isahc
+// natively and with fetch when compiled to wasm
+
+use serde::de::DeserializeOwned;
+use std::fmt;
+
+use reeves_types::*;
+
+#[derive(Debug)]
+pub enum Error {
+    // The request didn't get a response, e.g. the server is unreachable
+    Fetch(String),
+    // The server responded with something that wasn't the expected bincode
+    InvalidResponse(String),
+    Api(proto::ApiError),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Fetch(msg) => write!(f, "error on fetch: {}", msg),
+            Error::InvalidResponse(msg) => write!(f, "invalid response: {}", msg),
+            Error::Api(e) => e.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<proto::ApiError> for Error {
+    fn from(e: proto::ApiError) -> Self {
+        Error::Api(e)
+    }
+}
+
+struct Response {
+    status: u16,
+    protocol_version: Option<String>,
+    body: Vec<u8>,
+}
+
+#[derive(Clone)]
+pub struct Client {
+    base_url: String, // where the server is mounted, without the API_PREFIX
+    #[cfg(target_arch = "wasm32")]
+    signal: Option<web_sys::AbortSignal>,
+}
+
+impl Client {
+    pub fn new(base_url: &str) -> Self {
+        Self {
+            base_url: base_url.trim_end_matches('/').to_owned(),
+            #[cfg(target_arch = "wasm32")]
+            signal: None,
+        }
+    }
+
+    // Requests made by the returned client are aborted when the signal is
+    #[cfg(target_arch = "wasm32")]
+    pub fn with_abort_signal(mut self, signal: web_sys::AbortSignal) -> Self {
+        self.signal = Some(signal);
+        self
+    }
+
+    pub async fn search(&self, request: &proto::SearchRequest) -> Result<proto::SearchResult, Error> {
+        self.post::<proto::Search>(request).await
+    }
+
+    pub async fn search_batch(&self, request: &proto::BatchSearchRequest) -> Result<proto::BatchSearchResult, Error> {
+        self.post::<proto::BatchSearch>(request).await
+    }
+
+    pub async fn crate_detail(&self, krate: &str) -> Result<proto::CrateDetailResult, Error> {
+        self.post::<proto::CrateDetail>(&proto::CrateDetailRequest { krate: krate.to_owned() }).await
+    }
+
+    pub async fn feedback(&self, request: &proto::FeedbackRequest) -> Result<(), Error> {
+        self.post::<proto::Feedback>(request).await
+    }
+
+    pub async fn suggest(&self, q: &str) -> Result<proto::SuggestResult, Error> {
+        self.get(&format!("{}?q={}", proto::SUGGEST_PATH, encode_component(q))).await
+    }
+
+    pub async fn stats(&self) -> Result<proto::StatsResult, Error> {
+        self.get(proto::STATS_PATH).await
+    }
+
+    pub async fn index_status(&self, krate: &str) -> Result<proto::IndexStatus, Error> {
+        self.get(&format!("{}/{}", proto::INDEX_STATUS_PATH, encode_component(krate))).await
+    }
+
+    // The hash is the path_hash of the function's path
+    pub async fn fn_detail(&self, krate: &str, hash: &str) -> Result<proto::FnDetailResult, Error> {
+        self.get(&format!("{}/{}/{}", proto::FN_PATH, encode_component(krate), encode_component(hash))).await
+    }
+
+    pub async fn post<E: proto::Endpoint>(&self, request: &E::Request) -> Result<E::Response, Error> {
+        let body = bincode::serialize(request).unwrap();
+        self.request("POST", E::PATH, Some(body)).await
+    }
+
+    async fn get<T: DeserializeOwned>(&self, path: &str) -> Result<T, Error> {
+        self.request("GET", path, None).await
+    }
+
+    // Successful responses are bincode of T, failures (ideally) bincode of an ErrorResponse
+    async fn request<T: DeserializeOwned>(&self, method: &str, path: &str, body: Option<Vec<u8>>) -> Result<T, Error> {
+        let url = format!("{}{}{}", self.base_url, proto::API_PREFIX, path);
+        let res = self.fetch(method, &url, body).await.map_err(Error::Fetch)?;
+        // A different server's responses can't be decoded, so stop before trying
+        if let Some(server_version) = res.protocol_version {
+            if server_version != proto::PROTOCOL_VERSION.to_string() {
+                let server = server_version.parse().map_err(|_| Error::InvalidResponse(format!("invalid {} header", proto::PROTOCOL_VERSION_HEADER)))?;
+                return Err(proto::ApiError::ProtocolMismatch { server, client: proto::PROTOCOL_VERSION }.into())
+            }
+        }
+        if (200..300).contains(&res.status) {
+            bincode::deserialize(&res.body).map_err(|e| Error::InvalidResponse(e.to_string()))
+        } else {
+            match bincode::deserialize::<proto::ErrorResponse>(&res.body) {
+                Ok(proto::ErrorResponse { err }) => Err(err.into()),
+                Err(_) => Err(Error::InvalidResponse(format!("{} (unreadable error body)", res.status))),
+            }
+        }
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    async fn fetch(&self, method: &str, url: &str, body: Option<Vec<u8>>) -> Result<Response, String> {
+        use futures::io::AsyncReadExt;
+        use isahc::prelude::*;
+
+        let mut request = isahc::Request::builder()
+            .method(method)
+            .uri(url)
+            .header(proto::PROTOCOL_VERSION_HEADER, proto::PROTOCOL_VERSION.to_string());
+        if body.is_some() {
+            request = request.header("Content-Type", "application/octet-stream");
+        }
+        let request = request.body(body.unwrap_or_default()).map_err(|e| e.to_string())?;
+        let mut res = request.send_async().await.map_err(|e| e.to_string())?;
+        let protocol_version = res.headers().get(proto::PROTOCOL_VERSION_HEADER)
+            .map(|v| v.to_str().unwrap_or("").to_owned());
+        let mut body = vec![];
+        res.body_mut().read_to_end(&mut body).await
+            .map_err(|e| format!("{} (body error: {})", res.status(), e))?;
+        Ok(Response { status: res.status().as_u16(), protocol_version, body })
+    }
+
+    #[cfg(target_arch = "wasm32")]
+    async fn fetch(&self, method: &str, url: &str, body: Option<Vec<u8>>) -> Result<Response, String> {
+        use wasm_bindgen::{JsCast, JsValue};
+        use wasm_bindgen_futures::JsFuture;
+
+        fn js_err(e: JsValue) -> String {
+            format!("{:?}", e)
+        }
+
+        let body: Option<JsValue> = body.map(|body| js_sys::Uint8Array::from(&body[..]).into());
+        let mut init = web_sys::RequestInit::new();
+        init.method(method).body(body.as_ref()).signal(self.signal.as_ref());
+        let request = web_sys::Request::new_with_str_and_init(url, &init).map_err(js_err)?;
+        request.headers().set(proto::PROTOCOL_VERSION_HEADER, &proto::PROTOCOL_VERSION.to_string()).map_err(js_err)?;
+        if body.is_some() {
+            request.headers().set("Content-Type", "application/octet-stream").map_err(js_err)?;
+        }
+
+        let window = web_sys::window().expect("failed to retrieve window");
+        let response: web_sys::Response = JsFuture::from(window.fetch_with_request(&request)).await
+            .map_err(js_err)?
+            .dyn_into().expect("fetch didn't return a response");
+        let protocol_version = response.headers().get(proto::PROTOCOL_VERSION_HEADER).map_err(js_err)?;
+        let buf = JsFuture::from(response.array_buffer().map_err(js_err)?).await
+            .map_err(|e| format!("{} (body error: {})", response.status(), js_err(e)))?;
+        let body = js_sys::Uint8Array::new(&buf).to_vec();
+        Ok(Response { status: response.status(), protocol_version, body })
+    }
+}
@@ -1,30 +1,1561 @@
 use serde::{Serialize, Deserialize};
+use std::cmp::Ordering;
+use std::hash::{Hash, Hasher};
 
+// Also archived with rkyv, for reading in place from the db
 #[derive(Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[derive(rkyv::Archive, rkyv::Serialize, rkyv::Deserialize)]
+#[archive_attr(derive(bytecheck::CheckBytes))]
 #[serde(deny_unknown_fields)]
 #[derive(Debug)]
 pub struct FnDetail {
     pub krate: String,
+    pub version: String,
+    pub path: String, // fully qualified, starting with the crate import name, e.g. tar::Header::new_gnu
+    // The last segment of path, e.g. new_gnu
+    pub name: String,
+    pub kind: ItemKind,
     pub params: Vec<String>,
     pub ret: String,
+    // The same types as params and ret, for searches that need to look inside them
+    pub param_tys: Vec<ty::Type>,
+    pub ret_ty: ty::Type,
     pub s: String,
+    pub doc_summary: Option<String>, // first paragraph of the doc comment
+    pub is_unsafe: bool,
+    pub is_async: bool,
+    pub is_const: bool,
+    pub is_deprecated: bool,
+    // A call from the crate's examples or tests, a few lines long. Found by matching names rather than types, so it
+    // may occasionally be a call to a different fn of the same name
+    pub example: Option<String>,
+    // The crate that defines the fn when krate only re-exports it, e.g. futures_util for futures::future::join
+    pub reexported_from: Option<String>,
+    // The module the fn is defined in, which path may not go through, e.g. tar::header for tar::Header::new_gnu. Empty
+    // for analyses from before it was recorded
+    pub module: String,
+    pub source: Option<SourceSpan>,
+}
+
+// Where a fn is defined
+#[derive(Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[derive(rkyv::Archive, rkyv::Serialize, rkyv::Deserialize)]
+#[archive_attr(derive(bytecheck::CheckBytes))]
+#[serde(deny_unknown_fields)]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SourceSpan {
+    pub file: String, // relative to the crate's root directory, e.g. src/header.rs
+    pub start_line: u32, // 1-based and inclusive, covering the fn's doc comment and attributes
+    pub end_line: u32,
+}
+
+impl FnDetail {
+    // e.g. encode(&[u8]) -> String
+    pub fn short_signature(&self) -> String {
+        format!("{}({}){}", self.name, self.params.join(", "), self.ret_suffix())
+    }
+
+    // e.g. &Header.set_size(u64), or None if this isn't a method
+    pub fn method_call_signature(&self) -> Option<String> {
+        if self.kind != ItemKind::Method {
+            return None
+        }
+        let (receiver, params) = self.params.split_first()?;
+        Some(format!("{}.{}({}){}", receiver, self.name, params.join(", "), self.ret_suffix()))
+    }
+
+    // e.g. unsafe fn tar::Header::from_byte_slice(&[u8]) -> &Header
+    pub fn qualified_signature(&self) -> String {
+        let qualifiers: String = [(self.is_const, "const "), (self.is_async, "async "), (self.is_unsafe, "unsafe ")].iter()
+            .filter(|(is, _)| *is)
+            .map(|(_, q)| *q)
+            .collect();
+        format!("{}fn {}({}){}", qualifiers, self.path, self.params.join(", "), self.ret_suffix())
+    }
+
+    fn ret_suffix(&self) -> String {
+        if self.ret == "()" { String::new() } else { format!(" -> {}", self.ret) }
+    }
+
+    // What makes two fndetails the same function - everything else is derived from these
+    fn key(&self) -> (&str, &str, &str, &[String], &str) {
+        (&self.krate, &self.version, &self.path, &self.params, &self.ret)
+    }
+}
+
+impl PartialEq for FnDetail {
+    fn eq(&self, other: &Self) -> bool {
+        self.key() == other.key()
+    }
+}
+
+impl Eq for FnDetail {}
+
+// By crate, then version, then path, then params and return type
+impl Ord for FnDetail {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.key().cmp(&other.key())
+    }
+}
+
+impl PartialOrd for FnDetail {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Hash for FnDetail {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.key().hash(state)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[derive(rkyv::Archive, rkyv::Serialize, rkyv::Deserialize)]
+#[archive_attr(derive(bytecheck::CheckBytes))]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ItemKind {
+    Function, // a free function
+    Method, // an associated function taking self
+    AssocFn, // an associated function not taking self, e.g. a constructor
+    // Not a single function, but a builder's constructor followed by its build method, e.g. FooBuilder::new().build().
+    // The path is the constructor's followed by the build method, e.g. foo::FooBuilder::new().build, so it's distinct
+    // from the build method's own entry, and the params are the constructor's
+    BuilderChain,
+}
+
+impl ItemKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ItemKind::Function => "fn",
+            ItemKind::Method => "method",
+            ItemKind::AssocFn => "associated fn",
+            ItemKind::BuilderChain => "builder chain",
+        }
+    }
+}
+
+// FNV-1a, for ids that must be the same everywhere and across versions (unlike with DefaultHasher)
+pub fn fnv1a(s: &str) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for b in s.bytes() {
+        hash ^= b as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+// Identifies a function within its crate in permalinks
+pub fn path_hash(path: &str) -> String {
+    format!("{:016x}", fnv1a(path))
+}
+
+// Like encodeURIComponent, so it works the same everywhere
+pub fn encode_component(s: &str) -> String {
+    let mut encoded = String::new();
+    for b in s.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'!' | b'~' | b'*' | b'\'' | b'(' | b')' => encoded.push(b as char),
+            b => encoded.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    encoded
+}
+
+// The reverse of encode_component, or None if s isn't validly encoded
+pub fn decode_component(s: &str) -> Option<String> {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = s.get(i + 1..i + 3)?;
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+// Crates analyzed from git are saved as git+<url>#<commit>, with :<package> after for each package of a workspace.
+// (url, commit, package) if krate is one
+pub fn git_crate_source(krate: &str) -> Option<(&str, &str, Option<&str>)> {
+    let (url, rev) = krate.strip_prefix("git+")?.rsplit_once('#')?;
+    match rev.split_once(':') {
+        Some((commit, package)) => Some((url, commit, Some(package))),
+        None => Some((url, rev, None)),
+    }
+}
+
+// The standard library crates that get indexed from the sysroot
+pub const SYSROOT_CRATES: &[&str] = &["std", "core", "alloc"];
+
+// Whether a crate in the db is a standard library crate, saved as <crate>@<rustc version>
+pub fn is_sysroot_crate(krate_name: &str) -> bool {
+    krate_name.split_once('@').map_or(false, |(name, _)| SYSROOT_CRATES.contains(&name))
 }
 
 pub mod proto {
     use super::*;
+    use std::fmt;
+
+    // Bump when the wire format of anything in here (or FnDetail) changes
+    pub const PROTOCOL_VERSION: u32 = 29;
+    // Sent on every API response and by clients on requests, so each side can detect the other speaking a different
+    // protocol. Servers reject mismatched requests with a 409
+    pub const PROTOCOL_VERSION_HEADER: &str = "X-Reeves-Protocol";
+    pub const API_PREFIX: &str = "/api/v1";
+    // GET endpoints, under API_PREFIX
+    pub const SUGGEST_PATH: &str = "/suggest"; // ?q=<partial type>, returning a SuggestResult
+    pub const STATS_PATH: &str = "/stats"; // returning a StatsResult
+    pub const FN_PATH: &str = "/fn"; // /<crate>/<path_hash>, returning a FnDetailResult
+    pub const INDEX_STATUS_PATH: &str = "/index-status"; // /<crate>, returning an IndexStatus
+
+    // A POST endpoint under API_PREFIX, taking and returning bincode. Failures return an ErrorResponse
+    pub trait Endpoint {
+        const PATH: &'static str;
+        type Request: Serialize + for<'de> Deserialize<'de>;
+        type Response: Serialize + for<'de> Deserialize<'de>;
+    }
+
+    pub struct Search;
+    impl Endpoint for Search {
+        const PATH: &'static str = "/search";
+        type Request = SearchRequest;
+        type Response = SearchResult;
+    }
+
+    pub struct BatchSearch;
+    impl Endpoint for BatchSearch {
+        const PATH: &'static str = "/search/batch";
+        type Request = BatchSearchRequest;
+        type Response = BatchSearchResult;
+    }
+
+    pub struct CrateDetail;
+    impl Endpoint for CrateDetail {
+        const PATH: &'static str = "/crate";
+        type Request = CrateDetailRequest;
+        type Response = CrateDetailResult;
+    }
+
+    pub struct Feedback;
+    impl Endpoint for Feedback {
+        const PATH: &'static str = "/feedback";
+        type Request = FeedbackRequest;
+        type Response = ();
+    }
 
     #[derive(Serialize, Deserialize)]
+    #[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
     #[serde(deny_unknown_fields)]
-    #[derive(Debug)]
+    #[derive(Clone, Debug)]
     pub struct SearchRequest {
         pub params: Option<Vec<String>>,
         pub ret: Option<String>,
+        pub krate: Option<String>, // only return results from this crate
+        pub offset: usize,
+        pub limit: Option<usize>, // all remaining results if None
+        pub sort: SortOrder,
+        // Only fns with as many params as the query, rather than ones with extra params too
+        pub exact_params: bool,
     }
 
     #[derive(Serialize, Deserialize)]
+    #[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    pub enum SortOrder {
+        Relevance, // the order search finds them in
+        Crate,
+        Function,
+        Popularity, // std first, then crates by recent downloads, falling back to the most matches without download counts
+    }
+
+    impl SortOrder {
+        pub const ALL: &'static [SortOrder] = &[SortOrder::Relevance, SortOrder::Crate, SortOrder::Function, SortOrder::Popularity];
+
+        pub fn name(self) -> &'static str {
+            match self {
+                SortOrder::Relevance => "relevance",
+                SortOrder::Crate => "crate name",
+                SortOrder::Function => "function name",
+                SortOrder::Popularity => "popularity",
+            }
+        }
+
+        // Stable, so ties stay in relevance order. Items are sorted on their (crate, path), and facets are needed for
+        // popularity
+        pub fn sort<T>(self, items: &mut [T], krate_and_path: impl Fn(&T) -> (&str, &str), facets: &[CrateFacet]) {
+            let fn_name = |item: &T| {
+                let path = krate_and_path(item).1;
+                path.rsplit("::").next().unwrap_or(path).to_owned()
+            };
+            let crate_popularity = |item: &T| {
+                let krate = krate_and_path(item).0;
+                facets.iter().find(|f| f.krate == krate).map_or((false, 0, 0), |f| (f.is_std, f.recent_downloads.unwrap_or(0), f.count))
+            };
+            match self {
+                SortOrder::Relevance => (),
+                SortOrder::Crate => items.sort_by(|a, b| krate_and_path(a).0.cmp(krate_and_path(b).0)),
+                SortOrder::Function => items.sort_by(|a, b| fn_name(a).cmp(&fn_name(b))),
+                SortOrder::Popularity => items.sort_by(|a, b| {
+                    crate_popularity(b).cmp(&crate_popularity(a)).then_with(|| krate_and_path(a).0.cmp(krate_and_path(b).0))
+                }),
+            }
+        }
+    }
+
+    #[derive(Serialize, Deserialize)]
+    #[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
     #[serde(deny_unknown_fields)]
     #[derive(Debug)]
     pub struct SearchResult {
-        pub fndetails: Vec<FnDetail>,
+        pub hits: Vec<SearchHit>,
+        pub offset: usize,
+        pub total: usize, // across all pages
+        // The search stopped at its result limit or memory budget, so there may be more matches than total. When
+        // streaming, only the final summary says so, and batches of hits always have it false
+        pub truncated: bool,
+        // The search hit the server's deadline before trying the fuzzier candidate types, so there may be more matches
+        pub timed_out: bool,
+        // Set when the crate filter names a crate that isn't indexed, e.g. to say it's been queued for analysis
+        pub crate_status: Option<IndexStatus>,
+        pub facets: Vec<CrateFacet>, // ignoring any crate filter, most hits first
+        pub query: QueryEcho,
+        pub server_time_ms: u64,
+    }
+
+    // How a search was interpreted - the known types each part of the query was fuzzily matched to, closest first
+    #[derive(Serialize, Deserialize)]
+    #[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+    #[serde(deny_unknown_fields)]
+    #[derive(Clone, Debug)]
+    pub struct QueryEcho {
+        pub params: Option<Vec<String>>,
+        pub ret: Option<String>,
+        pub param_matches: Vec<Vec<String>>, // per param, or a single list for no params
+        pub ret_matches: Option<Vec<String>>,
+        pub exact_params: bool,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    #[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+    #[serde(deny_unknown_fields)]
+    #[derive(Debug)]
+    pub struct CrateFacet {
+        pub krate: String,
+        pub count: usize,
+        pub is_std: bool, // a standard library crate, rather than one from the ecosystem
+        pub recent_downloads: Option<u64>, // from crates.io, if the server has its popularity
+    }
+
+    #[derive(Serialize, Deserialize)]
+    #[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+    #[serde(deny_unknown_fields)]
+    #[derive(Debug)]
+    pub struct SearchHit {
+        pub fndetail: FnDetail,
+        pub docs_url: String,
+        // Matched through an element of a tuple or array param or ret, rather than a whole type
+        pub partial: bool,
+        // Returns the searched for type behind a different reference or smart pointer, e.g. `Arc<T>` for `T`
+        pub ownership_variant: bool,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    #[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+    #[serde(deny_unknown_fields)]
+    #[derive(Debug)]
+    pub struct BatchSearchRequest {
+        pub requests: Vec<SearchRequest>,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    #[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+    #[serde(deny_unknown_fields)]
+    #[derive(Debug)]
+    pub struct BatchSearchResult {
+        pub results: Vec<SearchResult>, // in the same order as the requests
+    }
+
+    #[derive(Serialize, Deserialize)]
+    #[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+    #[serde(deny_unknown_fields)]
+    #[derive(Debug)]
+    pub struct SuggestResult {
+        pub types: Vec<String>,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    #[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+    #[serde(deny_unknown_fields)]
+    #[derive(Debug)]
+    pub struct FnDetailResult {
+        pub hit: SearchHit,
+        pub similar: Vec<SearchHit>, // other functions with the same signature
+    }
+
+    #[derive(Serialize, Deserialize)]
+    #[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+    #[serde(deny_unknown_fields)]
+    #[derive(Clone, Debug)]
+    pub struct CrateDetailRequest {
+        pub krate: String,
+    }
+
+    // An indexed crate and everything found in it. Crates that aren't indexed are an ApiError::NotFound
+    #[derive(Serialize, Deserialize)]
+    #[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+    #[serde(deny_unknown_fields)]
+    #[derive(Debug)]
+    pub struct CrateDetailResult {
+        pub krate: String,
+        pub version: String, // the indexed one
+        pub is_std: bool,
+        pub recent_downloads: Option<u64>, // from crates.io, if the server has its popularity
+        pub fns: Vec<SearchHit>, // sorted by path
+    }
+
+    // A user flagging a result as wrong or unhelpful for their query, or following it
+    #[derive(Serialize, Deserialize)]
+    #[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+    #[serde(deny_unknown_fields)]
+    #[derive(Clone, Debug)]
+    pub struct FeedbackRequest {
+        pub params: Option<Vec<String>>, // the search the result came from
+        pub ret: Option<String>,
+        // Identifies the result - fn ids are internal to the db and change when a crate is re-analysed
+        pub krate: String,
+        pub version: String,
+        pub path: String,
+        pub s: String,
+        pub kind: FeedbackKind,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    #[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    pub enum FeedbackKind {
+        BadResult, // reported as wrong or unhelpful
+        ClickThrough, // the result's docs were opened
+    }
+
+    // Whether a crate is indexed, and if not, how far its on-demand analysis has got
+    #[derive(Serialize, Deserialize)]
+    #[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+    #[serde(deny_unknown_fields)]
+    #[derive(Clone, Debug, PartialEq)]
+    pub enum IndexStatus {
+        Indexed { version: String },
+        NotIndexed, // and won't be on demand, e.g. the server doesn't do that or the crate isn't on crates.io
+        QueueFull, // try searching again later
+        Queued { version: String },
+        Analyzing { version: String },
+        Failed { version: String, err: String },
+    }
+
+    #[derive(Serialize, Deserialize)]
+    #[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+    #[serde(deny_unknown_fields)]
+    #[derive(Debug)]
+    pub struct StatsResult {
+        pub num_crates: usize,
+        pub num_errored_crates: usize,
+        pub num_fns: usize,
+        pub last_updated_ms: Option<u64>, // since the unix epoch, None if nothing has been saved yet
+    }
+
+    #[derive(Serialize, Deserialize)]
+    #[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+    #[serde(deny_unknown_fields)]
+    #[derive(Debug)]
+    pub struct ErrorResponse {
+        pub err: ApiError,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    #[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+    #[serde(deny_unknown_fields)]
+    #[derive(Clone, Debug, PartialEq)]
+    pub enum ApiError {
+        BadRequest(String),
+        Unauthorized,
+        NotFound(String),
+        BackendUnavailable(String), // e.g. the text search backend, so worth retrying later
+        Internal(String),
+        ProtocolMismatch { server: u32, client: u32 },
+    }
+
+    impl ApiError {
+        // The HTTP status the error is returned with
+        pub fn status_code(&self) -> u16 {
+            match self {
+                ApiError::BadRequest(_) => 400,
+                ApiError::Unauthorized => 401,
+                ApiError::NotFound(_) => 404,
+                ApiError::BackendUnavailable(_) => 503,
+                ApiError::Internal(_) => 500,
+                ApiError::ProtocolMismatch { .. } => 409,
+            }
+        }
+    }
+
+    impl fmt::Display for ApiError {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            match self {
+                ApiError::BadRequest(msg) => write!(f, "bad request: {}", msg),
+                ApiError::Unauthorized => write!(f, "unauthorized"),
+                ApiError::NotFound(msg) => write!(f, "not found: {}", msg),
+                ApiError::BackendUnavailable(msg) => write!(f, "backend unavailable: {}", msg),
+                ApiError::Internal(msg) => write!(f, "internal error: {}", msg),
+                ApiError::ProtocolMismatch { server, client } => {
+                    write!(f, "protocol mismatch: the server speaks version {} but this client speaks {}, reload the page or upgrade the client",
+                           server, client)
+                },
+            }
+        }
+    }
+
+    impl std::error::Error for ApiError {}
+
+    // Definitions of every type that goes over the wire, for clients not written in Rust
+    #[cfg(feature = "schema")]
+    pub fn schema() -> schemars::schema::RootSchema {
+        let mut gen = schemars::gen::SchemaSettings::draft07().into_generator();
+        macro_rules! add {
+            ($($t:ty),*) => { $(gen.subschema_for::<$t>();)* };
+        }
+        add!(SearchRequest, SearchResult, BatchSearchRequest, BatchSearchResult, SuggestResult, FnDetailResult,
+             CrateDetailRequest, CrateDetailResult, FeedbackRequest, StatsResult, IndexStatus, ErrorResponse);
+        schemars::schema::RootSchema {
+            meta_schema: gen.settings().meta_schema.clone(),
+            schema: schemars::schema::SchemaObject::default(),
+            definitions: gen.take_definitions(),
+        }
+    }
+}
+
+// The query language shared by the page and the CLI
+pub mod query {
+    use std::fmt;
+
+    // A search written like a function signature, e.g. `fn(&str, usize) -> Result<_, Error>`. None means any
+    #[derive(Clone, Debug, PartialEq)]
+    pub struct Signature {
+        pub params: Option<Vec<String>>,
+        pub ret: Option<String>,
+    }
+
+    impl fmt::Display for Signature {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            match self.params.as_ref() {
+                Some(params) => write!(f, "fn({})", params.join(", "))?,
+                None => write!(f, "fn(*)")?,
+            }
+            match self.ret.as_ref() {
+                Some(ret) => write!(f, " -> {}", ret),
+                None => Ok(()),
+            }
+        }
+    }
+
+    fn is_any(s: &str) -> bool {
+        s == "*" || s == "_"
+    }
+
+    fn is_ident(s: &str) -> bool {
+        !s.is_empty() && s.chars().all(|c| c.is_alphanumeric() || c == '_')
+    }
+
+    // Accepts `fn(...)` or `fn name(...)` (the name is ignored), with an optional `-> ret`. A missing return type
+    // means any, rather than `()` as it would in Rust, since that's what you usually want when searching
+    pub fn parse_signature(s: &str) -> Result<Signature, String> {
+        let s = s.trim();
+        if !s.starts_with("fn") {
+            return Err("signature must start with `fn`".into())
+        }
+        let s = &s["fn".len()..];
+        let open = s.find('(').ok_or("missing `(` after `fn`")?;
+        let name = s[..open].trim();
+        if !name.is_empty() && (!is_ident(name) || !s.starts_with(char::is_whitespace)) {
+            return Err(format!("invalid function name `{}`", name))
+        }
+
+        let s = &s[open..];
+        let mut depth = 0;
+        let close = s.char_indices()
+            .find(|&(_, c)| {
+                match c {
+                    '(' => depth += 1,
+                    ')' => depth -= 1,
+                    _ => (),
+                }
+                depth == 0
+            })
+            .map(|(i, _)| i)
+            .ok_or("unclosed `(` in params")?;
+        let params = s[1..close].trim();
+        let params = if is_any(params) || params == ".." { None } else { Some(split_params(params)?) };
+
+        let tail = s[close+1..].trim();
+        let ret = if tail.is_empty() {
+            None
+        } else if tail.starts_with("->") {
+            match tail["->".len()..].trim() {
+                "" => return Err("missing return type after `->`".into()),
+                r if is_any(r) => None,
+                r => Some(r.to_owned()),
+            }
+        } else {
+            return Err(format!("expected `->` after params, found `{}`", tail))
+        };
+
+        Ok(Signature { params, ret })
+    }
+
+    // Splits on commas that aren't nested in brackets, so `HashMap<K, V>` stays as one param. Bindings like
+    // `s: &str` are stripped down to their type
+    pub fn split_params(s: &str) -> Result<Vec<String>, String> {
+        let mut pieces = vec![];
+        let mut depth = 0;
+        let mut start = 0;
+        let mut prev = None;
+        for (i, c) in s.char_indices() {
+            match c {
+                '<' | '(' | '[' => depth += 1,
+                // The `>` of a `->` in a fn pointer type doesn't close anything
+                '>' if prev == Some('-') => (),
+                '>' | ')' | ']' => depth -= 1,
+                ',' if depth == 0 => {
+                    pieces.push(&s[start..i]);
+                    start = i + 1;
+                },
+                _ => (),
+            }
+            if depth < 0 {
+                return Err(format!("unexpected `{}` in params", c))
+            }
+            prev = Some(c);
+        }
+        if depth != 0 {
+            return Err("unbalanced brackets in params".into())
+        }
+        pieces.push(&s[start..]);
+
+        Ok(pieces.into_iter()
+            .map(|p| {
+                let p = p.trim();
+                match p.find(':') {
+                    Some(i) if !p[i+1..].starts_with(':') && is_ident(p[..i].trim()) => p[i+1..].trim(),
+                    _ => p,
+                }
+            })
+            .filter(|p| !p.is_empty())
+            .map(str::to_owned)
+            .collect())
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        fn sig(params: Option<&[&str]>, ret: Option<&str>) -> Signature {
+            Signature { params: params.map(|ps| ps.iter().map(|p| p.to_string()).collect()), ret: ret.map(str::to_owned) }
+        }
+
+        #[test]
+        fn parse_signatures() {
+            assert_eq!(parse_signature("fn(&str, usize) -> Result<_, Error>"), Ok(sig(Some(&["&str", "usize"]), Some("Result<_, Error>"))));
+            assert_eq!(parse_signature("  fn parse(s: &str) -> u64 "), Ok(sig(Some(&["&str"]), Some("u64"))));
+            assert_eq!(parse_signature("fn(&str)"), Ok(sig(Some(&["&str"]), None)));
+            assert_eq!(parse_signature("fn() -> Instant"), Ok(sig(Some(&[]), Some("Instant"))));
+            assert_eq!(parse_signature("fn(*) -> String"), Ok(sig(None, Some("String"))));
+            assert_eq!(parse_signature("fn(..) -> _"), Ok(sig(None, None)));
+            assert_eq!(parse_signature("fn(fn(u8) -> u8) -> (u8, u8)"), Ok(sig(Some(&["fn(u8) -> u8"]), Some("(u8, u8)"))));
+        }
+
+        #[test]
+        fn parse_signature_errors() {
+            for s in &["parse(&str)", "fn &str", "fn(&str", "fn(&str) ->", "fn(&str) u64", "fnparse(&str)", "fn a-b(&str)", "fn(Vec<u8)"] {
+                assert!(parse_signature(s).is_err(), "{}", s);
+            }
+        }
+
+        #[test]
+        fn signature_display_round_trips() {
+            for s in &["fn(&str, usize) -> Result<_, Error>", "fn(&str)", "fn() -> Instant", "fn(*) -> String"] {
+                let parsed = parse_signature(s).unwrap();
+                assert_eq!(parsed.to_string(), *s);
+                assert_eq!(parse_signature(&parsed.to_string()), Ok(parsed));
+            }
+        }
+
+        #[test]
+        fn split_nested_params() {
+            assert_eq!(split_params("HashMap<K, V>, (u8, u8), [u8; 4]").unwrap(), ["HashMap<K, V>", "(u8, u8)", "[u8; 4]"]);
+            assert_eq!(split_params("f: fn(u8) -> u8, x: &str").unwrap(), ["fn(u8) -> u8", "&str"]);
+            assert_eq!(split_params("std::io::Error, e: std::io::Error").unwrap(), ["std::io::Error", "std::io::Error"]);
+            assert_eq!(split_params(" u8 , ").unwrap(), ["u8"]);
+            assert!(split_params("").unwrap().is_empty());
+            assert!(split_params("Vec<u8").is_err());
+            assert!(split_params("u8>, u8").is_err());
+        }
+    }
+}
+
+pub mod ty {
+    use serde::{Serialize, Deserialize};
+    use std::collections::HashMap;
+    use std::collections::hash_map::Entry;
+    use std::fmt;
+
+    // A type as it appears in a signature, parsed from how rust-analyzer displays it. Paths are kept as written, so
+    // `Vec<T>` and `std::vec::Vec<T>` are different paths
+    #[derive(Serialize, Deserialize)]
+    #[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+    #[derive(rkyv::Archive, rkyv::Serialize, rkyv::Deserialize)]
+    // The types are recursive, so the bounds rkyv and bytecheck would derive would be too
+    #[archive(bound(serialize = "__S: rkyv::ser::ScratchSpace + rkyv::ser::Serializer"))]
+    #[archive_attr(derive(bytecheck::CheckBytes), check_bytes(bound = "__C: rkyv::validation::ArchiveContext, <__C as rkyv::Fallible>::Error: std::error::Error"))]
+    #[serde(deny_unknown_fields)]
+    #[derive(Clone, Debug, PartialEq)]
+    pub enum Type {
+        // Includes primitives and type params, e.g. `u8`, `T`, `std::collections::HashMap<K, V>`
+        Path { path: String, #[omit_bounds] #[archive_attr(omit_bounds)] args: Vec<GenericArg> },
+        // `<T as Trait>::Name` or `T::Name`
+        Projection { #[omit_bounds] #[archive_attr(omit_bounds)] self_ty: Box<Type>, #[omit_bounds] #[archive_attr(omit_bounds)] trait_: Option<Box<Type>>, name: String },
+        Ref { mutable: bool, #[omit_bounds] #[archive_attr(omit_bounds)] inner: Box<Type> },
+        Ptr { mutable: bool, #[omit_bounds] #[archive_attr(omit_bounds)] inner: Box<Type> },
+        // `()` is the empty tuple
+        Tuple(#[omit_bounds] #[archive_attr(omit_bounds)] Vec<Type>),
+        Slice(#[omit_bounds] #[archive_attr(omit_bounds)] Box<Type>),
+        Array { #[omit_bounds] #[archive_attr(omit_bounds)] inner: Box<Type>, len: String },
+        FnPtr { #[omit_bounds] #[archive_attr(omit_bounds)] params: Vec<Type>, #[omit_bounds] #[archive_attr(omit_bounds)] ret: Box<Type> },
+        // Lifetime and `?Sized` bounds are left out
+        ImplTrait(#[omit_bounds] #[archive_attr(omit_bounds)] Vec<Type>),
+        DynTrait(#[omit_bounds] #[archive_attr(omit_bounds)] Vec<Type>),
+        Never,
+        // `_`, or a type rust-analyzer couldn't infer
+        Infer,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    #[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+    #[derive(rkyv::Archive, rkyv::Serialize, rkyv::Deserialize)]
+    #[archive(bound(serialize = "__S: rkyv::ser::ScratchSpace + rkyv::ser::Serializer"))]
+    #[archive_attr(derive(bytecheck::CheckBytes), check_bytes(bound = "__C: rkyv::validation::ArchiveContext, <__C as rkyv::Fallible>::Error: std::error::Error"))]
+    #[serde(deny_unknown_fields)]
+    #[derive(Clone, Debug, PartialEq)]
+    pub enum GenericArg {
+        Type(#[omit_bounds] #[archive_attr(omit_bounds)] Type),
+        Lifetime(String),
+        // e.g. the `Item = u8` of `Iterator<Item = u8>`
+        Binding { name: String, #[omit_bounds] #[archive_attr(omit_bounds)] ty: Type },
+    }
+
+    fn write_list<T: fmt::Display>(f: &mut fmt::Formatter, items: &[T], sep: &str) -> fmt::Result {
+        for (i, item) in items.iter().enumerate() {
+            if i > 0 {
+                f.write_str(sep)?;
+            }
+            write!(f, "{}", item)?;
+        }
+        Ok(())
+    }
+
+    impl fmt::Display for Type {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            match self {
+                Type::Path { path, args } => {
+                    f.write_str(path)?;
+                    // The Fn traits are parsed from their sugar, so are written back with it
+                    let is_fn_trait = matches!(path.rsplit("::").next(), Some("Fn") | Some("FnMut") | Some("FnOnce"));
+                    match (is_fn_trait, args.as_slice()) {
+                        (true, [GenericArg::Type(Type::Tuple(params))]) => {
+                            f.write_str("(")?;
+                            write_list(f, params, ", ")?;
+                            return f.write_str(")")
+                        },
+                        (true, [GenericArg::Type(Type::Tuple(params)), GenericArg::Binding { name, ty }]) if name == "Output" => {
+                            f.write_str("(")?;
+                            write_list(f, params, ", ")?;
+                            return write!(f, ") -> {}", ty)
+                        },
+                        _ => (),
+                    }
+                    if !args.is_empty() {
+                        f.write_str("<")?;
+                        write_list(f, args, ", ")?;
+                        f.write_str(">")?;
+                    }
+                    Ok(())
+                },
+                Type::Projection { self_ty, trait_: Some(trait_), name } => write!(f, "<{} as {}>::{}", self_ty, trait_, name),
+                Type::Projection { self_ty, trait_: None, name } => write!(f, "{}::{}", self_ty, name),
+                Type::Ref { mutable, inner } => write!(f, "&{}{}", if *mutable { "mut " } else { "" }, inner),
+                Type::Ptr { mutable, inner } => write!(f, "*{} {}", if *mutable { "mut" } else { "const" }, inner),
+                Type::Tuple(tys) if tys.len() == 1 => write!(f, "({},)", tys[0]),
+                Type::Tuple(tys) => {
+                    f.write_str("(")?;
+                    write_list(f, tys, ", ")?;
+                    f.write_str(")")
+                },
+                Type::Slice(inner) => write!(f, "[{}]", inner),
+                Type::Array { inner, len } => write!(f, "[{}; {}]", inner, len),
+                Type::FnPtr { params, ret } => {
+                    f.write_str("fn(")?;
+                    write_list(f, params, ", ")?;
+                    f.write_str(")")?;
+                    match &**ret {
+                        Type::Tuple(tys) if tys.is_empty() => Ok(()),
+                        ret => write!(f, " -> {}", ret),
+                    }
+                },
+                Type::ImplTrait(bounds) => {
+                    f.write_str("impl ")?;
+                    write_list(f, bounds, " + ")
+                },
+                Type::DynTrait(bounds) => {
+                    f.write_str("dyn ")?;
+                    write_list(f, bounds, " + ")
+                },
+                Type::Never => f.write_str("!"),
+                Type::Infer => f.write_str("_"),
+            }
+        }
+    }
+
+    impl fmt::Display for GenericArg {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            match self {
+                GenericArg::Type(ty) => write!(f, "{}", ty),
+                GenericArg::Lifetime(lifetime) => f.write_str(lifetime),
+                GenericArg::Binding { name, ty } => write!(f, "{} = {}", name, ty),
+            }
+        }
+    }
+
+    impl Type {
+        // The element types of tuples and arrays, looking through references and into nested tuples and arrays, e.g.
+        // usize and &str for (usize, &str), or u8 for &[u8; 32]
+        pub fn components(&self) -> Vec<&Type> {
+            fn walk<'a>(ty: &'a Type, components: &mut Vec<&'a Type>) {
+                match ty {
+                    Type::Ref { inner, .. } => walk(inner, components),
+                    Type::Tuple(tys) => for ty in tys {
+                        components.push(ty);
+                        walk(ty, components)
+                    },
+                    Type::Array { inner, .. } => {
+                        components.push(inner);
+                        walk(inner, components)
+                    },
+                    _ => (),
+                }
+            }
+            let mut components = vec![];
+            walk(self, &mut components);
+            components
+        }
+
+        // Drop lifetimes, which don't matter for search (those of references are already dropped by parsing), and
+        // leading `::`s of paths
+        pub fn normalize(&mut self) {
+            match self {
+                Type::Path { path, args } => {
+                    if let Some(rest) = path.strip_prefix("::") {
+                        *path = rest.to_owned();
+                    }
+                    args.retain(|arg| !matches!(arg, GenericArg::Lifetime(_)));
+                    for arg in args.iter_mut() {
+                        match arg {
+                            GenericArg::Type(ty) | GenericArg::Binding { ty, .. } => ty.normalize(),
+                            GenericArg::Lifetime(_) => (),
+                        }
+                    }
+                },
+                Type::Projection { self_ty, trait_, .. } => {
+                    self_ty.normalize();
+                    if let Some(trait_) = trait_ {
+                        trait_.normalize()
+                    }
+                },
+                Type::Ref { inner, .. } | Type::Ptr { inner, .. } | Type::Slice(inner) | Type::Array { inner, .. } => inner.normalize(),
+                Type::Tuple(tys) | Type::ImplTrait(tys) | Type::DynTrait(tys) => for ty in tys {
+                    ty.normalize()
+                },
+                Type::FnPtr { params, ret } => {
+                    for param in params {
+                        param.normalize()
+                    }
+                    ret.normalize()
+                },
+                Type::Never | Type::Infer => (),
+            }
+        }
+
+        // The types directly inside this one, in the order map_children visits them
+        pub fn children(&self) -> Vec<&Type> {
+            match self {
+                Type::Path { args, .. } => args.iter()
+                    .filter_map(|arg| match arg {
+                        GenericArg::Type(ty) | GenericArg::Binding { ty, .. } => Some(ty),
+                        GenericArg::Lifetime(_) => None,
+                    })
+                    .collect(),
+                Type::Projection { self_ty, trait_, .. } => {
+                    let mut children = vec![&**self_ty];
+                    children.extend(trait_.as_deref());
+                    children
+                },
+                Type::Ref { inner, .. } | Type::Ptr { inner, .. } | Type::Slice(inner) | Type::Array { inner, .. } => vec![&**inner],
+                Type::Tuple(tys) | Type::ImplTrait(tys) | Type::DynTrait(tys) => tys.iter().collect(),
+                Type::FnPtr { params, ret } => params.iter().chain(Some(&**ret)).collect(),
+                Type::Never | Type::Infer => vec![],
+            }
+        }
+
+        // A copy with each of children replaced by what f makes of it
+        pub fn map_children(&self, f: &mut dyn FnMut(&Type) -> Type) -> Type {
+            match self {
+                Type::Path { path, args } => Type::Path {
+                    path: path.clone(),
+                    args: args.iter()
+                        .map(|arg| match arg {
+                            GenericArg::Type(ty) => GenericArg::Type(f(ty)),
+                            GenericArg::Lifetime(lifetime) => GenericArg::Lifetime(lifetime.clone()),
+                            GenericArg::Binding { name, ty } => GenericArg::Binding { name: name.clone(), ty: f(ty) },
+                        })
+                        .collect(),
+                },
+                Type::Projection { self_ty, trait_, name } => {
+                    let self_ty = Box::new(f(self_ty));
+                    Type::Projection { self_ty, trait_: trait_.as_ref().map(|trait_| Box::new(f(trait_))), name: name.clone() }
+                },
+                Type::Ref { mutable, inner } => Type::Ref { mutable: *mutable, inner: Box::new(f(inner)) },
+                Type::Ptr { mutable, inner } => Type::Ptr { mutable: *mutable, inner: Box::new(f(inner)) },
+                Type::Tuple(tys) => Type::Tuple(tys.iter().map(|ty| f(ty)).collect()),
+                Type::Slice(inner) => Type::Slice(Box::new(f(inner))),
+                Type::Array { inner, len } => Type::Array { inner: Box::new(f(inner)), len: len.clone() },
+                Type::FnPtr { params, ret } => {
+                    let params = params.iter().map(|param| f(param)).collect();
+                    Type::FnPtr { params, ret: Box::new(f(ret)) }
+                },
+                Type::ImplTrait(tys) => Type::ImplTrait(tys.iter().map(|ty| f(ty)).collect()),
+                Type::DynTrait(tys) => Type::DynTrait(tys.iter().map(|ty| f(ty)).collect()),
+                Type::Never | Type::Infer => self.clone(),
+            }
+        }
+
+        // The number of types making up this one, itself included
+        pub fn size(&self) -> usize {
+            1 + self.children().iter().map(|child| child.size()).sum::<usize>()
+        }
+
+        // With type params (see is_type_param) and impl Traits, which are anonymous type params, replaced by `_`, if there
+        // are any, so e.g. `Vec<T>`, `Vec<U>` and `Vec<impl Read>` have the same shape `Vec<_>`
+        pub fn generic_shape(&self) -> Option<Type> {
+            fn walk(ty: &Type, found: &mut bool) -> Type {
+                match ty {
+                    Type::Path { path, args } if args.is_empty() && is_type_param(path) => {
+                        *found = true;
+                        Type::Infer
+                    },
+                    Type::ImplTrait(_) => {
+                        *found = true;
+                        Type::Infer
+                    },
+                    ty => ty.map_children(&mut |child| walk(child, found)),
+                }
+            }
+            let mut found = false;
+            let shape = walk(self, &mut found);
+            if found { Some(shape) } else { None }
+        }
+
+        // The type behind a reference or one of OWNERSHIP_WRAPPERS, if this is one
+        pub fn strip_ownership(&self) -> Option<&Type> {
+            match self {
+                Type::Ref { inner, .. } => Some(inner),
+                Type::Path { path, args } if OWNERSHIP_WRAPPERS.contains(&path.rsplit("::").next().unwrap()) => match args.as_slice() {
+                    [GenericArg::Type(inner)] => Some(inner),
+                    _ => None,
+                },
+                _ => None,
+            }
+        }
+    }
+
+    // Analysis keeps type params as they're named in signatures, without saying which names are params, so this goes by
+    // the convention of naming them with a capital letter and maybe digits, e.g. T, K, V, T2
+    pub fn is_type_param(path: &str) -> bool {
+        let mut chars = path.chars();
+        chars.next().map_or(false, |c| c.is_ascii_uppercase()) && path.len() <= 2 && chars.all(|c| c.is_ascii_uppercase() || c.is_ascii_digit())
+    }
+
+    pub fn generic_shape(s: &str) -> Option<String> {
+        parse_type(s).ok()?.generic_shape().map(|shape| shape.to_string())
+    }
+
+    // The query with types inside it replaced by `_`, to match against generic_shapes. Those replacing the fewest types
+    // (so the most specific) come first, and only the first limit are given. The whole query is never replaced, as
+    // a bare type param matches anything
+    pub fn generalizations(s: &str, limit: usize) -> Vec<String> {
+        // Each with how many types were replaced
+        fn walk(ty: &Type, limit: usize) -> Vec<(usize, Type)> {
+            let mut options = vec![(0, ty.clone())];
+            for (i, child) in ty.children().into_iter().enumerate() {
+                let mut child_options = walk(child, limit);
+                if *child != Type::Infer {
+                    child_options.push((child.size(), Type::Infer));
+                }
+                let mut next = vec![];
+                for (replaced, option) in options.iter() {
+                    for (child_replaced, child_option) in child_options.iter() {
+                        let mut j = 0;
+                        let combined = option.map_children(&mut |c| {
+                            j += 1;
+                            if j - 1 == i { child_option.clone() } else { c.clone() }
+                        });
+                        next.push((replaced + child_replaced, combined));
+                    }
+                }
+                next.sort_by_key(|(replaced, _)| *replaced);
+                next.truncate(limit + 1);
+                options = next;
+            }
+            options
+        }
+        let ty = match parse_type(s) {
+            Ok(ty) => ty,
+            Err(_) => return vec![],
+        };
+        let mut generalizations: Vec<String> = vec![];
+        for (replaced, generalization) in walk(&ty, limit) {
+            let generalization = generalization.to_string();
+            if replaced > 0 && !generalizations.contains(&generalization) {
+                generalizations.push(generalization)
+            }
+        }
+        generalizations.truncate(limit);
+        generalizations
+    }
+
+    // Whether the type params of generic can stand for types such that it's the query, with each param standing for
+    // the same type everywhere, e.g. `HashMap<K, V>` and `HashMap<T, T>` for `HashMap<String, String>`. Each impl Trait
+    // is its own param, though trait bounds aren't checked - except against another impl Trait, which has to be the same
+    pub fn unifies(query: &str, generic: &str) -> bool {
+        fn walk<'a>(query: &'a Type, generic: &'a Type, bindings: &mut HashMap<&'a str, &'a Type>) -> bool {
+            if let Type::ImplTrait(_) = generic {
+                return !matches!(query, Type::ImplTrait(_)) || query == generic
+            }
+            if let Type::Path { path, args } = generic {
+                if args.is_empty() && is_type_param(path) {
+                    return match bindings.entry(path.as_str()) {
+                        Entry::Occupied(entry) => *entry.get() == query,
+                        Entry::Vacant(entry) => {
+                            entry.insert(query);
+                            true
+                        },
+                    }
+                }
+            }
+            let (query_children, generic_children) = (query.children(), generic.children());
+            // Everything but the children has to be the same
+            query.map_children(&mut |_| Type::Infer) == generic.map_children(&mut |_| Type::Infer) &&
+                query_children.len() == generic_children.len() &&
+                query_children.into_iter().zip(generic_children).all(|(q, g)| walk(q, g, bindings))
+        }
+        match (parse_type(query), parse_type(generic)) {
+            (Ok(query), Ok(generic)) => walk(&query, &generic, &mut HashMap::new()),
+            _ => false,
+        }
+    }
+
+    // Smart pointers that, like references, rarely matter to someone looking for what gives them a type
+    pub const OWNERSHIP_WRAPPERS: &[&str] = &["Box", "Rc", "Arc"];
+
+    // The other ways of holding the same type, e.g. `String`, `&mut String`, `Box<String>` etc. for `&String`, in
+    // normal form. Only one layer is looked through, so `&Box<T>` gives `Box<T>`, `&Box<T>`, `Rc<Box<T>>`...
+    pub fn ownership_variants(s: &str) -> Vec<String> {
+        let mut ty = match parse_type(s) {
+            Ok(ty) => ty,
+            Err(_) => return vec![],
+        };
+        ty.normalize();
+        let inner = ty.strip_ownership().unwrap_or(&ty).clone();
+        let mut variants = vec![
+            inner.clone(),
+            Type::Ref { mutable: false, inner: Box::new(inner.clone()) },
+            Type::Ref { mutable: true, inner: Box::new(inner.clone()) },
+        ];
+        for wrapper in OWNERSHIP_WRAPPERS {
+            variants.push(Type::Path { path: (*wrapper).to_owned(), args: vec![GenericArg::Type(inner.clone())] });
+        }
+        variants.into_iter().filter(|variant| *variant != ty).map(|variant| variant.to_string()).collect()
+    }
+
+    #[derive(Clone, Debug, PartialEq)]
+    enum Token {
+        Ident(String),
+        Lifetime(String),
+        Literal(String), // the ABI of an `extern "C" fn`
+        Punct(&'static str),
+    }
+
+    const PUNCTS: &[&str] = &["::", ":", "->", "{unknown}", "<", ">", "(", ")", "[", "]", ",", "&", "*", "=", "+", ";", "!", "?"];
+
+    fn tokenize(s: &str) -> Result<Vec<Token>, String> {
+        let mut tokens = vec![];
+        let mut rest = s;
+        while let Some(c) = rest.chars().next() {
+            if c.is_whitespace() {
+                rest = &rest[c.len_utf8()..];
+            } else if let Some(punct) = PUNCTS.iter().find(|p| rest.starts_with(**p)) {
+                tokens.push(Token::Punct(punct));
+                rest = &rest[punct.len()..];
+            } else if c == '\'' || c.is_alphanumeric() || c == '_' {
+                let len = rest[c.len_utf8()..].find(|c: char| !(c.is_alphanumeric() || c == '_')).map_or(rest.len(), |i| i + c.len_utf8());
+                let word = rest[..len].to_owned();
+                tokens.push(if c == '\'' { Token::Lifetime(word) } else { Token::Ident(word) });
+                rest = &rest[len..];
+            } else if c == '"' {
+                let len = rest[1..].find('"').ok_or("unclosed string")? + 2;
+                tokens.push(Token::Literal(rest[1..len-1].to_owned()));
+                rest = &rest[len..];
+            } else {
+                return Err(format!("unexpected `{}`", c))
+            }
+        }
+        Ok(tokens)
+    }
+
+    // Types from real signatures are nowhere near this, and walking a deeper one (here or over the parsed Type) could
+    // overflow the stack
+    const MAX_TYPE_DEPTH: usize = 64;
+
+    struct Parser {
+        tokens: Vec<Token>,
+        pos: usize,
+        depth: usize, // of types being parsed
+    }
+
+    impl Parser {
+        fn peek(&self) -> Option<&Token> {
+            self.tokens.get(self.pos)
+        }
+
+        fn peek_punct(&self, punct: &str) -> bool {
+            matches!(self.peek(), Some(Token::Punct(p)) if *p == punct)
+        }
+
+        fn peek_ident(&self, ident: &str) -> bool {
+            matches!(self.peek(), Some(Token::Ident(i)) if i == ident)
+        }
+
+        // A name followed by `punct`, e.g. the `Item =` of `Item = u8`
+        fn peek_binding(&self, punct: &str) -> bool {
+            matches!(self.tokens.get(self.pos), Some(Token::Ident(_))) &&
+                matches!(self.tokens.get(self.pos + 1), Some(Token::Punct(p)) if *p == punct)
+        }
+
+        fn eat_punct(&mut self, punct: &str) -> bool {
+            let found = self.peek_punct(punct);
+            if found { self.pos += 1 }
+            found
+        }
+
+        fn eat_ident(&mut self, ident: &str) -> bool {
+            let found = self.peek_ident(ident);
+            if found { self.pos += 1 }
+            found
+        }
+
+        fn expect_punct(&mut self, punct: &str) -> Result<(), String> {
+            if self.eat_punct(punct) { Ok(()) } else { Err(format!("expected `{}`, found {}", punct, self.describe_next())) }
+        }
+
+        fn ident(&mut self) -> Result<String, String> {
+            match self.peek().cloned() {
+                Some(Token::Ident(ident)) => { self.pos += 1; Ok(ident) },
+                _ => Err(format!("expected a name, found {}", self.describe_next())),
+            }
+        }
+
+        fn describe_next(&self) -> String {
+            match self.peek() {
+                Some(Token::Ident(s)) | Some(Token::Lifetime(s)) => format!("`{}`", s),
+                Some(Token::Literal(s)) => format!("\"{}\"", s),
+                Some(Token::Punct(p)) => format!("`{}`", p),
+                None => "end of type".into(),
+            }
+        }
+
+        // Comma separated, allowing a trailing comma, up to (and consuming) `close`. Also says whether there was a
+        // trailing comma, to tell `(T,)` from `(T)`
+        fn list<T>(&mut self, close: &str, mut item: impl FnMut(&mut Self) -> Result<T, String>) -> Result<(Vec<T>, bool), String> {
+            let mut items = vec![];
+            let mut trailing_comma = false;
+            while !self.eat_punct(close) {
+                items.push(item(self)?);
+                trailing_comma = self.eat_punct(",");
+                if !trailing_comma && !self.peek_punct(close) {
+                    return Err(format!("expected `,` or `{}`, found {}", close, self.describe_next()))
+                }
+            }
+            Ok((items, trailing_comma))
+        }
+
+        fn ty(&mut self) -> Result<Type, String> {
+            if self.depth == MAX_TYPE_DEPTH {
+                return Err("type nested too deeply".into())
+            }
+            self.depth += 1;
+            let ty = self.unnested_ty();
+            self.depth -= 1;
+            ty
+        }
+
+        // Only to be called by ty, which tracks the depth
+        fn unnested_ty(&mut self) -> Result<Type, String> {
+            if self.eat_punct("&") {
+                if let Some(Token::Lifetime(_)) = self.peek() {
+                    self.pos += 1;
+                }
+                let mutable = self.eat_ident("mut");
+                return Ok(Type::Ref { mutable, inner: Box::new(self.ty()?) })
+            }
+            if self.eat_punct("*") {
+                let mutable = if self.eat_ident("mut") {
+                    true
+                } else if self.eat_ident("const") {
+                    false
+                } else {
+                    return Err(format!("expected `const` or `mut` after `*`, found {}", self.describe_next()))
+                };
+                return Ok(Type::Ptr { mutable, inner: Box::new(self.ty()?) })
+            }
+            if self.eat_punct("(") {
+                let (mut tys, trailing_comma) = self.list(")", Self::ty)?;
+                return Ok(if tys.len() == 1 && !trailing_comma { tys.remove(0) } else { Type::Tuple(tys) })
+            }
+            if self.eat_punct("[") {
+                let inner = Box::new(self.ty()?);
+                if self.eat_punct("]") {
+                    return Ok(Type::Slice(inner))
+                }
+                self.expect_punct(";")?;
+                // Lengths can be arbitrary expressions, so just keep them as written
+                let mut len = vec![];
+                let mut depth = 0;
+                loop {
+                    match self.peek().cloned() {
+                        None => return Err("unclosed `[`".into()),
+                        Some(Token::Punct("]")) if depth == 0 => break,
+                        Some(tok) => {
+                            match tok {
+                                Token::Punct("[") | Token::Punct("(") => depth += 1,
+                                Token::Punct("]") | Token::Punct(")") => depth -= 1,
+                                _ => (),
+                            }
+                            len.push(match tok {
+                                Token::Ident(s) | Token::Lifetime(s) => s,
+                                Token::Literal(s) => format!("\"{}\"", s),
+                                Token::Punct(p) => p.to_owned(),
+                            });
+                            self.pos += 1;
+                        },
+                    }
+                }
+                self.pos += 1;
+                return Ok(Type::Array { inner, len: len.join(" ") })
+            }
+            if self.eat_punct("!") {
+                return Ok(Type::Never)
+            }
+            if self.eat_punct("{unknown}") || self.eat_ident("_") {
+                return Ok(Type::Infer)
+            }
+            if self.eat_ident("impl") {
+                return Ok(Type::ImplTrait(self.bounds()?))
+            }
+            if self.eat_ident("dyn") {
+                return Ok(Type::DynTrait(self.bounds()?))
+            }
+            if self.peek_ident("fn") || self.peek_ident("unsafe") || self.peek_ident("extern") {
+                return self.fn_ptr()
+            }
+            if self.eat_punct("<") {
+                let self_ty = Box::new(self.ty()?);
+                let trait_ = if self.eat_ident("as") { Some(Box::new(self.path()?)) } else { None };
+                self.expect_punct(">")?;
+                self.expect_punct("::")?;
+                let name = self.ident()?;
+                let mut ty = Type::Projection { self_ty, trait_, name };
+                while self.eat_punct("::") {
+                    ty = Type::Projection { self_ty: Box::new(ty), trait_: None, name: self.ident()? };
+                }
+                return Ok(ty)
+            }
+            self.path()
+        }
+
+        fn fn_ptr(&mut self) -> Result<Type, String> {
+            self.eat_ident("unsafe");
+            if self.eat_ident("extern") {
+                if let Some(Token::Literal(_)) = self.peek() {
+                    self.pos += 1;
+                }
+            }
+            if !self.eat_ident("fn") {
+                return Err(format!("expected `fn`, found {}", self.describe_next()))
+            }
+            self.expect_punct("(")?;
+            let (params, _) = self.list(")", |p| {
+                // Params of fn pointers may be named
+                if p.peek_binding(":") {
+                    p.pos += 2;
+                }
+                p.ty()
+            })?;
+            let ret = if self.eat_punct("->") { self.ty()? } else { Type::Tuple(vec![]) };
+            Ok(Type::FnPtr { params, ret: Box::new(ret) })
+        }
+
+        fn bounds(&mut self) -> Result<Vec<Type>, String> {
+            let mut bounds = vec![];
+            loop {
+                match self.peek() {
+                    Some(Token::Lifetime(_)) => self.pos += 1,
+                    Some(Token::Punct("?")) => { self.pos += 1; self.path()?; },
+                    _ => {
+                        // Higher-ranked lifetimes don't matter for search
+                        if self.eat_ident("for") {
+                            self.expect_punct("<")?;
+                            self.list(">", |p| match p.peek() {
+                                Some(Token::Lifetime(_)) => { p.pos += 1; Ok(()) },
+                                _ => Err(format!("expected a lifetime, found {}", p.describe_next())),
+                            })?;
+                        }
+                        bounds.push(self.path()?)
+                    },
+                }
+                if !self.eat_punct("+") {
+                    return Ok(bounds)
+                }
+            }
+        }
+
+        // Also handles the `Fn(A, B) -> R` sugar, as `Fn<(A, B), Output = R>`
+        fn path(&mut self) -> Result<Type, String> {
+            let mut path = String::new();
+            if self.eat_punct("::") {
+                path.push_str("::");
+            }
+            let mut args = vec![];
+            loop {
+                path.push_str(&self.ident()?);
+                if self.eat_punct("<") {
+                    args = self.list(">", Self::generic_arg)?.0;
+                } else if self.eat_punct("(") {
+                    let (params, _) = self.list(")", Self::ty)?;
+                    args = vec![GenericArg::Type(Type::Tuple(params))];
+                    if self.eat_punct("->") {
+                        args.push(GenericArg::Binding { name: "Output".into(), ty: self.ty()? });
+                    }
+                }
+                // A `::` after args is an associated type, e.g. `Vec<T>::Item`
+                if !self.eat_punct("::") {
+                    return Ok(Type::Path { path, args })
+                }
+                if !args.is_empty() {
+                    let name = self.ident()?;
+                    let mut ty = Type::Projection { self_ty: Box::new(Type::Path { path, args }), trait_: None, name };
+                    while self.eat_punct("::") {
+                        ty = Type::Projection { self_ty: Box::new(ty), trait_: None, name: self.ident()? };
+                    }
+                    return Ok(ty)
+                }
+                path.push_str("::");
+            }
+        }
+
+        fn generic_arg(&mut self) -> Result<GenericArg, String> {
+            if let Some(Token::Lifetime(lifetime)) = self.peek().cloned() {
+                self.pos += 1;
+                return Ok(GenericArg::Lifetime(lifetime))
+            }
+            if self.peek_binding("=") {
+                let name = self.ident()?;
+                self.pos += 1;
+                return Ok(GenericArg::Binding { name, ty: self.ty()? })
+            }
+            Ok(GenericArg::Type(self.ty()?))
+        }
+    }
+
+    pub fn parse_type(s: &str) -> Result<Type, String> {
+        let mut parser = Parser { tokens: tokenize(s)?, pos: 0, depth: 0 };
+        let ty = parser.ty()?;
+        if parser.pos != parser.tokens.len() {
+            return Err(format!("unexpected {} after type", parser.describe_next()))
+        }
+        Ok(ty)
+    }
+
+    // The form types are indexed and searched in, so spellings of the same type like `& str`, `&'a str` and `&str`
+    // have the same key. Types that don't parse just have their whitespace collapsed
+    pub fn normalize_type(s: &str) -> String {
+        match parse_type(s) {
+            Ok(mut ty) => {
+                ty.normalize();
+                ty.to_string()
+            },
+            Err(_) => s.split_whitespace().collect::<Vec<_>>().join(" "),
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        // Written as rust-analyzer and users do, with varying spacing and lifetimes
+        const TYPES: &[&str] = &[
+            "&str", "& str", "&'a str", "&'static mut [u8]", "Vec < u8 >", "HashMap<K,V>", "Option<&'a T>",
+            "impl Fn(u8)->bool", "dyn Read+Send", "fn(u8) -> u8", "(u8,)", "()", "[u8; 4]", "<T as Iterator>::Item",
+            "T::Item", "*const u8", "!", "_", "Result<(), Box<dyn std::error::Error + Send + Sync>>",
+            "impl Iterator<Item = &'a str>", "&mut dyn FnMut(&str) -> Option<String>",
+        ];
+
+        #[test]
+        fn normalize_ignores_spacing_and_lifetimes() {
+            assert_eq!(normalize_type("& str"), "&str");
+            assert_eq!(normalize_type("&'a str"), "&str");
+            assert_eq!(normalize_type("&str"), "&str");
+            assert_eq!(normalize_type("&'static mut [u8]"), "&mut [u8]");
+            assert_eq!(normalize_type("HashMap<K,V>"), "HashMap<K, V>");
+        }
+
+        #[test]
+        fn parse_non_ascii_identifiers() {
+            assert_eq!(parse_type("é").unwrap(), Type::Path { path: "é".into(), args: vec![] });
+            assert_eq!(normalize_type("Vec<Ünïcode>"), "Vec<Ünïcode>");
+            assert_eq!(normalize_type("&'ä 日本"), "&日本");
+        }
+
+        #[test]
+        fn parse_rejects_deep_nesting() {
+            assert!(parse_type(&format!("{}u8", "&".repeat(MAX_TYPE_DEPTH - 1))).is_ok());
+            assert_eq!(parse_type(&format!("{}u8", "&".repeat(MAX_TYPE_DEPTH))), Err("type nested too deeply".into()));
+            let deep = format!("{}u8{}", "Vec<".repeat(10_000), ">".repeat(10_000));
+            assert!(parse_type(&deep).is_err());
+            assert!(generalizations(&deep, 5).is_empty());
+            assert!(!unifies(&deep, "T"));
+        }
+
+        #[test]
+        fn normalize_is_idempotent() {
+            for ty in TYPES {
+                let normalized = normalize_type(ty);
+                assert_eq!(normalize_type(&normalized), normalized, "{}", ty);
+            }
+        }
+
+        #[test]
+        fn parse_display_round_trips() {
+            for ty in TYPES {
+                let parsed = parse_type(ty).unwrap();
+                assert_eq!(parse_type(&parsed.to_string()).unwrap(), parsed, "{}", ty);
+                // Display is the normal form
+                assert_eq!(parsed.to_string(), normalize_type(ty), "{}", ty);
+            }
+        }
+
+        #[test]
+        fn unifies_binds_params_consistently() {
+            assert!(unifies("HashMap<String, u8>", "HashMap<K, V>"));
+            assert!(unifies("HashMap<String, String>", "HashMap<T, T>"));
+            assert!(!unifies("HashMap<String, u8>", "HashMap<T, T>"));
+            assert!(unifies("Vec<u8>", "T"));
+            assert!(unifies("Result<Vec<u8>, Error>", "Result<Vec<T>, E>"));
+            assert!(!unifies("Vec<u8>", "Option<T>"));
+            assert!(!unifies("&str", "&mut T"));
+            assert!(!unifies("Vec<u8>", "HashMap<K, V>"));
+            assert!(!unifies("garbage<", "T"));
+        }
+
+        #[test]
+        fn unifies_impl_trait_as_anonymous_params() {
+            assert!(unifies("&mut File", "&mut impl Read"));
+            assert!(unifies("&mut R", "&mut impl Read"));
+            assert!(unifies("(u8, String)", "(impl Copy, impl Display)"));
+            assert!(unifies("impl Read", "impl Read"));
+            assert!(!unifies("impl Write", "impl Read"));
+        }
+
+        #[test]
+        fn generalizations_most_specific_first() {
+            assert_eq!(generalizations("Vec<u8>", 5), ["Vec<_>"]);
+            assert_eq!(generalizations("HashMap<String, u8>", 5), ["HashMap<String, _>", "HashMap<_, u8>", "HashMap<_, _>"]);
+            assert_eq!(generalizations("HashMap<String, u8>", 1), ["HashMap<String, _>"]);
+            assert_eq!(generalizations("Option<Vec<u8>>", 5), ["Option<Vec<_>>", "Option<_>"]);
+            // Never the whole type, which would match anything
+            assert!(generalizations("u8", 5).is_empty());
+            assert!(generalizations("garbage<", 5).is_empty());
+        }
+
+        #[test]
+        fn ownership_variants_look_through_one_layer() {
+            assert_eq!(ownership_variants("&String"), ["String", "&mut String", "Box<String>", "Rc<String>", "Arc<String>"]);
+            assert_eq!(ownership_variants("String"), ["&String", "&mut String", "Box<String>", "Rc<String>", "Arc<String>"]);
+            assert_eq!(ownership_variants("Arc<Mutex<u8>>"), ["Mutex<u8>", "&Mutex<u8>", "&mut Mutex<u8>", "Box<Mutex<u8>>", "Rc<Mutex<u8>>"]);
+            assert_eq!(ownership_variants("&'a  Box<str>")[0], "Box<str>");
+            assert!(ownership_variants("garbage<").is_empty());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn components_round_trip() {
+        for s in &["tar", "std@1.55.0", "git+https://github.com/tokio-rs/tokio#0123abc:tokio-util", "a b%c"] {
+            assert_eq!(decode_component(&encode_component(s)).as_deref(), Some(*s));
+        }
+        assert_eq!(encode_component("git+https://x/y#c"), "git%2Bhttps%3A%2F%2Fx%2Fy%23c");
+        assert_eq!(decode_component("%zz"), None);
+        assert_eq!(decode_component("%2"), None);
+    }
+
+    #[test]
+    fn git_crate_sources() {
+        assert_eq!(git_crate_source("git+https://github.com/a/b#0123abc"), Some(("https://github.com/a/b", "0123abc", None)));
+        assert_eq!(git_crate_source("git+https://github.com/a/b#0123abc:b-util"), Some(("https://github.com/a/b", "0123abc", Some("b-util"))));
+        assert_eq!(git_crate_source("tar"), None);
+        assert_eq!(git_crate_source("std@1.55.0"), None);
+    }
+
+    #[test]
+    fn sysroot_crates() {
+        assert!(is_sysroot_crate("std@1.55.0"));
+        assert!(is_sysroot_crate("alloc@1.60.0"));
+        assert!(!is_sysroot_crate("std"));
+        assert!(!is_sysroot_crate("tar"));
+        assert!(!is_sysroot_crate("stdx@1.0.0"));
+    }
+    #[test]
+    fn fnv1a_hashes() {
+        assert_eq!(fnv1a(""), 0xcbf29ce484222325);
+        assert_eq!(fnv1a("a"), 0xaf63dc4c8601ec8c);
+        assert_eq!(fnv1a("foobar"), 0x85944171f73967e8);
+        assert_eq!(path_hash("foobar"), "85944171f73967e8");
     }
 }
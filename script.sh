@@ -33,6 +33,11 @@ if [ "$1" = build ]; then
         # https://github.com/rustwasm/wasm-pack/issues/811
         rm pkg/package.json pkg/.gitignore
         cp -r --dereference static/* pkg/
+        # Precompressed variants, served in preference to compressing on the fly
+        (cd pkg && gzip -9 -k -n *.wasm *.js)
+        if command -v brotli >/dev/null; then
+            (cd pkg && brotli -k *.wasm *.js)
+        fi
         # https://reproducible-builds.org/docs/archives/
         (cd pkg && tar --sort=name \
             --mtime="@0" \
@@ -0,0 +1,425 @@
+// Distributes the crates of an analysis run to workers on other machines over HTTP. Workers claim a job, analyze the
+// crate and report back, and the coordinator saves results to the db - so only the coordinator needs the db. Workers
+// can alternatively push analyses to a server, so only the server needs the db
+
+use actix_web::{App, HttpRequest, HttpResponse, HttpServer};
+use actix_web::http::header;
+use actix_web::middleware;
+use actix_web::web;
+use anyhow::anyhow;
+use either::Either;
+use isahc::prelude::*;
+use log::{info, warn};
+use serde::{Serialize, Deserialize};
+use subtle::ConstantTimeEq;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use reeves_types::*;
+
+const CLAIM_PATH: &str = "/jobs/claim";
+const RESULT_PATH: &str = "/jobs/result";
+
+const MAX_RESULT_SIZE: usize = 256 * 1024 * 1024;
+
+// How long a worker waits before retrying after failing to reach the coordinator
+const WORKER_RETRY_DELAY: Duration = Duration::from_secs(10);
+// How many times in a row a worker retries an unreachable coordinator before assuming the run is over - long enough
+// to restart the coordinator
+const WORKER_MAX_RETRIES: u32 = 360;
+// How long the coordinator keeps telling workers there's nothing left after the last job finishes, so workers waiting
+// on other workers' jobs (or finishing a duplicate of one) see that rather than a coordinator that's gone
+pub const FINISH_GRACE: Duration = Duration::from_secs(60);
+// How many times a worker retries pushing an analysis to a server that's unreachable or erroring before giving up on
+// the job
+const PUSH_MAX_RETRIES: u32 = 30;
+
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+#[derive(Debug)]
+pub struct Job {
+    #[serde(rename = "crate")]
+    pub krate: String,
+    pub version: String,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+#[derive(Debug)]
+pub struct JobResult {
+    #[serde(rename = "crate")]
+    pub krate: String,
+    pub version: String,
+    pub outcome: JobOutcome,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+#[derive(Debug)]
+pub enum JobOutcome {
+    // The fndetails, or the error the analysis reported, for the coordinator to save
+    Analyzed(Either<Vec<FnDetail>, String>),
+    // The worker sent the analysis straight to a server, so there's nothing for the coordinator to save
+    Pushed,
+    // The analysis failed, or couldn't be pushed to the server, so there's nothing to save
+    Failed(String),
+}
+
+// Where workers send analyses instead of the coordinator, via the server's admin ingest endpoint
+pub struct PushTarget {
+    pub url: String,
+    pub token: String,
+}
+
+pub struct CoordinatorConfig {
+    pub addr: String,
+    pub token: String,
+    pub lease: Duration, // how long before a claimed job is assumed lost
+    pub grace: Duration, // how long to keep answering workers after the last job finishes
+}
+
+struct Jobs {
+    pending: VecDeque<(String, String)>,
+    claimed: HashMap<(String, String), Instant>,
+}
+
+struct Coordinator {
+    db: sled::Db,
+    token: String,
+    lease: Duration,
+    grace: Duration,
+    jobs: Mutex<Jobs>,
+    count: Mutex<crate::CratesProgressCounter>,
+}
+
+fn is_authorized(coordinator: &Coordinator, req: &HttpRequest) -> bool {
+    req.headers().get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .map_or(false, |token| bool::from(token.as_bytes().ct_eq(coordinator.token.as_bytes())))
+}
+
+async fn srv_post_claim(coordinator: web::Data<Arc<Coordinator>>, req: HttpRequest) -> HttpResponse {
+    if !is_authorized(&coordinator, &req) {
+        return HttpResponse::Unauthorized().finish()
+    }
+    let mut jobs = coordinator.jobs.lock().unwrap();
+    // Workers that died took their jobs with them, so hand those out again
+    let now = Instant::now();
+    let expired: Vec<_> = jobs.claimed.iter()
+        .filter(|(_, claimed_at)| now.duration_since(**claimed_at) > coordinator.lease)
+        .map(|(job, _)| job.clone())
+        .collect();
+    for job in expired {
+        warn!("lease expired for {}-{}, requeueing", job.0, job.1);
+        jobs.claimed.remove(&job);
+        jobs.pending.push_front(job);
+    }
+    match jobs.pending.pop_front() {
+        Some((name, version)) => {
+            info!("handing out {}-{}", name, version);
+            jobs.claimed.insert((name.clone(), version.clone()), now);
+            HttpResponse::Ok().json(Job { krate: name, version })
+        },
+        // Jobs still out with other workers may yet be handed out again, so don't let this worker leave
+        None if !jobs.claimed.is_empty() => HttpResponse::ServiceUnavailable().finish(),
+        None => HttpResponse::NoContent().finish(),
+    }
+}
+
+async fn srv_post_result(coordinator: web::Data<Arc<Coordinator>>, req: HttpRequest, body: web::Json<JobResult>) -> HttpResponse {
+    if !is_authorized(&coordinator, &req) {
+        return HttpResponse::Unauthorized().finish()
+    }
+    let JobResult { krate, version, outcome } = body.into_inner();
+    let job = (krate, version);
+    {
+        let mut jobs = coordinator.jobs.lock().unwrap();
+        let was_claimed = jobs.claimed.remove(&job).is_some();
+        let pending_pos = jobs.pending.iter().position(|pending| *pending == job);
+        // A job whose lease expired may be reported by both the original and the new worker
+        if let Some(pos) = pending_pos {
+            jobs.pending.remove(pos);
+        } else if !was_claimed {
+            warn!("ignoring result for {}-{}, which isn't an outstanding job", job.0, job.1);
+            return HttpResponse::Ok().finish()
+        }
+    }
+
+    let inner = coordinator.get_ref().clone();
+    let (name, version) = job;
+    let res = web::block(move || -> Result<_, ()> {
+        let status = match outcome {
+            JobOutcome::Analyzed(res) => crate::cli_finish_and_save_analysis(&inner.db, Ok(res), &name, &version, &inner.count),
+            JobOutcome::Failed(e) => crate::cli_finish_and_save_analysis(&inner.db, Err(anyhow!(e)), &name, &version, &inner.count),
+            JobOutcome::Pushed => {
+                info!("crate {}-{} was pushed to the server by the worker", name, version);
+                inner.count.lock().unwrap().processed += 1;
+                reeves::RunStatus::Done
+            },
+        };
+        // At worst the crate is analysed again on resume
+        if let Err(e) = reeves::set_analysis_run_status(&inner.db, &name, &version, status) {
+            warn!("failed to record run status of {}-{}: {}", name, version, e)
+        }
+        Ok(())
+    }).await;
+    if res.is_err() {
+        return HttpResponse::InternalServerError().finish()
+    }
+
+    let jobs = coordinator.jobs.lock().unwrap();
+    // Duplicate results arriving during the grace period find nothing outstanding and returned above
+    if jobs.pending.is_empty() && jobs.claimed.is_empty() {
+        info!("all jobs finished: {:?}", coordinator.count.lock().unwrap());
+        let system = actix_rt::System::current();
+        let grace = coordinator.grace;
+        actix_rt::spawn(async move {
+            actix_rt::time::delay_for(grace).await;
+            system.stop()
+        })
+    }
+    HttpResponse::Ok().finish()
+}
+
+// Serve jobs until every crate has been reported on
+pub fn coordinate(db: sled::Db, crates: Vec<(String, String)>, config: CoordinatorConfig) {
+    let CoordinatorConfig { addr, token, lease, grace } = config;
+    if crates.is_empty() {
+        info!("no crates to coordinate");
+        return
+    }
+    let count = crate::CratesProgressCounter { errored: 0, processed: 0, total: crates.len() };
+    let coordinator = Arc::new(Coordinator {
+        db,
+        token,
+        lease,
+        grace,
+        jobs: Mutex::new(Jobs { pending: crates.into_iter().collect(), claimed: HashMap::new() }),
+        count: Mutex::new(count),
+    });
+
+    let app_factory = move || {
+        App::new()
+            .data(coordinator.clone())
+            .wrap(middleware::Logger::default())
+            .route(CLAIM_PATH, web::post().to(srv_post_claim))
+            .service(web::resource(RESULT_PATH)
+                // Results for big crates can be many MB of fndetails
+                .app_data(web::Json::<JobResult>::configure(|cfg| cfg.limit(MAX_RESULT_SIZE)))
+                .route(web::post().to(srv_post_result)))
+    };
+
+    info!("Coordinator starting on {}", addr);
+    let server = HttpServer::new(app_factory).bind(addr).unwrap();
+    actix_rt::System::new("actix coordinator").block_on(async {
+        server
+            .run()
+            .await
+    }).unwrap()
+}
+
+enum Claim {
+    Job(Job),
+    // Other workers' jobs may yet be handed out again
+    Wait,
+    Done,
+}
+
+fn claim(coordinator_url: &str, token: &str) -> Result<Claim, anyhow::Error> {
+    let mut res = isahc::Request::post(format!("{}{}", coordinator_url, CLAIM_PATH))
+        .header("Authorization", format!("Bearer {}", token))
+        .body(())?
+        .send()?;
+    match res.status().as_u16() {
+        200 => Ok(Claim::Job(res.json()?)),
+        204 => Ok(Claim::Done),
+        503 => Ok(Claim::Wait),
+        status => Err(anyhow!("coordinator returned {} for claim", status)),
+    }
+}
+
+fn is_connection_failure(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<isahc::Error>()
+        .map_or(false, |e| matches!(e.kind(), isahc::error::ErrorKind::ConnectionFailed))
+}
+
+enum PushError {
+    // The server refused the analysis (e.g. a bad token or crate name), so retrying won't help
+    Rejected(anyhow::Error),
+    Failed(anyhow::Error),
+}
+
+fn push(target: &PushTarget, output: &crate::AnalyzeAndPrintOutput) -> Result<(), PushError> {
+    let res = isahc::Request::post(format!("{}{}/admin/ingest", target.url, proto::API_PREFIX))
+        .header("Authorization", format!("Bearer {}", target.token))
+        .header("Content-Type", "application/json")
+        .body(serde_json::to_vec(output).unwrap())
+        .map_err(|e| PushError::Failed(e.into()))?
+        .send()
+        .map_err(|e| PushError::Failed(e.into()))?;
+    let status = res.status();
+    if status.is_client_error() {
+        return Err(PushError::Rejected(api_error(res).context("server rejected ingest")))
+    } else if !status.is_success() {
+        return Err(PushError::Failed(api_error(res).context("server failed ingest")))
+    }
+    Ok(())
+}
+
+fn push_with_retries(target: &PushTarget, output: &crate::AnalyzeAndPrintOutput) -> Result<(), anyhow::Error> {
+    let mut retries = 0;
+    loop {
+        match push(target, output) {
+            Ok(()) => return Ok(()),
+            Err(PushError::Rejected(e)) => return Err(e),
+            Err(PushError::Failed(e)) => {
+                retries += 1;
+                if retries > PUSH_MAX_RETRIES {
+                    return Err(e.context(format!("giving up after {} retries", PUSH_MAX_RETRIES)))
+                }
+                warn!("failed to push analysis of {}-{}: {}", output.crate_name, output.crate_version, e);
+                thread::sleep(WORKER_RETRY_DELAY);
+            },
+        }
+    }
+}
+
+// Server errors come as a bincode ErrorResponse, but may not if e.g. a proxy failed in front of it
+fn api_error(mut res: isahc::Response<isahc::Body>) -> anyhow::Error {
+    let status = res.status();
+    let mut body = vec![];
+    match res.copy_to(&mut body).ok().and_then(|_| bincode::deserialize::<proto::ErrorResponse>(&body).ok()) {
+        Some(proto::ErrorResponse { err }) => anyhow::Error::new(err),
+        None => anyhow!("server returned {}", status),
+    }
+}
+
+fn report(coordinator_url: &str, token: &str, result: &JobResult) -> Result<(), anyhow::Error> {
+    let res = isahc::Request::post(format!("{}{}", coordinator_url, RESULT_PATH))
+        .header("Authorization", format!("Bearer {}", token))
+        .header("Content-Type", "application/json")
+        .body(serde_json::to_vec(result).unwrap())?
+        .send()?;
+    if !res.status().is_success() {
+        return Err(anyhow!("coordinator returned {} for result", res.status()))
+    }
+    Ok(())
+}
+
+// Claim and analyze jobs until the coordinator has none left. An unreachable coordinator is retried for a while, so
+// it can be restarted (e.g. with --resume) without restarting workers - unless it was already waiting on other workers'
+// jobs, in which case it has most likely finished them and stopped
+pub fn work(sandbox: &crate::SandboxConfig, crate_source: &crate::CrateSource, coordinator_url: &str, token: &str, push_to: Option<&PushTarget>) {
+    work_loop(coordinator_url, token, WORKER_RETRY_DELAY, |krate, version| {
+        match crate::container_analyze_registry_crate(sandbox, crate_source, krate, version) {
+            Ok(res) => match push_to {
+                Some(target) => {
+                    let output = crate::AnalyzeAndPrintOutput { crate_name: krate.to_owned(), crate_version: version.to_owned(), res };
+                    match push_with_retries(target, &output) {
+                        Ok(()) => JobOutcome::Pushed,
+                        Err(e) => {
+                            warn!("failed to push analysis of {}-{}: {:?}", krate, version, e);
+                            JobOutcome::Failed(format!("failed to push analysis: {:?}", e))
+                        },
+                    }
+                },
+                None => JobOutcome::Analyzed(res),
+            },
+            Err(e) => JobOutcome::Failed(format!("{:?}", e)),
+        }
+    })
+}
+
+fn work_loop(coordinator_url: &str, token: &str, retry_delay: Duration, mut analyze: impl FnMut(&str, &str) -> JobOutcome) {
+    let mut waiting = false;
+    let mut retries = 0;
+    loop {
+        let Job { krate, version } = match claim(coordinator_url, token) {
+            Ok(Claim::Job(job)) => job,
+            Ok(Claim::Done) => return,
+            Ok(Claim::Wait) => {
+                waiting = true;
+                retries = 0;
+                thread::sleep(retry_delay);
+                continue
+            },
+            Err(e) if waiting && is_connection_failure(&e) => {
+                info!("coordinator went away while waiting for other workers, assuming it finished");
+                return
+            },
+            Err(e) => {
+                warn!("failed to claim job: {}", e);
+                retries += 1;
+                if retries > WORKER_MAX_RETRIES {
+                    warn!("giving up on the coordinator after {} retries", WORKER_MAX_RETRIES);
+                    return
+                }
+                thread::sleep(retry_delay);
+                continue
+            },
+        };
+        waiting = false;
+        retries = 0;
+        info!("analyzing crate {}-{}", krate, version);
+        let outcome = analyze(&krate, &version);
+        let result = JobResult { krate, version, outcome };
+        while let Err(e) = report(coordinator_url, token, &result) {
+            warn!("failed to report result for {}-{}: {}", result.krate, result.version, e);
+            retries += 1;
+            if retries > WORKER_MAX_RETRIES {
+                warn!("giving up on the coordinator after {} retries", WORKER_MAX_RETRIES);
+                return
+            }
+            thread::sleep(retry_delay);
+        }
+        retries = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn workers_exit_when_jobs_run_out() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        // Bind and drop to find a free port
+        let addr = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let crates = vec![("slow".to_owned(), "1.0.0".to_owned()), ("fast".to_owned(), "1.0.0".to_owned())];
+        let coordinator = {
+            let db = db.clone();
+            let config = CoordinatorConfig {
+                addr: addr.to_string(),
+                token: "token".to_owned(),
+                lease: Duration::from_secs(3600),
+                grace: Duration::from_secs(1),
+            };
+            thread::spawn(move || coordinate(db, crates, config))
+        };
+
+        // The worker that gets the fast crate is told to wait on the slow one, then must still exit
+        let url = format!("http://{}", addr);
+        let workers: Vec<_> = (0..2)
+            .map(|_| {
+                let url = url.clone();
+                thread::spawn(move || work_loop(&url, "token", Duration::from_millis(100), |krate, _| {
+                    if krate == "slow" {
+                        thread::sleep(Duration::from_secs(1))
+                    }
+                    JobOutcome::Pushed
+                }))
+            })
+            .collect();
+        for worker in workers {
+            worker.join().unwrap()
+        }
+        coordinator.join().unwrap();
+
+        let mut statuses: Vec<_> = reeves::analysis_run(&db).unwrap().into_iter().map(|(name, _, status)| (name, status)).collect();
+        statuses.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(statuses, [("fast".to_owned(), reeves::RunStatus::Done), ("slow".to_owned(), reeves::RunStatus::Done)]);
+    }
+}
@@ -1,350 +1,2297 @@
-use ra_base_db::Upcast;
-use ra_hir::db::{DefDatabase, HirDatabase};
-use ra_hir::{HasVisibility, HirDisplay};
+use ra_base_db::{SourceDatabaseExt, Upcast};
+use ra_hir::db::{AstDatabase, DefDatabase, HirDatabase};
+use ra_hir::{Docs, HasAttrs, HasSource, HasVisibility, HirDisplay};
 use ra_hir::Crate;
 use ra_hir::ItemInNs;
 use ra_hir::ModuleDef;
+use ra_hir::ScopeDef;
 use ra_hir::Visibility;
 use ra_paths::{AbsPath, AbsPathBuf};
 use ra_profile::StopWatch;
 use ra_project_model::{CargoConfig, ProjectManifest, ProjectWorkspace, TargetKind};
+use ra_syntax::AstNode;
 use rust_analyzer::cli::load_cargo::{LoadCargoConfig, load_workspace_at};
 
-use anyhow::{Error, Result, anyhow};
-use log::{trace, debug, info};
+use log::{trace, debug, info, warn};
+#[cfg(feature = "meilisearch")]
 use meilisearch_sdk as meili;
+use once_cell::sync::OnceCell;
 use serde::{Serialize, Deserialize};
+use serde::de::DeserializeOwned;
 use sled::Transactional;
-use sled::transaction::TransactionError;
+use sled::transaction::{ConflictableTransactionError, TransactionError};
 use std::cmp;
-use std::collections::{HashMap, HashSet};
-use std::path::Path;
+use std::collections::{BTreeSet, BinaryHeap, HashMap, HashSet};
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
 use std::str;
-use void::Void;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tracing::Instrument;
 
 use reeves_types::*;
+use text_search::{TextSearch, TextSearchBackend};
+
+pub mod mini_index;
+pub mod popularity;
+pub mod text_search;
+mod usage;
+#[cfg(test)]
+mod test_util;
 
 const FUZZY_SEARCH_LIMIT: usize = 100;
 const MAX_RESULTS: usize = 500;
+const SUGGEST_LIMIT: usize = 10;
+// Most ways of making a query type generic to look for, see ty::generalizations
+const MAX_GENERALIZATIONS: usize = 16;
 
 const FN_ID_COUNTER: &str = "next_fn_id"; // single u64 serialized value
-const PARAM_TREE: &str = "param"; // param_type_str.as_bytes() => bincode::serialize(HashSet<fn_id: u64>)
-const RET_TREE: &str = "ret"; // ret_type_str.as_bytes() => bincode::serialize(HashSet<fn_id: u64>)
-const FN_TREE: &str = "fn"; // bincode::serialize(fn_id: u64) => bincode::serialize(FnDetail)
+const LAST_UPDATED: &str = "last_updated"; // single u64 serialized value, ms since the unix epoch
+const PARAM_TREE: &str = "param"; // param_type_str.as_bytes() => bincode::serialize(BTreeSet<fn_id: u64>), see FnIds
+const RET_TREE: &str = "ret"; // ret_type_str.as_bytes() => bincode::serialize(BTreeSet<fn_id: u64>), see FnIds
+// As for PARAM_TREE and RET_TREE, but keyed on the element types of tuples and arrays, see part_types
+const PARAM_PART_TREE: &str = "param-part";
+const RET_PART_TREE: &str = "ret-part";
+// As for PARAM_TREE, but keyed on the param type and the number of params of the fns, see arity_key
+const PARAM_ARITY_TREE: &str = "param-arity";
+// "param" or "ret", NUL, generic shape, see ty::Type::generic_shape => bincode::serialize(BTreeSet<type_str: String>)
+// of the PARAM_TREE or RET_TREE keys with that shape
+const GENERIC_TREE: &str = "generic";
+const DB_FORMAT: &str = "db_format"; // single u32 serialized value, see CURRENT_DB_FORMAT
+// Single u32 serialized value, the format whose FN_TREE layout the fns have - only there during a migration from before
+// FN_LAYOUT_FORMAT, once the fns have been rewritten but the format hasn't caught up
+const DB_FN_LAYOUT: &str = "db_fn_layout";
+const FN_TREE: &str = "fn"; // bincode::serialize(fn_id: u64) => rkyv archive of FnDetail, see FnView
 const CRATE_TREE: &str = "crate"; // crate_name_str.as_bytes() => bincode::serialize((version: String, fn_ids: Vec<u64>))
 const ERROR_TREE: &str = "crate-error"; // crate_name_str.as_bytes() => bincode::serialize((version: String, err: String))
+const QUERY_LOG_TREE: &str = "query-log"; // log_id: u64.to_be_bytes() => bincode::serialize(QueryLogEntry)
+const FEEDBACK_TREE: &str = "feedback"; // query pattern, NUL, crate name, NUL, fn path => bincode::serialize(FeedbackCounts)
+const ANALYSIS_RUN_TREE: &str = "analysis-run"; // crate_name_str.as_bytes() => bincode::serialize((version: String, status: RunStatus))
+const TEXT_SEARCH_SYNC_TREE: &str = "text-search-sync"; // "param" or "ret", NUL, type_str => () for types in the text search
+const POPULARITY_TREE: &str = "popularity"; // crate_name_str.as_bytes() => bincode::serialize(popularity::CratePopularity)
+
+// Bump when the layout of a tree changes, migrating older dbs in open_db. 1 had bincode FN_TREE values, 2 had unsorted
+// type tree fn ids, 5 had no part trees, 6 had no arity tree, 7 had unnormalized types, 8 had no generic tree, 9 had
+// no fn modules or source spans, 10 could have methods keyed on Self, 11 had no impl Trait generic shapes, 12 had builder
+// chains with their build method's path
+const CURRENT_DB_FORMAT: u32 = 13;
+// The format the current FN_TREE layout is from
+const FN_LAYOUT_FORMAT: u32 = 10;
+
+// Longer types in a query are rejected rather than parsed - real signatures' types are far shorter
+const MAX_QUERY_TYPE_LEN: usize = 1024;
+
+// Search stops looking at fuzzier candidate types once the fn ids of a pass would take more than this
+const DEFAULT_SEARCH_MEMORY_BUDGET: usize = 256 * 1024 * 1024;
+static SEARCH_MEMORY_BUDGET: OnceCell<usize> = OnceCell::new();
+// Search stops starting new passes when the next would likely end after this long. There's no deadline by default
+static SEARCH_DEADLINE: OnceCell<Duration> = OnceCell::new();
+// Whether a ret also finds fns returning the same type by reference or smart pointer, see ty::ownership_variants. On by
+// default
+static RET_OWNERSHIP_VARIANTS: OnceCell<bool> = OnceCell::new();
+
+// Oldest query log entries are dropped beyond this
+const QUERY_LOG_MAX_ENTRIES: u64 = 1_000_000;
+
+// A result needs at least this many reports for a query pattern before it's demoted for it
+const FEEDBACK_DEMOTE_REPORTS: u64 = 3;
+
+// A sentinel to represent functions with no arguments (must not be a possible type)
+const NIL_PARAMS: &str = "<NOARGS>";
+
+// For fuzzy searching
+const PARAM_TYPES_INDEX: &str = "param_types";
+const RET_TYPES_INDEX: &str = "ret_types";
+
+fn stop_watch() -> StopWatch {
+    StopWatch::start()
+}
+
+static TEXT_SEARCH_BACKEND: OnceCell<TextSearchBackend> = OnceCell::new();
+static TEXT_SEARCH_URL: OnceCell<String> = OnceCell::new();
+
+// These set the process-wide settings the free functions use, so can each only be set once, before any text search
+// operations or searches. To use different settings, e.g. in tests, give a Reeves its own Options instead
+
+// Without it, tantivy is used
+pub fn set_text_search_backend(backend: TextSearchBackend) -> Result<(), ReevesError> {
+    TEXT_SEARCH_BACKEND.set(backend).map_err(|_| ReevesError::AlreadySet("text search backend"))
+}
+
+// Without it, the backend's default url is used
+pub fn set_text_search_url(url: String) -> Result<(), ReevesError> {
+    TEXT_SEARCH_URL.set(url).map_err(|_| ReevesError::AlreadySet("text search url"))
+}
+
+pub fn set_search_memory_budget(bytes: usize) -> Result<(), ReevesError> {
+    SEARCH_MEMORY_BUDGET.set(bytes).map_err(|_| ReevesError::AlreadySet("search memory budget"))
+}
+
+pub fn set_search_deadline(deadline: Duration) -> Result<(), ReevesError> {
+    SEARCH_DEADLINE.set(deadline).map_err(|_| ReevesError::AlreadySet("search deadline"))
+}
+
+pub fn set_ret_ownership_variants(enabled: bool) -> Result<(), ReevesError> {
+    RET_OWNERSHIP_VARIANTS.set(enabled).map_err(|_| ReevesError::AlreadySet("ret ownership variants"))
+}
+
+// Everything reading or writing the index can fail with. Db and text search errors may be transient, corrupt records
+// won't fix themselves and need the crate reanalysing (or the db verifying)
+#[derive(Debug, thiserror::Error)]
+pub enum ReevesError {
+    #[error("db error: {0}")]
+    Db(#[from] sled::Error),
+    #[error("text search backend error: {0}")]
+    TextSearch(String),
+    #[error("text search backend didn't finish an update")]
+    TextSearchUpdate,
+    #[error("corrupt {tree} record {key}: {reason}")]
+    Corrupt { tree: &'static str, key: String, reason: String },
+    #[error("db format {found} is newer than this reeves understands ({supported})")]
+    UnsupportedDbFormat { found: u32, supported: u32 },
+    #[error("invalid query: {0}")]
+    InvalidQuery(String),
+    #[error("io error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("failed to {action} {}: {reason}", path.display())]
+    File { action: &'static str, path: PathBuf, reason: String },
+    #[error("invalid JSON: {0}")]
+    Json(String),
+    #[error("analysis failed: {0}")]
+    Analysis(String),
+    #[error("refusing to restore a snapshot into a non-empty db ({crates} crates, {errored} errored)")]
+    NotEmpty { crates: usize, errored: usize },
+    #[error("{0} is already set")]
+    AlreadySet(&'static str),
+}
+
+#[cfg(feature = "meilisearch")]
+impl From<meili::errors::Error> for ReevesError {
+    fn from(e: meili::errors::Error) -> Self {
+        ReevesError::TextSearch(format!("{:?}", e))
+    }
+}
+
+impl From<TransactionError<ReevesError>> for ReevesError {
+    fn from(e: TransactionError<ReevesError>) -> Self {
+        match e {
+            TransactionError::Abort(e) => e,
+            TransactionError::Storage(e) => ReevesError::Db(e),
+        }
+    }
+}
+
+// A failing text search backend can recover, unlike a failing db
+impl From<ReevesError> for proto::ApiError {
+    fn from(e: ReevesError) -> Self {
+        match e {
+            ReevesError::TextSearch(_) | ReevesError::TextSearchUpdate => proto::ApiError::BackendUnavailable(e.to_string()),
+            ReevesError::Db(_) | ReevesError::Corrupt { .. } | ReevesError::UnsupportedDbFormat { .. } => proto::ApiError::Internal(e.to_string()),
+            ReevesError::InvalidQuery(_) => proto::ApiError::BadRequest(e.to_string()),
+            ReevesError::Io(_) | ReevesError::File { .. } | ReevesError::Json(_) | ReevesError::Analysis(_) |
+            ReevesError::NotEmpty { .. } | ReevesError::AlreadySet(_) => proto::ApiError::Internal(e.to_string()),
+        }
+    }
+}
+
+fn corrupt(tree: &'static str, key: impl fmt::Display, reason: impl fmt::Display) -> ReevesError {
+    ReevesError::Corrupt { tree, key: key.to_string(), reason: reason.to_string() }
+}
+
+fn file_error(action: &'static str, path: &Path, reason: impl fmt::Display) -> ReevesError {
+    ReevesError::File { action, path: path.to_owned(), reason: reason.to_string() }
+}
+
+fn decode<T: DeserializeOwned>(tree: &'static str, key: impl fmt::Display, bs: &[u8]) -> Result<T, ReevesError> {
+    bincode::deserialize(bs).map_err(|e| corrupt(tree, key, e))
+}
+
+fn decode_name(tree: &'static str, key: &[u8]) -> Result<String, ReevesError> {
+    str::from_utf8(key).map(str::to_owned).map_err(|e| corrupt(tree, String::from_utf8_lossy(key), e))
+}
+
+// For errors inside sled transactions, which are unwrapped again by From<TransactionError>
+fn abort(e: ReevesError) -> ConflictableTransactionError<ReevesError> {
+    ConflictableTransactionError::Abort(e)
+}
+
+// Settings for a Reeves. The free functions use the ones set with set_text_search_backend, set_text_search_url,
+// set_search_memory_budget, set_search_deadline and set_ret_ownership_variants
+#[derive(Clone, Debug)]
+pub struct Options {
+    pub text_search_backend: TextSearchBackend,
+    pub text_search_url: String,
+    pub search_memory_budget: usize, // bytes, see DEFAULT_SEARCH_MEMORY_BUDGET
+    pub search_deadline: Option<Duration>, // from the start of a search, see SEARCH_DEADLINE
+    pub ret_ownership_variants: bool, // see RET_OWNERSHIP_VARIANTS
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            text_search_backend: TextSearchBackend::default(),
+            text_search_url: TextSearchBackend::default().default_url().to_owned(),
+            search_memory_budget: DEFAULT_SEARCH_MEMORY_BUDGET,
+            search_deadline: None,
+            ret_ownership_variants: true,
+        }
+    }
+}
+
+impl Options {
+    fn from_globals() -> Self {
+        let text_search_backend = TEXT_SEARCH_BACKEND.get().copied().unwrap_or_default();
+        Self {
+            text_search_backend,
+            text_search_url: TEXT_SEARCH_URL.get().cloned().unwrap_or_else(|| text_search_backend.default_url().to_owned()),
+            search_memory_budget: *SEARCH_MEMORY_BUDGET.get().unwrap_or(&DEFAULT_SEARCH_MEMORY_BUDGET),
+            search_deadline: SEARCH_DEADLINE.get().copied(),
+            ret_ownership_variants: *RET_OWNERSHIP_VARIANTS.get().unwrap_or(&true),
+        }
+    }
+
+    fn text_search(&self) -> Box<dyn TextSearch> {
+        self.text_search_backend.connect(&self.text_search_url)
+    }
+}
+
+// An index of functions and the text search over their types, for embedding reeves in other programs. Unlike the free
+// functions, which use process-wide settings, each Reeves carries its own so several can be used at once. The sync
+// methods that use the text search (searching, suggesting and loading or syncing it) wait for it on an executor of
+// their own, so mustn't be called from async code, which may deadlock - use their _async variants there
+#[derive(Clone)]
+pub struct Reeves {
+    db: sled::Db,
+    options: Options,
+}
+
+impl Reeves {
+    // Open (creating or migrating if necessary) the db at path
+    pub fn open(path: &Path, options: Options) -> Result<Self, ReevesError> {
+        Ok(Self::from_db(open_db(path)?, options))
+    }
+
+    // The db must have been opened with open_db
+    pub fn from_db(db: sled::Db, options: Options) -> Self {
+        Self { db, options }
+    }
+
+    pub fn db(&self) -> &sled::Db {
+        &self.db
+    }
+
+    pub fn options(&self) -> &Options {
+        &self.options
+    }
+
+    // Analysis doesn't touch the db, so the result can be inspected before saving. Fails if the path isn't a workspace
+    // rust-analyzer can load, otherwise each package has its own result
+    pub fn analyze(&self, path: &Path) -> Result<Vec<(String, String, Result<Vec<FnDetail>, ReevesError>)>, ReevesError> {
+        analyze_crate_path(path)
+    }
+
+    // Replaces any existing analysis of the crate. The text search is only updated by sync_text_search or a load
+    pub fn save(&self, krate_name: &str, krate_version: &str, fndetails: Vec<FnDetail>) -> Result<(), ReevesError> {
+        save_analysis(&self.db, krate_name, krate_version, fndetails)
+    }
+
+    pub fn save_error(&self, krate_name: &str, krate_version: &str, err: &str) -> Result<(), ReevesError> {
+        save_analysis_error(&self.db, krate_name, krate_version, err)
+    }
+
+    pub fn remove(&self, krate_name: &str) -> Result<bool, ReevesError> {
+        remove_crate(&self.db, krate_name)
+    }
+
+    pub fn crate_fns(&self, krate_name: &str) -> Result<Option<(String, Vec<FnDetail>)>, ReevesError> {
+        crate_fns(&self.db, krate_name)
+    }
+
+    pub fn crate_fn(&self, krate_name: &str, hash: &str) -> Result<Option<FnDetail>, ReevesError> {
+        crate_fn(&self.db, krate_name, hash)
+    }
+
+    pub fn list_crates(&self) -> Result<Vec<(String, String, usize)>, ReevesError> {
+        list_crates(&self.db)
+    }
+
+    pub fn search(&self, params_search: Option<Vec<String>>, ret_search: Option<String>) -> Result<Vec<FnDetail>, ReevesError> {
+        Ok(self.search_with_info(params_search, ret_search, false)?.0.iter().map(FnView::to_fndetail).collect())
+    }
+
+    pub fn search_with_info(&self, params_search: Option<Vec<String>>, ret_search: Option<String>, exact_params: bool) -> Result<(Vec<FnView>, SearchInfo), ReevesError> {
+        let mut ret = vec![];
+        let info = self.search_batches(params_search, ret_search, exact_params, |_query, batch| { ret.extend(batch); true })?;
+        Ok((ret, info))
+    }
+
+    pub fn search_batches(&self, params_search: Option<Vec<String>>, ret_search: Option<String>, exact_params: bool, on_batch: impl FnMut(&proto::QueryEcho, Vec<FnView>) -> bool) -> Result<SearchInfo, ReevesError> {
+        search_batches_opts(&self.db, &self.options, params_search, ret_search, exact_params, on_batch)
+    }
+
+    pub async fn search_async(&self, params_search: Option<Vec<String>>, ret_search: Option<String>) -> Result<Vec<FnDetail>, ReevesError> {
+        Ok(self.search_with_info_async(params_search, ret_search, false).await?.0.iter().map(FnView::to_fndetail).collect())
+    }
+
+    pub async fn search_with_info_async(&self, params_search: Option<Vec<String>>, ret_search: Option<String>, exact_params: bool) -> Result<(Vec<FnView>, SearchInfo), ReevesError> {
+        search_with_info_async_opts(&self.db, &self.options, params_search, ret_search, exact_params).await
+    }
+
+    pub fn suggest(&self, query: &str) -> Result<Vec<String>, ReevesError> {
+        futures::executor::block_on(suggest_async_opts(&self.options, query))
+    }
+
+    pub async fn suggest_async(&self, query: &str) -> Result<Vec<String>, ReevesError> {
+        suggest_async_opts(&self.options, query).await
+    }
+
+    pub fn stats(&self) -> Result<proto::StatsResult, ReevesError> {
+        stats(&self.db)
+    }
+
+    pub fn preload(&self) -> Result<PreloadStats, ReevesError> {
+        preload(&self.db)
+    }
+
+    pub fn export(&self, out: &mut dyn Write) -> Result<usize, ReevesError> {
+        export_jsonl(&self.db, out)
+    }
+
+    pub fn import(&self, input: &mut dyn BufRead) -> Result<usize, ReevesError> {
+        import_jsonl(&self.db, input)
+    }
+
+    pub fn type_graph(&self, krate_name: Option<&str>) -> Result<TypeGraph, ReevesError> {
+        type_graph(&self.db, krate_name)
+    }
+
+    // Replace the text search indexes with the types in the db
+    pub fn load_text_search(&self) -> Result<(), ReevesError> {
+        futures::executor::block_on(self.load_text_search_async())
+    }
+
+    pub async fn load_text_search_async(&self) -> Result<(), ReevesError> {
+        load_text_search_async_opts(&self.db, &self.options).await
+    }
+
+    pub fn load_text_search_incremental(&self) -> Result<(), ReevesError> {
+        futures::executor::block_on(self.load_text_search_incremental_async())
+    }
+
+    pub async fn load_text_search_incremental_async(&self) -> Result<(), ReevesError> {
+        load_text_search_incremental_async_opts(&self.db, &self.options).await
+    }
+
+    pub fn sync_text_search(&self, krate_names: &[String]) -> Result<(), ReevesError> {
+        futures::executor::block_on(self.sync_text_search_async(krate_names))
+    }
+
+    pub async fn sync_text_search_async(&self, krate_names: &[String]) -> Result<(), ReevesError> {
+        sync_text_search_async_opts(&self.db, &self.options, krate_names).await
+    }
+}
+
+pub fn open_db(path: &Path) -> Result<sled::Db, ReevesError> {
+    let db = sled::open(path)?;
+    // Every db that's been opened has a counter, so one without is new and has nothing to migrate
+    if !db.contains_key(FN_ID_COUNTER)? {
+        db.insert(FN_ID_COUNTER, bincode::serialize(&0u64).unwrap())?;
+        set_db_format(&db, CURRENT_DB_FORMAT)?;
+    }
+    let format: u32 = match db.get(DB_FORMAT)? {
+        Some(bs) => decode("db", DB_FORMAT, &bs)?,
+        None => 1,
+    };
+    if format > CURRENT_DB_FORMAT {
+        return Err(ReevesError::UnsupportedDbFormat { found: format, supported: CURRENT_DB_FORMAT })
+    }
+    // The other steps read fns with the current layout, so fns from formats before it are rewritten first. Until the
+    // format catches up, DB_FN_LAYOUT records that they have been
+    let fn_layout: u32 = match db.get(DB_FN_LAYOUT)? {
+        Some(bs) => decode("db", DB_FN_LAYOUT, &bs)?,
+        None => format,
+    };
+    if fn_layout < FN_LAYOUT_FORMAT {
+        migrate_fn_layout(&db, fn_layout)?;
+    }
+    // Each step records the format it brings the db to once its changes are flushed, so a crash part way through
+    // redoes the step rather than leaving data that doesn't match the format. The steps are safe to redo
+    if format < 3 {
+        for treename in &[PARAM_TREE, RET_TREE] {
+            let tree = db.open_tree(treename)?;
+            info!("migrating {} {} types to sorted fn ids", tree.len(), treename);
+            for kv in tree.iter() {
+                let (k, v) = kv?;
+                // Sets have the same serialization whatever their order, so this just sorts
+                let fn_ids: BTreeSet<u64> = decode(*treename, String::from_utf8_lossy(&k), &v)?;
+                tree.insert(k, bincode::serialize(&fn_ids).unwrap())?;
+            }
+        }
+        set_db_format(&db, 3)?;
+    }
+    if format < 6 {
+        let fn_tree = db.open_tree(FN_TREE)?;
+        info!("adding tuple and array element types of {} fns", fn_tree.len());
+        let mut param_part_sets: HashMap<String, BTreeSet<u64>> = HashMap::new();
+        let mut ret_part_sets: HashMap<String, BTreeSet<u64>> = HashMap::new();
+        for kv in fn_tree.iter() {
+            let (k, v) = kv?;
+            let fn_id: u64 = decode(FN_TREE, format_args!("{:?}", k), &k)?;
+            let (param_parts, ret_parts) = part_types(&FnView::new(fn_id, &v)?.to_fndetail());
+            for part in param_parts {
+                param_part_sets.entry(part).or_default().insert(fn_id);
+            }
+            for part in ret_parts {
+                ret_part_sets.entry(part).or_default().insert(fn_id);
+            }
+        }
+        for (treename, sets) in &[(PARAM_PART_TREE, param_part_sets), (RET_PART_TREE, ret_part_sets)] {
+            let tree = db.open_tree(treename)?;
+            for (part, fn_ids) in sets {
+                tree.insert(part.as_bytes(), bincode::serialize(fn_ids).unwrap())?;
+            }
+        }
+        set_db_format(&db, 6)?;
+    }
+    if format < 7 {
+        let fn_tree = db.open_tree(FN_TREE)?;
+        info!("adding param counts of {} fns", fn_tree.len());
+        let mut arity_sets: HashMap<String, BTreeSet<u64>> = HashMap::new();
+        for kv in fn_tree.iter() {
+            let (k, v) = kv?;
+            let fn_id: u64 = decode(FN_TREE, format_args!("{:?}", k), &k)?;
+            for key in arity_keys(&FnView::new(fn_id, &v)?.to_fndetail()) {
+                arity_sets.entry(key).or_default().insert(fn_id);
+            }
+        }
+        let tree = db.open_tree(PARAM_ARITY_TREE)?;
+        for (key, fn_ids) in arity_sets {
+            tree.insert(key.as_bytes(), bincode::serialize(&fn_ids).unwrap())?;
+        }
+        set_db_format(&db, 7)?;
+    }
+    if format < 8 {
+        let fn_tree = db.open_tree(FN_TREE)?;
+        info!("normalizing types of {} fns", fn_tree.len());
+        // Every type tree is keyed on the types, so they're rebuilt from scratch rather than having keys moved
+        let mut param_sets: HashMap<String, BTreeSet<u64>> = HashMap::new();
+        let mut ret_sets: HashMap<String, BTreeSet<u64>> = HashMap::new();
+        let mut param_part_sets: HashMap<String, BTreeSet<u64>> = HashMap::new();
+        let mut ret_part_sets: HashMap<String, BTreeSet<u64>> = HashMap::new();
+        let mut param_arity_sets: HashMap<String, BTreeSet<u64>> = HashMap::new();
+        let mut num_renamed = 0;
+        for kv in fn_tree.iter() {
+            let (k, v) = kv?;
+            let fn_id: u64 = decode(FN_TREE, format_args!("{:?}", k), &k)?;
+            let mut fndetail = FnView::new(fn_id, &v)?.to_fndetail();
+            if normalize_fndetail(&mut fndetail) {
+                fn_tree.insert(&k, archive_fndetail(&fndetail).as_slice())?;
+                num_renamed += 1;
+            }
+            if fndetail.params.is_empty() {
+                param_sets.entry(NIL_PARAMS.into()).or_default().insert(fn_id);
+            }
+            for param in fndetail.params.iter() {
+                param_sets.entry(param.to_owned()).or_default().insert(fn_id);
+            }
+            ret_sets.entry(fndetail.ret.to_owned()).or_default().insert(fn_id);
+            let (param_parts, ret_parts) = part_types(&fndetail);
+            for part in param_parts {
+                param_part_sets.entry(part).or_default().insert(fn_id);
+            }
+            for part in ret_parts {
+                ret_part_sets.entry(part).or_default().insert(fn_id);
+            }
+            for key in arity_keys(&fndetail) {
+                param_arity_sets.entry(key).or_default().insert(fn_id);
+            }
+        }
+        let trees = [
+            (PARAM_TREE, param_sets),
+            (RET_TREE, ret_sets),
+            (PARAM_PART_TREE, param_part_sets),
+            (RET_PART_TREE, ret_part_sets),
+            (PARAM_ARITY_TREE, param_arity_sets),
+        ];
+        for (treename, sets) in &trees {
+            let tree = db.open_tree(treename)?;
+            tree.clear()?;
+            for (key, fn_ids) in sets {
+                tree.insert(key.as_bytes(), bincode::serialize(fn_ids).unwrap())?;
+            }
+        }
+        if num_renamed > 0 {
+            warn!("types of {} fns have been renamed, run load-text-search --incremental to update the text search", num_renamed);
+        }
+        set_db_format(&db, 8)?;
+    }
+    if format < 11 {
+        // Methods analyzed before Self was substituted are keyed on Self, so only found by searching for it. What Self
+        // was, with its type params, is only known to the analysis, so the crates have to be re-analyzed
+        let fn_tree = db.open_tree(FN_TREE)?;
+        info!("looking for methods keyed on Self among {} fns", fn_tree.len());
+        let mentions_self = |ty: &str| substitute_self(ty, "_") != ty;
+        let mut stale_crates: BTreeSet<String> = BTreeSet::new();
+        for kv in fn_tree.iter() {
+            let (k, v) = kv?;
+            let fnview = FnView::new(format_args!("{:?}", k), &v)?;
+            let fndetail = fnview.get();
+            if fndetail.params.iter().any(|param| mentions_self(param.as_str())) || mentions_self(fndetail.ret.as_str()) {
+                stale_crates.insert(fndetail.krate.to_string());
+            }
+        }
+        if !stale_crates.is_empty() {
+            warn!("{} crates have methods keyed on Self, which searches for their types won't find until they're re-analyzed: {}",
+                  stale_crates.len(), stale_crates.into_iter().collect::<Vec<_>>().join(", "));
+        }
+        set_db_format(&db, 11)?;
+    }
+    if format < 12 {
+        let mut generic_sets: HashMap<String, BTreeSet<String>> = HashMap::new();
+        for treename in &[PARAM_TREE, RET_TREE] {
+            let tree = db.open_tree(treename)?;
+            info!("finding generic {} types among {}", treename, tree.len());
+            let tys = tree.iter().keys()
+                .map(|key| decode_name(*treename, &key?))
+                .collect::<Result<Vec<_>, ReevesError>>()?;
+            add_generic_shapes(&mut generic_sets, treename, tys.iter().map(String::as_str));
+        }
+        let generic_tree = db.open_tree(GENERIC_TREE)?;
+        generic_tree.clear()?;
+        for (key, tys) in generic_sets {
+            generic_tree.insert(key.as_bytes(), bincode::serialize(&tys).unwrap())?;
+        }
+        set_db_format(&db, 12)?;
+    }
+    if format < 13 {
+        let fn_tree = db.open_tree(FN_TREE)?;
+        info!("giving builder chains among {} fns their own paths", fn_tree.len());
+        for kv in fn_tree.iter() {
+            let (k, v) = kv?;
+            let mut fndetail = FnView::new(format_args!("{:?}", k), &v)?.to_fndetail();
+            if fndetail.kind != ItemKind::BuilderChain {
+                continue
+            }
+            // s is `fn <constructor path>(<params>).<build method>() -> <ret>`
+            let constructor_path = fndetail.s.strip_prefix("fn ").and_then(|rest| rest.split('(').next())
+                .ok_or_else(|| corrupt(FN_TREE, &fndetail.path, "bad builder chain signature"))?;
+            fndetail.path = builder_chain_path(constructor_path, &fndetail.name);
+            fn_tree.insert(&k, archive_fndetail(&fndetail).as_slice())?;
+        }
+        set_db_format(&db, 13)?;
+    }
+    db.remove(DB_FN_LAYOUT)?;
+    Ok(db)
+}
+
+// Record that the db has been migrated to a format, once the migration's changes are on disk
+fn set_db_format(db: &sled::Db, format: u32) -> Result<(), ReevesError> {
+    db.flush()?;
+    db.insert(DB_FORMAT, bincode::serialize(&format).unwrap())?;
+    db.flush()?;
+    Ok(())
+}
+
+// Rewrite fns with the layout of an older format in the current one - format 1 serialized them with bincode, and 2 to 9
+// archived them with a layout of their own (see FnDetailV1 to FnDetailV9). The rewrite is one transaction, with the new
+// layout recorded in it, so no fn is read with the wrong layout whenever the migration stops
+fn migrate_fn_layout(db: &sled::Db, layout: u32) -> Result<(), ReevesError> {
+    let fn_tree = db.open_tree(FN_TREE)?;
+    let crate_tree = db.open_tree(CRATE_TREE)?;
+    info!("migrating {} fns from the format {} layout to the current one", fn_tree.len(), layout);
+    // Format 1 fns didn't have their version, which was only kept with the crate
+    let mut versions: HashMap<String, String> = HashMap::new();
+    if layout < 2 {
+        for kv in crate_tree.iter() {
+            let (k, v) = kv?;
+            let name = decode_name(CRATE_TREE, &k)?;
+            let (version, _fn_ids): (String, Vec<u64>) = decode(CRATE_TREE, &name, &v)?;
+            versions.insert(name, version);
+        }
+    }
+    let mut migrated = vec![];
+    for kv in fn_tree.iter() {
+        let (k, v) = kv?;
+        let fndetail = if layout < 2 {
+            let old: FnDetailV1 = decode(FN_TREE, String::from_utf8_lossy(&k), &v)?;
+            let version = versions.get(&old.krate).cloned().unwrap_or_default();
+            old.into_fndetail(version)
+        } else {
+            let mut aligned = rkyv::AlignedVec::with_capacity(v.len());
+            aligned.extend_from_slice(&v);
+            let key = format!("{:?}", k);
+            if layout < 4 {
+                let old = check_archive::<FnDetailV3>(&key, &aligned)?;
+                let old: FnDetailV3 = rkyv::Deserialize::deserialize(old, &mut rkyv::Infallible).unwrap();
+                old.into_fndetail()
+            } else if layout < 5 {
+                let old = check_archive::<FnDetailV4>(&key, &aligned)?;
+                let old: FnDetailV4 = rkyv::Deserialize::deserialize(old, &mut rkyv::Infallible).unwrap();
+                old.into_fndetail()
+            } else {
+                let old = check_archive::<FnDetailV9>(&key, &aligned)?;
+                let old: FnDetailV9 = rkyv::Deserialize::deserialize(old, &mut rkyv::Infallible).unwrap();
+                old.into_fndetail()
+            }
+        };
+        migrated.push((k, archive_fndetail(&fndetail)));
+    }
+    let default_tree: &sled::Tree = db;
+    let ret: Result<_, TransactionError<ReevesError>> = (&fn_tree, default_tree).transaction(|(fn_tree, default_tree)| {
+        for (k, v) in migrated.iter() {
+            fn_tree.insert(k.clone(), v.as_slice())?;
+        }
+        default_tree.insert(DB_FN_LAYOUT, bincode::serialize(&FN_LAYOUT_FORMAT).unwrap())?;
+        Ok(())
+    });
+    ret?;
+    db.flush()?;
+    Ok(())
+}
+
+// Put the types of a fn into normal form, returning whether any changed, for fns analyzed before types were normalized
+// or read from outside the analysis (e.g. an import). The signature is left as rust analyzer displayed it
+fn normalize_fndetail(fndetail: &mut FnDetail) -> bool {
+    let mut changed = false;
+    for (param, param_ty) in fndetail.params.iter_mut().zip(fndetail.param_tys.iter_mut()) {
+        changed |= normalize_in_place(param, param_ty);
+    }
+    changed |= normalize_in_place(&mut fndetail.ret, &mut fndetail.ret_ty);
+    changed
+}
+
+fn normalize_in_place(s: &mut String, parsed: &mut ty::Type) -> bool {
+    let normalized = ty::normalize_type(s);
+    if normalized == *s {
+        return false
+    }
+    if let Ok(ty) = ty::parse_type(&normalized) {
+        *parsed = ty;
+    }
+    *s = normalized;
+    true
+}
+
+// FnDetail as serialized with bincode in format 1 dbs
+#[derive(Deserialize)]
+struct FnDetailV1 {
+    krate: String,
+    params: Vec<String>,
+    ret: String,
+    s: String,
+}
+
+impl FnDetailV1 {
+    fn into_fndetail(self, version: String) -> FnDetail {
+        let FnDetailV1 { krate, params, ret, s } = self;
+        // s is `fn <path>(<params>) -> <ret>`, with the path from the crate root. The import name wasn't recorded, but is
+        // the crate name unless the lib target is renamed
+        let path_in_crate = s.strip_prefix("fn ").and_then(|rest| rest.split('(').next()).unwrap_or("");
+        let path = format!("{}::{}", krate.replace('-', "_"), path_in_crate);
+        let name = path.rsplit("::").next().unwrap().to_owned();
+        let parse_type = |ty: &str| ty::parse_type(ty).unwrap_or(ty::Type::Infer);
+        let param_tys = params.iter().map(|param| parse_type(param)).collect();
+        let ret_ty = parse_type(&ret);
+        // The kind wasn't recorded either, and methods can't be told apart from free functions by the path alone
+        FnDetail {
+            krate, version, path, name, kind: ItemKind::Function, params, ret, param_tys, ret_ty, s,
+            doc_summary: None, is_unsafe: false, is_async: false, is_const: false, is_deprecated: false,
+            example: None, reexported_from: None, module: String::new(), source: None,
+        }
+    }
+}
+
+// FnDetail as archived before examples were recorded, so older dbs can be migrated
+#[derive(rkyv::Archive, rkyv::Deserialize)]
+#[archive_attr(derive(bytecheck::CheckBytes))]
+struct FnDetailV3 {
+    krate: String,
+    version: String,
+    path: String,
+    name: String,
+    kind: ItemKind,
+    params: Vec<String>,
+    ret: String,
+    param_tys: Vec<ty::Type>,
+    ret_ty: ty::Type,
+    s: String,
+    doc_summary: Option<String>,
+    is_unsafe: bool,
+    is_async: bool,
+    is_const: bool,
+    is_deprecated: bool,
+}
+
+impl FnDetailV3 {
+    fn into_fndetail(self) -> FnDetail {
+        let FnDetailV3 { krate, version, path, name, kind, params, ret, param_tys, ret_ty, s, doc_summary, is_unsafe, is_async, is_const, is_deprecated } = self;
+        FnDetail { krate, version, path, name, kind, params, ret, param_tys, ret_ty, s, doc_summary, is_unsafe, is_async, is_const, is_deprecated, example: None, reexported_from: None, module: String::new(), source: None }
+    }
+}
+
+// FnDetail as archived before re-exports were followed
+#[derive(rkyv::Archive, rkyv::Deserialize)]
+#[archive_attr(derive(bytecheck::CheckBytes))]
+struct FnDetailV4 {
+    krate: String,
+    version: String,
+    path: String,
+    name: String,
+    kind: ItemKind,
+    params: Vec<String>,
+    ret: String,
+    param_tys: Vec<ty::Type>,
+    ret_ty: ty::Type,
+    s: String,
+    doc_summary: Option<String>,
+    is_unsafe: bool,
+    is_async: bool,
+    is_const: bool,
+    is_deprecated: bool,
+    example: Option<String>,
+}
+
+impl FnDetailV4 {
+    fn into_fndetail(self) -> FnDetail {
+        let FnDetailV4 { krate, version, path, name, kind, params, ret, param_tys, ret_ty, s, doc_summary, is_unsafe, is_async, is_const, is_deprecated, example } = self;
+        FnDetail { krate, version, path, name, kind, params, ret, param_tys, ret_ty, s, doc_summary, is_unsafe, is_async, is_const, is_deprecated, example, reexported_from: None, module: String::new(), source: None }
+    }
+}
+
+// FnDetail as archived before modules and source spans were recorded
+#[derive(rkyv::Archive, rkyv::Deserialize)]
+#[archive_attr(derive(bytecheck::CheckBytes))]
+struct FnDetailV9 {
+    krate: String,
+    version: String,
+    path: String,
+    name: String,
+    kind: ItemKind,
+    params: Vec<String>,
+    ret: String,
+    param_tys: Vec<ty::Type>,
+    ret_ty: ty::Type,
+    s: String,
+    doc_summary: Option<String>,
+    is_unsafe: bool,
+    is_async: bool,
+    is_const: bool,
+    is_deprecated: bool,
+    example: Option<String>,
+    reexported_from: Option<String>,
+}
+
+impl FnDetailV9 {
+    fn into_fndetail(self) -> FnDetail {
+        let FnDetailV9 { krate, version, path, name, kind, params, ret, param_tys, ret_ty, s, doc_summary, is_unsafe, is_async, is_const, is_deprecated, example, reexported_from } = self;
+        FnDetail { krate, version, path, name, kind, params, ret, param_tys, ret_ty, s, doc_summary, is_unsafe, is_async, is_const, is_deprecated, example, reexported_from, module: String::new(), source: None }
+    }
+}
+
+// A fn read from the db, which can be looked at without deserializing it - search filters, sorts and pages results
+// this way, and only deserializes what it returns
+pub struct FnView {
+    bytes: rkyv::AlignedVec,
+    partial: bool,
+    ownership_variant: bool,
+}
+
+impl FnView {
+    // The key is what to call the fn if it's corrupt
+    fn new(key: impl fmt::Display, bytes: &[u8]) -> Result<Self, ReevesError> {
+        // sled values have no alignment guarantees, and archives must be aligned
+        let mut aligned = rkyv::AlignedVec::with_capacity(bytes.len());
+        aligned.extend_from_slice(bytes);
+        check_archive::<FnDetail>(key, &aligned)?;
+        Ok(FnView { bytes: aligned, partial: false, ownership_variant: false })
+    }
+
+    pub fn get(&self) -> &ArchivedFnDetail {
+        // Safe as the archive was checked when the view was made
+        unsafe { rkyv::archived_root::<FnDetail>(&self.bytes) }
+    }
+
+    // Whether a search found this through the element types of a tuple or array, see PARAM_PART_TREE
+    pub fn is_partial(&self) -> bool {
+        self.partial
+    }
+
+    // Whether a search found this by returning the searched for type differently held, see ty::ownership_variants
+    pub fn is_ownership_variant(&self) -> bool {
+        self.ownership_variant
+    }
+
+    pub fn to_fndetail(&self) -> FnDetail {
+        rkyv::Deserialize::deserialize(self.get(), &mut rkyv::Infallible).unwrap()
+    }
+}
+
+// FN_TREE values are checked before they're read in place, so a corrupt one is an error rather than undefined behaviour
+fn check_archive<'a, T: rkyv::Archive>(key: impl fmt::Display, bytes: &'a [u8]) -> Result<&'a T::Archived, ReevesError>
+    where T::Archived: bytecheck::CheckBytes<rkyv::validation::validators::DefaultValidator<'a>>
+{
+    rkyv::check_archived_root::<T>(bytes).map_err(|e| corrupt(FN_TREE, key, e))
+}
+
+// The same order as FnDetail's Ord
+fn cmp_fn_views(a: &FnView, b: &FnView) -> cmp::Ordering {
+    let (a, b) = (a.get(), b.get());
+    (a.krate.as_str(), a.version.as_str(), a.path.as_str()).cmp(&(b.krate.as_str(), b.version.as_str(), b.path.as_str()))
+        .then_with(|| a.params.iter().map(|p| p.as_str()).cmp(b.params.iter().map(|p| p.as_str())))
+        .then_with(|| a.ret.as_str().cmp(b.ret.as_str()))
+}
+
+fn archive_fndetail(fndetail: &FnDetail) -> rkyv::AlignedVec {
+    rkyv::to_bytes::<_, 1024>(fndetail).unwrap()
+}
+
+pub fn save_analysis(db: &sled::Db, krate_name: &str, krate_version: &str, fndetails: Vec<FnDetail>) -> Result<(), ReevesError> {
+    purge_crate(db, krate_name)?;
+    add_crate(db, krate_name, krate_version, fndetails)?;
+    mark_updated(db)
+}
+
+pub fn save_analysis_error(db: &sled::Db, krate_name: &str, krate_version: &str, err: &str) -> Result<(), ReevesError> {
+    purge_crate(db, krate_name)?;
+    add_crate_error(db, krate_name, krate_version, err)?;
+    mark_updated(db)
+}
+
+// Remove all results (successful or errored) for a crate, returning whether there was anything to remove
+pub fn remove_crate(db: &sled::Db, krate_name: &str) -> Result<bool, ReevesError> {
+    let crate_tree = db.open_tree(CRATE_TREE)?;
+    let error_tree = db.open_tree(ERROR_TREE)?;
+    let had_analysis = crate_tree.contains_key(krate_name.as_bytes())?;
+    purge_crate(db, krate_name)?;
+    let had_error = error_tree.remove(krate_name.as_bytes())?.is_some();
+    mark_updated(db)?;
+    Ok(had_analysis || had_error)
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64
+}
+
+fn mark_updated(db: &sled::Db) -> Result<(), ReevesError> {
+    db.insert(LAST_UPDATED, bincode::serialize(&now_ms()).unwrap())?;
+    Ok(())
+}
+
+pub fn stats(db: &sled::Db) -> Result<proto::StatsResult, ReevesError> {
+    let fn_tree = db.open_tree(FN_TREE)?;
+    let crate_tree = db.open_tree(CRATE_TREE)?;
+    let error_tree = db.open_tree(ERROR_TREE)?;
+    // Only for display, so a bad timestamp isn't worth failing over
+    let last_updated_ms = db.get(LAST_UPDATED)?.and_then(|bs| decode("db", LAST_UPDATED, &bs)
+        .map_err(|e| warn!("ignoring last updated time: {}", e)).ok());
+    Ok(proto::StatsResult {
+        num_crates: crate_tree.len(),
+        num_errored_crates: error_tree.len(),
+        num_fns: fn_tree.len(),
+        last_updated_ms,
+    })
+}
+
+// The types with the most fns are read by the most searches, so are made most recently used by preload
+const PRELOAD_HOT_TYPES: usize = 10_000;
+
+pub struct PreloadStats {
+    pub num_fns: usize,
+    pub num_hot_types: usize,
+    pub bytes: usize,
+}
+
+// Read the fns and hottest types into sled's cache, so the first searches after opening don't wait on disk
+pub fn preload(db: &sled::Db) -> Result<PreloadStats, ReevesError> {
+    let fn_tree = db.open_tree(FN_TREE)?;
+    let mut stats = PreloadStats { num_fns: 0, num_hot_types: 0, bytes: 0 };
+    for kv in fn_tree.iter() {
+        let (k, v) = kv?;
+        stats.num_fns += 1;
+        stats.bytes += k.len() + v.len();
+    }
+    for treename in &[PARAM_TREE, RET_TREE] {
+        let tree = db.open_tree(treename)?;
+        let mut sizes: Vec<(usize, sled::IVec)> = tree.iter()
+            .map(|kv| kv.map(|(k, v)| (v.len(), k)))
+            .collect::<Result<_, _>>()?;
+        sizes.sort_by(|(len1, _), (len2, _)| len2.cmp(len1));
+        for (_, k) in sizes.into_iter().take(PRELOAD_HOT_TYPES) {
+            // May have been removed since the scan
+            if let Some(v) = tree.get(&k)? {
+                stats.num_hot_types += 1;
+                stats.bytes += k.len() + v.len();
+            }
+        }
+    }
+    Ok(stats)
+}
+
+// The version and functions analysed for a crate, or None if it isn't in the db. Read in a transaction, so the crate
+// being saved or removed at the same time is seen either before or after
+pub fn crate_fns(db: &sled::Db, krate_name: &str) -> Result<Option<(String, Vec<FnDetail>)>, ReevesError> {
+    let fn_tree = db.open_tree(FN_TREE)?;
+    let crate_tree = db.open_tree(CRATE_TREE)?;
+    let ret: Result<_, TransactionError<ReevesError>> = (&crate_tree, &fn_tree).transaction(|(crate_tree, fn_tree)| {
+        let bs = match crate_tree.get(krate_name.as_bytes())? {
+            Some(bs) => bs,
+            None => return Ok(None),
+        };
+        let (version, fn_ids): (String, Vec<u64>) = decode(CRATE_TREE, krate_name, &bs).map_err(abort)?;
+        let mut fndetails = vec![];
+        for fn_id in fn_ids {
+            let bs = fn_tree.get(bincode::serialize(&fn_id).unwrap())?
+                .ok_or_else(|| abort(corrupt(CRATE_TREE, krate_name, format_args!("fn {} is missing", fn_id))))?;
+            fndetails.push(FnView::new(fn_id, &bs).map_err(abort)?.to_fndetail());
+        }
+        Ok(Some((version, fndetails)))
+    });
+    Ok(ret?)
+}
+
+// The fn of a crate with the path_hash, as in permalinks. Only the fn found is deserialized
+pub fn crate_fn(db: &sled::Db, krate_name: &str, hash: &str) -> Result<Option<FnDetail>, ReevesError> {
+    let fn_tree = db.open_tree(FN_TREE)?;
+    let crate_tree = db.open_tree(CRATE_TREE)?;
+    let fn_ids: Vec<u64> = match crate_tree.get(krate_name.as_bytes())? {
+        Some(bs) => decode::<(String, Vec<u64>)>(CRATE_TREE, krate_name, &bs)?.1,
+        None => return Ok(None),
+    };
+    for fn_id in fn_ids {
+        // Gone if the crate is being reanalyzed, in which case the fn may be found under its new id next time
+        let bs = match fn_tree.get(bincode::serialize(&fn_id).unwrap())? {
+            Some(bs) => bs,
+            None => continue,
+        };
+        let fnview = FnView::new(fn_id, &bs)?;
+        if path_hash(fnview.get().path.as_str()) == hash {
+            return Ok(Some(fnview.to_fndetail()))
+        }
+    }
+    Ok(None)
+}
+
+// (name, version, error) for every crate whose last analysis reported an error
+pub fn crate_errors(db: &sled::Db) -> Result<Vec<(String, String, String)>, ReevesError> {
+    let error_tree = db.open_tree(ERROR_TREE)?;
+    error_tree.iter()
+        .map(|kv| -> Result<_, ReevesError> {
+            let (k, v) = kv?;
+            let name = decode_name(ERROR_TREE, &k)?;
+            let (version, err): (String, String) = decode(ERROR_TREE, &name, &v)?;
+            Ok((name, version, err))
+        })
+        .collect()
+}
+
+// (name, version, number of fns) for every successfully analysed crate
+pub fn list_crates(db: &sled::Db) -> Result<Vec<(String, String, usize)>, ReevesError> {
+    let crate_tree = db.open_tree(CRATE_TREE)?;
+    crate_tree.iter()
+        .map(|kv| -> Result<_, ReevesError> {
+            let (k, v) = kv?;
+            let name = decode_name(CRATE_TREE, &k)?;
+            let (version, fn_ids): (String, Vec<u64>) = decode(CRATE_TREE, &name, &v)?;
+            Ok((name, version, fn_ids.len()))
+        })
+        .collect()
+}
+
+// Check the trees are consistent with each other, returning a description of each problem found. Corrupt records are
+// problems too, rather than errors
+pub fn verify_db(db: &sled::Db) -> Result<Vec<String>, ReevesError> {
+    let param_tree = db.open_tree(PARAM_TREE)?;
+    let ret_tree = db.open_tree(RET_TREE)?;
+    let param_part_tree = db.open_tree(PARAM_PART_TREE)?;
+    let ret_part_tree = db.open_tree(RET_PART_TREE)?;
+    let param_arity_tree = db.open_tree(PARAM_ARITY_TREE)?;
+    let fn_tree = db.open_tree(FN_TREE)?;
+    let crate_tree = db.open_tree(CRATE_TREE)?;
+    let mut problems = vec![];
+
+    let next_fn_id: u64 = match db.get(FN_ID_COUNTER)?.map(|bs| decode("db", FN_ID_COUNTER, &bs)) {
+        Some(Ok(next_fn_id)) => next_fn_id,
+        Some(Err(e)) => { problems.push(e.to_string()); u64::MAX },
+        None => { problems.push("fn id counter is missing".to_owned()); u64::MAX },
+    };
+
+    // Every crate's fns should exist and belong to it, and no fn should belong to multiple crates
+    let mut crate_fn_ids: HashMap<u64, String> = HashMap::new();
+    for kv in crate_tree.iter() {
+        let (k, v) = kv?;
+        let name = String::from_utf8_lossy(&k).into_owned();
+        let fn_ids = match decode::<(String, Vec<u64>)>(CRATE_TREE, &name, &v) {
+            Ok((_version, fn_ids)) => fn_ids,
+            Err(e) => { problems.push(e.to_string()); continue },
+        };
+        for fn_id in fn_ids {
+            if let Some(other) = crate_fn_ids.insert(fn_id, name.clone()) {
+                problems.push(format!("fn {} is referenced by crates {} and {}", fn_id, other, name));
+            }
+            if fn_id >= next_fn_id {
+                problems.push(format!("fn {} of crate {} is beyond the fn id counter {}", fn_id, name, next_fn_id));
+            }
+            match fn_tree.get(bincode::serialize(&fn_id).unwrap())? {
+                None => problems.push(format!("fn {} of crate {} is missing", fn_id, name)),
+                // Git crates are keyed on their identity rather than the package name, so only check plain crates
+                Some(bs) => match FnView::new(fn_id, &bs) {
+                    Ok(fnview) => {
+                        let krate = fnview.get().krate.as_str();
+                        if !name.starts_with("git+") && krate != name {
+                            problems.push(format!("fn {} of crate {} says it is from crate {}", fn_id, name, krate));
+                        }
+                    },
+                    Err(e) => problems.push(e.to_string()),
+                },
+            }
+        }
+    }
+
+    // Every fn should be findable from its crate and its types. The secondary trees are only checked for dangling
+    // fns, e.g. part trees only have fns with tuples and arrays
+    let mut type_fn_ids: HashSet<u64> = HashSet::new();
+    let type_trees = [
+        (PARAM_TREE, &param_tree, false),
+        (RET_TREE, &ret_tree, false),
+        (PARAM_PART_TREE, &param_part_tree, true),
+        (RET_PART_TREE, &ret_part_tree, true),
+        (PARAM_ARITY_TREE, &param_arity_tree, true),
+    ];
+    for (treename, tree, is_secondary) in &type_trees {
+        for kv in tree.iter() {
+            let (k, v) = kv?;
+            let fn_ids = match decode::<HashSet<u64>>(*treename, String::from_utf8_lossy(&k), &v) {
+                Ok(fn_ids) => fn_ids,
+                Err(e) => { problems.push(e.to_string()); continue },
+            };
+            for fn_id in fn_ids {
+                if !crate_fn_ids.contains_key(&fn_id) {
+                    problems.push(format!("{} type {} references fn {}, which no crate has", treename, String::from_utf8_lossy(&k), fn_id));
+                }
+                if !is_secondary {
+                    type_fn_ids.insert(fn_id);
+                }
+            }
+        }
+    }
+    for kv in fn_tree.iter() {
+        let (k, _v) = kv?;
+        let fn_id: u64 = match decode(FN_TREE, format_args!("{:?}", k), &k) {
+            Ok(fn_id) => fn_id,
+            Err(e) => { problems.push(e.to_string()); continue },
+        };
+        if !crate_fn_ids.contains_key(&fn_id) {
+            problems.push(format!("fn {} is not referenced by any crate", fn_id));
+        }
+        if !type_fn_ids.contains(&fn_id) {
+            problems.push(format!("fn {} is not referenced by any param or ret type", fn_id));
+        }
+    }
+
+    // Every generic type should be findable from its shape. Shapes may name types that have since been removed
+    let generic_tree = db.open_tree(GENERIC_TREE)?;
+    for (treename, tree) in &[(PARAM_TREE, &param_tree), (RET_TREE, &ret_tree)] {
+        for k in tree.iter().keys() {
+            let k = k?;
+            let ty = String::from_utf8_lossy(&k);
+            let shape = match ty::generic_shape(&ty) {
+                Some(shape) => shape,
+                None => continue,
+            };
+            let key = generic_key(treename, &shape);
+            let has_shape = match generic_tree.get(&key)?.map(|bs| decode::<BTreeSet<String>>(GENERIC_TREE, &key, &bs)) {
+                Some(Ok(tys)) => tys.contains(ty.as_ref()),
+                Some(Err(e)) => { problems.push(e.to_string()); continue },
+                None => false,
+            };
+            if !has_shape {
+                problems.push(format!("{} type {} is missing from generic shape {}", treename, ty, shape));
+            }
+        }
+    }
+
+    Ok(problems)
+}
+
+// One line of a JSONL export, so an index can be moved between machines or sled versions without copying the db
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+#[derive(Debug)]
+pub enum ExportRecord {
+    Crate { name: String, version: String, fns: Vec<FnDetail> },
+    Error { name: String, version: String, error: String },
+}
+
+// Returns the number of records written
+pub fn export_jsonl(db: &sled::Db, out: &mut dyn Write) -> Result<usize, ReevesError> {
+    let mut num = 0;
+    for (name, _version, _num_fns) in list_crates(db)? {
+        // The db may be in use, so the crate is re-read at whichever version it has now
+        let (version, fns) = match crate_fns(db, &name)? {
+            Some(crate_fns) => crate_fns,
+            None => {
+                warn!("crate {} was removed during export, skipping it", name);
+                continue
+            },
+        };
+        out.write_all(&serde_json::to_vec(&ExportRecord::Crate { name, version, fns }).unwrap())?;
+        out.write_all(b"\n")?;
+        num += 1;
+    }
+    for (name, version, error) in crate_errors(db)? {
+        out.write_all(&serde_json::to_vec(&ExportRecord::Error { name, version, error }).unwrap())?;
+        out.write_all(b"\n")?;
+        num += 1;
+    }
+    out.flush()?;
+    Ok(num)
+}
+
+// Records replace whatever the db has for the same crate. Returns the number of records imported
+pub fn import_jsonl(db: &sled::Db, input: &mut dyn BufRead) -> Result<usize, ReevesError> {
+    let mut num = 0;
+    for (i, line) in input.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue
+        }
+        let record: ExportRecord = serde_json::from_str(&line)
+            .map_err(|e| ReevesError::Json(format!("invalid record on line {}: {}", i + 1, e)))?;
+        match record {
+            ExportRecord::Crate { name, version, fns } => save_analysis(db, &name, &version, fns)?,
+            ExportRecord::Error { name, version, error } => save_analysis_error(db, &name, &version, &error)?,
+        }
+        num += 1;
+    }
+    Ok(num)
+}
+
+// Types as nodes and functions as edges from each of their param types to their ret type, for visualizing what can be
+// reached from a type. Fns without params only add their ret type to the nodes
+#[derive(Serialize, Debug, Default)]
+pub struct TypeGraph {
+    pub nodes: BTreeSet<String>,
+    pub edges: Vec<TypeGraphEdge>,
+}
+
+#[derive(Serialize, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct TypeGraphEdge {
+    pub from: String,
+    pub to: String,
+    #[serde(rename = "fn")]
+    pub path: String,
+}
+
+impl TypeGraph {
+    // Only the types (and fns between them) that can be got to by following edges from a type
+    pub fn reachable_from(self, ty: &str) -> TypeGraph {
+        if !self.nodes.contains(ty) {
+            return TypeGraph::default()
+        }
+        let mut nodes = BTreeSet::new();
+        nodes.insert(ty.to_owned());
+        let mut frontier = vec![ty.to_owned()];
+        while let Some(ty) = frontier.pop() {
+            for edge in self.edges.iter().filter(|edge| edge.from == ty) {
+                if nodes.insert(edge.to.clone()) {
+                    frontier.push(edge.to.clone())
+                }
+            }
+        }
+        let edges = self.edges.into_iter().filter(|edge| nodes.contains(&edge.from)).collect();
+        TypeGraph { nodes, edges }
+    }
+
+    pub fn write_dot(&self, out: &mut dyn Write) -> Result<(), ReevesError> {
+        let quote = |s: &str| format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""));
+        writeln!(out, "digraph types {{")?;
+        for node in self.nodes.iter() {
+            writeln!(out, "    {};", quote(node))?;
+        }
+        for edge in self.edges.iter() {
+            writeln!(out, "    {} -> {} [label={}];", quote(&edge.from), quote(&edge.to), quote(&edge.path))?;
+        }
+        writeln!(out, "}}")?;
+        out.flush()?;
+        Ok(())
+    }
+}
+
+// The graph of every fn in the db, or of just one crate
+pub fn type_graph(db: &sled::Db, krate_name: Option<&str>) -> Result<TypeGraph, ReevesError> {
+    let crate_tree = db.open_tree(CRATE_TREE)?;
+    let fn_tree = db.open_tree(FN_TREE)?;
+
+    let wanted: Option<HashSet<u64>> = match krate_name {
+        Some(krate_name) => {
+            let (_version, fn_ids): (String, Vec<u64>) = match crate_tree.get(krate_name)? {
+                Some(bs) => decode(CRATE_TREE, krate_name, &bs)?,
+                None => return Ok(TypeGraph::default()),
+            };
+            Some(fn_ids.into_iter().collect())
+        },
+        None => None,
+    };
+
+    // The type trees are keyed on type, so have to be turned around to find the types of each fn
+    let mut params: HashMap<u64, Vec<String>> = HashMap::new();
+    let mut rets: HashMap<u64, String> = HashMap::new();
+    for (treename, is_param) in &[(PARAM_TREE, true), (RET_TREE, false)] {
+        for kv in db.open_tree(treename)?.iter() {
+            let (ty, fn_ids) = kv?;
+            let ty = decode_name(*treename, &ty)?;
+            for fn_id in FnIds::new(fn_ids) {
+                if wanted.as_ref().map_or(false, |wanted| !wanted.contains(&fn_id)) {
+                    continue
+                }
+                if *is_param {
+                    params.entry(fn_id).or_default().push(ty.clone())
+                } else {
+                    rets.insert(fn_id, ty.clone());
+                }
+            }
+        }
+    }
+
+    let mut graph = TypeGraph::default();
+    for (fn_id, ret) in rets {
+        let path = match fn_tree.get(bincode::serialize(&fn_id).unwrap())? {
+            Some(fn_bytes) => FnView::new(fn_id, &fn_bytes)?.get().path.to_string(),
+            None => { warn!("fn {} in type tree did not have an entry in db, skipping it", fn_id); continue },
+        };
+        for param in params.remove(&fn_id).unwrap_or_default() {
+            if param == NIL_PARAMS {
+                continue
+            }
+            graph.edges.push(TypeGraphEdge { from: param.clone(), to: ret.clone(), path: path.clone() });
+            graph.nodes.insert(param);
+        }
+        graph.nodes.insert(ret);
+    }
+    graph.edges.sort();
+    Ok(graph)
+}
+
+// Write an export to the path atomically, so it can be published somewhere being served from
+pub fn publish_snapshot(db: &sled::Db, path: &Path) -> Result<usize, ReevesError> {
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    let tmp_path = PathBuf::from(tmp_path);
+    let mut out = BufWriter::new(File::create(&tmp_path).map_err(|e| file_error("create", &tmp_path, e))?);
+    let num = export_jsonl(db, &mut out)?;
+    out.flush().map_err(|e| file_error("write", &tmp_path, e))?;
+    drop(out);
+    fs::rename(&tmp_path, path).map_err(|e| file_error("rename to", path, e))?;
+    Ok(num)
+}
+
+// Populate an empty db from a snapshot - merging into an existing index is what import is for
+pub fn restore_snapshot(db: &sled::Db, path: &Path) -> Result<usize, ReevesError> {
+    let stats = stats(db)?;
+    if stats.num_crates > 0 || stats.num_errored_crates > 0 {
+        return Err(ReevesError::NotEmpty { crates: stats.num_crates, errored: stats.num_errored_crates })
+    }
+    let mut input = BufReader::new(File::open(path).map_err(|e| file_error("open", path, e))?);
+    import_jsonl(db, &mut input)
+}
+
+pub fn has_crate(db: &sled::Db, krate_name: &str, krate_version: &str) -> Result<bool, ReevesError> {
+    Ok(crate_version(db, krate_name)?.map_or(false, |version| version == krate_version))
+}
+
+// The version of a crate the db has results (successful or errored) for
+pub fn crate_version(db: &sled::Db, krate_name: &str) -> Result<Option<String>, ReevesError> {
+    let crate_tree = db.open_tree(CRATE_TREE)?;
+    let error_tree = db.open_tree(ERROR_TREE)?;
+    // Have a successful analysis of the crate?
+    if let Some(bs) = crate_tree.get(krate_name.as_bytes())? {
+        let (version, _fn_ids): (String, Vec<u64>) = decode(CRATE_TREE, krate_name, &bs)?;
+        return Ok(Some(version))
+    }
+    // Have an errored analysis of the crate?
+    if let Some(bs) = error_tree.get(krate_name.as_bytes())? {
+        let (version, _err): (String, String) = decode(ERROR_TREE, krate_name, &bs)?;
+        return Ok(Some(version))
+    }
+    Ok(None)
+}
+
+fn load_analysis_host(path: &Path, cargo_config: &CargoConfig) -> Result<ra_ide::AnalysisHost, ReevesError> {
+    let mut db_load_sw = stop_watch();
+    if !path.is_dir() {
+        return Err(ReevesError::Analysis(format!("{} is not a directory", path.display())))
+    }
+    info!("loading workspace at path: {}", path.display());
+    let load_cargo_config = LoadCargoConfig {
+        load_out_dirs_from_check: false, // build scripts
+        with_proc_macro: false,
+        prefill_caches: false,
+    };
+    let (host, _vfs, _proc_macro) = load_workspace_at(&path, cargo_config, &load_cargo_config, &|_| {})
+        .map_err(|e| ReevesError::Analysis(format!("failed to load workspace at {}: {:#}", path.display(), e)))?;
+    info!("{:<20} {}", "Database loaded:", db_load_sw.elapsed());
+    Ok(host)
+}
+
+// A (name, version, analysis) for each package of the workspace at path - just the one for crates from crates.io.
+// Members without a lib, e.g. tooling like xtask, are only reported if no member has one
+pub fn analyze_crate_path(path: &Path) -> Result<Vec<(String, String, Result<Vec<FnDetail>, ReevesError>)>, ReevesError> {
+    let mut cargo_config = CargoConfig::default();
+    cargo_config.no_sysroot = false;
+    let host = load_analysis_host(path, &cargo_config)?;
+    let rootdb = host.raw_database();
+
+    let hirdb: &dyn HirDatabase = rootdb.upcast();
+    let defdb: &dyn DefDatabase = rootdb.upcast();
+    let srcdb: &dyn SourceDatabaseExt = rootdb;
+
+    use std::convert::TryInto;
+    let abspath: AbsPathBuf = path.canonicalize().map_err(|e| file_error("canonicalize", path, e))?
+        .try_into().unwrap(); // canonical paths are absolute
+    let packages = discover_lib_packages(&abspath, &cargo_config)?;
+    let any_libs = packages.iter().any(|package| package.import_name.is_ok());
+
+    let krates = Crate::all(hirdb);
+    let mut ret = vec![];
+    for package in packages {
+        let LibPackage { name: krate_name, version: krate_version, dir, import_name } = package;
+        let krate_import_name = match import_name {
+            Ok(import_name) => import_name,
+            Err(_) if any_libs => {
+                debug!("skipping package {} {} without a lib", krate_name, krate_version);
+                continue
+            },
+            Err(err) => {
+                let err = ReevesError::Analysis(format!("failed to interpret crate as a lib: {}", err));
+                ret.push((krate_name, krate_version, Err(err)));
+                continue
+            },
+        };
+        let krate = krates.iter().copied()
+            .find(|krate| krate.display_name(hirdb).map_or(false, |display_name| display_name.to_string() == krate_import_name));
+        let krate = match krate {
+            Some(krate) => krate,
+            None => {
+                let err = ReevesError::Analysis(format!("didn't find crate {} (import name {}) in the workspace", krate_name, krate_import_name));
+                ret.push((krate_name, krate_version, Err(err)));
+                continue
+            },
+        };
+        info!("found crate: {:?} {} (import name {})", krate_name, krate_version, krate_import_name);
+        let krate_info = KrateInfo { name: &krate_name, import_name: &krate_import_name, version: &krate_version, srcdb };
+        let mut fndetails = analyze_krate(hirdb, defdb, krate, &krate_info);
+        usage::add_examples(&dir, &mut fndetails);
+        ret.push((krate_name, krate_version, Ok(fndetails)))
+    }
+    Ok(ret)
+}
+
+// Shared with the page, which links standard library crates differently
+pub use reeves_types::{SYSROOT_CRATES, is_sysroot_crate};
+
+// Analyze the standard library crates of the sysroot rust-analyzer finds for a project, i.e. of the toolchain the
+// project would be built with. The project itself isn't analyzed
+pub fn analyze_sysroot(project_path: &Path, version: &str) -> Result<Vec<(String, Vec<FnDetail>)>, ReevesError> {
+    let mut cargo_config = CargoConfig::default();
+    cargo_config.no_sysroot = false;
+    let host = load_analysis_host(project_path, &cargo_config)?;
+    let rootdb = host.raw_database();
+
+    let hirdb: &dyn HirDatabase = rootdb.upcast();
+    let defdb: &dyn DefDatabase = rootdb.upcast();
+    let srcdb: &dyn SourceDatabaseExt = rootdb;
+
+    let mut ret = vec![];
+    for krate in Crate::all(hirdb) {
+        let display_name = match krate.display_name(hirdb) {
+            Some(display_name) => display_name.to_string(),
+            None => continue,
+        };
+        if !SYSROOT_CRATES.contains(&display_name.as_str()) {
+            continue
+        }
+        info!("found sysroot crate: {} {}", display_name, version);
+        let krate_info = KrateInfo { name: &display_name, import_name: &display_name, version, srcdb };
+        let fndetails = analyze_krate(hirdb, defdb, krate, &krate_info);
+        ret.push((display_name, fndetails))
+    }
+    Ok(ret)
+}
+
+fn analyze_krate(hirdb: &dyn HirDatabase, defdb: &dyn DefDatabase, krate: Crate, krate_info: &KrateInfo) -> Vec<FnDetail> {
+    let mut moddefs = HashSet::new();
+    let import_map = defdb.import_map(krate.into());
+    let mut items = vec![];
+    for (item, importinfo) in import_map.map.iter() {
+        let item: ItemInNs = item.to_owned().into();
+        // skip macros
+        let moddef = if let Some(moddef) = item.as_module_def() { moddef } else { continue };
+        let isnew = moddefs.insert(moddef);
+        if !isnew { continue }
+        items.push((moddef, importinfo.path.to_string()));
+    }
+
+    // Facade crates like futures are mostly `pub use` of modules from their dependencies, so follow those into the
+    // dependency to index what they contain under the paths users import
+    let root = krate.root_module(hirdb);
+    let mut worklist: Vec<(ra_hir::Module, String)> = items.iter()
+        .filter_map(|(moddef, path)| match moddef {
+            ModuleDef::Module(m) if m.krate() != krate => Some((*m, path.clone())),
+            _ => None,
+        })
+        .collect();
+    while let Some((module, path)) = worklist.pop() {
+        for (name, def) in module.scope(hirdb, Some(root)) {
+            let moddef = if let ScopeDef::ModuleDef(moddef) = def { moddef } else { continue };
+            if !moddefs.insert(moddef) { continue }
+            let item_path = format!("{}::{}", path, name);
+            if let ModuleDef::Module(m) = moddef {
+                worklist.push((m, item_path.clone()))
+            }
+            items.push((moddef, item_path));
+        }
+    }
+
+    let mut fndetails = vec![];
+    for (moddef, path) in items {
+        let path = &path;
+        let mut import_fndetails = match moddef {
+            ModuleDef::Function(f) => analyze_function(hirdb, krate_info, f, ItemKind::Function, path, None),
+            ModuleDef::Adt(a) => analyze_adt(hirdb, krate_info, a, path),
+            ModuleDef::Trait(t) => analyze_trait(hirdb, krate_info, t, path),
+            x @ ModuleDef::Variant(_) |
+            x @ ModuleDef::Const(_) |
+            x @ ModuleDef::Static(_) |
+            x @ ModuleDef::Module(_) |
+            x @ ModuleDef::TypeAlias(_) |
+            x @ ModuleDef::BuiltinType(_) => {
+                trace!("skipping non-function {:?} {:?}", x.name(hirdb), x);
+                vec![]
+            },
+        };
+        let def_krate = moddef.module(hirdb).map(|m| m.krate());
+        if let Some(def_krate) = def_krate.filter(|def_krate| *def_krate != krate) {
+            let def_krate_name = def_krate.display_name(hirdb).map(|name| name.to_string());
+            trace!("{} is re-exported from {:?}", path, def_krate_name);
+            for fndetail in import_fndetails.iter_mut() {
+                fndetail.reexported_from = def_krate_name.clone()
+            }
+        }
+        trace!("adding {} items", import_fndetails.len());
+        fndetails.extend(import_fndetails);
+    }
+    fndetails
+}
+
+pub fn search(db: &sled::Db, params_search: Option<Vec<String>>, ret_search: Option<String>) -> Result<Vec<FnDetail>, ReevesError> {
+    Ok(search_with_info(db, params_search, ret_search, false)?.0.iter().map(FnView::to_fndetail).collect())
+}
+
+// What a search found out besides its results
+pub struct SearchInfo {
+    // Stopped at MAX_RESULTS or the memory budget, so there may be more matches than were returned. Streamed searches
+    // only know this once they finish, so it's only set in the final summary and is always false in result batches
+    pub truncated: bool,
+    pub timed_out: bool, // stopped before the fuzzier passes at the search deadline, so there may be more matches
+    pub query: proto::QueryEcho,
+}
+
+// With exact_params, only fns with as many params as the query are found. Otherwise fns with extra params are too, after
+// those with fewer extras
+pub fn search_with_info(db: &sled::Db, params_search: Option<Vec<String>>, ret_search: Option<String>, exact_params: bool) -> Result<(Vec<FnView>, SearchInfo), ReevesError> {
+    let mut ret = vec![];
+    let info = search_batches(db, params_search, ret_search, exact_params, |_query, batch| { ret.extend(batch); true })?;
+    Ok((ret, info))
+}
+
+// Like search, but hands over results as each pass over the candidate types completes, so callers can show the
+// closest matches before the (potentially expensive) fuzzier passes are done. How the query was interpreted is known
+// before any results, so is given with each batch. Returning false from on_batch stops the search, e.g. when nobody is
+// waiting for the rest
+pub fn search_batches(db: &sled::Db, params_search: Option<Vec<String>>, ret_search: Option<String>, exact_params: bool, on_batch: impl FnMut(&proto::QueryEcho, Vec<FnView>) -> bool) -> Result<SearchInfo, ReevesError> {
+    search_batches_opts(db, &Options::from_globals(), params_search, ret_search, exact_params, on_batch)
+}
+
+fn search_batches_opts(db: &sled::Db, opts: &Options, params_search: Option<Vec<String>>, ret_search: Option<String>, exact_params: bool, on_batch: impl FnMut(&proto::QueryEcho, Vec<FnView>) -> bool) -> Result<SearchInfo, ReevesError> {
+    let _span = tracing::info_span!("search", params = ?params_search, ret = ?ret_search, exact_params).entered();
+    let deadline = opts.search_deadline.map(|deadline| Instant::now() + deadline);
+    let query = futures::executor::block_on(interpret_query(opts, params_search, ret_search, exact_params)
+        .instrument(tracing::info_span!("fuzzy_candidates")))?;
+    search_candidates(db, opts.search_memory_budget, deadline, opts.ret_ownership_variants, query, on_batch)
+}
+
+pub async fn search_async(db: &sled::Db, params_search: Option<Vec<String>>, ret_search: Option<String>) -> Result<Vec<FnDetail>, ReevesError> {
+    Ok(search_with_info_async(db, params_search, ret_search, false).await?.0.iter().map(FnView::to_fndetail).collect())
+}
+
+pub async fn search_with_info_async(db: &sled::Db, params_search: Option<Vec<String>>, ret_search: Option<String>, exact_params: bool) -> Result<(Vec<FnView>, SearchInfo), ReevesError> {
+    search_with_info_async_opts(db, &Options::from_globals(), params_search, ret_search, exact_params).await
+}
+
+async fn search_with_info_async_opts(db: &sled::Db, opts: &Options, params_search: Option<Vec<String>>, ret_search: Option<String>, exact_params: bool) -> Result<(Vec<FnView>, SearchInfo), ReevesError> {
+    let span = tracing::info_span!("search", params = ?params_search, ret = ?ret_search, exact_params);
+    async {
+        // The text search counts towards the deadline, as it's part of what the caller waits for
+        let deadline = opts.search_deadline.map(|deadline| Instant::now() + deadline);
+        let query = interpret_query(opts, params_search, ret_search, exact_params)
+            .instrument(tracing::info_span!("fuzzy_candidates")).await?;
+        let db = db.clone();
+        let budget = opts.search_memory_budget;
+        let ret_ownership_variants = opts.ret_ownership_variants;
+        blocking(move || {
+            let mut ret = vec![];
+            let info = search_candidates(&db, budget, deadline, ret_ownership_variants, query, |_query, batch| { ret.extend(batch); true })?;
+            Ok((ret, info))
+        }).await
+    }.instrument(span).await
+}
+
+// Find the types the text search thinks each part of the query could mean, closest first
+async fn interpret_query(opts: &Options, params_search: Option<Vec<String>>, ret_search: Option<String>, exact_params: bool) -> Result<proto::QueryEcho, ReevesError> {
+    let text_search = opts.text_search();
+    if let Some(long) = params_search.iter().flatten().chain(ret_search.as_ref()).find(|s| s.len() > MAX_QUERY_TYPE_LEN) {
+        return Err(ReevesError::InvalidQuery(format!("type of {} bytes is longer than the limit of {}", long.len(), MAX_QUERY_TYPE_LEN)))
+    }
+    // In the form analysis indexes types in, so e.g. `&'a str` finds `&str` exactly
+    let params_search = params_search.map(|params| params.iter().map(|p| ty::normalize_type(p)).collect::<Vec<_>>());
+    let ret_search = ret_search.map(|ret| ty::normalize_type(&ret));
+    let mut query = proto::QueryEcho { params: params_search.clone(), ret: ret_search.clone(), param_matches: vec![], ret_matches: None, exact_params };
+
+    if let Some(ret_search) = ret_search {
+        query.ret_matches = Some(fuzzy_types(&*text_search, RET_TYPES_INDEX, &ret_search, FUZZY_SEARCH_LIMIT).await?);
+    }
+
+    if let Some(mut params_search) = params_search {
+        if params_search.is_empty() {
+            params_search = vec![NIL_PARAMS.into()];
+        }
+        for param in params_search {
+            query.param_matches.push(fuzzy_types(&*text_search, PARAM_TYPES_INDEX, &param, FUZZY_SEARCH_LIMIT).await?);
+        }
+    }
+    Ok(query)
+}
+
+// The db side of a search, once the query has been interpreted. Entirely blocking
+fn search_candidates(db: &sled::Db, budget: usize, deadline: Option<Instant>, ret_ownership_variants: bool, mut query: proto::QueryEcho, mut on_batch: impl FnMut(&proto::QueryEcho, Vec<FnView>) -> bool) -> Result<SearchInfo, ReevesError> {
+    let param_tree = db.open_tree(PARAM_TREE)?;
+    let ret_tree = db.open_tree(RET_TREE)?;
+    let param_part_tree = db.open_tree(PARAM_PART_TREE)?;
+    let ret_part_tree = db.open_tree(RET_PART_TREE)?;
+    let param_arity_tree = db.open_tree(PARAM_ARITY_TREE)?;
+    let fn_tree = db.open_tree(FN_TREE)?;
+    let popularity_tree = db.open_tree(POPULARITY_TREE)?;
+    let feedback_tree = db.open_tree(FEEDBACK_TREE)?;
+    let demoted = demoted_results(&feedback_tree, &query_pattern(query.params.as_deref(), query.ret.as_deref()))?;
+
+    // Generic types the query could be, e.g. `Vec<T>` for `Vec<String>`, go just after its own types in the candidates,
+    // so fns over exactly them still come first
+    let generic_tree = db.open_tree(GENERIC_TREE)?;
+    if let (Some(ret), Some(ret_matches)) = (query.ret.as_ref(), query.ret_matches.as_mut()) {
+        let generics = generic_candidates(&generic_tree, RET_TREE, &ret_tree, ret, ret_matches)?;
+        insert_candidates(ret_matches, ret, generics);
+    }
+    for (param, param_matches) in query.params.iter().flatten().zip(query.param_matches.iter_mut()) {
+        let generics = generic_candidates(&generic_tree, PARAM_TREE, &param_tree, param, param_matches)?;
+        insert_candidates(param_matches, param, generics);
+    }
+
+    // Other ways of holding the ret go before those, as they're the same type. The text search would have found any
+    // that were closer, so only those the db has are worth adding
+    let mut ret_variants = HashSet::new();
+    if let (true, Some(ret), Some(ret_matches)) = (ret_ownership_variants, query.ret.as_ref(), query.ret_matches.as_mut()) {
+        let mut new_variants = vec![];
+        for variant in ty::ownership_variants(ret) {
+            if !ret_matches.contains(&variant) && (ret_tree.contains_key(&variant)? || ret_part_tree.contains_key(&variant)?) {
+                new_variants.push(variant.clone())
+            }
+            ret_variants.insert(variant);
+        }
+        insert_candidates(ret_matches, ret, new_variants);
+    }
+
+    // Each column is its type tree, part tree, whether it's a param and its candidate types
+    let mut candidate_types: Vec<(&sled::Tree, &sled::Tree, bool, &[String])> = vec![];
+    if let Some(ret_candidates) = &query.ret_matches {
+        candidate_types.push((&ret_tree, &ret_part_tree, false, ret_candidates));
+    }
+    for param_candidates in query.param_matches.iter() {
+        candidate_types.push((&param_tree, &param_part_tree, true, param_candidates));
+    }
+
+    // Params are matched by number of params, from the query's upwards, so fns with fewer extra params come first
+    // without having to look at them. The most params any candidate type is used with bounds the extras
+    let num_params = query.params.as_ref().map(|params| params.len());
+    let mut max_arity = num_params.unwrap_or(0);
+    if num_params.is_some() && !query.exact_params {
+        for param_candidates in query.param_matches.iter() {
+            for ct in param_candidates.iter() {
+                max_arity = cmp::max(max_arity, type_arities(&param_arity_tree, ct)?.last().copied().unwrap_or(0));
+            }
+        }
+    }
+
+    // TODO: at each pass, reorder to have the most restrictive type candidates first
+    // TODO: at each pass, remember the sets we've built so far so we don't recreate and keep
+    // removing the fn ids that have been selected
+    let max_candidate_depth = candidate_types.iter().map(|(_, _, _, ct)| ct.len()).max().unwrap_or(0);
+    let mut num_results = 0;
+    let mut truncated = false;
+    let mut timed_out = false;
+    let mut last_pass_time: Option<Duration> = None;
+    let mut fn_ids_set = HashSet::new(); // already returned, so at most MAX_RESULTS
+    let mut krate_ranks: HashMap<String, u64> = HashMap::new();
+    let mut checked_missing: HashSet<&str> = HashSet::new();
+    // Whole type matches come first. Then the passes are repeated with the element types of tuples and arrays too,
+    // which only finds fns that need those, as the rest were found by the whole type pass of the same depth. Part trees
+    // don't know the number of params, so partial passes look at all of them at once
+    let mut passes = vec![];
+    for &partial in &[false, true] {
+        for i in 1..max_candidate_depth {
+            match num_params {
+                Some(num_params) if !partial => passes.extend((num_params..=max_arity).map(|arity| (partial, i, Some(arity)))),
+                _ => passes.push((partial, i, None)),
+            }
+        }
+    }
+    for (partial, i, arity) in passes {
+        // Passes get slower as they get fuzzier, so if one more like the last wouldn't fit it's not worth starting.
+        // The first pass always runs, so there's something to return
+        let pass_start = Instant::now();
+        if let (Some(deadline), Some(last_pass_time)) = (deadline, last_pass_time) {
+            if pass_start + last_pass_time > deadline {
+                debug!("search deadline reached, skipping passes from depth {}{}", i, if partial { " (partial)" } else { "" });
+                timed_out = true;
+                break
+            }
+        }
+        let _pass_span = tracing::info_span!("intersection_pass", depth = i, partial, arity = ?arity).entered();
+        // Ids are streamed out of the db values rather than collected, so a pass only needs the values themselves
+        let mut loaded = 0;
+        let mut columns = vec![];
+        for (tree, part_tree, is_param, ct_column) in candidate_types.iter() {
+            let mut lists = vec![];
+            for (j, ct) in ct_column[..cmp::min(i, ct_column.len())].iter().enumerate() {
+                let mut ivecs = vec![];
+                match arity {
+                    Some(arity) if *is_param => ivecs.extend(param_arity_tree.get(arity_key(ct, arity))?),
+                    _ => ivecs.extend(tree.get(ct)?),
+                }
+                if partial {
+                    ivecs.extend(part_tree.get(ct)?);
+                }
+                // The text search can lag behind the db, so may know of types the db no longer has. Types only in
+                // tuples and arrays are expected to be missing until the partial passes, and most types aren't used
+                // with every number of params. Each type comes up in many passes, so is only checked once
+                if ivecs.is_empty() {
+                    if checked_missing.insert(ct.as_str()) && !tree.contains_key(ct)? && !part_tree.contains_key(ct)? {
+                        warn!("candidate type {} is not in the db, skipping it", ct);
+                    }
+                    continue
+                }
+                let len: usize = ivecs.iter().map(|ivec| ivec.len()).sum();
+                // The closest candidate is always used, so there's something to intersect
+                if j > 0 && loaded + len > budget {
+                    debug!("search memory budget of {} bytes reached, ignoring candidate type {} and fuzzier", budget, ct);
+                    truncated = true;
+                    break
+                }
+                loaded += len;
+                lists.extend(ivecs.into_iter().map(FnIds::new));
+            }
+            columns.push(FnIdsUnion::new(lists).peekable());
+        }
+
+        let remaining = MAX_RESULTS - num_results;
+        let mut new_fn_ids: Vec<_> = FnIdsIntersection { columns }
+            .filter(|fn_id| !fn_ids_set.contains(fn_id))
+            .take(remaining + 1)
+            .collect();
+        if new_fn_ids.len() > remaining {
+            new_fn_ids.truncate(remaining);
+            truncated = true;
+        }
+        num_results += new_fn_ids.len();
 
-// A sentinel to represent functions with no arguments (must not be a possible type)
-const NIL_PARAMS: &str = "<NOARGS>";
+        let detail_span = tracing::info_span!("detail_fetch", num_fns = new_fn_ids.len()).entered();
+        let mut batch = vec![];
+        for fn_id in new_fn_ids.iter() {
+            // One dangling fn id shouldn't lose the rest of the results, verify_db will point it out
+            match fn_tree.get(bincode::serialize(fn_id).unwrap())? {
+                Some(fn_bytes) => match FnView::new(fn_id, &fn_bytes) {
+                    Ok(fnview) => {
+                        let mut fnview = FnView { partial, ..fnview };
+                        fnview.ownership_variant = ret_variants.contains(fnview.get().ret.as_str());
+                        batch.push(fnview)
+                    },
+                    Err(e) => warn!("skipping fn: {}", e),
+                },
+                None => warn!("fn {} in type tree did not have an entry in db, skipping it", fn_id),
+            }
+        }
+        // Part trees don't know the number of params, so partial matches have to be checked
+        if partial && query.exact_params {
+            let before = batch.len();
+            batch.retain(|fnview| Some(fnview.get().params.len()) == num_params);
+            num_results -= before - batch.len();
+        }
+        // Within a pass, functions from more popular crates come first, apart from ones reported as bad results for
+        // this query and ones only returning the ret differently held
+        for fnview in batch.iter() {
+            let krate = fnview.get().krate.as_str();
+            if !krate_ranks.contains_key(krate) {
+                krate_ranks.insert(krate.to_owned(), popularity::crate_rank(&popularity_tree, krate)?);
+            }
+        }
+        batch.sort_by(|a, b| {
+            let rank = |fnview: &FnView| krate_ranks[fnview.get().krate.as_str()];
+            let is_demoted = |fnview: &FnView| {
+                let fndetail = fnview.get();
+                demoted.get(fndetail.krate.as_str()).map_or(false, |paths| paths.contains(fndetail.path.as_str()))
+            };
+            is_demoted(a).cmp(&is_demoted(b))
+                .then_with(|| a.ownership_variant.cmp(&b.ownership_variant))
+                .then_with(|| rank(b).cmp(&rank(a)))
+                .then_with(|| cmp_fn_views(a, b))
+        });
+        drop(detail_span);
+        fn_ids_set.extend(new_fn_ids);
+        if !batch.is_empty() && !on_batch(&query, batch) {
+            debug!("search stopped by caller after {} results", num_results);
+            break
+        }
 
-// For fuzzy searching
-const PARAM_TYPES_INDEX: &str = "param_types";
-const RET_TYPES_INDEX: &str = "ret_types";
+        if num_results >= MAX_RESULTS {
+            break
+        }
+        last_pass_time = Some(pass_start.elapsed());
+    }
 
-fn stop_watch() -> StopWatch {
-    StopWatch::start()
+    Ok(SearchInfo { truncated, timed_out, query })
+}
+
+// The fn ids of a type tree value, in order. Bincode sets are a u64 length followed by the u64s, so they're read straight
+// out of the value rather than deserialized
+struct FnIds {
+    bytes: sled::IVec,
+    pos: usize,
 }
 
-pub fn open_db(path: &Path) -> sled::Db {
-    let db = sled::open(path).unwrap();
-    if !db.contains_key(FN_ID_COUNTER).unwrap() {
-        db.insert(FN_ID_COUNTER, bincode::serialize(&0u64).unwrap()).unwrap();
+impl FnIds {
+    fn new(bytes: sled::IVec) -> Self {
+        FnIds { bytes, pos: 8 }
     }
-    db
 }
 
-pub fn save_analysis(db: &sled::Db, krate_name: &str, krate_version: &str, fndetails: Vec<FnDetail>) {
-    purge_crate(db, krate_name);
-    add_crate(db, krate_name, krate_version, fndetails);
+impl Iterator for FnIds {
+    type Item = u64;
+
+    fn next(&mut self) -> Option<u64> {
+        use std::convert::TryInto;
+        let id_bytes = self.bytes.get(self.pos..self.pos + 8)?;
+        self.pos += 8;
+        Some(u64::from_le_bytes(id_bytes.try_into().unwrap()))
+    }
 }
 
-pub fn save_analysis_error(db: &sled::Db, krate_name: &str, krate_version: &str, err: &str) {
-    purge_crate(db, krate_name);
-    add_crate_error(db, krate_name, krate_version, err);
+// The ids in any of the lists, in order and without duplicates
+struct FnIdsUnion {
+    lists: Vec<FnIds>,
+    heads: BinaryHeap<cmp::Reverse<(u64, usize)>>, // the next id of each list, and which list
+    last: Option<u64>,
 }
 
-pub fn has_crate(db: &sled::Db, krate_name: &str, krate_version: &str) -> bool {
-    let crate_tree = db.open_tree(CRATE_TREE).unwrap();
-    let error_tree = db.open_tree(ERROR_TREE).unwrap();
-    // Have a successful analysis of the crate?
-    if let Some(bs) = crate_tree.get(krate_name.as_bytes()).unwrap() {
-        let (version, _fn_ids): (String, Vec<u64>) = bincode::deserialize(&bs).unwrap();
-        return version == krate_version
+impl FnIdsUnion {
+    fn new(mut lists: Vec<FnIds>) -> Self {
+        let heads = lists.iter_mut().enumerate()
+            .filter_map(|(i, list)| list.next().map(|id| cmp::Reverse((id, i))))
+            .collect();
+        FnIdsUnion { lists, heads, last: None }
     }
-    // Have an errored analysis of the crate?
-    if let Some(bs) = error_tree.get(krate_name.as_bytes()).unwrap() {
-        let version = str::from_utf8(&bs).unwrap();
-        return version == krate_version
+}
+
+impl Iterator for FnIdsUnion {
+    type Item = u64;
+
+    fn next(&mut self) -> Option<u64> {
+        loop {
+            let cmp::Reverse((id, i)) = self.heads.pop()?;
+            if let Some(next) = self.lists[i].next() {
+                self.heads.push(cmp::Reverse((next, i)))
+            }
+            if self.last != Some(id) {
+                self.last = Some(id);
+                return Some(id)
+            }
+        }
     }
-    false
 }
 
-pub fn analyze_crate_path(path: &Path) -> (String, String, Result<Vec<FnDetail>>) {
-    let mut db_load_sw = stop_watch();
-    if !path.is_dir() {
-        panic!("path is not a directory")
+// The ids in all of the columns, in order
+struct FnIdsIntersection {
+    columns: Vec<std::iter::Peekable<FnIdsUnion>>,
+}
+
+impl Iterator for FnIdsIntersection {
+    type Item = u64;
+
+    fn next(&mut self) -> Option<u64> {
+        let mut target = *self.columns.first_mut()?.peek()?;
+        loop {
+            // Skip each column up to the target, raising the target if a column doesn't have it
+            let mut all_match = true;
+            for column in self.columns.iter_mut() {
+                while *column.peek()? < target {
+                    column.next();
+                }
+                let id = *column.peek()?;
+                if id > target {
+                    target = id;
+                    all_match = false;
+                }
+            }
+            if all_match {
+                for column in self.columns.iter_mut() {
+                    column.next();
+                }
+                return Some(target)
+            }
+        }
     }
-    info!("loading workspace at path: {}", path.display());
-    let mut cargo_config = CargoConfig::default();
-    cargo_config.no_sysroot = false;
-    let load_cargo_config = LoadCargoConfig {
-        load_out_dirs_from_check: false, // build scripts
-        with_proc_macro: false,
-        prefill_caches: false,
+}
+
+// Known types matching the query in the text search index, closest first
+async fn fuzzy_types(text_search: &dyn TextSearch, index: &str, query: &str, limit: usize) -> Result<Vec<String>, ReevesError> {
+    text_search.search(index, query, limit)
+        .instrument(tracing::debug_span!("text_search", index, query))
+        .await
+}
+
+// Suggest known type names (from both params and rets) for a partially typed query
+pub fn suggest(query: &str) -> Result<Vec<String>, ReevesError> {
+    futures::executor::block_on(suggest_async_opts(&Options::from_globals(), query))
+}
+
+pub async fn suggest_async(query: &str) -> Result<Vec<String>, ReevesError> {
+    suggest_async_opts(&Options::from_globals(), query).await
+}
+
+async fn suggest_async_opts(opts: &Options, query: &str) -> Result<Vec<String>, ReevesError> {
+    let text_search = opts.text_search();
+    async {
+        let mut seen = HashSet::new();
+        let mut types = vec![];
+        for index in &[PARAM_TYPES_INDEX, RET_TYPES_INDEX] {
+            for ty in fuzzy_types(&*text_search, index, query, SUGGEST_LIMIT).await? {
+                if ty != NIL_PARAMS && seen.insert(ty.clone()) {
+                    types.push(ty)
+                }
+            }
+        }
+        types.truncate(SUGGEST_LIMIT);
+        Ok(types)
+    }.instrument(tracing::info_span!("suggest", query)).await
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct QueryLogEntry {
+    pub timestamp_ms: u64,
+    pub params: Option<Vec<String>>,
+    pub ret: Option<String>,
+    pub num_results: usize,
+    pub duration_ms: u64,
+}
+
+pub fn log_query(db: &sled::Db, params: Option<&[String]>, ret: Option<&str>, num_results: usize, duration: Duration) -> Result<(), ReevesError> {
+    let query_log_tree = db.open_tree(QUERY_LOG_TREE)?;
+    let entry = QueryLogEntry {
+        timestamp_ms: now_ms(),
+        // So trivially different spellings of a query are grouped together in the log
+        params: params.map(|params| params.iter().map(|p| ty::normalize_type(p)).collect()),
+        ret: ret.map(ty::normalize_type),
+        num_results,
+        duration_ms: duration.as_millis() as u64,
     };
-    let (host, _vfs, _proc_macro) =
-        load_workspace_at(&path, &cargo_config, &load_cargo_config, &|_| {}).unwrap();
-    let rootdb = host.raw_database();
-    info!("{:<20} {}", "Database loaded:", db_load_sw.elapsed());
+    let log_id = db.generate_id()?;
+    query_log_tree.insert(log_id.to_be_bytes(), bincode::serialize(&entry).unwrap())?;
+    // Ids are increasing (but may skip), so trimming the oldest entry on each insert keeps the log bounded
+    if let Some((oldest_key, _)) = query_log_tree.first()? {
+        let mut oldest_id = [0u8; 8];
+        oldest_id.copy_from_slice(&oldest_key);
+        if u64::from_be_bytes(oldest_id) + QUERY_LOG_MAX_ENTRIES < log_id {
+            query_log_tree.remove(oldest_key)?;
+        }
+    }
+    Ok(())
+}
 
-    let hirdb: &dyn HirDatabase = rootdb.upcast();
-    let defdb: &dyn DefDatabase = rootdb.upcast();
+// Query log entries, oldest first. Unreadable entries are skipped, the rest of the log is still useful
+pub fn query_log(db: &sled::Db) -> Result<Vec<QueryLogEntry>, ReevesError> {
+    let query_log_tree = db.open_tree(QUERY_LOG_TREE)?;
+    let mut entries = vec![];
+    for kv in query_log_tree.iter() {
+        let (k, v) = kv?;
+        match decode(QUERY_LOG_TREE, format_args!("{:?}", k), &v) {
+            Ok(entry) => entries.push(entry),
+            Err(e) => warn!("skipping query log entry: {}", e),
+        }
+    }
+    Ok(entries)
+}
 
-    use std::convert::TryInto;
-    let abspath: AbsPathBuf = path.canonicalize().unwrap().try_into().unwrap();
-    let (krate_name, krate_import_name, krate_version) = match discover_lib_crate_import_name(&abspath, &cargo_config) {
-        LibCrateResult::Ok(name, import_name, version) => (name, import_name, version),
-        LibCrateResult::Err(name, version, err) => return (name, version, Err(err.context("failed to interpret crate as a lib"))),
+// How a result has been received for a query pattern
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct FeedbackCounts {
+    pub reports: u64,
+    pub clicks: u64,
+}
+
+impl FeedbackCounts {
+    // Reports only count once there are a few and they outnumber the times the result was followed, so one user
+    // can't bury a result that's useful to others
+    pub fn is_demoted(&self) -> bool {
+        self.reports >= FEEDBACK_DEMOTE_REPORTS && self.reports > self.clicks
+    }
+}
+
+// What was searched for, in normal form so feedback on respellings of the same query is combined, e.g. `(&str) -> *`
+pub fn query_pattern(params: Option<&[String]>, ret: Option<&str>) -> String {
+    let params = match params {
+        Some(params) => format!("({})", params.iter().map(|p| ty::normalize_type(p)).collect::<Vec<_>>().join(", ")),
+        None => "*".into(),
     };
+    format!("{} -> {}", params, ret.map_or("*".into(), ty::normalize_type))
+}
 
-    let krates = Crate::all(hirdb);
-    for krate in krates {
-        let display_name = krate.display_name(hirdb).unwrap().to_string();
-        if krate_import_name != display_name {
+pub fn record_feedback(db: &sled::Db, feedback: &proto::FeedbackRequest) -> Result<(), ReevesError> {
+    let feedback_tree = db.open_tree(FEEDBACK_TREE)?;
+    let pattern = query_pattern(feedback.params.as_deref(), feedback.ret.as_deref());
+    let key = format!("{}\0{}\0{}", pattern, feedback.krate, feedback.path);
+    feedback_tree.update_and_fetch(key.as_bytes(), |old| {
+        // Feedback is only a nudge, so an unreadable entry is just started again
+        let mut counts: FeedbackCounts = old.and_then(|bs| bincode::deserialize(bs).ok()).unwrap_or_default();
+        match feedback.kind {
+            proto::FeedbackKind::BadResult => counts.reports += 1,
+            proto::FeedbackKind::ClickThrough => counts.clicks += 1,
+        }
+        Some(bincode::serialize(&counts).unwrap())
+    })?;
+    Ok(())
+}
+
+// Paths of the results demoted for a query pattern, by crate
+fn demoted_results(feedback_tree: &sled::Tree, pattern: &str) -> Result<HashMap<String, HashSet<String>>, ReevesError> {
+    let prefix = format!("{}\0", pattern);
+    let mut demoted: HashMap<String, HashSet<String>> = HashMap::new();
+    for kv in feedback_tree.scan_prefix(&prefix) {
+        let (k, v) = kv?;
+        let key = decode_name(FEEDBACK_TREE, &k)?;
+        let counts: FeedbackCounts = decode(FEEDBACK_TREE, &key, &v)?;
+        if !counts.is_demoted() {
             continue
         }
-        info!("found crate: {:?} {} (import name {})", krate_name, krate_version, display_name);
-        let mut moddefs = HashSet::new();
-        let import_map = defdb.import_map(krate.into());
-        let mut fndetails = vec![];
-        for (item, importinfo) in import_map.map.iter() {
-            let item: ItemInNs = item.to_owned().into();
-            // skip macros
-            let moddef = if let Some(moddef) = item.as_module_def() { moddef } else { continue };
-            let isnew = moddefs.insert(moddef);
-            if !isnew { continue }
-            let path = &importinfo.path.to_string();
-            let import_fndetails = match moddef {
-                ModuleDef::Function(f) => analyze_function(hirdb, &krate_name, f, path),
-                ModuleDef::Adt(a) => analyze_adt(hirdb, &krate_name, a, path),
-                ModuleDef::Trait(t) => analyze_trait(hirdb, &krate_name, t, path),
-                x @ ModuleDef::Variant(_) |
-                x @ ModuleDef::Const(_) |
-                x @ ModuleDef::Static(_) |
-                x @ ModuleDef::Module(_) |
-                x @ ModuleDef::TypeAlias(_) |
-                x @ ModuleDef::BuiltinType(_) => {
-                    trace!("skipping non-function {:?} {:?}", x.name(hirdb), x);
-                    vec![]
-                },
-            };
-            trace!("adding {} items", import_fndetails.len());
-            fndetails.extend(import_fndetails);
+        match key[prefix.len()..].split_once('\0') {
+            Some((krate, path)) => { demoted.entry(krate.to_owned()).or_default().insert(path.to_owned()); },
+            None => return Err(corrupt(FEEDBACK_TREE, &key, "missing fn path")),
         }
-        return (krate_name, krate_version, Ok(fndetails))
     }
-    panic!("didn't find crate {} (import name {})!", krate_name, krate_import_name)
+    Ok(demoted)
 }
 
-pub fn search(db: &sled::Db, params_search: Option<Vec<String>>, ret_search: Option<String>) -> Vec<FnDetail> {
-    let client = meili::client::Client::new("http://localhost:7700", "no_key");
-    let param_types_search = client.assume_index(PARAM_TYPES_INDEX);
-    let ret_types_search = client.assume_index(RET_TYPES_INDEX);
+#[derive(Serialize, Deserialize, Debug)]
+pub struct FeedbackEntry {
+    pub pattern: String,
+    #[serde(rename = "crate")]
+    pub krate: String,
+    pub path: String,
+    pub counts: FeedbackCounts,
+    pub demoted: bool,
+}
 
-    let param_tree = db.open_tree(PARAM_TREE).unwrap();
-    let ret_tree = db.open_tree(RET_TREE).unwrap();
-    let fn_tree = db.open_tree(FN_TREE).unwrap();
+// All feedback and whether it's demoting its result, for maintainers to audit. Unreadable entries are skipped like
+// the query log's
+pub fn feedback_entries(db: &sled::Db) -> Result<Vec<FeedbackEntry>, ReevesError> {
+    let feedback_tree = db.open_tree(FEEDBACK_TREE)?;
+    let mut entries = vec![];
+    for kv in feedback_tree.iter() {
+        let (k, v) = kv?;
+        let key = String::from_utf8_lossy(&k);
+        let mut parts = key.splitn(3, '\0');
+        let (pattern, krate, path) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(pattern), Some(krate), Some(path)) => (pattern, krate, path),
+            _ => {
+                warn!("skipping feedback entry with bad key {:?}", key);
+                continue
+            },
+        };
+        let counts: FeedbackCounts = match decode(FEEDBACK_TREE, &key, &v) {
+            Ok(counts) => counts,
+            Err(e) => {
+                warn!("skipping feedback entry: {}", e);
+                continue
+            },
+        };
+        entries.push(FeedbackEntry {
+            pattern: pattern.to_owned(),
+            krate: krate.to_owned(),
+            path: path.to_owned(),
+            demoted: counts.is_demoted(),
+            counts,
+        });
+    }
+    Ok(entries)
+}
 
-    let mut candidate_types: Vec<(&sled::Tree, Vec<String>)> = vec![];
+// Progress of a bulk analysis run, so it can be resumed after a crash
+#[derive(Serialize, Deserialize)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RunStatus {
+    Pending,
+    Done, // analysis (or the error it reported) saved
+    Errored, // analysis failed, nothing saved
+}
 
-    if let Some(ret_search) = ret_search {
-        let ret_candidates = futures::executor::block_on(async {
-            ret_types_search.search()
-                .with_query(&ret_search)
-                .with_limit(FUZZY_SEARCH_LIMIT)
-                .execute::<TypeInFnResult>()
-                .await
-                .unwrap()
-        });
-        candidate_types.push((&ret_tree, ret_candidates.hits.into_iter().map(|c| c.result.orig_ty).collect()));
+// Replaces any previous run
+pub fn start_analysis_run(db: &sled::Db, crates: &[(String, String)]) -> Result<(), ReevesError> {
+    let run_tree = db.open_tree(ANALYSIS_RUN_TREE)?;
+    run_tree.clear()?;
+    let mut batch = sled::Batch::default();
+    for (name, version) in crates {
+        batch.insert(name.as_bytes(), bincode::serialize(&(version, RunStatus::Pending)).unwrap());
     }
+    run_tree.apply_batch(batch)?;
+    run_tree.flush()?;
+    Ok(())
+}
 
-    if let Some(mut params_search) = params_search {
-        if params_search.is_empty() {
-            params_search = vec!["<NOARGS>".into()];
-        }
-        for param in params_search {
-            let param_candidates = futures::executor::block_on(async {
-                param_types_search.search()
-                    .with_query(&param)
-                    .with_limit(FUZZY_SEARCH_LIMIT)
-                    .execute::<TypeInFnResult>()
-                    .await
-                    .unwrap()
-            });
-            candidate_types.push((&param_tree, param_candidates.hits.into_iter().map(|c| c.result.orig_ty).collect()));
+pub fn set_analysis_run_status(db: &sled::Db, krate_name: &str, krate_version: &str, status: RunStatus) -> Result<(), ReevesError> {
+    let run_tree = db.open_tree(ANALYSIS_RUN_TREE)?;
+    run_tree.insert(krate_name.as_bytes(), bincode::serialize(&(krate_version, status)).unwrap())?;
+    Ok(())
+}
+
+// (name, version, status) for every crate in the current run
+pub fn analysis_run(db: &sled::Db) -> Result<Vec<(String, String, RunStatus)>, ReevesError> {
+    let run_tree = db.open_tree(ANALYSIS_RUN_TREE)?;
+    run_tree.iter()
+        .map(|kv| -> Result<_, ReevesError> {
+            let (k, v) = kv?;
+            let name = decode_name(ANALYSIS_RUN_TREE, &k)?;
+            let (version, status): (String, RunStatus) = decode(ANALYSIS_RUN_TREE, &name, &v)?;
+            Ok((name, version, status))
+        })
+        .collect()
+}
+
+// The types currently in a text search index, for the trees it's loaded from
+fn synced_types(sync_tree: &sled::Tree, entrytype: &str) -> Result<HashSet<String>, ReevesError> {
+    sync_tree.scan_prefix(format!("{}\0", entrytype))
+        .map(|kv| -> Result<_, ReevesError> {
+            let (key, _val) = kv?;
+            decode_name(TEXT_SEARCH_SYNC_TREE, &key[entrytype.len() + 1..])
+        })
+        .collect()
+}
+
+fn record_synced<'a>(sync_tree: &sled::Tree, entrytype: &str, tys: impl Iterator<Item=&'a String>, synced: bool) -> Result<(), ReevesError> {
+    let mut batch = sled::Batch::default();
+    for ty in tys {
+        let key = format!("{}\0{}", entrytype, ty);
+        if synced {
+            batch.insert(key.as_bytes(), &b""[..]);
+        } else {
+            batch.remove(key.as_bytes());
         }
     }
+    sync_tree.apply_batch(batch)?;
+    Ok(())
+}
 
-    // TODO: at each pass, reorder to have the most restrictive type candidates first
-    // TODO: at each pass, remember the sets we've built so far so we don't recreate and keep
-    // removing the fn ids that have been selected
-    let max_candidate_depth = candidate_types.iter().map(|(_, ct)| ct.len()).max().unwrap_or(0);
-    let mut fn_ids = vec![];
-    let mut fn_ids_set = HashSet::new();
-    let mut ranges = vec![];
-    for i in 1..max_candidate_depth {
-        let mut iteration_fn_ids: Option<HashSet<u64>> = None;
-        for (tree, ct_column) in candidate_types.iter() {
-            let mut ct_column_fn_ids = HashSet::new();
-            for ct in &ct_column[..cmp::min(i, ct_column.len())] {
-                let match_fns: HashSet<u64> = tree.get(ct).unwrap()
-                    .map(|ivec| bincode::deserialize(&ivec).unwrap())
-                    .expect("candidate type did not already have an entry in db");
-                ct_column_fn_ids.extend(match_fns)
-            }
-            // Update the fn ids for this iteration, or initialise them (if the first column)
-            if let Some(ifnids) = iteration_fn_ids.as_mut() {
-                *ifnids = ifnids.intersection(&ct_column_fn_ids).cloned().collect()
-            } else {
-                iteration_fn_ids = Some(ct_column_fn_ids)
+// Purging a crate leaves behind the types it used with empty fn sets, which aren't worth finding. The types of a tree
+// and its part tree go in the same index, so element types of tuples and arrays can be searched for
+fn live_types(db: &sled::Db, treenames: [&'static str; 2]) -> Result<HashSet<String>, ReevesError> {
+    let mut tys = HashSet::new();
+    for treename in treenames.iter() {
+        for kv in db.open_tree(treename)?.iter() {
+            let (key, val) = kv?;
+            let name = decode_name(treename, &key)?;
+            let fn_ids: HashSet<u64> = decode(*treename, &name, &val)?;
+            if !fn_ids.is_empty() {
+                tys.insert(name);
             }
         }
+    }
+    Ok(tys)
+}
 
-        let ifnids = iteration_fn_ids.expect("unexpectedly ran out of fn ids");
-        let new_fn_ids: Vec<_> = ifnids.difference(&fn_ids_set).cloned().collect();
-        ranges.push(fn_ids.len()..fn_ids.len()+new_fn_ids.len());
-        fn_ids.extend_from_slice(&new_fn_ids);
-        fn_ids_set.extend(new_fn_ids);
-
-        if fn_ids.len() >= MAX_RESULTS {
-            break
+// Drop the types left with empty fn sets by purges, which otherwise pile up as crates are reanalyzed, and the generic
+// shapes naming them. A type that's been used again since its set was read is left alone, so this is safe to run
+// alongside saves
+pub fn remove_empty_types(db: &sled::Db) -> Result<usize, ReevesError> {
+    let mut num_removed = 0;
+    for treename in &[PARAM_TREE, RET_TREE, PARAM_PART_TREE, RET_PART_TREE, PARAM_ARITY_TREE] {
+        let tree = db.open_tree(treename)?;
+        for kv in tree.iter() {
+            let (key, val) = kv?;
+            if FnIds::new(val.clone()).next().is_some() {
+                continue
+            }
+            if tree.compare_and_swap(&key, Some(&val), None as Option<&[u8]>)?.is_ok() {
+                num_removed += 1
+            }
         }
     }
-    let end = cmp::min(fn_ids.len(), MAX_RESULTS);
-    let fn_ids = &fn_ids[..end];
-    if let Some(range) = ranges.pop() {
-        ranges.push(range.start..end)
-    }
-
-    let mut ret = vec![];
-    for fn_id in fn_ids {
-        let fn_bytes = fn_tree.get(bincode::serialize(&fn_id).unwrap()).unwrap().unwrap();
-        let fndetail: FnDetail = bincode::deserialize(&fn_bytes).unwrap();
-        ret.push(fndetail);
+    let param_tree = db.open_tree(PARAM_TREE)?;
+    let ret_tree = db.open_tree(RET_TREE)?;
+    let generic_tree = db.open_tree(GENERIC_TREE)?;
+    for kv in generic_tree.iter() {
+        let (key, val) = kv?;
+        let name = decode_name(GENERIC_TREE, &key)?;
+        let tys: BTreeSet<String> = decode(GENERIC_TREE, &name, &val)?;
+        let tree = if name.starts_with(&generic_key(PARAM_TREE, "")) { &param_tree } else { &ret_tree };
+        let mut live = BTreeSet::new();
+        for ty in tys.iter() {
+            if tree.contains_key(ty)? {
+                live.insert(ty.clone());
+            }
+        }
+        if live.len() == tys.len() {
+            continue
+        }
+        let new = if live.is_empty() { None } else { Some(bincode::serialize(&live).unwrap()) };
+        // Lost to a concurrent save, which will have added types rather than removed them
+        let _ = generic_tree.compare_and_swap(&key, Some(&val), new)?;
     }
+    Ok(num_removed)
+}
 
-    for range in ranges {
-        ret[range].sort_by(|fd1, fd2| {
-            let krate_cmp = fd1.krate.cmp(&fd2.krate);
-            if krate_cmp.is_eq() { fd1.s.cmp(&fd2.s) } else { krate_cmp }
-        });
+// Sled calls block, so async callers have them run on tokio's blocking pool. Outside a runtime (e.g. from the sync
+// functions here) there's no pool and nothing else to hold up, so they just run inline
+async fn blocking<T: Send + 'static>(f: impl FnOnce() -> T + Send + 'static) -> T {
+    let span = tracing::Span::current();
+    let f = move || span.in_scope(f);
+    match tokio::runtime::Handle::try_current() {
+        Ok(_) => tokio::task::spawn_blocking(f).await.expect("blocking db task panicked"),
+        Err(_) => f(),
     }
+}
 
-    ret
+pub fn load_text_search(db: &sled::Db) -> Result<(), ReevesError> {
+    futures::executor::block_on(load_text_search_async_opts(db, &Options::from_globals()))
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-struct TypeInFn {
-    id: u64,
-    ty: String,
-    orig_ty: String,
+pub async fn load_text_search_async(db: &sled::Db) -> Result<(), ReevesError> {
+    load_text_search_async_opts(db, &Options::from_globals()).await
 }
 
-impl meili::document::Document for TypeInFn {
-    type UIDType = u64;
+async fn load_text_search_async_opts(db: &sled::Db, opts: &Options) -> Result<(), ReevesError> {
+    let sync_tree = db.open_tree(TEXT_SEARCH_SYNC_TREE)?;
+
+    let text_search = opts.text_search();
+    text_search.delete().await?;
+    text_search.prepare().await?;
+    let tree = sync_tree.clone();
+    blocking(move || tree.clear()).await?;
 
-    fn get_uid(&self) -> &Self::UIDType {
-        &self.id
+    for (entrytype, index, treenames) in vec![("param", PARAM_TYPES_INDEX, [PARAM_TREE, PARAM_PART_TREE]), ("ret", RET_TYPES_INDEX, [RET_TREE, RET_PART_TREE])] {
+        let db = db.clone();
+        let tys = blocking(move || live_types(&db, treenames)).await?;
+        text_search.add_types(index, &tys).await?;
+        let sync_tree = sync_tree.clone();
+        blocking(move || record_synced(&sync_tree, entrytype, tys.iter(), true)).await?;
     }
+    Ok(())
 }
 
-#[derive(Serialize, Deserialize)]
-struct TypeInFnResult {
-    orig_ty: String,
+// Bring the text search up to date with the db by adding and removing only the types that changed since it was last
+// loaded or synced, which is much cheaper than a full load after small updates
+pub fn load_text_search_incremental(db: &sled::Db) -> Result<(), ReevesError> {
+    futures::executor::block_on(load_text_search_incremental_async_opts(db, &Options::from_globals()))
 }
 
-pub fn load_text_search(db: &sled::Db) {
-    let param_tree = db.open_tree(PARAM_TREE).unwrap();
-    let ret_tree = db.open_tree(RET_TREE).unwrap();
+pub async fn load_text_search_incremental_async(db: &sled::Db) -> Result<(), ReevesError> {
+    load_text_search_incremental_async_opts(db, &Options::from_globals()).await
+}
 
-    fn tokenize_type(s: &str) -> String {
-        let mut s = s
-            .replace('<', " < ")
-            .replace('>', " > ")
-            .replace('[', " [ ")
-            .replace(']', " ] ")
-            .replace('&', " & ");
-        loop {
-            let news = s.replace("  ", " ");
-            if news == s {
-                return s
-            }
-            s = news
-        }
+async fn load_text_search_incremental_async_opts(db: &sled::Db, opts: &Options) -> Result<(), ReevesError> {
+    let sync_tree = db.open_tree(TEXT_SEARCH_SYNC_TREE)?;
+
+    let text_search = opts.text_search();
+    text_search.prepare().await?;
+
+    for (entrytype, index, treenames) in vec![("param", PARAM_TYPES_INDEX, [PARAM_TREE, PARAM_PART_TREE]), ("ret", RET_TYPES_INDEX, [RET_TREE, RET_PART_TREE])] {
+        let db = db.clone();
+        let sync = sync_tree.clone();
+        let (live, synced) = blocking(move || -> Result<_, ReevesError> {
+            Ok((live_types(&db, treenames)?, synced_types(&sync, entrytype)?))
+        }).await?;
+        let added: HashSet<String> = live.difference(&synced).cloned().collect();
+        let removed: HashSet<String> = synced.difference(&live).cloned().collect();
+        info!("{} types: {} to add, {} to remove", entrytype, added.len(), removed.len());
+        text_search.add_types(index, &added).await?;
+        let sync = sync_tree.clone();
+        blocking(move || record_synced(&sync, entrytype, added.iter(), true)).await?;
+        text_search.remove_types(index, &removed).await?;
+        let sync = sync_tree.clone();
+        blocking(move || record_synced(&sync, entrytype, removed.iter(), false)).await?;
     }
+    Ok(())
+}
 
-    let client = meili::client::Client::new("http://localhost:7700", "no_key");
+// Add the types of some newly analysed crates to the text search, without looking through the whole db. Types no
+// longer used by any function are left for the next incremental or full load to remove
+pub fn sync_text_search(db: &sled::Db, krate_names: &[String]) -> Result<(), ReevesError> {
+    futures::executor::block_on(sync_text_search_async_opts(db, &Options::from_globals(), krate_names))
+}
 
-    futures::executor::block_on(async move {
-        let settings = meili::settings::Settings {
-            synonyms: None,
-            stop_words: Some(vec![]),
-            ranking_rules: None,
-            distinct_attribute: None,
-            filterable_attributes: Some(vec![]),
-            searchable_attributes: Some(vec!["ty".into()]),
-            displayed_attributes: Some(vec!["orig_ty".into()]),
-        };
-        client.delete_index_if_exists("param_types").await.unwrap();
-        let param_types = client.get_or_create("param_types").await.unwrap();
-        param_types.set_settings(&settings).await.unwrap().wait_for_pending_update(None, None).await.unwrap().unwrap();
-        client.delete_index_if_exists("ret_types").await.unwrap();
-        let ret_types = client.get_or_create("ret_types").await.unwrap();
-        ret_types.set_settings(&settings).await.unwrap().wait_for_pending_update(None, None).await.unwrap().unwrap();
-
-        async fn do_batch(entrytype: &str, index: &meili::indexes::Index, batch: &mut Vec<TypeInFn>, progress: &mut usize, total: usize) {
-            index.add_documents(batch, Some("id")).await.unwrap()
-                .wait_for_pending_update(None, None).await.unwrap().unwrap();
-            *progress += batch.len();
-            info!("Added {}/{} {} entries in total", progress, total, entrytype);
-            batch.clear();
-        }
-
-        let mut progress = 0;
-        let mut batch = vec![];
-        let num_params = param_tree.len();
-        for (i, kv) in param_tree.iter().enumerate() {
-            let (key, _val) = kv.unwrap();
-            let str_key = str::from_utf8(&key).unwrap();
-            let tokenized_key = tokenize_type(str_key);
-            batch.push(TypeInFn { id: i as u64, ty: tokenized_key, orig_ty: str_key.to_owned() });
-            if batch.len() >= 500 {
-                do_batch("param", &param_types, &mut batch, &mut progress, num_params).await;
-            }
-        }
-        do_batch("param", &param_types, &mut batch, &mut progress, num_params).await;
+pub async fn sync_text_search_async(db: &sled::Db, krate_names: &[String]) -> Result<(), ReevesError> {
+    sync_text_search_async_opts(db, &Options::from_globals(), krate_names).await
+}
 
-        let mut progress = 0;
-        let mut batch = vec![];
-        let num_rets = param_tree.len();
-        for (i, kv) in ret_tree.iter().enumerate() {
-            let (key, _val) = kv.unwrap();
-            let str_key = str::from_utf8(&key).unwrap();
-            let tokenized_key = tokenize_type(str_key);
-            batch.push(TypeInFn { id: i as u64, ty: tokenized_key, orig_ty: str_key.to_owned() });
-            if batch.len() >= 500 {
-                do_batch("ret", &ret_types, &mut batch, &mut progress, num_rets).await;
+async fn sync_text_search_async_opts(db: &sled::Db, opts: &Options, krate_names: &[String]) -> Result<(), ReevesError> {
+    let sync_tree = db.open_tree(TEXT_SEARCH_SYNC_TREE)?;
+    let (params, rets) = {
+        let db = db.clone();
+        let krate_names = krate_names.to_vec();
+        blocking(move || -> Result<_, ReevesError> {
+            let mut params: HashSet<String> = HashSet::new();
+            let mut rets: HashSet<String> = HashSet::new();
+            for krate_name in krate_names.iter() {
+                let (_version, fndetails) = match crate_fns(&db, krate_name)? {
+                    Some(res) => res,
+                    None => continue,
+                };
+                for fndetail in fndetails {
+                    if fndetail.params.is_empty() {
+                        params.insert(NIL_PARAMS.into());
+                    }
+                    let (param_parts, ret_parts) = part_types(&fndetail);
+                    params.extend(param_parts);
+                    rets.extend(ret_parts);
+                    params.extend(fndetail.params);
+                    rets.insert(fndetail.ret);
+                }
             }
-        }
-        do_batch("ret", &ret_types, &mut batch, &mut progress, num_params).await;
-    })
+            Ok((params, rets))
+        }).await?
+    };
+
+    let text_search = opts.text_search();
+    text_search.prepare().await?;
+
+    for (entrytype, index, tys) in vec![("param", PARAM_TYPES_INDEX, params), ("ret", RET_TYPES_INDEX, rets)] {
+        text_search.add_types(index, &tys).await?;
+        let sync_tree = sync_tree.clone();
+        blocking(move || record_synced(&sync_tree, entrytype, tys.iter(), true)).await?;
+    }
+    Ok(())
 }
 
-pub fn debugdb(db: &sled::Db) {
-    fn debugtree(name: &str, tree: &sled::Tree) {
+pub fn debugdb(db: &sled::Db) -> Result<(), ReevesError> {
+    fn debugtree(name: &str, tree: &sled::Tree) -> Result<(), ReevesError> {
         for kv in tree.iter() {
-            let (key, val) = kv.unwrap();
+            let (key, val) = kv?;
             let short_val_str = if val.len() > 16 {
                 format!("{:?}...", &val[..16])
             } else {
@@ -352,69 +2299,92 @@ pub fn debugdb(db: &sled::Db) {
             };
             info!("tree: {}, key: ({:?} | {:?}) -> {}", name, String::from_utf8_lossy(&key), key, short_val_str)
         }
+        Ok(())
     }
 
     for treename in db.tree_names() {
-        let namestr = str::from_utf8(&treename).unwrap();
+        let namestr = String::from_utf8_lossy(&treename);
         info!("# tree: {:?}", namestr);
-        let tree = db.open_tree(&treename).unwrap();
-        debugtree(namestr, &tree);
+        let tree = db.open_tree(&treename)?;
+        debugtree(&namestr, &tree)?;
     }
+    Ok(())
 }
 
-enum LibCrateResult {
-    Ok(String, String, String), // name, import_name, version
-    Err(String, String, Error), // name, version, why not a lib
+// A member package of a workspace
+struct LibPackage {
+    name: String,
+    version: String,
+    dir: PathBuf, // where its Cargo.toml is
+    import_name: Result<String, &'static str>, // of its lib, or why it isn't one
 }
-fn discover_lib_crate_import_name(path: &AbsPath, cargo_config: &CargoConfig) -> LibCrateResult {
+
+// Crates from crates.io are a single package, but git repos are often workspaces of several, e.g. tokio
+fn discover_lib_packages(path: &AbsPath, cargo_config: &CargoConfig) -> Result<Vec<LibPackage>, ReevesError> {
     // If you want to see some of the complexity here:
     // - md-5 package name is 'md-5', but target name (and import name) is 'md5'
-    //
-    // We are taking crates from crates.io, so we can assume:
-    // - there is only one package (i.e. not a workspace)
-    // - there is only one lib
-    let root = ProjectManifest::discover_single(path).unwrap();
-    let ws = ProjectWorkspace::load(root, cargo_config, &|_| {}).unwrap();
+    let root = ProjectManifest::discover_single(path)
+        .map_err(|e| ReevesError::Analysis(format!("failed to find a Cargo.toml at {}: {:#}", path.display(), e)))?;
+    let ws = ProjectWorkspace::load(root, cargo_config, &|_| {})
+        .map_err(|e| ReevesError::Analysis(format!("failed to load workspace at {}: {:#}", path.display(), e)))?;
     let cargo = match ws {
         ProjectWorkspace::Cargo { cargo, .. } => cargo,
-        _ => panic!("unexpected workspace type"),
+        _ => return Err(ReevesError::Analysis(format!("workspace at {} isn't a cargo workspace", path.display()))),
     };
     let members = cargo.packages().map(|pd| &cargo[pd]).filter(|pd| pd.is_member).collect::<Vec<_>>();
-    assert_eq!(members.len(), 1, "{:?}", members);
-    let name = members[0].name.clone();
-    let version = members[0].version.to_string();
-    let lib_targets = members[0].targets.iter().map(|&t| &cargo[t]).filter(|t| t.kind == TargetKind::Lib).collect::<Vec<_>>();
-    if lib_targets.len() == 0 {
-        LibCrateResult::Err(name, version, anyhow!("no lib targets found in crate"))
-    } else if lib_targets.len() == 1 {
-        LibCrateResult::Ok(name, lib_targets[0].name.replace('-', "_"), version)
-    } else {
-        LibCrateResult::Err(name, version, anyhow!("multiple lib targets found in crate"))
+    if members.is_empty() {
+        return Err(ReevesError::Analysis(format!("no member packages in workspace at {}", path.display())))
     }
+    Ok(members.into_iter().map(|member| {
+        let lib_targets = member.targets.iter().map(|&t| &cargo[t]).filter(|t| t.kind == TargetKind::Lib).collect::<Vec<_>>();
+        let import_name = if lib_targets.len() == 0 {
+            Err("no lib targets found in crate")
+        } else if lib_targets.len() == 1 {
+            Ok(lib_targets[0].name.replace('-', "_"))
+        } else {
+            Err("multiple lib targets found in crate")
+        };
+        let dir: &Path = member.manifest.parent().unwrap().as_ref();
+        LibPackage { name: member.name.clone(), version: member.version.to_string(), dir: dir.to_owned(), import_name }
+    }).collect())
 }
 
-fn add_crate(db: &sled::Db, name: &str, version: &str, fndetails: Vec<FnDetail>) {
-    let param_tree = db.open_tree(PARAM_TREE).unwrap();
-    let ret_tree = db.open_tree(RET_TREE).unwrap();
-    let fn_tree = db.open_tree(FN_TREE).unwrap();
-    let crate_tree = db.open_tree(CRATE_TREE).unwrap();
+fn add_crate(db: &sled::Db, name: &str, version: &str, mut fndetails: Vec<FnDetail>) -> Result<(), ReevesError> {
+    // Fns may come from an import, snapshot or pushed analysis made before types were normalized, and would otherwise
+    // be keyed on types no query can match
+    for fndetail in fndetails.iter_mut() {
+        normalize_fndetail(fndetail);
+    }
+    let param_tree = db.open_tree(PARAM_TREE)?;
+    let ret_tree = db.open_tree(RET_TREE)?;
+    let param_part_tree = db.open_tree(PARAM_PART_TREE)?;
+    let ret_part_tree = db.open_tree(RET_PART_TREE)?;
+    let param_arity_tree = db.open_tree(PARAM_ARITY_TREE)?;
+    let generic_tree = db.open_tree(GENERIC_TREE)?;
+    let fn_tree = db.open_tree(FN_TREE)?;
+    let crate_tree = db.open_tree(CRATE_TREE)?;
 
     // Get a guaranteed-unique fn id range from the DB. Doesn't matter if it doesn't get used, u64 is
     // pretty big :)
-    fn reserve_fn_id_range(db: &sled::Db, num: usize) -> u64 {
-        let ret: Result<u64, TransactionError<Void>> = db.transaction(|db| {
-            let fn_id: u64 = bincode::deserialize(&db.get(FN_ID_COUNTER).unwrap().unwrap()).unwrap();
+    fn reserve_fn_id_range(db: &sled::Db, num: usize) -> Result<u64, ReevesError> {
+        let ret: Result<u64, TransactionError<ReevesError>> = db.transaction(|db| {
+            let bs = db.get(FN_ID_COUNTER)?
+                .ok_or_else(|| abort(corrupt("db", FN_ID_COUNTER, "missing")))?;
+            let fn_id: u64 = decode("db", FN_ID_COUNTER, &bs).map_err(abort)?;
             let range_end = fn_id + num as u64;
-            db.insert(FN_ID_COUNTER, bincode::serialize(&range_end).unwrap()).unwrap();
+            db.insert(FN_ID_COUNTER, bincode::serialize(&range_end).unwrap())?;
             Ok(fn_id)
         });
-        ret.unwrap()
+        Ok(ret?)
     }
 
-    let start_fn_id = reserve_fn_id_range(db, fndetails.len());
+    let start_fn_id = reserve_fn_id_range(db, fndetails.len())?;
     // Calculate everything to update
     let mut param_sets: HashMap<String, HashSet<u64>> = HashMap::new();
     let mut ret_sets: HashMap<String, HashSet<u64>> = HashMap::new();
+    let mut param_part_sets: HashMap<String, HashSet<u64>> = HashMap::new();
+    let mut ret_part_sets: HashMap<String, HashSet<u64>> = HashMap::new();
+    let mut param_arity_sets: HashMap<String, HashSet<u64>> = HashMap::new();
     let mut fn_ids: Vec<u64> = vec![];
     let nil_params: Vec<String> = vec![NIL_PARAMS.into()];
     for (i, fndetail) in fndetails.iter().enumerate() {
@@ -433,92 +2403,256 @@ fn add_crate(db: &sled::Db, name: &str, version: &str, fndetails: Vec<FnDetail>)
         let isnew = ret_set.insert(fn_id);
         assert!(isnew, "{:?}", fndetail.s);
 
+        let (param_parts, ret_parts) = part_types(fndetail);
+        for part in param_parts {
+            param_part_sets.entry(part).or_default().insert(fn_id);
+        }
+        for part in ret_parts {
+            ret_part_sets.entry(part).or_default().insert(fn_id);
+        }
+        for key in arity_keys(fndetail) {
+            param_arity_sets.entry(key).or_default().insert(fn_id);
+        }
+
         fn_ids.push(fn_id);
     }
+    let mut generic_sets: HashMap<String, BTreeSet<String>> = HashMap::new();
+    add_generic_shapes(&mut generic_sets, PARAM_TREE, param_sets.keys().map(String::as_str));
+    add_generic_shapes(&mut generic_sets, RET_TREE, ret_sets.keys().map(String::as_str));
 
     debug!("performed precomputation for crate {} with {} fns", name, fndetails.len());
 
-    let ret: Result<(), TransactionError<Void>> = (&param_tree, &ret_tree, &fn_tree, &crate_tree)
-        .transaction(|(param_tree, ret_tree, fn_tree, crate_tree)| {
+    let ret: Result<(), TransactionError<ReevesError>> = (&param_tree, &ret_tree, &param_part_tree, &ret_part_tree, &param_arity_tree, &generic_tree, &fn_tree, &crate_tree)
+        .transaction(|(param_tree, ret_tree, param_part_tree, ret_part_tree, param_arity_tree, generic_tree, fn_tree, crate_tree)| {
             debug!("inserting {} params for crate {}", param_sets.len(), name);
             for (param, fn_ids) in param_sets.iter() {
-                let mut param_set: HashSet<u64> = param_tree.get(param).unwrap()
-                    .map(|d| bincode::deserialize(d.as_ref()).unwrap()).unwrap_or_else(HashSet::new);
+                let mut param_set: BTreeSet<u64> = match param_tree.get(param)? {
+                    Some(d) => decode(PARAM_TREE, param, &d).map_err(abort)?,
+                    None => BTreeSet::new(),
+                };
                 param_set.extend(fn_ids);
-                param_tree.insert(param.as_bytes(), bincode::serialize(&param_set).unwrap()).unwrap();
+                param_tree.insert(param.as_bytes(), bincode::serialize(&param_set).unwrap())?;
             }
 
             debug!("inserting {} rets for crate {}", param_sets.len(), name);
             for (ret, fn_ids) in ret_sets.iter() {
-                let mut ret_set: HashSet<u64> = ret_tree.get(ret).unwrap()
-                    .map(|d| bincode::deserialize(d.as_ref()).unwrap()).unwrap_or_else(HashSet::new);
+                let mut ret_set: BTreeSet<u64> = match ret_tree.get(ret)? {
+                    Some(d) => decode(RET_TREE, ret, &d).map_err(abort)?,
+                    None => BTreeSet::new(),
+                };
                 ret_set.extend(fn_ids);
-                ret_tree.insert(ret.as_bytes(), bincode::serialize(&ret_set).unwrap()).unwrap();
+                ret_tree.insert(ret.as_bytes(), bincode::serialize(&ret_set).unwrap())?;
+            }
+
+            debug!("inserting {} param parts, {} ret parts and {} param arities for crate {}", param_part_sets.len(), ret_part_sets.len(), param_arity_sets.len(), name);
+            let secondary = [
+                (PARAM_PART_TREE, param_part_tree, &param_part_sets),
+                (RET_PART_TREE, ret_part_tree, &ret_part_sets),
+                (PARAM_ARITY_TREE, param_arity_tree, &param_arity_sets),
+            ];
+            for (treename, tree, sets) in &secondary {
+                for (key, fn_ids) in sets.iter() {
+                    let mut key_set: BTreeSet<u64> = match tree.get(key)? {
+                        Some(d) => decode(*treename, key, &d).map_err(abort)?,
+                        None => BTreeSet::new(),
+                    };
+                    key_set.extend(*fn_ids);
+                    tree.insert(key.as_bytes(), bincode::serialize(&key_set).unwrap())?;
+                }
+            }
+
+            debug!("inserting {} generic shapes for crate {}", generic_sets.len(), name);
+            for (key, tys) in generic_sets.iter() {
+                let mut key_tys: BTreeSet<String> = match generic_tree.get(key)? {
+                    Some(d) => decode(GENERIC_TREE, key, &d).map_err(abort)?,
+                    None => BTreeSet::new(),
+                };
+                key_tys.extend(tys.iter().cloned());
+                generic_tree.insert(key.as_bytes(), bincode::serialize(&key_tys).unwrap())?;
             }
 
             debug!("inserting {} fndetails for crate {}", fndetails.len(), name);
             for (i, fndetail) in fndetails.iter().enumerate() {
                 let fn_id = start_fn_id + i as u64;
-                fn_tree.insert(bincode::serialize(&fn_id).unwrap(), bincode::serialize(fndetail).unwrap()).unwrap();
+                fn_tree.insert(bincode::serialize(&fn_id).unwrap(), archive_fndetail(fndetail).as_slice())?;
                 debug!("inserted fndetail {}/{}: [{}] {}", i+1, fndetails.len(), fndetail.krate, fndetail.s);
             }
-            crate_tree.insert(name.as_bytes(), bincode::serialize(&(version, &fn_ids)).unwrap()).unwrap();
+            crate_tree.insert(name.as_bytes(), bincode::serialize(&(version, &fn_ids)).unwrap())?;
             Ok(())
         });
 
     debug!("completed inserting crate {}", name);
-    ret.unwrap()
+    Ok(ret?)
 }
 
-fn add_crate_error(db: &sled::Db, name: &str, version: &str, err: &str) {
-    let error_tree = db.open_tree(ERROR_TREE).unwrap();
-    error_tree.insert(name.as_bytes(), bincode::serialize(&(version, err)).unwrap()).unwrap();
+fn add_crate_error(db: &sled::Db, name: &str, version: &str, err: &str) -> Result<(), ReevesError> {
+    let error_tree = db.open_tree(ERROR_TREE)?;
+    error_tree.insert(name.as_bytes(), bincode::serialize(&(version, err)).unwrap())?;
+    Ok(())
 }
 
-fn purge_crate(db: &sled::Db, name: &str) {
-    let param_tree = db.open_tree(PARAM_TREE).unwrap();
-    let ret_tree = db.open_tree(RET_TREE).unwrap();
-    let fn_tree = db.open_tree(FN_TREE).unwrap();
-    let crate_tree = db.open_tree(CRATE_TREE).unwrap();
-    let ret: Result<(), TransactionError<Void>> = (&**db, &param_tree, &ret_tree, &fn_tree, &crate_tree)
-        .transaction(|(_db, param_tree, ret_tree, fn_tree, crate_tree)| {
-            let (_version, fn_ids): (String, Vec<u64>) = match crate_tree.remove(name.as_bytes()).unwrap() {
-                Some(bs) => bincode::deserialize(&bs).unwrap(),
+fn purge_crate(db: &sled::Db, name: &str) -> Result<(), ReevesError> {
+    let param_tree = db.open_tree(PARAM_TREE)?;
+    let ret_tree = db.open_tree(RET_TREE)?;
+    let param_part_tree = db.open_tree(PARAM_PART_TREE)?;
+    let ret_part_tree = db.open_tree(RET_PART_TREE)?;
+    let param_arity_tree = db.open_tree(PARAM_ARITY_TREE)?;
+    let fn_tree = db.open_tree(FN_TREE)?;
+    let crate_tree = db.open_tree(CRATE_TREE)?;
+    let ret: Result<(), TransactionError<ReevesError>> = (&**db, &param_tree, &ret_tree, &param_part_tree, &ret_part_tree, &param_arity_tree, &fn_tree, &crate_tree)
+        .transaction(|(_db, param_tree, ret_tree, param_part_tree, ret_part_tree, param_arity_tree, fn_tree, crate_tree)| {
+            let (_version, fn_ids): (String, Vec<u64>) = match crate_tree.remove(name.as_bytes())? {
+                Some(bs) => decode(CRATE_TREE, name, &bs).map_err(abort)?,
                 None => return Ok(()),
             };
-            let fndetails: Vec<(u64, FnDetail)> = fn_ids.into_iter()
-                .map(|fn_id| (fn_id, fn_tree.remove(bincode::serialize(&fn_id).unwrap()).unwrap().unwrap()))
-                .map(|(fn_id, bytes)| (fn_id, bincode::deserialize(&bytes).unwrap()))
-                .collect();
+            let mut fndetails: Vec<(u64, FnDetail)> = vec![];
+            for fn_id in fn_ids {
+                // Nothing to unlink from the type trees if the fn is already gone
+                match fn_tree.remove(bincode::serialize(&fn_id).unwrap())? {
+                    Some(bytes) => fndetails.push((fn_id, FnView::new(fn_id, &bytes).map_err(abort)?.to_fndetail())),
+                    None => warn!("fn {} of crate {} was missing when purging it", fn_id, name),
+                }
+            }
             for (fn_id, fndetail) in fndetails {
+                let (param_parts, ret_parts) = part_types(&fndetail);
+                let secondary = [
+                    (PARAM_PART_TREE, param_part_tree, param_parts),
+                    (RET_PART_TREE, ret_part_tree, ret_parts),
+                    (PARAM_ARITY_TREE, param_arity_tree, arity_keys(&fndetail)),
+                ];
+                for (treename, tree, keys) in &secondary {
+                    for key in keys {
+                        let mut key_set: BTreeSet<u64> = match tree.get(key)? {
+                            Some(d) => decode(*treename, key, &d).map_err(abort)?,
+                            None => continue,
+                        };
+                        key_set.remove(&fn_id);
+                        tree.insert(key.as_bytes(), bincode::serialize(&key_set).unwrap())?;
+                    }
+                }
+
                 let mut params = fndetail.params;
                 if params.is_empty() {
                     params = vec!["<NOARGS>".into()];
                 }
                 for param in params {
-                    let mut param_set: HashSet<u64> = param_tree.get(&param).unwrap()
-                        .map(|d| bincode::deserialize(d.as_ref()).unwrap()).unwrap_or_else(HashSet::new);
+                    let mut param_set: BTreeSet<u64> = match param_tree.get(&param)? {
+                        Some(d) => decode(PARAM_TREE, &param, &d).map_err(abort)?,
+                        None => BTreeSet::new(),
+                    };
                     // May not be deleted if multiple params of the same type
                     let _didremove = param_set.remove(&fn_id);
-                    param_tree.insert(param.as_bytes(), bincode::serialize(&param_set).unwrap()).unwrap();
+                    param_tree.insert(param.as_bytes(), bincode::serialize(&param_set).unwrap())?;
                 }
 
-                let mut ret_set: HashSet<u64> = ret_tree.get(&fndetail.ret).unwrap()
-                    .map(|d| bincode::deserialize(d.as_ref()).unwrap()).unwrap_or_else(HashSet::new);
-                let didremove = ret_set.remove(&fn_id);
-                assert!(didremove, "{:?}", fndetail.s);
-                ret_tree.insert(fndetail.ret.as_bytes(), bincode::serialize(&ret_set).unwrap()).unwrap();
+                let mut ret_set: BTreeSet<u64> = match ret_tree.get(&fndetail.ret)? {
+                    Some(d) => decode(RET_TREE, &fndetail.ret, &d).map_err(abort)?,
+                    None => BTreeSet::new(),
+                };
+                if !ret_set.remove(&fn_id) {
+                    warn!("fn {} of crate {} was missing from ret type {} when purging it", fn_id, name, fndetail.ret);
+                }
+                ret_tree.insert(fndetail.ret.as_bytes(), bincode::serialize(&ret_set).unwrap())?;
             }
             Ok(())
         });
-    let () = ret.unwrap();
+    Ok(ret?)
+}
+
+// The element types of tuples and arrays in a fn's params and ret, which it's also findable by (as a partial match)
+fn part_types(fndetail: &FnDetail) -> (HashSet<String>, HashSet<String>) {
+    let parts = |ty: &ty::Type| ty.components().into_iter().map(|part| part.to_string()).collect::<Vec<_>>();
+    let param_parts = fndetail.param_tys.iter().flat_map(parts).collect();
+    let ret_parts = parts(&fndetail.ret_ty).into_iter().collect();
+    (param_parts, ret_parts)
+}
+
+// The GENERIC_TREE key of a shape - which type tree it's from (param or ret), a NUL, and the shape
+fn generic_key(treename: &str, shape: &str) -> String {
+    format!("{}\0{}", treename, shape)
+}
+
+// Types with type params, by the key of their shape in GENERIC_TREE
+fn add_generic_shapes<'a>(generic_sets: &mut HashMap<String, BTreeSet<String>>, treename: &str, tys: impl Iterator<Item=&'a str>) {
+    for ty in tys {
+        if let Some(shape) = ty::generic_shape(ty) {
+            generic_sets.entry(generic_key(treename, &shape)).or_default().insert(ty.to_owned());
+        }
+    }
+}
+
+// Types in a tree that the query type is an instance of, e.g. `Vec<T>` for `Vec<String>`, most specific first and
+// leaving out those already in existing
+fn generic_candidates(generic_tree: &sled::Tree, treename: &str, tree: &sled::Tree, query_ty: &str, existing: &[String]) -> Result<Vec<String>, ReevesError> {
+    let mut candidates: Vec<String> = vec![];
+    for shape in ty::generalizations(query_ty, MAX_GENERALIZATIONS) {
+        let key = generic_key(treename, &shape);
+        let generics: BTreeSet<String> = match generic_tree.get(&key)? {
+            Some(bs) => decode(GENERIC_TREE, &key, &bs)?,
+            None => continue,
+        };
+        for generic in generics {
+            // Shapes aren't updated when types are purged, so may name types that are gone
+            if ty::unifies(query_ty, &generic) && !existing.contains(&generic) && !candidates.contains(&generic) && tree.contains_key(&generic)? {
+                candidates.push(generic)
+            }
+        }
+    }
+    Ok(candidates)
+}
+
+// Just after the query's own type if it's a candidate, or the closest candidate if not
+fn insert_candidates(candidates: &mut Vec<String>, query_ty: &str, new: Vec<String>) {
+    let at = candidates.iter().position(|ct| ct == query_ty).map_or(cmp::min(1, candidates.len()), |i| i + 1);
+    candidates.splice(at..at, new);
+}
+
+fn arity_key(ty: &str, arity: usize) -> String {
+    format!("{}\0{:04}", ty, arity)
+}
+
+// The PARAM_ARITY_TREE keys of a fn - its param types (or NIL_PARAMS), a NUL, and its number of params padded so keys
+// for a type sort by number of params
+fn arity_keys(fndetail: &FnDetail) -> HashSet<String> {
+    let arity = fndetail.params.len();
+    if arity == 0 {
+        return vec![arity_key(NIL_PARAMS, 0)].into_iter().collect()
+    }
+    fndetail.params.iter().map(|param| arity_key(param, arity)).collect()
+}
+
+// The numbers of params of the fns with a param type, in order
+fn type_arities(param_arity_tree: &sled::Tree, ty: &str) -> Result<Vec<usize>, ReevesError> {
+    let prefix = format!("{}\0", ty);
+    let mut arities = vec![];
+    for key in param_arity_tree.scan_prefix(&prefix).keys() {
+        let key = key?;
+        let arity = str::from_utf8(&key[prefix.len()..]).ok().and_then(|arity| arity.parse().ok())
+            .ok_or_else(|| corrupt(PARAM_ARITY_TREE, String::from_utf8_lossy(&key), "bad param count"))?;
+        arities.push(arity)
+    }
+    Ok(arities)
+}
+
+// The crate currently being analyzed
+struct KrateInfo<'a> {
+    name: &'a str,
+    import_name: &'a str,
+    version: &'a str,
+    srcdb: &'a dyn SourceDatabaseExt, // for the files fns are in
 }
 
-fn analyze_function(hirdb: &dyn HirDatabase, krate_name: &str, function: ra_hir::Function, path: &str) -> Vec<FnDetail> {
+// self_ty is what Self means for impl and trait methods, which is substituted so the index is keyed on real types
+fn analyze_function(hirdb: &dyn HirDatabase, krate_info: &KrateInfo, function: ra_hir::Function, kind: ItemKind, path: &str, self_ty: Option<&str>) -> Vec<FnDetail> {
+    // Types are kept in the same normal form as queries, so they can be looked up by key
+    let substitute = |ty: String| ty::normalize_type(&match self_ty {
+        Some(self_ty) => substitute_self(&ty, self_ty),
+        None => ty,
+    });
     let assoc_params_pretty = function.assoc_fn_params(hirdb)
-        .into_iter().map(|param| param.ty().display(hirdb).to_string())
+        .into_iter().map(|param| substitute(param.ty().display(hirdb).to_string()))
         .collect::<Vec<_>>();
-    let ret_pretty = function.ret_type(hirdb).display(hirdb).to_string();
+    let ret_pretty = substitute(function.ret_type(hirdb).display(hirdb).to_string());
     if log::log_enabled!(log::Level::Info) {
         let self_param_pretty = function.self_param(hirdb)
             .map(|param| param.display(hirdb).to_string());
@@ -531,15 +2665,115 @@ fn analyze_function(hirdb: &dyn HirDatabase, krate_name: &str, function: ra_hir:
     }
     let assoc_params_str = assoc_params_pretty.join(", ");
     let s = format!("fn {}({}) -> {}", path, assoc_params_str, ret_pretty);
+    let doc_summary = function.docs(hirdb).and_then(|docs| doc_summary(docs.as_str()));
+    // Qualifiers are only available from the syntax
+    let source = function.source(hirdb).value;
+    let parse_type = |s: &str| ty::parse_type(s).unwrap_or_else(|e| {
+        debug!("failed to parse type {} of fn {}: {}", s, path, e);
+        ty::Type::Infer
+    });
+    let param_tys = assoc_params_pretty.iter().map(|param| parse_type(param)).collect();
+    let ret_ty = parse_type(&ret_pretty);
+    let source_span = source_span(hirdb, krate_info.srcdb, function);
+    if source_span.is_none() {
+        trace!("no source file for fn {}", path);
+    }
     vec![FnDetail {
-        krate: krate_name.to_owned(),
+        krate: krate_info.name.to_owned(),
+        version: krate_info.version.to_owned(),
+        path: format!("{}::{}", krate_info.import_name, path),
+        name: function.name(hirdb).to_string(),
+        kind,
         params: assoc_params_pretty,
         ret: ret_pretty,
+        param_tys,
+        ret_ty,
         s,
+        doc_summary,
+        is_unsafe: function.is_unsafe(hirdb),
+        is_async: source.async_token().is_some(),
+        is_const: source.const_token().is_some(),
+        is_deprecated: function.attrs(hirdb).by_key("deprecated").exists(),
+        example: None, // filled in from the crate's examples and tests once all fns are known
+        reexported_from: None, // filled in by analyze_krate, which knows where the fn was found
+        module: module_path(hirdb, function.module(hirdb)),
+        source: source_span,
     }]
 }
 
-fn analyze_adt(hirdb: &dyn HirDatabase, krate_name: &str, adt: ra_hir::Adt, path: &str) -> Vec<FnDetail> {
+// e.g. tar::header, with the crate's import name
+fn module_path(hirdb: &dyn HirDatabase, module: ra_hir::Module) -> String {
+    let krate_name = module.krate().display_name(hirdb).map_or_else(String::new, |name| name.to_string());
+    let mut segments = vec![krate_name];
+    // From the module up to the crate root, which has no name
+    segments.extend(module.path_to_root(hirdb).into_iter().rev().filter_map(|m| m.name(hirdb)).map(|name| name.to_string()));
+    segments.join("::")
+}
+
+// The file is relative to the package it's in, found by its Cargo.toml, so it's the same wherever the crate was
+// unpacked. Fns in files outside of packages, e.g. generated into a build script's out dir, have no span
+fn source_span(hirdb: &dyn HirDatabase, srcdb: &dyn SourceDatabaseExt, function: ra_hir::Function) -> Option<SourceSpan> {
+    let astdb: &dyn AstDatabase = hirdb.upcast();
+    let source = function.source(hirdb);
+    // Fns from macros are given the span of the macro call
+    let file_range = source.as_ref().map(|fn_def| fn_def.syntax()).original_file_range(astdb);
+    let source_root = srcdb.source_root(srcdb.file_source_root(file_range.file_id));
+    let file_path: &Path = source_root.path_for_file(&file_range.file_id)?.as_path()?.as_ref();
+    let package_dir = file_path.ancestors().skip(1).find(|dir| dir.join("Cargo.toml").is_file())?;
+    let file = file_path.strip_prefix(package_dir).unwrap().to_string_lossy().into_owned();
+    let text = srcdb.file_text(file_range.file_id);
+    let line_at = |offset: usize| text[..offset].matches('\n').count() as u32 + 1;
+    Some(SourceSpan {
+        file,
+        start_line: line_at(file_range.range.start().into()),
+        end_line: line_at(file_range.range.end().into()),
+    })
+}
+
+// Replace Self as a whole identifier, e.g. in Option<Self> or Self::Item, but not in e.g. SelfRef. An impl Trait self_ty
+// stays the trait in associated types, e.g. Iterator::Item for Self::Item
+fn substitute_self(ty: &str, self_ty: &str) -> String {
+    let is_ident = |c: char| c.is_alphanumeric() || c == '_';
+    let mut ret = String::with_capacity(ty.len());
+    let mut rest = ty;
+    while let Some(i) = rest.find("Self") {
+        let before_ok = rest[..i].chars().next_back().map_or(true, |c| !is_ident(c));
+        let after_ok = rest[i + 4..].chars().next().map_or(true, |c| !is_ident(c));
+        let replacement = match self_ty.strip_prefix("impl ") {
+            Some(trait_ty) if rest[i + 4..].starts_with("::") => trait_ty,
+            _ => self_ty,
+        };
+        ret.push_str(&rest[..i]);
+        ret.push_str(if before_ok && after_ok { replacement } else { "Self" });
+        rest = &rest[i + 4..];
+    }
+    ret.push_str(rest);
+    ret
+}
+
+// The name of a type or trait with its type params, e.g. HashMap<K, V, S>, for substituting Self
+fn generic_name(hirdb: &dyn HirDatabase, name: String, def: ra_hir::GenericDef) -> String {
+    let params: Vec<String> = def.params(hirdb).into_iter()
+        .filter_map(|param| match param {
+            ra_hir::GenericParam::TypeParam(tp) => Some(tp.name(hirdb).to_string()),
+            _ => None,
+        })
+        .collect();
+    if params.is_empty() { name } else { format!("{}<{}>", name, params.join(", ")) }
+}
+
+// The first paragraph, like rustdoc uses for item summaries
+fn doc_summary(docs: &str) -> Option<String> {
+    let summary = docs.lines()
+        .map(str::trim)
+        .skip_while(|line| line.is_empty())
+        .take_while(|line| !line.is_empty())
+        .collect::<Vec<_>>()
+        .join(" ");
+    if summary.is_empty() { None } else { Some(summary) }
+}
+
+fn analyze_adt(hirdb: &dyn HirDatabase, krate_info: &KrateInfo, adt: ra_hir::Adt, path: &str) -> Vec<FnDetail> {
     let mut methods = vec![];
     let ty = adt.ty(hirdb);
     let krate = adt.module(hirdb).krate();
@@ -554,14 +2788,186 @@ fn analyze_adt(hirdb: &dyn HirDatabase, krate_name: &str, adt: ra_hir::Adt, path
     let methods: Vec<_> = methods.into_iter()
         .filter(|m| m.visibility(hirdb) == Visibility::Public).collect();
     trace!("adt {} {:?}", path, methods);
+    let self_ty = generic_name(hirdb, adt.name(hirdb).to_string(), adt.into());
     let mut fndetails = vec![];
     for method in methods {
-        fndetails.extend(analyze_function(hirdb, krate_name, method, &(path.to_owned() + "::" + &method.name(hirdb).to_string())));
+        let kind = if method.self_param(hirdb).is_some() { ItemKind::Method } else { ItemKind::AssocFn };
+        fndetails.extend(analyze_function(hirdb, krate_info, method, kind, &(path.to_owned() + "::" + &method.name(hirdb).to_string()), Some(&self_ty)));
+    }
+    let chains = builder_chains(&fndetails, &ty::normalize_type(&self_ty));
+    if !chains.is_empty() {
+        debug!("adt {} is a builder, adding {} constructor chains", path, chains.len());
+        fndetails.extend(chains);
     }
     fndetails
 }
 
-fn analyze_trait(hirdb: &dyn HirDatabase, _krate_name: &str, tr: ra_hir::Trait, path: &str) -> Vec<FnDetail> {
+// Names of the methods that finish a builder, consuming or borrowing it to make what it builds
+const BUILDER_FINISHERS: &[&str] = &["build", "finish"];
+
+// e.g. foo::FooBuilder::new().build
+fn builder_chain_path(constructor_path: &str, finisher_name: &str) -> String {
+    format!("{}().{}", constructor_path, finisher_name)
+}
+
+// A type is a builder if it has a constructor, methods returning the builder (by value or reference) to chain, and a
+// build method. Each construction that can then be written as one expression, e.g. FooBuilder::new().build(), is
+// returned as its own entry, so a search for what's built finds it even though no single fn returns it from scratch
+fn builder_chains(fndetails: &[FnDetail], self_ty: &str) -> Vec<FnDetail> {
+    let is_self = |ty: &str| ty == self_ty || ty.strip_prefix("&mut ").or_else(|| ty.strip_prefix('&')) == Some(self_ty);
+    let has_setters = fndetails.iter().any(|f| f.kind == ItemKind::Method && !BUILDER_FINISHERS.contains(&f.name.as_str()) && is_self(&f.ret));
+    if !has_setters {
+        return vec![]
+    }
+    let constructors = fndetails.iter().filter(|f| f.kind == ItemKind::AssocFn && f.ret == self_ty);
+    // Finishers that need more than the builder can't be chained straight on
+    let finishers: Vec<_> = fndetails.iter()
+        .filter(|f| f.kind == ItemKind::Method && BUILDER_FINISHERS.contains(&f.name.as_str()) && f.params.len() == 1)
+        .filter(|f| !is_self(&f.ret) && f.ret != "()")
+        .collect();
+    let mut chains = vec![];
+    for constructor in constructors {
+        for finisher in finishers.iter() {
+            chains.push(FnDetail {
+                krate: finisher.krate.clone(),
+                version: finisher.version.clone(),
+                path: builder_chain_path(&constructor.path, &finisher.name),
+                name: finisher.name.clone(),
+                kind: ItemKind::BuilderChain,
+                params: constructor.params.clone(),
+                ret: finisher.ret.clone(),
+                param_tys: constructor.param_tys.clone(),
+                ret_ty: finisher.ret_ty.clone(),
+                s: format!("fn {}({}).{}() -> {}", constructor.path, constructor.params.join(", "), finisher.name, finisher.ret),
+                doc_summary: finisher.doc_summary.clone(),
+                is_unsafe: constructor.is_unsafe || finisher.is_unsafe,
+                is_async: constructor.is_async || finisher.is_async,
+                is_const: false,
+                is_deprecated: constructor.is_deprecated || finisher.is_deprecated,
+                example: None,
+                reexported_from: None,
+                module: finisher.module.clone(),
+                source: finisher.source.clone(),
+            })
+        }
+    }
+    chains
+}
+
+// The trait's own declarations, with Self as `impl Trait` - implementations are found through the types that have them.
+// Generic searches match impl Trait like a type param, so e.g. Read::read is found for `&mut R`, `&mut impl Read` and
+// `&mut File`
+fn analyze_trait(hirdb: &dyn HirDatabase, krate_info: &KrateInfo, tr: ra_hir::Trait, path: &str) -> Vec<FnDetail> {
     trace!("trait {} {:?}", path, tr.items(hirdb));
-    vec![]
+    let self_ty = format!("impl {}", generic_name(hirdb, tr.name(hirdb).to_string(), tr.into()));
+    let mut fndetails = vec![];
+    for item in tr.items(hirdb) {
+        let method = if let ra_hir::AssocItem::Function(f) = item { f } else { continue };
+        let kind = if method.self_param(hirdb).is_some() { ItemKind::Method } else { ItemKind::AssocFn };
+        fndetails.extend(analyze_function(hirdb, krate_info, method, kind, &(path.to_owned() + "::" + &method.name(hirdb).to_string()), Some(&self_ty)));
+    }
+    fndetails
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::fndetail;
+
+    #[test]
+    fn substitute_self_types() {
+        assert_eq!(substitute_self("Self", "Header"), "Header");
+        assert_eq!(substitute_self("&Self", "Header"), "&Header");
+        assert_eq!(substitute_self("&mut Self", "Header"), "&mut Header");
+        assert_eq!(substitute_self("Option<Self>", "Header"), "Option<Header>");
+        assert_eq!(substitute_self("Vec<Self>", "Header"), "Vec<Header>");
+        assert_eq!(substitute_self("Result<Self, Error>", "Header"), "Result<Header, Error>");
+        assert_eq!(substitute_self("(Self, Self)", "Header"), "(Header, Header)");
+    }
+
+    #[test]
+    fn substitute_self_generic_impls() {
+        // impl<T> Foo<T>
+        assert_eq!(substitute_self("Self", "Foo<T>"), "Foo<T>");
+        assert_eq!(substitute_self("&Self", "Foo<T>"), "&Foo<T>");
+        assert_eq!(substitute_self("Option<Self>", "Foo<T>"), "Option<Foo<T>>");
+        assert_eq!(substitute_self("Vec<Self>", "HashMap<K, V, S>"), "Vec<HashMap<K, V, S>>");
+        assert_eq!(substitute_self("Self::Item", "Foo<T>"), "Foo<T>::Item");
+    }
+
+    #[test]
+    fn substitute_self_traits() {
+        assert_eq!(substitute_self("&mut Self", "impl Read"), "&mut impl Read");
+        assert_eq!(substitute_self("Option<Self>", "impl Iterator"), "Option<impl Iterator>");
+        assert_eq!(substitute_self("Option<Self::Item>", "impl Iterator"), "Option<Iterator::Item>");
+        assert_eq!(substitute_self("Self", "impl From<T>"), "impl From<T>");
+    }
+
+    #[test]
+    fn open_format_10_db() {
+        let path = std::env::temp_dir().join(format!("reeves-format-10-test-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&path);
+        {
+            // A format 10 db: fns have the current layout, but builder chains have their build method's path and
+            // there's no generic tree
+            let db = sled::open(&path).unwrap();
+            db.insert(FN_ID_COUNTER, bincode::serialize(&0u64).unwrap()).unwrap();
+            add_crate(&db, "foo", "1.0.0", vec![
+                FnDetail {
+                    s: "fn foo::FooBuilder::new(u8).build() -> Foo".to_owned(),
+                    ..fndetail("1.0.0", "foo::FooBuilder::build", ItemKind::BuilderChain, &["u8"], "Foo")
+                },
+                fndetail("1.0.0", "foo::wrap", ItemKind::Function, &["u8"], "Vec<T>"),
+            ]).unwrap();
+            db.drop_tree(GENERIC_TREE).unwrap();
+            db.insert(DB_FORMAT, bincode::serialize(&10u32).unwrap()).unwrap();
+            db.flush().unwrap();
+        }
+        let db = open_db(&path).unwrap();
+        let format: u32 = bincode::deserialize(&db.get(DB_FORMAT).unwrap().unwrap()).unwrap();
+        assert_eq!(format, CURRENT_DB_FORMAT);
+        assert!(!db.contains_key(DB_FN_LAYOUT).unwrap());
+        let (version, fndetails) = crate_fns(&db, "foo").unwrap().unwrap();
+        assert_eq!(version, "1.0.0");
+        let mut paths: Vec<_> = fndetails.iter().map(|fndetail| fndetail.path.as_str()).collect();
+        paths.sort();
+        assert_eq!(paths, ["foo::FooBuilder::new().build", "foo::wrap"]);
+        assert!(!db.open_tree(GENERIC_TREE).unwrap().is_empty());
+        drop(db);
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn import_normalizes_types() {
+        let path = std::env::temp_dir().join(format!("reeves-import-test-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&path);
+        let db = open_db(&path).unwrap();
+        let fns = vec![fndetail("1.0.0", "foo::count", ItemKind::Function, &["&'a str", "HashMap<K,V>"], "usize")];
+        let record = ExportRecord::Crate { name: "foo".to_owned(), version: "1.0.0".to_owned(), fns };
+        let jsonl = serde_json::to_string(&record).unwrap();
+        assert_eq!(import_jsonl(&db, &mut jsonl.as_bytes()).unwrap(), 1);
+        let found = search(&db, Some(vec!["&str".to_owned(), "HashMap<K, V>".to_owned()]), Some("usize".to_owned())).unwrap();
+        assert_eq!(found.iter().map(|fndetail| fndetail.path.as_str()).collect::<Vec<_>>(), ["foo::count"]);
+        drop(db);
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn new_db_is_current_format() {
+        let path = std::env::temp_dir().join(format!("reeves-new-db-test-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&path);
+        let db = open_db(&path).unwrap();
+        let format: u32 = bincode::deserialize(&db.get(DB_FORMAT).unwrap().unwrap()).unwrap();
+        assert_eq!(format, CURRENT_DB_FORMAT);
+        drop(db);
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn substitute_self_whole_identifiers_only() {
+        assert_eq!(substitute_self("SelfRef", "Header"), "SelfRef");
+        assert_eq!(substitute_self("MySelf", "Header"), "MySelf");
+        assert_eq!(substitute_self("Self_", "Header"), "Self_");
+        assert_eq!(substitute_self("&str", "Header"), "&str");
+    }
 }
@@ -1,6 +1,6 @@
 use reeves;
 
-use anyhow::{Context, Result, bail};
+use anyhow::{Context, Result, anyhow, bail};
 use either::Either;
 use futures::executor::ThreadPool;
 use futures::stream::{FuturesUnordered, StreamExt};
@@ -14,13 +14,20 @@ use std::fs;
 use std::io::{self, Write};
 use std::os::unix::process::CommandExt;
 use std::path::{Path, PathBuf};
-use std::process::Command;
-use std::sync::Mutex;
+use std::process::{self, Command, Output, Stdio};
+use std::collections::HashMap;
+use std::sync::{Arc, Condvar, Mutex, mpsc};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use structopt::StructOpt;
 
+use reeves::text_search::TextSearchBackend;
 use reeves_types::*;
 
+mod coordinator;
 mod server;
+mod sparse;
 
 // We re-exec this in a container, so need to know how to invoke it
 const ANALYZE_AND_PRINT_COMMAND: &str = "analyze-and-print";
@@ -33,7 +40,8 @@ struct AnalyzeAndPrintOutput {
 }
 
 // NOTE: this variable assumes that reeves never re-executes itself in the
-// same environment (inside a container is fine, as the environment isn't shared)
+// same environment (inside a container is fine, as the environment isn't shared,
+// and other sandboxes remove it from the environment)
 // We need this because some parts of RA can execute themselves, but we use
 // it as a library, so to differentiate whether we're starting reeves or rust
 // analyzer, we set this variable on reeves startup
@@ -42,17 +50,237 @@ const ENV_RUST_ANALYZER_EXEC: &str = "REEVES_INTERNAL_RUST_ANALYZER_EXEC";
 // what to exec
 const ENV_RUST_ANALYZER_BINARY: &str = "REEVES_INTERNAL_RUST_ANALYZER_BINARY";
 
-const CRATE_WORK_DIR: &str = "/tmp/crate";
+const DEFAULT_WORK_DIR: &str = "/tmp/crate";
+
+// A directory of its own under the work dir, so concurrent analyses (including by other reeves processes) don't
+// collide. Removed on drop, so it's cleaned up even if analysis panics
+struct TempWorkDir(PathBuf);
+
+impl TempWorkDir {
+    fn new(work_dir: &Path, prefix: &str) -> TempWorkDir {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let path = work_dir.join(format!("{}-{}-{}", prefix, process::id(), COUNTER.fetch_add(1, Ordering::SeqCst)));
+        // Left behind by an earlier process with the same pid that was killed
+        if let Err(e) = fs::remove_dir_all(&path) {
+            if e.kind() != io::ErrorKind::NotFound { panic!("{}", e) }
+        }
+        fs::create_dir_all(&path).unwrap();
+        TempWorkDir(path)
+    }
+
+    fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempWorkDir {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_dir_all(&self.0) {
+            warn!("failed to remove work dir {}: {}", self.0.display(), e)
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Sandbox {
+    // Run directly on the host, only suitable for trusted crates
+    None,
+    // Filesystem and network isolation with bubblewrap, using the host toolchain
+    Bwrap,
+    // Run in a container with the toolchain from container-state
+    Container,
+}
+
+impl std::str::FromStr for Sandbox {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, String> {
+        Ok(match s {
+            "none" => Sandbox::None,
+            "bwrap" => Sandbox::Bwrap,
+            "container" => Sandbox::Container,
+            _ => return Err(format!("unknown sandbox {}, expected none, bwrap or container", s)),
+        })
+    }
+}
+
+// Where analysis finds crates and their versions
+#[derive(Clone, Debug)]
+pub enum CrateSource {
+    // A full mirror of the index and crates
+    Panamax(PathBuf),
+    // Crates are looked up in the index and downloaded as needed
+    Sparse(sparse::SparseIndex),
+}
+
+// Users can't depend on yanked versions, so they're not worth indexing. None if every version is yanked
+fn highest_unyanked_version(krate: &crates_index::Crate) -> Option<String> {
+    krate.versions().iter()
+        .filter(|v| !v.is_yanked())
+        .filter_map(|v| semver::Version::parse(v.version()).ok().map(|semver| (semver, v.version())))
+        .max_by(|(a, _), (b, _)| a.cmp(b))
+        .map(|(_, version)| version.to_owned())
+}
+
+impl CrateSource {
+    // Every crate with its highest unyanked version, if any
+    fn crates(&self) -> Result<Vec<(String, Option<String>)>> {
+        match self {
+            CrateSource::Panamax(path) => {
+                let index = crates_index::Index::new(path.join("crates.io-index"));
+                if !index.exists() {
+                    bail!("no index in panamax mirror {}", path.display())
+                }
+                Ok(index.crates()
+                    .map(|c| (c.name().to_owned(), highest_unyanked_version(&c)))
+                    .collect())
+            },
+            CrateSource::Sparse(index) => index.crates(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+enum CrateSourceKind {
+    Panamax,
+    Sparse,
+}
+
+impl std::str::FromStr for CrateSourceKind {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, String> {
+        Ok(match s {
+            "panamax" => CrateSourceKind::Panamax,
+            "sparse" => CrateSourceKind::Sparse,
+            _ => return Err(format!("unknown crate source {}, expected panamax or sparse", s)),
+        })
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct SandboxConfig {
+    pub kind: Sandbox,
+    pub runtime: String,
+    pub image: String,
+    // Wall-clock limit for analyzing a single crate, including prep
+    pub timeout: Option<Duration>,
+    // Where to keep the full output of each crate's analysis
+    pub log_dir: Option<PathBuf>,
+    // Where crates are downloaded and extracted to for analysis
+    pub work_dir: PathBuf,
+    // How many crates to prep at once in bulk analysis, alongside the analysis jobs
+    pub prep_jobs: Option<usize>,
+    // Set when analyses run concurrently, to limit how many are in each stage
+    pub stages: Option<Arc<AnalysisStages>>,
+}
+
+// Prep is mostly waiting on the network and analysis is mostly CPU, so running them as separate stages with their own
+// limits lets one crate be prepped while another is analyzed
+#[derive(Debug)]
+pub struct AnalysisStages {
+    prep: Slots,
+    analyze: Slots,
+}
+
+impl AnalysisStages {
+    pub fn new(prep_jobs: usize, analyze_jobs: usize) -> AnalysisStages {
+        AnalysisStages { prep: Slots::new(prep_jobs), analyze: Slots::new(analyze_jobs) }
+    }
+}
+
+#[derive(Debug)]
+struct Slots {
+    available: Mutex<usize>,
+    freed: Condvar,
+}
+
+struct SlotGuard<'a>(&'a Slots);
+
+impl Slots {
+    fn new(n: usize) -> Slots {
+        Slots { available: Mutex::new(n), freed: Condvar::new() }
+    }
+
+    fn acquire(&self) -> SlotGuard {
+        let mut available = self.available.lock().unwrap();
+        while *available == 0 {
+            available = self.freed.wait(available).unwrap();
+        }
+        *available -= 1;
+        SlotGuard(self)
+    }
+}
+
+impl Drop for SlotGuard<'_> {
+    fn drop(&mut self) {
+        *self.0.available.lock().unwrap() += 1;
+        self.0.freed.notify_one();
+    }
+}
+
+impl SandboxConfig {
+    // Better to find out now than after identifying thousands of crates to analyze
+    fn check(&self) -> Result<()> {
+        let (program, flag) = match self.kind {
+            Sandbox::None => return Ok(()),
+            Sandbox::Bwrap => ("bwrap", "--sandbox"),
+            Sandbox::Container => (self.runtime.as_str(), "--container-runtime"),
+        };
+        match Command::new(program).arg("--version").output() {
+            Ok(res) if res.status.success() => Ok(()),
+            Ok(res) => bail!("sandbox program {} is not working: {}", program, String::from_utf8_lossy(&res.stderr)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                bail!("sandbox program {} not found, install it or choose another with {}", program, flag)
+            },
+            Err(e) => bail!("failed to run sandbox program {}: {}", program, e),
+        }
+    }
+}
 
 #[derive(Debug, StructOpt)]
 #[structopt(name = "reeves", about = "A tool for indexing and searching crates")]
 struct ReevesOpt {
-    #[structopt(long, default_value = "reeves.db")]
-    db: PathBuf,
-    #[structopt(long, default_value = "panamax-mirror")]
-    panamax_mirror: PathBuf,
-    #[structopt(long, default_value = "rust-analyzer/target/release/rust-analyzer")]
-    rust_analyzer: PathBuf,
+    #[structopt(long, help = "Config file to take settings from, flags take precedence [default: reeves.toml, if present]")]
+    config: Option<PathBuf>,
+    #[structopt(long, help = "[default: reeves.db]")]
+    db: Option<PathBuf>,
+    #[structopt(long, help = "[default: panamax-mirror]")]
+    panamax_mirror: Option<PathBuf>,
+    #[structopt(long, possible_values = &["panamax", "sparse"])]
+    #[structopt(help = "Where to find crates - the panamax mirror, or the crates.io sparse index with crates downloaded as needed [default: panamax]")]
+    crate_source: Option<CrateSourceKind>,
+    #[structopt(long, help = "Directory to cache downloaded crates and the crates.io database dump in [default: crate-cache]")]
+    crate_cache: Option<PathBuf>,
+    #[structopt(long, help = "[default: rust-analyzer/target/release/rust-analyzer]")]
+    rust_analyzer: Option<PathBuf>,
+    #[structopt(long, possible_values = &["tantivy", "meilisearch", "elasticsearch"])]
+    #[structopt(help = "Text search backend - tantivy is embedded, elasticsearch also works with anything serving its API, e.g. OpenSearch [default: tantivy]")]
+    text_search_backend: Option<TextSearchBackend>,
+    #[structopt(long, alias = "meili-url")]
+    #[structopt(help = "URL of the text search backend, or directory of tantivy's indexes [default: <db>.text-search for tantivy, http://localhost:7700 for meilisearch, http://localhost:9200 for elasticsearch]")]
+    text_search_url: Option<String>,
+    #[structopt(long, help = "Memory a search may use for the fn ids of candidate types, beyond which fuzzier candidates are skipped [default: 256]")]
+    search_memory_mb: Option<usize>,
+    #[structopt(long, help = "Milliseconds after which a search stops trying fuzzier candidate types and returns what it has [default: no deadline]")]
+    search_deadline_ms: Option<u64>,
+    #[structopt(long, help = "Only find fns returning the searched for type as written, not behind a different reference or Box/Rc/Arc")]
+    exact_ret_ownership: bool,
+    #[structopt(long, possible_values = &["none", "bwrap", "container"])]
+    #[structopt(help = "How to isolate crate analysis - none is only suitable for trusted crates [default: container]")]
+    sandbox: Option<Sandbox>,
+    #[structopt(long, help = "Container runtime for analysis, e.g. podman or docker [default: podman]")]
+    container_runtime: Option<String>,
+    #[structopt(long, help = "Image to run analysis in [default: ubuntu:20.04]")]
+    container_image: Option<String>,
+    #[structopt(long, help = "Give up on analyzing a crate after this many seconds, recording a timeout error")]
+    analysis_timeout: Option<u64>,
+    #[structopt(long, help = "Directory to write the full sandbox output of each crate analysis to")]
+    log_dir: Option<PathBuf>,
+    #[structopt(long, help = "Directory to download and extract crates into for analysis [default: /tmp/crate]")]
+    work_dir: Option<PathBuf>,
+    #[structopt(long, parse(try_from_str = parse_jobs), help = "Number of crates to fetch dependencies for at once in bulk analysis, on top of the analysis jobs [default: same as jobs]")]
+    prep_jobs: Option<usize>,
+    #[structopt(long, possible_values = &["text", "json"], help = "Format of log lines on stderr [default: text]")]
+    log_format: Option<LogFormat>,
     #[structopt(subcommand)]
     cmd: ReevesCmd,
 }
@@ -72,35 +300,275 @@ enum ReevesCmd {
     ContainerAnalyzeAndPrint {
         crate_path: PathBuf,
     },
-    #[structopt(about = "Analyze top 100 crates from play.rust-lang.org in containers and save results (requires: container state, panamax mirror, reeves DB)")]
-    AnalyzeTop100Crates,
-    #[structopt(about = "Analyze all crates (latest version) from crates.io in containers and save results (requires: container state, panamax mirror, reeves DB)")]
-    AnalyzeAllCrates,
+    #[structopt(about = "Analyze a crate from the registry in a container and save results, using the latest version if none is given (requires: container state, reeves DB)")]
+    AnalyzeCrate {
+        name: String,
+        version: Option<String>,
+    },
+    #[structopt(about = "Analyze the crate or workspace at the root of a git repository in a container and save results as git+<url>#<rev>, with :<package> after for each package of a workspace (requires: container state, reeves DB)")]
+    AnalyzeGit {
+        url: String,
+        #[structopt(long, help = "Branch, tag or commit to analyze (default: the remote HEAD)")]
+        rev: Option<String>,
+    },
+    #[structopt(about = "Analyze the standard library crates of a toolchain and save results as <crate>@<rustc version> (requires: rust analyzer, rustup with the rust-src component, reeves DB)")]
+    AnalyzeStd {
+        #[structopt(long, default_value = "stable", help = "Rustup toolchain to take the standard library from")]
+        toolchain: String,
+    },
+    #[structopt(about = "Analyze the most downloaded crates (latest version) from crates.io in containers and save results (requires: container state, reeves DB)")]
+    #[structopt(name = "analyze-top-n-crates", alias = "analyze-top100-crates")]
+    AnalyzeTopNCrates {
+        #[structopt(long, default_value = "100", help = "Number of crates to analyze, by download count")]
+        count: usize,
+        #[structopt(long, parse(try_from_str = parse_jobs), help = "Number of crates to analyze at once (default: CPU count, capped by available memory)")]
+        jobs: Option<usize>,
+        #[structopt(long, help = "Print the crates that would be analyzed, without analyzing them")]
+        dry_run: bool,
+        #[structopt(long, help = "File of crate[@version] lines to exclude from analysis")]
+        skip_list: Option<PathBuf>,
+        #[structopt(long, help = "File of crate[@version] lines to restrict analysis to")]
+        only_list: Option<PathBuf>,
+        #[structopt(long, help = "Write a JSON summary of the run to this file, updated with what's in progress while running")]
+        report: Option<PathBuf>,
+        #[structopt(long, help = "Exit with code 2 if more than this fraction of crates (0 to 1) fail or report an error")]
+        max_failure_rate: Option<f64>,
+    },
+    #[structopt(about = "Analyze all crates (latest version) from crates.io in containers and save results (requires: container state, panamax mirror or --crate-source sparse, reeves DB)")]
+    AnalyzeAllCrates {
+        #[structopt(long, parse(try_from_str = parse_jobs), help = "Number of crates to analyze at once (default: CPU count, capped by available memory)")]
+        jobs: Option<usize>,
+        #[structopt(long, help = "Continue the previous run, analyzing only the crates it hadn't got to")]
+        resume: bool,
+        #[structopt(long, help = "Print the crates that would be analyzed, without analyzing them")]
+        dry_run: bool,
+        #[structopt(long, help = "File of crate[@version] lines to exclude from analysis")]
+        skip_list: Option<PathBuf>,
+        #[structopt(long, help = "File of crate[@version] lines to restrict analysis to")]
+        only_list: Option<PathBuf>,
+        #[structopt(long, help = "Write a JSON summary of the run to this file, updated with what's in progress while running")]
+        report: Option<PathBuf>,
+        #[structopt(long, help = "Exit with code 2 if more than this fraction of crates (0 to 1) fail or report an error")]
+        max_failure_rate: Option<f64>,
+    },
+    #[structopt(about = "Repeatedly update a crates.io index and analyze new crate versions, updating the text search as they're saved (requires: container state, reeves DB, running text search)")]
+    Watch {
+        #[structopt(long, default_value = "crates.io-index", help = "Where to keep the crates.io index for finding new versions")]
+        index_path: PathBuf,
+        #[structopt(long, default_value = "3600", help = "Seconds between index updates")]
+        interval: u64,
+        #[structopt(long, parse(try_from_str = parse_jobs), help = "Number of crates to analyze at once (default: CPU count, capped by available memory)")]
+        jobs: Option<usize>,
+        #[structopt(long, help = "File of crate[@version] lines to exclude from analysis")]
+        skip_list: Option<PathBuf>,
+        #[structopt(long, help = "File of crate[@version] lines to restrict analysis to")]
+        only_list: Option<PathBuf>,
+    },
+    #[structopt(about = "Serve the crates of an all-crates run as jobs for workers, saving the results they send back (requires: panamax mirror or --crate-source sparse, reeves DB)")]
+    Coordinate {
+        #[structopt(long, default_value = "127.0.0.1")]
+        ip: String,
+        #[structopt(long)]
+        port: u16,
+        #[structopt(long, env = "REEVES_WORKER_TOKEN", hide_env_values = true, help = "Bearer token workers must present")]
+        token: String,
+        #[structopt(long, default_value = "3600", help = "Seconds before a job claimed by a worker that hasn't reported back is handed out again")]
+        lease: u64,
+        #[structopt(long, help = "Continue the previous run, analyzing only the crates it hadn't got to")]
+        resume: bool,
+        #[structopt(long, help = "File of crate[@version] lines to exclude from analysis")]
+        skip_list: Option<PathBuf>,
+        #[structopt(long, help = "File of crate[@version] lines to restrict analysis to")]
+        only_list: Option<PathBuf>,
+    },
+    #[structopt(about = "Analyze crates handed out by a coordinator in containers, sending the results back (requires: container state)")]
+    Worker {
+        #[structopt(long, help = "URL of the coordinator, e.g. http://coordinator:8001")]
+        coordinator: String,
+        #[structopt(long, env = "REEVES_WORKER_TOKEN", hide_env_values = true, help = "Bearer token for the coordinator")]
+        token: String,
+        #[structopt(long, parse(try_from_str = parse_jobs), help = "Number of crates to analyze at once (default: CPU count, capped by available memory)")]
+        jobs: Option<usize>,
+        #[structopt(long, requires = "push-token", help = "URL of a reeves server to send analyses to directly, e.g. http://server:8000")]
+        push_to: Option<String>,
+        #[structopt(long, env = "REEVES_ADMIN_TOKEN", hide_env_values = true, help = "Admin token for the server given by --push-to")]
+        push_token: Option<String>,
+    },
+    #[structopt(about = "Re-analyze crates whose last analysis reported an error, e.g. after a rust-analyzer upgrade (requires: container state, panamax mirror or --crate-source sparse, reeves DB)")]
+    RetryErrors {
+        #[structopt(long, help = "Only retry crates whose error contains this text")]
+        matching: Option<String>,
+        #[structopt(long, parse(try_from_str = parse_jobs), help = "Number of crates to analyze at once (default: CPU count, capped by available memory)")]
+        jobs: Option<usize>,
+        #[structopt(long, help = "Write a JSON summary of the run to this file, updated with what's in progress while running")]
+        report: Option<PathBuf>,
+        #[structopt(long, help = "Exit with code 2 if more than this fraction of crates (0 to 1) fail or report an error")]
+        max_failure_rate: Option<f64>,
+    },
+    #[structopt(about = "Load crate download counts and categories from the crates.io database dump, for ranking results and analyze-top-n-crates (requires: reeves DB)")]
+    IngestPopularity {
+        #[structopt(long, help = "Database dump to load, rather than downloading the latest into the crate cache")]
+        dump: Option<PathBuf>,
+    },
     #[structopt(about = "Populate the text search backend, using the reeves DB (requires: reeves DB, running text search)")]
-    LoadTextSearch,
+    LoadTextSearch {
+        #[structopt(long, help = "Only add and remove the types that changed since the text search was last loaded, rather than rebuilding it")]
+        incremental: bool,
+    },
     #[structopt(about = "Perform a search for some comma-separated param types and a ret type (requires: reeves DB, running+loaded text search)")]
     Search {
         params_search: String,
         ret_search: String,
+        #[structopt(long, default_value = "text", possible_values = &["text", "json", "table"])]
+        format: SearchFormat,
+    },
+    #[structopt(about = "Interactively search with signatures like `fn(&str) -> usize` (requires: reeves DB, running+loaded text search)")]
+    Repl {
+        #[structopt(long, default_value = "table", possible_values = &["text", "json", "table"])]
+        format: SearchFormat,
+        #[structopt(long, help = "File to keep query history in [default: ~/.reeves_history]")]
+        history: Option<PathBuf>,
     },
     #[structopt(about = "Start the reeves server (requires: wasm built, reeves db, loaded+running text search)")]
     Serve {
-        #[structopt(long, default_value = "page/pkg.tar")]
-        static_tar: PathBuf,
+        #[structopt(long = "static", alias = "static-tar", default_value = "page/pkg.tar")]
+        #[structopt(help = "Tar or directory of static files to serve (a directory is read on each request, useful in development)")]
+        static_path: PathBuf,
         #[structopt(long, default_value = "127.0.0.1")]
         ip: String,
-        #[structopt(long)]
-        port: String,
+        #[structopt(long, required_unless = "uds")]
+        port: Option<String>,
+        #[structopt(long, conflicts_with = "port", help = "Listen on a unix socket at this path instead of TCP")]
+        uds: Option<PathBuf>,
+        #[structopt(long, env = "REEVES_ADMIN_TOKEN", hide_env_values = true)]
+        #[structopt(help = "Bearer token enabling the /admin API (disabled if unset)")]
+        admin_token: Option<String>,
+        #[structopt(long, requires = "admin-token", help = "URL to POST to when an analysis queued via the admin API finishes")]
+        analysis_webhook: Option<String>,
+        #[structopt(long, requires = "admin-token", help = "Queue crates for analysis when searches filter on them but they aren't indexed")]
+        index_on_demand: bool,
+        #[structopt(long, help = "Record queries, result counts and latencies in the reeves DB")]
+        query_log: bool,
+        #[structopt(long, help = "Read the index into memory before listening, so the first searches aren't slow")]
+        preload: bool,
+        #[structopt(long, help = "Seconds between catching the text search up with crates saved by other processes")]
+        text_search_sync_interval: Option<u64>,
+        #[structopt(long, help = "Seconds between removing types left with no functions by reanalyzed or deleted crates")]
+        gc_interval: Option<u64>,
+        #[structopt(long, help = "Seconds between flushing the reeves DB to disk")]
+        flush_interval: Option<u64>,
+        #[structopt(long, default_value = "30", help = "Seconds without a search before maintenance given by the intervals runs")]
+        maintenance_idle: u64,
+    },
+    #[structopt(about = "List analysed crates with their version and number of functions (requires: reeves DB)")]
+    ListCrates {
+        #[structopt(long, help = "List crates whose analysis reported an error instead")]
+        errored: bool,
+    },
+    #[structopt(about = "Print a summary of the index (requires: reeves DB)")]
+    Stats,
+    #[structopt(about = "Check the reeves DB trees are consistent with each other (requires: reeves DB)")]
+    VerifyDb,
+    #[structopt(about = "Export analysed crates and errors as JSONL (requires: reeves DB)")]
+    Export {
+        output: PathBuf,
+    },
+    #[structopt(about = "Export the graph of types (nodes) and the functions between them (edges, from each param type to the ret type) for visualization (requires: reeves DB)")]
+    ExportTypeGraph {
+        #[structopt(long, help = "Write to this file rather than stdout")]
+        output: Option<PathBuf>,
+        #[structopt(long, default_value = "dot", possible_values = &["dot", "json"])]
+        format: GraphFormat,
+        #[structopt(long = "crate", help = "Only include the functions of this crate")]
+        krate: Option<String>,
+        #[structopt(long, help = "Only include what can be reached from this type by following functions")]
+        from: Option<String>,
+    },
+    #[structopt(about = "Import a JSONL export, replacing any existing results for the same crates (requires: reeves DB)")]
+    Import {
+        input: PathBuf,
+    },
+    #[structopt(about = "Publish or restore a snapshot of the whole index (requires: reeves DB)")]
+    Snapshot(SnapshotCmd),
+    #[structopt(about = "Print a JSON Schema of the API types, for clients not written in Rust")]
+    Schema {
+        #[structopt(long, help = "Write to this file rather than stdout")]
+        output: Option<PathBuf>,
     },
     #[structopt(about = "Dump contents of the reeves DB (requires: reeves DB)")]
     DebugDB,
 }
 
-fn main() -> Result<()> {
-    env_logger::init();
+const DEFAULT_CONFIG_PATH: &str = "reeves.toml";
+
+// Everything is optional, and anything given as a flag overrides the file
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+struct ReevesConfig {
+    db: Option<PathBuf>,
+    panamax_mirror: Option<PathBuf>,
+    crate_source: Option<String>,
+    crate_cache: Option<PathBuf>,
+    rust_analyzer: Option<PathBuf>,
+    text_search_backend: Option<String>,
+    #[serde(alias = "meili-url")]
+    text_search_url: Option<String>,
+    search_memory_mb: Option<usize>,
+    search_deadline_ms: Option<u64>,
+    exact_ret_ownership: Option<bool>,
+    sandbox: Option<String>,
+    container_runtime: Option<String>,
+    container_image: Option<String>,
+    analysis_timeout: Option<u64>,
+    log_dir: Option<PathBuf>,
+    work_dir: Option<PathBuf>,
+    log_format: Option<String>,
+    jobs: Option<usize>,
+    prep_jobs: Option<usize>,
+}
+
+impl ReevesConfig {
+    // An explicitly given config must exist, the default one is only used if it's there
+    fn load(path: Option<&Path>) -> Result<ReevesConfig> {
+        let path = match path {
+            Some(path) => path,
+            None if Path::new(DEFAULT_CONFIG_PATH).exists() => Path::new(DEFAULT_CONFIG_PATH),
+            None => return Ok(ReevesConfig::default()),
+        };
+        let content = fs::read_to_string(path).with_context(|| format!("failed to read config {}", path.display()))?;
+        let config: ReevesConfig = toml::from_str(&content).with_context(|| format!("failed to parse config {}", path.display()))?;
+        if config.jobs == Some(0) || config.prep_jobs == Some(0) {
+            bail!("jobs and prep-jobs in config {} must be at least 1", path.display())
+        }
+        Ok(config)
+    }
+}
+
+// Nothing would ever run with no jobs
+fn parse_jobs(s: &str) -> Result<usize, String> {
+    match s.parse::<usize>() {
+        Ok(0) => Err("must be at least 1".to_owned()),
+        Ok(jobs) => Ok(jobs),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+#[derive(Debug, StructOpt)]
+enum SnapshotCmd {
+    #[structopt(about = "Atomically write a snapshot to a path, e.g. somewhere being served from")]
+    Publish {
+        path: PathBuf,
+    },
+    #[structopt(about = "Populate an empty DB from a snapshot")]
+    Restore {
+        path: PathBuf,
+    },
+}
 
+fn main() -> Result<()> {
     // See comment on ENV_RUST_ANALYZER_EXEC
     if env::var_os(ENV_RUST_ANALYZER_EXEC).is_some() {
+        env_logger::init();
         debug!("Re-executing rust-analyzer");
         let mut cmd = Command::new(env::var_os(ENV_RUST_ANALYZER_BINARY).unwrap());
         cmd.args(env::args_os().skip(1)).exec();
@@ -110,95 +578,368 @@ fn main() -> Result<()> {
     }
 
     let opt = ReevesOpt::from_args();
+    let config = ReevesConfig::load(opt.config.as_deref())?;
+    let config_log_format = config.log_format.as_ref().map(|s| s.parse()).transpose().map_err(|e: String| anyhow!(e))?;
+    init_logging(opt.log_format.or(config_log_format).unwrap_or(LogFormat::Text));
 
-    env::set_var(ENV_RUST_ANALYZER_BINARY, opt.rust_analyzer);
+    let db_path = opt.db.or(config.db).unwrap_or_else(|| "reeves.db".into());
+    let panamax_mirror = opt.panamax_mirror.or(config.panamax_mirror).unwrap_or_else(|| "panamax-mirror".into());
+    let config_crate_source = config.crate_source.map(|s| s.parse()).transpose().map_err(|e: String| anyhow!(e))?;
+    let crate_cache = opt.crate_cache.or(config.crate_cache).unwrap_or_else(|| "crate-cache".into());
+    let crate_source = match opt.crate_source.or(config_crate_source).unwrap_or(CrateSourceKind::Panamax) {
+        CrateSourceKind::Panamax => CrateSource::Panamax(panamax_mirror),
+        CrateSourceKind::Sparse => CrateSource::Sparse(sparse::SparseIndex::new(sparse::CRATES_IO_SPARSE_INDEX, &crate_cache)),
+    };
+    let rust_analyzer = opt.rust_analyzer.or(config.rust_analyzer)
+        .unwrap_or_else(|| "rust-analyzer/target/release/rust-analyzer".into());
+    let config_sandbox = config.sandbox.map(|s| s.parse()).transpose().map_err(|e: String| anyhow!(e))?;
+    let config_text_search_backend = config.text_search_backend.map(|s| s.parse()).transpose().map_err(|e: String| anyhow!(e))?;
+    let default_jobs_setting = config.jobs;
+
+    env::set_var(ENV_RUST_ANALYZER_BINARY, rust_analyzer);
+    let text_search_backend = opt.text_search_backend.or(config_text_search_backend).unwrap_or_default();
+    reeves::set_text_search_backend(text_search_backend)?;
+    match opt.text_search_url.or(config.text_search_url) {
+        Some(url) => reeves::set_text_search_url(url)?,
+        // Kept with the db it indexes, so each db gets its own
+        None if text_search_backend == TextSearchBackend::Tantivy => {
+            let mut dir = db_path.as_os_str().to_owned();
+            dir.push(".text-search");
+            reeves::set_text_search_url(dir.to_string_lossy().into_owned())?
+        },
+        None => (),
+    }
+    if let Some(mb) = opt.search_memory_mb.or(config.search_memory_mb) {
+        reeves::set_search_memory_budget(mb * 1024 * 1024)?
+    }
+    if let Some(ms) = opt.search_deadline_ms.or(config.search_deadline_ms) {
+        reeves::set_search_deadline(Duration::from_millis(ms))?
+    }
+    if opt.exact_ret_ownership || config.exact_ret_ownership.unwrap_or(false) {
+        reeves::set_ret_ownership_variants(false)?
+    }
+
+    let sandbox = SandboxConfig {
+        kind: opt.sandbox.or(config_sandbox).unwrap_or(Sandbox::Container),
+        runtime: opt.container_runtime.or(config.container_runtime).unwrap_or_else(|| "podman".into()),
+        image: opt.container_image.or(config.container_image).unwrap_or_else(|| "ubuntu:20.04".into()),
+        timeout: opt.analysis_timeout.or(config.analysis_timeout).map(Duration::from_secs),
+        log_dir: opt.log_dir.or(config.log_dir),
+        work_dir: opt.work_dir.or(config.work_dir).unwrap_or_else(|| DEFAULT_WORK_DIR.into()),
+        prep_jobs: opt.prep_jobs.or(config.prep_jobs),
+        stages: None,
+    };
+    let needs_sandbox = match &opt.cmd {
+        ReevesCmd::ContainerAnalyzeAndPrint { .. } |
+        ReevesCmd::AnalyzeCrate { .. } |
+        ReevesCmd::AnalyzeGit { .. } |
+        ReevesCmd::Watch { .. } |
+        ReevesCmd::Worker { .. } |
+        ReevesCmd::RetryErrors { .. } => true,
+        ReevesCmd::AnalyzeTopNCrates { dry_run, .. } |
+        ReevesCmd::AnalyzeAllCrates { dry_run, .. } => !dry_run,
+        ReevesCmd::Serve { admin_token, .. } => admin_token.is_some(),
+        ReevesCmd::AnalyzeAndSave { .. } |
+        ReevesCmd::AnalyzeAndPrint { .. } |
+        ReevesCmd::AnalyzeStd { .. } |
+        ReevesCmd::IngestPopularity { .. } |
+        ReevesCmd::LoadTextSearch { .. } |
+        ReevesCmd::Search { .. } |
+        ReevesCmd::Repl { .. } |
+        ReevesCmd::Coordinate { .. } |
+        ReevesCmd::ListCrates { .. } |
+        ReevesCmd::Stats |
+        ReevesCmd::VerifyDb |
+        ReevesCmd::Export { .. } |
+        ReevesCmd::ExportTypeGraph { .. } |
+        ReevesCmd::Import { .. } |
+        ReevesCmd::Snapshot(_) |
+        ReevesCmd::Schema { .. } |
+        ReevesCmd::DebugDB => false,
+    };
+    if needs_sandbox {
+        sandbox.check()?;
+    }
 
     match opt.cmd {
 
         ReevesCmd::AnalyzeAndSave { crate_path } => {
             info!("analyzing crate path {}", crate_path.display());
-            let (crate_name, crate_version, fndetails) = reeves::analyze_crate_path(&crate_path);
-            let db = reeves::open_db(&opt.db);
-            match fndetails {
-                Ok(fndetails) => {
-                    info!("finished analysing functions, inserting {} function details into db", fndetails.len());
-                    reeves::save_analysis(&db, &crate_name, &crate_version, fndetails);
-                },
-                Err(err) => {
-                    let err = format!("{:?}", err);
-                    warn!("analysis failed, saving error to db: {}", err);
-                    reeves::save_analysis_error(&db, &crate_name, &crate_version, &err);
-                },
+            let packages = reeves::analyze_crate_path(&crate_path)?;
+            let db = reeves::open_db(&db_path)?;
+            for (crate_name, crate_version, fndetails) in packages {
+                match fndetails {
+                    Ok(fndetails) => {
+                        info!("finished analysing functions of {} {}, inserting {} function details into db", crate_name, crate_version, fndetails.len());
+                        reeves::save_analysis(&db, &crate_name, &crate_version, fndetails)?;
+                    },
+                    Err(err) => {
+                        let err = err.to_string();
+                        warn!("analysis of {} {} failed, saving error to db: {}", crate_name, crate_version, err);
+                        reeves::save_analysis_error(&db, &crate_name, &crate_version, &err)?;
+                    },
+                }
             }
             info!("finished inserting into db");
         },
 
         ReevesCmd::AnalyzeAndPrint { crate_path } => {
-            let (crate_name, crate_version, res) = reeves::analyze_crate_path(&crate_path);
-            let res = match res {
-                Ok(fndetails) => Either::Left(fndetails),
-                Err(e) => Either::Right(format!("{:?}", e)),
-            };
-            let res = AnalyzeAndPrintOutput { crate_name, crate_version, res };
+            let res: Vec<_> = reeves::analyze_crate_path(&crate_path)?.into_iter()
+                .map(|(crate_name, crate_version, res)| {
+                    let res = match res {
+                        Ok(fndetails) => Either::Left(fndetails),
+                        Err(e) => Either::Right(e.to_string()),
+                    };
+                    AnalyzeAndPrintOutput { crate_name, crate_version, res }
+                })
+                .collect();
             let out = serde_json::to_vec(&res).unwrap();
             io::stdout().write_all(&out).unwrap();
         },
 
         ReevesCmd::ContainerAnalyzeAndPrint { crate_path } => {
-            let res: AnalyzeAndPrintOutput = container_analyze_crate_path(&crate_path)
+            let res: Vec<AnalyzeAndPrintOutput> = container_analyze_crate_path(&sandbox, &crate_path)
                 .with_context(|| format!("failed to analyze path {} in a container", crate_path.display()))?;
             let out = serde_json::to_vec(&res).unwrap();
             io::stdout().write_all(&out).unwrap();
         },
 
-        ReevesCmd::AnalyzeTop100Crates => {
-            let panamax_mirror_path = &opt.panamax_mirror;
+        ReevesCmd::AnalyzeCrate { name, version } => {
+            let crate_source = &crate_source;
+            let version = match version {
+                Some(version) => version,
+                None => resolve_latest_version(crate_source, &name)
+                    .with_context(|| format!("failed to find latest version of {}", name))?,
+            };
+            let db = reeves::open_db(&db_path)?;
+
+            info!("analyzing crate {}-{}", name, version);
+            let res = container_analyze_registry_crate(&sandbox, crate_source, &name, &version);
+            save_container_analysis(&db, res, &name, &version)
+                .with_context(|| format!("failed to analyze {}-{}", name, version))?;
+        },
 
-            #[derive(Deserialize)]
-            struct PlayCrates {
-                crates: Vec<PlayCrate>,
+        ReevesCmd::AnalyzeGit { url, rev } => {
+            let db = reeves::open_db(&db_path)?;
+            let packages = container_analyze_git(&sandbox, &url, rev.as_deref())
+                .with_context(|| format!("failed to analyze {}", url))?;
+            for (name, version, res) in packages {
+                // Keep the package name in fndetails for paths, but the git identity for everything keyed on the crate
+                let res = res.map_left(|fndetails| {
+                    fndetails.into_iter().map(|fndetail| FnDetail { krate: name.clone(), ..fndetail }).collect()
+                });
+                save_container_analysis(&db, Ok(res), &name, &version)
+                    .with_context(|| format!("failed to save analysis of {}", name))?;
             }
-            #[derive(Deserialize)]
-            struct PlayCrate {
-                name: String,
-                version: String,
-                #[allow(unused)]
-                id: String, // the alias play uses
+        },
+
+        ReevesCmd::AnalyzeStd { toolchain } => {
+            let rustc = |args: &[&str]| -> Result<String> {
+                let res = Command::new("rustup").args(&["run", &toolchain, "rustc"]).args(args).output()
+                    .context("failed to run rustup")?;
+                if !res.status.success() {
+                    bail!("rustc {} failed for toolchain {}: {}", args.join(" "), toolchain, String::from_utf8_lossy(&res.stderr))
+                }
+                Ok(String::from_utf8(res.stdout).unwrap().trim().to_owned())
+            };
+            // e.g. rustc 1.55.0 (c8dfcfe04 2021-09-06)
+            let version = rustc(&["--version"])?.split_whitespace().nth(1).unwrap().to_owned();
+            let sysroot = PathBuf::from(rustc(&["--print", "sysroot"])?);
+            if !sysroot.join("lib/rustlib/src/rust/library").is_dir() {
+                bail!("no standard library sources in {}, install them with: rustup component add rust-src --toolchain {}",
+                      sysroot.display(), toolchain)
             }
-            let mut res = isahc::get("https://play.rust-lang.org/meta/crates").unwrap();
-            let crates: PlayCrates = res.json().unwrap();
 
-            let db = reeves::open_db(&opt.db);
+            // Rust analyzer only loads the sysroot as part of a project, so give it an empty one to load
+            let project_dir = TempWorkDir::new(&sandbox.work_dir, "std");
+            let project_path = project_dir.path();
+            fs::create_dir_all(project_path.join("src")).unwrap();
+            fs::write(project_path.join("Cargo.toml"), "[package]\nname = \"reeves-std\"\nversion = \"0.0.0\"\n").unwrap();
+            fs::write(project_path.join("src/lib.rs"), "").unwrap();
+            // Picked up by the rustup proxies rust analyzer invokes
+            env::set_var("RUSTUP_TOOLCHAIN", &toolchain);
 
-            info!("considering {} crates", crates.crates.len());
-            cli_container_parallel_process_crates(&db, panamax_mirror_path, &mut crates.crates.into_iter().map(|krate| (krate.name, krate.version)));
+            info!("analyzing standard library of toolchain {} (rustc {})", toolchain, version);
+            let krates = reeves::analyze_sysroot(project_path, &version)?;
+            drop(project_dir);
+            if krates.is_empty() {
+                bail!("didn't find any standard library crates in the sysroot")
+            }
+
+            let db = reeves::open_db(&db_path)?;
+            for (name, fndetails) in krates {
+                // Versioned names, so multiple toolchains can be indexed side by side
+                let name = format!("{}@{}", name, version);
+                info!("inserting {} function details for {} into db", fndetails.len(), name);
+                let fndetails = fndetails.into_iter().map(|fndetail| FnDetail { krate: name.clone(), ..fndetail }).collect();
+                reeves::save_analysis(&db, &name, &version, fndetails)?;
+            }
         }
 
-        ReevesCmd::AnalyzeAllCrates => {
-            let panamax_mirror_path = &opt.panamax_mirror;
+        ReevesCmd::AnalyzeTopNCrates { count, jobs, dry_run, skip_list, only_list, report, max_failure_rate } => {
+            let crate_source = &crate_source;
+            let filter = CrateFilter::load(skip_list.as_deref(), only_list.as_deref())?;
 
-            let db = reeves::open_db(&opt.db);
+            let db = reeves::open_db(&db_path)?;
 
-            let index = crates_index::Index::new(panamax_mirror_path.join("crates.io-index"));
-            assert!(index.exists());
+            // Filtering happens after ranking, so skipped crates make room for the next most popular rather than
+            // reducing the count
+            let crates: Vec<_> = if reeves::popularity::has_popularity(&db)? {
+                top_crates_by_popularity(&db, count, &filter)?
+            } else {
+                info!("no crate popularity in the db, asking crates.io for the most downloaded crates (run ingest-popularity to avoid this)");
+                top_crates_by_downloads(count, &filter)?
+            };
 
-            // TODO: exclude yanked versions?
-            info!("identifying crates to analyze");
-            let crates: Vec<_> = index.crates().map(|c| (c.name().to_owned(), c.highest_version().version().to_owned())).collect();
+            let num_ranked = crates.len();
+            let crates = unindexed_crates(&db, crates)?;
+            info!("{} of the top {} crates are already in the db", num_ranked - crates.len(), num_ranked);
+            if dry_run {
+                print_dry_run(&crates, jobs.or(default_jobs_setting));
+                return Ok(())
+            }
 
-            info!("looking at {} crates to filter those already in db", crates.len());
-            let crates: Vec<_> = crates.into_iter().filter(|(name, version)| !reeves::has_crate(&db, name, version)).collect();
+            info!("considering {} crates", crates.len());
+            let run_report = cli_container_parallel_process_crates(&db, &sandbox, crate_source, jobs.or(default_jobs_setting), false, report.as_deref(), &mut crates.into_iter());
+            finish_run_report(&run_report, report.as_deref(), max_failure_rate)?;
+        }
+
+        ReevesCmd::AnalyzeAllCrates { jobs, resume, dry_run, skip_list, only_list, report, max_failure_rate } => {
+            let crate_source = &crate_source;
+            let filter = CrateFilter::load(skip_list.as_deref(), only_list.as_deref())?;
+
+            let db = reeves::open_db(&db_path)?;
+
+            let crates = all_crates_run(&db, crate_source, &filter, resume, !dry_run)?;
+            if dry_run {
+                print_dry_run(&crates, jobs.or(default_jobs_setting));
+                return Ok(())
+            }
 
             info!("considering {} crates", crates.len());
-            cli_container_parallel_process_crates(&db, panamax_mirror_path, &mut crates.into_iter());
+            let run_report = cli_container_parallel_process_crates(&db, &sandbox, crate_source, jobs.or(default_jobs_setting), true, report.as_deref(), &mut crates.into_iter());
+            finish_run_report(&run_report, report.as_deref(), max_failure_rate)?;
+        }
+
+        ReevesCmd::Watch { index_path, interval, jobs, skip_list, only_list } => {
+            let crate_source = &crate_source;
+            let filter = CrateFilter::load(skip_list.as_deref(), only_list.as_deref())?;
+
+            let db = reeves::open_db(&db_path)?;
+            let index = crates_index::Index::new(&index_path);
+
+            loop {
+                info!("updating index at {}", index_path.display());
+                // A failed update is likely a network blip, so just work with what we have and try again next time
+                if let Err(e) = index.retrieve_or_update() {
+                    warn!("failed to update index: {}", e)
+                }
+
+                if index.exists() {
+                    let (crates, yanked) = partition_yanked(index.crates().map(|c| (c.name().to_owned(), highest_unyanked_version(&c))));
+                    if let Err(e) = remove_yanked_crates(&db, &yanked) {
+                        warn!("failed to remove yanked crates: {:?}", e)
+                    }
+                    let crates = unindexed_crates(&db, crates.into_iter().filter(|(name, version)| filter.allows(name, version)))?;
+                    if crates.is_empty() {
+                        info!("no new crate versions")
+                    } else {
+                        info!("found {} new crate versions", crates.len());
+                        let names: Vec<_> = crates.iter().map(|(name, _)| name.clone()).collect();
+                        cli_container_parallel_process_crates(&db, &sandbox, crate_source, jobs.or(default_jobs_setting), false, None, &mut crates.into_iter());
+                        info!("updating text search");
+                        // The crates are in the db regardless, so an incremental load can catch up later
+                        if let Err(e) = reeves::sync_text_search(&db, &names) {
+                            warn!("failed to update text search, run load-text-search --incremental to catch up: {}", e)
+                        }
+                    }
+                }
+
+                info!("sleeping for {}s", interval);
+                thread::sleep(Duration::from_secs(interval));
+            }
+        }
+
+        ReevesCmd::Coordinate { ip, port, token, lease, resume, skip_list, only_list } => {
+            let filter = CrateFilter::load(skip_list.as_deref(), only_list.as_deref())?;
+            let db = reeves::open_db(&db_path)?;
+            let crates = all_crates_run(&db, &crate_source, &filter, resume, true)?;
+            info!("coordinating {} crates", crates.len());
+            coordinator::coordinate(db, crates, coordinator::CoordinatorConfig {
+                addr: format!("{}:{}", ip, port),
+                token,
+                lease: Duration::from_secs(lease),
+                grace: coordinator::FINISH_GRACE,
+            })
+        }
+
+        ReevesCmd::Worker { coordinator, token, jobs, push_to, push_token } => {
+            let push_to = push_to.map(|url| Arc::new(coordinator::PushTarget { url, token: push_token.unwrap() }));
+            let jobs = jobs.or(default_jobs_setting).unwrap_or_else(default_jobs);
+            let prep_jobs = sandbox.prep_jobs.unwrap_or(jobs);
+            info!("working for {} with {} jobs and {} prep jobs", coordinator, jobs, prep_jobs);
+            let sandbox = SandboxConfig { stages: Some(Arc::new(AnalysisStages::new(prep_jobs, jobs))), ..sandbox };
+            // Enough for every stage to be busy
+            let threads: Vec<_> = (0..jobs + prep_jobs)
+                .map(|_| {
+                    let sandbox = sandbox.clone();
+                    let crate_source = crate_source.clone();
+                    let coordinator = coordinator.clone();
+                    let token = token.clone();
+                    let push_to = push_to.clone();
+                    thread::spawn(move || coordinator::work(&sandbox, &crate_source, &coordinator, &token, push_to.as_deref()))
+                })
+                .collect();
+            for thread in threads {
+                thread.join().unwrap()
+            }
+            info!("no jobs left, exiting")
         }
 
-        ReevesCmd::LoadTextSearch => {
-            let db = reeves::open_db(&opt.db);
-            reeves::load_text_search(&db)
+        ReevesCmd::RetryErrors { matching, jobs, report, max_failure_rate } => {
+            let crate_source = &crate_source;
+
+            let db = reeves::open_db(&db_path)?;
+
+            let errors = reeves::crate_errors(&db)?;
+            info!("found {} crates with errors", errors.len());
+            let crates: Vec<_> = errors.into_iter()
+                .filter(|(_, _, err)| matching.as_ref().map_or(true, |matching| err.contains(matching.as_str())))
+                .filter(|(name, _, _)| {
+                    // Not in the registry, so need re-analyzing with analyze-git
+                    let is_git = name.starts_with("git+");
+                    if is_git { warn!("skipping git crate {}", name) }
+                    !is_git
+                })
+                .map(|(name, version, _)| (name, version))
+                .collect();
+
+            info!("retrying {} crates", crates.len());
+            let run_report = cli_container_parallel_process_crates(&db, &sandbox, crate_source, jobs.or(default_jobs_setting), false, report.as_deref(), &mut crates.into_iter());
+            finish_run_report(&run_report, report.as_deref(), max_failure_rate)?;
+        },
+
+        ReevesCmd::IngestPopularity { dump } => {
+            let dump = match dump {
+                Some(dump) => dump,
+                None => reeves::popularity::fetch_db_dump(&crate_cache, CRATES_IO_USER_AGENT)?,
+            };
+            let db = reeves::open_db(&db_path)?;
+            let num_crates = reeves::popularity::ingest_db_dump(&db, &dump)
+                .with_context(|| format!("failed to ingest database dump {}", dump.display()))?;
+            info!("loaded popularity of {} crates", num_crates);
         },
 
-        ReevesCmd::Search { params_search, ret_search } => {
+        ReevesCmd::LoadTextSearch { incremental } => {
+            let db = reeves::open_db(&db_path)?;
+            if incremental {
+                reeves::load_text_search_incremental(&db)?
+            } else {
+                reeves::load_text_search(&db)?
+            }
+        },
+
+        ReevesCmd::Search { params_search, ret_search, format } => {
             let params_search: Vec<_> = if params_search.is_empty() {
                 vec![]
             } else {
@@ -209,22 +950,150 @@ fn main() -> Result<()> {
             } else {
                 Some(ret_search.to_owned())
             };
-            let db = reeves::open_db(&opt.db);
-            let fndetails = reeves::search(&db, Some(params_search), ret_search);
-            for fndetail in fndetails {
-                println!("res: {}", fndetail.s)
-            }
+            let db = reeves::open_db(&db_path)?;
+            let fndetails = reeves::search(&db, Some(params_search), ret_search)
+                .context("search failed")?;
+            print_search_results(&fndetails, format);
         }
 
-        ReevesCmd::Serve { ip, port, static_tar } => {
-            let db = reeves::open_db(&opt.db);
-            let addr = format!("{}:{}", ip, port);
-            server::serve(db, addr, static_tar)
+        ReevesCmd::Repl { format, history } => {
+            let db = reeves::open_db(&db_path)?;
+            let history = history.or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".reeves_history")));
+            repl(&db, format, history.as_deref())?;
         },
 
+        ReevesCmd::Serve { ip, port, uds, static_path, admin_token, analysis_webhook, index_on_demand, query_log, preload,
+                          text_search_sync_interval, gc_interval, flush_interval, maintenance_idle } => {
+            let db = reeves::open_db(&db_path)?;
+            if preload {
+                let start = Instant::now();
+                let stats = reeves::preload(&db)?;
+                info!("preloaded {} fns and {} hot types ({} MB) in {:.1}s",
+                      stats.num_fns, stats.num_hot_types, stats.bytes / 1024 / 1024, start.elapsed().as_secs_f64());
+            }
+            let listen = match (uds, port) {
+                (Some(path), _) => server::Listen::Uds(path),
+                (None, Some(port)) => server::Listen::Tcp(format!("{}:{}", ip, port)),
+                (None, None) => unreachable!("port is required without uds"),
+            };
+            let admin = admin_token.map(|token| server::AdminConfig {
+                token,
+                sandbox: sandbox.clone(),
+                crate_source: crate_source.clone(),
+                webhook: analysis_webhook,
+                index_on_demand,
+            });
+            let maintenance = if text_search_sync_interval.is_some() || gc_interval.is_some() || flush_interval.is_some() {
+                Some(server::MaintenanceConfig {
+                    text_search_sync_interval: text_search_sync_interval.map(Duration::from_secs),
+                    gc_interval: gc_interval.map(Duration::from_secs),
+                    flush_interval: flush_interval.map(Duration::from_secs),
+                    idle: Duration::from_secs(maintenance_idle),
+                })
+            } else {
+                None
+            };
+            server::serve(db, server::ServerConfig { listen, static_path, admin, query_log, maintenance })
+        },
+
+        ReevesCmd::ListCrates { errored } => {
+            let db = reeves::open_db(&db_path)?;
+            if errored {
+                for (name, version, err) in reeves::crate_errors(&db)? {
+                    // Errors can be long and multiline, the first line is usually enough to tell what went wrong
+                    println!("{} {} {}", name, version, err.lines().next().unwrap_or(""))
+                }
+            } else {
+                for (name, version, num_fns) in reeves::list_crates(&db)? {
+                    println!("{} {} {}", name, version, num_fns)
+                }
+            }
+        }
+
+        ReevesCmd::Stats => {
+            let db = reeves::open_db(&db_path)?;
+            let stats = reeves::stats(&db)?;
+            println!("crates: {}", stats.num_crates);
+            println!("errored crates: {}", stats.num_errored_crates);
+            println!("functions: {}", stats.num_fns);
+            match stats.last_updated_ms {
+                Some(ms) => println!("last updated: {}ms since the unix epoch", ms),
+                None => println!("last updated: unknown"),
+            }
+        }
+
+        ReevesCmd::VerifyDb => {
+            let db = reeves::open_db(&db_path)?;
+            let problems = reeves::verify_db(&db)?;
+            for problem in problems.iter() {
+                println!("{}", problem)
+            }
+            if !problems.is_empty() {
+                bail!("found {} problems in the db", problems.len())
+            }
+            info!("no problems found")
+        }
+
+        ReevesCmd::Export { output } => {
+            let db = reeves::open_db(&db_path)?;
+            let mut out = io::BufWriter::new(fs::File::create(&output)
+                .with_context(|| format!("failed to create {}", output.display()))?);
+            let num = reeves::export_jsonl(&db, &mut out)?;
+            info!("exported {} records to {}", num, output.display())
+        }
+
+        ReevesCmd::ExportTypeGraph { output, format, krate, from } => {
+            let db = reeves::open_db(&db_path)?;
+            let mut graph = reeves::type_graph(&db, krate.as_deref())?;
+            if let Some(from) = from {
+                graph = graph.reachable_from(&from);
+            }
+            let mut out: Box<dyn Write> = match &output {
+                Some(output) => Box::new(io::BufWriter::new(fs::File::create(output)
+                    .with_context(|| format!("failed to create {}", output.display()))?)),
+                None => Box::new(io::stdout()),
+            };
+            match format {
+                GraphFormat::Dot => graph.write_dot(&mut out)?,
+                GraphFormat::Json => {
+                    serde_json::to_writer(&mut out, &graph)?;
+                    out.flush()?
+                },
+            }
+            info!("exported {} types and {} functions between them", graph.nodes.len(), graph.edges.len())
+        }
+
+        ReevesCmd::Import { input } => {
+            let db = reeves::open_db(&db_path)?;
+            let mut input_reader = io::BufReader::new(fs::File::open(&input)
+                .with_context(|| format!("failed to open {}", input.display()))?);
+            let num = reeves::import_jsonl(&db, &mut input_reader)?;
+            info!("imported {} records from {}, run load-text-search to make them searchable", num, input.display())
+        }
+
+        ReevesCmd::Snapshot(SnapshotCmd::Publish { path }) => {
+            let db = reeves::open_db(&db_path)?;
+            let num = reeves::publish_snapshot(&db, &path)?;
+            info!("published snapshot of {} records to {}", num, path.display())
+        }
+
+        ReevesCmd::Snapshot(SnapshotCmd::Restore { path }) => {
+            let db = reeves::open_db(&db_path)?;
+            let num = reeves::restore_snapshot(&db, &path)?;
+            info!("restored {} records from {}, run load-text-search to make them searchable", num, path.display())
+        }
+
+        ReevesCmd::Schema { output } => {
+            let schema = serde_json::to_string_pretty(&proto::schema()).unwrap();
+            match output {
+                Some(output) => fs::write(&output, schema).with_context(|| format!("failed to write {}", output.display()))?,
+                None => println!("{}", schema),
+            }
+        }
+
         ReevesCmd::DebugDB => {
-            let db = reeves::open_db(&opt.db);
-            reeves::debugdb(&db)
+            let db = reeves::open_db(&db_path)?;
+            reeves::debugdb(&db)?
         }
 
     }
@@ -239,85 +1108,817 @@ struct CratesProgressCounter {
     total: usize,
 }
 
-fn cli_container_parallel_process_crates(db: &sled::Db, panamax_mirror_path: &Path, crates: &mut dyn ExactSizeIterator<Item=(String, String)>) {
-    let count = Mutex::new(CratesProgressCounter { errored: 0, processed: 0, total: crates.len() });
-    let pool = ThreadPool::new().unwrap();
+// Analyzing a large crate with rust-analyzer can use a few GB, so more jobs than this would risk the OOM killer
+const ANALYSIS_MEMORY_ESTIMATE_KB: u64 = 4 * 1024 * 1024;
+
+fn default_jobs() -> usize {
+    let cpus = num_cpus::get();
+    let mem_available_kb = fs::read_to_string("/proc/meminfo").ok().and_then(|meminfo| {
+        meminfo.lines()
+            .find(|line| line.starts_with("MemAvailable:"))
+            .and_then(|line| line.split_whitespace().nth(1))
+            .and_then(|kb| kb.parse::<u64>().ok())
+    });
+    match mem_available_kb {
+        Some(kb) => {
+            let mem_jobs = cmp::max(1, (kb / ANALYSIS_MEMORY_ESTIMATE_KB) as usize);
+            if mem_jobs < cpus {
+                info!("limiting parallelism to {} jobs (of {} cpus) because of available memory", mem_jobs, cpus);
+            }
+            cmp::min(cpus, mem_jobs)
+        },
+        None => {
+            warn!("couldn't find available memory, using one job per cpu");
+            cpus
+        },
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+enum LogFormat {
+    Text,
+    Json,
+}
+
+impl std::str::FromStr for LogFormat {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, String> {
+        Ok(match s {
+            "text" => LogFormat::Text,
+            "json" => LogFormat::Json,
+            _ => return Err(format!("unknown log format {}, expected text or json", s)),
+        })
+    }
+}
+
+// Levels are still controlled by RUST_LOG
+fn init_logging(format: LogFormat) {
+    let mut builder = env_logger::Builder::from_default_env();
+    if let LogFormat::Json = format {
+        builder.format(|buf, record| {
+            let line = serde_json::json!({
+                "ts": buf.timestamp().to_string(),
+                "level": record.level().to_string(),
+                "target": record.target(),
+                "msg": record.args().to_string(),
+            });
+            writeln!(buf, "{}", line)
+        });
+    }
+    builder.init()
+}
+
+#[derive(Clone, Copy, Debug)]
+enum SearchFormat {
+    Text,
+    Json,
+    Table,
+}
+
+impl std::str::FromStr for SearchFormat {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, String> {
+        Ok(match s {
+            "text" => SearchFormat::Text,
+            "json" => SearchFormat::Json,
+            "table" => SearchFormat::Table,
+            _ => return Err(format!("unknown format {}, expected text, json or table", s)),
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+enum GraphFormat {
+    Dot,
+    Json,
+}
+
+impl std::str::FromStr for GraphFormat {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, String> {
+        Ok(match s {
+            "dot" => GraphFormat::Dot,
+            "json" => GraphFormat::Json,
+            _ => return Err(format!("unknown format {}, expected dot or json", s)),
+        })
+    }
+}
+
+const REPL_HELP: &str = "\
+Enter a signature to search for, e.g. `fn(&str, usize) -> Option<char>`. Use `*` for any params or return type, or
+leave out `-> ...` to match any return type.
+Commands:
+    :format text|json|table    change how results are shown
+    :help                      show this help
+    :quit                      exit (as does ctrl-d)";
+
+fn repl(db: &sled::Db, mut format: SearchFormat, history: Option<&Path>) -> Result<()> {
+    let mut editor = rustyline::Editor::<()>::new();
+    if let Some(history) = history {
+        // Missing on first use
+        let _ = editor.load_history(history);
+    }
+    println!("{}", REPL_HELP);
+    loop {
+        let line = match editor.readline("reeves> ") {
+            Ok(line) => line,
+            // Abandon the current line, like a shell
+            Err(rustyline::error::ReadlineError::Interrupted) => continue,
+            Err(rustyline::error::ReadlineError::Eof) => break,
+            Err(e) => return Err(e).context("failed to read line"),
+        };
+        let line = line.trim();
+        if line.is_empty() {
+            continue
+        }
+        editor.add_history_entry(line);
+
+        if let Some(cmd) = line.strip_prefix(':') {
+            let mut args = cmd.split_whitespace();
+            match (args.next(), args.next()) {
+                (Some("format"), Some(new_format)) => match new_format.parse() {
+                    Ok(new_format) => format = new_format,
+                    Err(e) => println!("{}", e),
+                },
+                (Some("help"), None) => println!("{}", REPL_HELP),
+                (Some("quit"), None) | (Some("q"), None) => break,
+                _ => println!("unknown command, try :help"),
+            }
+            continue
+        }
+
+        let sig = match query::parse_signature(line) {
+            Ok(sig) => sig,
+            Err(e) => {
+                println!("invalid signature: {}", e);
+                continue
+            },
+        };
+        let start = Instant::now();
+        match reeves::search(db, sig.params, sig.ret) {
+            Ok(fndetails) => {
+                print_search_results(&fndetails, format);
+                println!("{} results in {:.2}s", fndetails.len(), start.elapsed().as_secs_f64());
+            },
+            Err(e) => println!("search failed: {}", e),
+        }
+    }
+    if let Some(history) = history {
+        if let Err(e) = editor.save_history(history) {
+            warn!("failed to save history to {}: {}", history.display(), e)
+        }
+    }
+    Ok(())
+}
+
+// Search results are in relevance order, so the rank is the best score we have
+fn print_search_results(fndetails: &[FnDetail], format: SearchFormat) {
+    match format {
+        SearchFormat::Text => {
+            for fndetail in fndetails {
+                println!("res: {}", fndetail.s)
+            }
+        },
+        SearchFormat::Json => {
+            #[derive(Serialize)]
+            struct SearchResultOutput<'a> {
+                rank: usize,
+                #[serde(flatten)]
+                fndetail: &'a FnDetail,
+            }
+            let out: Vec<_> = fndetails.iter().enumerate()
+                .map(|(i, fndetail)| SearchResultOutput { rank: i + 1, fndetail })
+                .collect();
+            serde_json::to_writer_pretty(io::stdout(), &out).unwrap();
+            println!();
+        },
+        SearchFormat::Table => {
+            let header = ["RANK", "CRATE", "VERSION", "PATH", "SIGNATURE"];
+            let rows: Vec<[String; 5]> = fndetails.iter().enumerate()
+                .map(|(i, fndetail)| [(i + 1).to_string(), fndetail.krate.clone(), fndetail.version.clone(), fndetail.path.clone(), fndetail.short_signature()])
+                .collect();
+            // The signature is last, so isn't padded
+            let mut widths = [0; 4];
+            for (i, width) in widths.iter_mut().enumerate() {
+                *width = rows.iter().map(|row| row[i].len()).chain(Some(header[i].len())).max().unwrap();
+            }
+            let print_row = |row: [&str; 5]| {
+                println!("{:<w0$}  {:<w1$}  {:<w2$}  {:<w3$}  {}", row[0], row[1], row[2], row[3], row[4],
+                         w0 = widths[0], w1 = widths[1], w2 = widths[2], w3 = widths[3]);
+            };
+            print_row(header);
+            for row in rows.iter() {
+                print_row([&row[0], &row[1], &row[2], &row[3], &row[4]]);
+            }
+        },
+    }
+}
+
+// Lines are crate or crate@version, blank lines and lines starting with # are ignored
+struct CrateList(Vec<(String, Option<String>)>);
+
+impl CrateList {
+    fn load(path: &Path) -> Result<CrateList> {
+        let content = fs::read_to_string(path).with_context(|| format!("failed to read crate list {}", path.display()))?;
+        let entries = content.lines()
+            .map(|line| line.trim())
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(|line| match line.find('@') {
+                Some(i) => (line[..i].to_owned(), Some(line[i+1..].to_owned())),
+                None => (line.to_owned(), None),
+            })
+            .collect();
+        Ok(CrateList(entries))
+    }
+
+    fn contains(&self, name: &str, version: &str) -> bool {
+        self.0.iter().any(|(n, v)| n == name && v.as_ref().map_or(true, |v| v == version))
+    }
+}
+
+struct CrateFilter {
+    skip: Option<CrateList>,
+    only: Option<CrateList>,
+}
+
+impl CrateFilter {
+    fn load(skip_list: Option<&Path>, only_list: Option<&Path>) -> Result<CrateFilter> {
+        Ok(CrateFilter {
+            skip: skip_list.map(CrateList::load).transpose()?,
+            only: only_list.map(CrateList::load).transpose()?,
+        })
+    }
+
+    fn allows(&self, name: &str, version: &str) -> bool {
+        !self.skip.as_ref().map_or(false, |l| l.contains(name, version)) &&
+            self.only.as_ref().map_or(true, |l| l.contains(name, version))
+    }
+}
+
+// The crates for an all-crates run, recording them as a new run (if asked) unless resuming the previous one
+fn all_crates_run(db: &sled::Db, crate_source: &CrateSource, filter: &CrateFilter, resume: bool, record: bool) -> Result<Vec<(String, String)>> {
+    let crates: Vec<_> = if resume {
+        let run = reeves::analysis_run(db)?;
+        if run.is_empty() {
+            bail!("no previous run to resume")
+        }
+        let num_done = run.iter().filter(|(_, _, status)| *status == reeves::RunStatus::Done).count();
+        let num_errored = run.iter().filter(|(_, _, status)| *status == reeves::RunStatus::Errored).count();
+        info!("resuming run: {} done, {} errored", num_done, num_errored);
+        run.into_iter()
+            .filter(|(_, _, status)| *status == reeves::RunStatus::Pending)
+            .map(|(name, version, _)| (name, version))
+            .filter(|(name, version)| filter.allows(name, version))
+            .collect()
+    } else {
+        info!("identifying crates to analyze");
+        let (crates, yanked) = partition_yanked(crate_source.crates()?.into_iter());
+        if record {
+            remove_yanked_crates(db, &yanked)?;
+        }
+        let num_in_index = crates.len();
+        let crates: Vec<_> = crates.into_iter().filter(|(name, version)| filter.allows(name, version)).collect();
+        info!("{} of {} crates in the index excluded by crate lists", num_in_index - crates.len(), num_in_index);
+
+        info!("looking at {} crates to filter those already in db", crates.len());
+        let num_allowed = crates.len();
+        let crates = unindexed_crates(db, crates)?;
+        info!("{} crates already in the db", num_allowed - crates.len());
+        if record {
+            reeves::start_analysis_run(db, &crates)?;
+        }
+        crates
+    };
+    Ok(crates)
+}
+
+// Split crates into those with an unyanked version to analyze and those with every version yanked
+fn partition_yanked(crates: impl Iterator<Item=(String, Option<String>)>) -> (Vec<(String, String)>, Vec<String>) {
+    let mut unyanked = vec![];
+    let mut yanked = vec![];
+    for (name, version) in crates {
+        match version {
+            Some(version) => unyanked.push((name, version)),
+            None => yanked.push(name),
+        }
+    }
+    (unyanked, yanked)
+}
+
+// Crates with a newly yanked version are re-analyzed at the next best version like any other version change, which
+// leaves those with nothing left to depend on to remove
+fn remove_yanked_crates(db: &sled::Db, yanked: &[String]) -> Result<()> {
+    for name in yanked {
+        if let Some(version) = reeves::crate_version(db, name)? {
+            info!("removing crate {}-{} as every version is yanked", name, version);
+            reeves::remove_crate(db, name)?;
+        }
+    }
+    Ok(())
+}
+
+// The crates without results in the db at the same version
+fn unindexed_crates(db: &sled::Db, crates: impl IntoIterator<Item=(String, String)>) -> Result<Vec<(String, String)>> {
+    let mut unindexed = vec![];
+    for (name, version) in crates {
+        if !reeves::has_crate(db, &name, &version)? {
+            unindexed.push((name, version))
+        }
+    }
+    Ok(unindexed)
+}
+
+fn print_dry_run(crates: &[(String, String)], jobs: Option<usize>) {
+    for (name, version) in crates {
+        println!("{}@{}", name, version)
+    }
+    let jobs = jobs.unwrap_or_else(default_jobs);
+    info!("would analyze {} crates with {} jobs, about {} crates per job", crates.len(), jobs, (crates.len() + jobs - 1) / jobs);
+}
+
+// Exit code when a bulk run's failure rate is over --max-failure-rate, to tell it apart from reeves itself failing
+const FAILURE_RATE_EXIT_CODE: i32 = 2;
+
+// While a run is going the report is rewritten every WATCHDOG_INTERVAL with what's being analyzed, so it can be
+// watched, and finished is set once it's done
+#[derive(Serialize)]
+struct RunReport {
+    started_at: u64, // unix timestamp
+    duration_secs: u64,
+    finished: bool,
+    total: usize,
+    analyzed: usize,
+    // The analysis ran but reported an error, which is saved to the db
+    analysis_errors: usize,
+    // The analysis itself failed, so there was nothing to save
+    failed: usize,
+    failures: Vec<RunReportFailure>,
+    // Analyses that took longer than expected, however they turned out
+    slow: Vec<RunReportSlow>,
+    in_progress: Vec<RunReportInProgress>,
+}
+
+#[derive(Clone, Serialize)]
+struct RunReportSlow {
+    #[serde(rename = "crate")]
+    krate: String,
+    version: String,
+    duration_secs: u64,
+}
+
+#[derive(Serialize)]
+struct RunReportInProgress {
+    #[serde(rename = "crate")]
+    krate: String,
+    version: String,
+    running_secs: u64,
+    slow: bool,
+}
+
+#[derive(Clone, Serialize)]
+struct RunReportFailure {
+    #[serde(rename = "crate")]
+    krate: String,
+    version: String,
+    class: &'static str,
+    error: String,
+    duration_secs: u64,
+}
+
+impl RunReport {
+    fn failure_rate(&self) -> f64 {
+        if self.total == 0 {
+            return 0.0
+        }
+        (self.analysis_errors + self.failed) as f64 / self.total as f64
+    }
+}
+
+// Roughly what went wrong, so runs can be compared without reading every error
+fn classify_failure(res: &Result<Either<Vec<FnDetail>, String>>) -> Option<(&'static str, String)> {
+    match res {
+        Ok(Either::Left(_)) => None,
+        Ok(Either::Right(err)) if err.starts_with("Timeout:") => Some(("timeout", err.clone())),
+        Ok(Either::Right(err)) => Some(("analysis", err.clone())),
+        Err(e) => Some(("sandbox", format!("{:?}", e))),
+    }
+}
+
+// Written to a temporary file and moved into place, so whatever's watching the report never sees half of one
+fn write_run_report(report: &RunReport, report_path: &Path) -> Result<()> {
+    let mut tmp_path = report_path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    let tmp_path = PathBuf::from(tmp_path);
+    let f = fs::File::create(&tmp_path).with_context(|| format!("failed to create report {}", tmp_path.display()))?;
+    serde_json::to_writer_pretty(f, report)?;
+    fs::rename(&tmp_path, report_path).with_context(|| format!("failed to move report to {}", report_path.display()))?;
+    Ok(())
+}
+
+fn finish_run_report(report: &RunReport, report_path: Option<&Path>, max_failure_rate: Option<f64>) -> Result<()> {
+    if let Some(report_path) = report_path {
+        write_run_report(report, report_path)?;
+        info!("wrote run report to {}", report_path.display());
+    }
+    if let Some(max_failure_rate) = max_failure_rate {
+        let failure_rate = report.failure_rate();
+        if failure_rate > max_failure_rate {
+            warn!("failure rate {:.3} is over the maximum of {:.3}", failure_rate, max_failure_rate);
+            process::exit(FAILURE_RATE_EXIT_CODE)
+        }
+    }
+    Ok(())
+}
+
+// How often a running analysis is checked on, and the report (if any) rewritten
+const WATCHDOG_INTERVAL: Duration = Duration::from_secs(60);
+// How long an analysis is expected to take at most without a timeout. With one, it should have been killed a little
+// after the timeout, so one that's still going is probably stuck. Waiting for a turn at a stage counts here, so under
+// heavy contention healthy analyses may be reported too
+const SLOW_ANALYSIS: Duration = Duration::from_secs(30 * 60);
+const TIMEOUT_GRACE: Duration = Duration::from_secs(5 * 60);
+
+struct InFlightAnalysis {
+    start: Instant,
+    warned: bool,
+}
+
+// Shared between the jobs, the loop saving their results and the watchdog
+struct RunProgress {
+    started_at: u64, // unix timestamp
+    start: Instant,
+    slow_after: Duration,
+    count: Mutex<CratesProgressCounter>,
+    failures: Mutex<Vec<RunReportFailure>>,
+    slow: Mutex<Vec<RunReportSlow>>,
+    in_flight: Mutex<HashMap<(String, String), InFlightAnalysis>>,
+}
+
+impl RunProgress {
+    fn start_analysis(&self, name: &str, version: &str) {
+        let analysis = InFlightAnalysis { start: Instant::now(), warned: false };
+        self.in_flight.lock().unwrap().insert((name.to_owned(), version.to_owned()), analysis);
+    }
+
+    fn finish_analysis(&self, name: &str, version: &str) {
+        let analysis = self.in_flight.lock().unwrap().remove(&(name.to_owned(), version.to_owned()));
+        if let Some(InFlightAnalysis { start, warned }) = analysis {
+            let duration = start.elapsed();
+            if duration > self.slow_after {
+                if warned {
+                    info!("slow analysis of {}-{} finished after {}s", name, version, duration.as_secs())
+                }
+                self.slow.lock().unwrap().push(RunReportSlow { krate: name.to_owned(), version: version.to_owned(), duration_secs: duration.as_secs() })
+            }
+        }
+    }
+
+    // Warn (once each) about analyses that have gone on longer than expected
+    fn check_in_flight(&self) {
+        for ((name, version), analysis) in self.in_flight.lock().unwrap().iter_mut() {
+            let running = analysis.start.elapsed();
+            if running > self.slow_after && !analysis.warned {
+                warn!("analysis of {}-{} has been running for {}s, it may be stuck", name, version, running.as_secs());
+                analysis.warned = true
+            }
+        }
+    }
+
+    fn report(&self, finished: bool) -> RunReport {
+        let count = self.count.lock().unwrap();
+        let failures = self.failures.lock().unwrap();
+        let analysis_errors = failures.iter().filter(|failure| failure.class != "sandbox").count();
+        let mut in_progress: Vec<_> = self.in_flight.lock().unwrap().iter()
+            .map(|((name, version), analysis)| {
+                let running = analysis.start.elapsed();
+                RunReportInProgress { krate: name.clone(), version: version.clone(), running_secs: running.as_secs(), slow: running > self.slow_after }
+            })
+            .collect();
+        in_progress.sort_by(|a, b| b.running_secs.cmp(&a.running_secs));
+        RunReport {
+            started_at: self.started_at,
+            duration_secs: self.start.elapsed().as_secs(),
+            finished,
+            total: count.total,
+            // A failure is recorded just before its crate is counted
+            analyzed: count.processed.saturating_sub(analysis_errors),
+            analysis_errors,
+            failed: count.errored,
+            failures: failures.clone(),
+            slow: self.slow.lock().unwrap().clone(),
+            in_progress,
+        }
+    }
+}
+
+// Until the returned sender is dropped
+fn spawn_run_watchdog(progress: Arc<RunProgress>, report_path: Option<PathBuf>) -> mpsc::Sender<()> {
+    let (stop_tx, stop_rx) = mpsc::channel::<()>();
+    thread::spawn(move || {
+        while let Err(mpsc::RecvTimeoutError::Timeout) = stop_rx.recv_timeout(WATCHDOG_INTERVAL) {
+            progress.check_in_flight();
+            if let Some(report_path) = report_path.as_ref() {
+                if let Err(e) = write_run_report(&progress.report(false), report_path) {
+                    warn!("failed to write run report: {:?}", e)
+                }
+            }
+        }
+    });
+    stop_tx
+}
+
+// If tracking a run, each crate's status is recorded as it finishes
+fn cli_container_parallel_process_crates(db: &sled::Db, sandbox: &SandboxConfig, crate_source: &CrateSource, jobs: Option<usize>, track_run: bool, report_path: Option<&Path>, crates: &mut dyn ExactSizeIterator<Item=(String, String)>) -> RunReport {
+    let progress = Arc::new(RunProgress {
+        started_at: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs(),
+        start: Instant::now(),
+        slow_after: sandbox.timeout.map_or(SLOW_ANALYSIS, |timeout| timeout + TIMEOUT_GRACE),
+        count: Mutex::new(CratesProgressCounter { errored: 0, processed: 0, total: crates.len() }),
+        failures: Mutex::new(vec![]),
+        slow: Mutex::new(vec![]),
+        in_flight: Mutex::new(HashMap::new()),
+    });
+    let jobs = jobs.unwrap_or_else(default_jobs);
+    let prep_jobs = sandbox.prep_jobs.unwrap_or(jobs);
+    info!("analyzing with {} jobs and {} prep jobs", jobs, prep_jobs);
+    let sandbox = &SandboxConfig { stages: Some(Arc::new(AnalysisStages::new(prep_jobs, jobs))), ..sandbox.clone() };
+    // Enough for every stage to be busy
+    let pool = ThreadPool::builder().pool_size(jobs + prep_jobs).create().unwrap();
+    let watchdog = spawn_run_watchdog(progress.clone(), report_path.map(Path::to_owned));
     // TODO: stop iteration on panic or report somehow?
     let mut futs: FuturesUnordered<_> = crates.into_iter()
         .map(|(name, version)| {
-            let panamax_mirror_path = panamax_mirror_path.to_owned();
+            let sandbox = sandbox.clone();
+            let crate_source = crate_source.clone();
+            let progress = progress.clone();
             pool.spawn_with_handle(futures::future::lazy(move |_| {
                 info!("analyzing crate {}-{}", name, version);
-                let res = container_analyze_crate(&panamax_mirror_path, &name, &version);
-                ((name, version), res)
+                let crate_start = Instant::now();
+                progress.start_analysis(&name, &version);
+                let res = container_analyze_crate(&sandbox, &crate_source, &name, &version);
+                progress.finish_analysis(&name, &version);
+                ((name, version), res, crate_start.elapsed())
             })).unwrap()
         })
         .collect();
     futures::executor::block_on(async {
-        while let Some(((name, version), res)) = futs.next().await {
-            cli_finish_and_save_analysis(&db, res, &name, &version, &count)
+        while let Some(((name, version), res, duration)) = futs.next().await {
+            if let Some((class, error)) = classify_failure(&res) {
+                progress.failures.lock().unwrap()
+                    .push(RunReportFailure { krate: name.clone(), version: version.clone(), class, error, duration_secs: duration.as_secs() })
+            }
+            let status = cli_finish_and_save_analysis(&db, res, &name, &version, &progress.count);
+            if track_run {
+                // At worst the crate is analysed again on resume
+                if let Err(e) = reeves::set_analysis_run_status(&db, &name, &version, status) {
+                    warn!("failed to record run status of {}-{}: {}", name, version, e)
+                }
+            }
         }
     });
-    info!("finished: {:?}", count);
+    drop(watchdog);
+    info!("finished: {:?}", progress.count.lock().unwrap());
+    progress.report(true)
 }
 
-fn cli_finish_and_save_analysis(db: &sled::Db, res: Result<Either<Vec<FnDetail>, String>>, name: &str, version: &str, count: &Mutex<CratesProgressCounter>) {
+fn cli_finish_and_save_analysis(db: &sled::Db, res: Result<Either<Vec<FnDetail>, String>>, name: &str, version: &str, count: &Mutex<CratesProgressCounter>) -> reeves::RunStatus {
     info!("analyzing crate {}-{}", name, version);
-    match res {
-        Ok(Either::Left(fndetails)) => {
+    if let Err(e) = save_container_analysis(db, res, name, version) {
+        warn!("failed to analyze {}-{}: {:?}", name, version, e);
+        {
+            let mut count = count.lock().unwrap();
+            count.errored += 1;
+        }
+        return reeves::RunStatus::Errored
+    }
+    {
+        let mut count = count.lock().unwrap();
+        count.processed += 1;
+        info!("progress: {} processed, {} errored, {} remaining",
+              count.processed, count.errored, count.total - (count.processed + count.errored));
+    }
+    reeves::RunStatus::Done
+}
+
+// Save the result of a container analysis, either the fndetails or the error the analysis reported. Fails if the
+// container analysis itself failed (in which case there's nothing to save)
+fn save_container_analysis(db: &sled::Db, res: Result<Either<Vec<FnDetail>, String>>, name: &str, version: &str) -> Result<()> {
+    match res? {
+        Either::Left(fndetails) => {
             info!("finished analysing functions for {} {}, inserting {} function details into db",
                   name, version, fndetails.len());
-            reeves::save_analysis(db, &name, &version, fndetails);
+            reeves::save_analysis(db, name, version, fndetails)?;
         },
-        Ok(Either::Right(err)) => {
+        Either::Right(err) => {
             warn!("analysis reported error for {} {}, saving to db", name, version);
-            reeves::save_analysis_error(db, &name, &version, &err);
+            reeves::save_analysis_error(db, name, version, &err)?;
         },
-        Err(e) => {
-            warn!("failed to analyze {}-{}: {:?}", name, version, e);
-            {
-                let mut count = count.lock().unwrap();
-                count.errored += 1;
-            }
-            return
-        }
     };
     info!("finished inserting into db for {} {}", name, version);
-    {
-        let mut count = count.lock().unwrap();
-        count.processed += 1;
-        info!("progress: {} processed, {} errored, {} remaining",
-              count.processed, count.errored, count.total - (count.processed + count.errored));
+    Ok(())
+}
+
+// Prefer the panamax mirror, falling back to the crates.io API
+fn resolve_latest_version(crate_source: &CrateSource, crate_name: &str) -> Result<String> {
+    let panamax_mirror_path = match crate_source {
+        CrateSource::Panamax(path) => path,
+        CrateSource::Sparse(index) => match index.krate(crate_name)? {
+            Some(krate) => match highest_unyanked_version(&krate) {
+                Some(version) => return Ok(version),
+                None => bail!("every version of crate {} is yanked", crate_name),
+            },
+            None => bail!("crate {} not in sparse index", crate_name),
+        },
+    };
+    let index = crates_index::Index::new(panamax_mirror_path.join("crates.io-index"));
+    if index.exists() {
+        if let Some(krate) = index.crate_(crate_name) {
+            return highest_unyanked_version(&krate).ok_or_else(|| anyhow!("every version of crate {} is yanked", crate_name))
+        }
+        info!("crate {} not in panamax mirror index, asking crates.io", crate_name);
     }
+
+    #[derive(Deserialize)]
+    struct CratesIoCrate {
+        #[serde(rename = "crate")]
+        krate: CratesIoCrateInfo,
+    }
+    #[derive(Deserialize)]
+    struct CratesIoCrateInfo {
+        max_version: String,
+    }
+    let mut res = isahc::Request::get(format!("https://crates.io/api/v1/crates/{}", crate_name))
+        .header("User-Agent", CRATES_IO_USER_AGENT)
+        .body(())?
+        .send()?;
+    if !res.status().is_success() {
+        bail!("crates.io returned {} for crate {}", res.status(), crate_name)
+    }
+    let krate: CratesIoCrate = res.json()?;
+    Ok(krate.krate.max_version)
+}
+
+// From an ingested database dump, so without any requests to crates.io. Crates with no unyanked versions are left out,
+// as there's nothing to analyze
+fn top_crates_by_popularity(db: &sled::Db, count: usize, filter: &CrateFilter) -> Result<Vec<(String, String)>> {
+    let crates = reeves::popularity::top_crates(db, usize::MAX)?;
+    Ok(crates.into_iter()
+        .filter_map(|(name, popularity)| popularity.max_version.map(|version| (name, version)))
+        .filter(|(name, version)| filter.allows(name, version))
+        .take(count)
+        .collect())
+}
+
+// The crates.io API pages by at most 100, and asks for no more than one request a second
+fn top_crates_by_downloads(count: usize, filter: &CrateFilter) -> Result<Vec<(String, String)>> {
+    const PER_PAGE: usize = 100;
+
+    #[derive(Deserialize)]
+    struct CratesIoCrates {
+        crates: Vec<CratesIoCrateInfo>,
+    }
+    #[derive(Deserialize)]
+    struct CratesIoCrateInfo {
+        name: String,
+        max_version: String,
+    }
+
+    let mut crates = vec![];
+    let mut page = 1;
+    while crates.len() < count {
+        if page > 1 {
+            thread::sleep(Duration::from_secs(1));
+        }
+        let url = format!("https://crates.io/api/v1/crates?sort=downloads&per_page={}&page={}", PER_PAGE, page);
+        let mut res = isahc::Request::get(url)
+            .header("User-Agent", CRATES_IO_USER_AGENT)
+            .body(())?
+            .send()?;
+        if !res.status().is_success() {
+            bail!("crates.io returned {} for page {} of crates by downloads", res.status(), page)
+        }
+        let res: CratesIoCrates = res.json()?;
+        if res.crates.is_empty() {
+            break
+        }
+        crates.extend(res.crates.into_iter()
+            .map(|krate| (krate.name, krate.max_version))
+            .filter(|(name, version)| filter.allows(name, version)));
+        page += 1;
+    }
+    crates.truncate(count);
+    Ok(crates)
 }
 
-fn container_analyze_crate(panamax_mirror_path: &Path, crate_name: &str, crate_version: &str) -> Result<Either<Vec<FnDetail>, String>> {
+// crates.io asks that API users identify themselves
+const CRATES_IO_USER_AGENT: &str = "reeves (https://github.com/hadeaninc/typesearch.rs)";
+
+// Analyze from the panamax mirror if the crate is there, otherwise download it from crates.io
+fn container_analyze_registry_crate(sandbox: &SandboxConfig, crate_source: &CrateSource, crate_name: &str, crate_version: &str) -> Result<Either<Vec<FnDetail>, String>> {
+    let panamax_mirror_path = match crate_source {
+        CrateSource::Panamax(path) => path,
+        CrateSource::Sparse(_) => return container_analyze_crate(sandbox, crate_source, crate_name, crate_version),
+    };
     let crate_tar_path = crate_to_tar_path(panamax_mirror_path, crate_name, crate_version);
-    let crate_tar_path = crate_tar_path.to_str().unwrap(); // where the crate tar currently is
-    let crate_path = format!("{}/{}-{}", CRATE_WORK_DIR, crate_name, crate_version); // where it will get extracted to
+    if crate_tar_path.exists() {
+        return container_analyze_crate_tar(sandbox, &crate_tar_path, crate_name, crate_version)
+    }
 
-    fs::create_dir_all(CRATE_WORK_DIR).unwrap();
-    if let Err(e) = fs::remove_dir_all(&crate_path) {
-        if e.kind() != io::ErrorKind::NotFound { panic!("{}", e) }
+    info!("crate {}-{} not in panamax mirror, downloading from crates.io", crate_name, crate_version);
+    let download_dir = TempWorkDir::new(&sandbox.work_dir, "download");
+    let download_path = download_dir.path().join(format!("{}-{}.crate", crate_name, crate_version));
+    let mut res = isahc::Request::get(format!("https://static.crates.io/crates/{0}/{0}-{1}.crate", crate_name, crate_version))
+        .header("User-Agent", CRATES_IO_USER_AGENT)
+        .body(())?
+        .send()?;
+    if !res.status().is_success() {
+        bail!("crates.io returned {} for crate {}-{}", res.status(), crate_name, crate_version)
     }
+    res.copy_to_file(&download_path).context("failed to download crate")?;
+
+    container_analyze_crate_tar(sandbox, &download_path, crate_name, crate_version)
+}
+
+// Returns the git+<url>#<commit> crate identity, the crate version and the analysis of each package in the repo.
+// Branches and tags are resolved to a commit, so the identity refers to exactly what was analyzed. Workspaces of more
+// than one package have the package name after the commit, e.g. git+<url>#<commit>:tokio-util
+fn container_analyze_git(sandbox: &SandboxConfig, url: &str, rev: Option<&str>) -> Result<Vec<(String, String, Either<Vec<FnDetail>, String>)>> {
+    let clone_dir = TempWorkDir::new(&sandbox.work_dir, "git");
+    // Named after the repo rather than the temp dir, as that's what the analysis log is named after
+    let repo_name = url.trim_end_matches('/').trim_end_matches(".git").rsplit('/').next().unwrap_or("repo");
+    let crate_path = clone_dir.path().join(repo_name);
+    let crate_path = crate_path.to_str().unwrap();
+
+    let git = |args: &[&str]| -> Result<String> {
+        let res = Command::new("git").args(args).output().context("failed to run git")?;
+        if !res.status.success() {
+            bail!("git {} failed: {}", args.join(" "), String::from_utf8_lossy(&res.stderr))
+        }
+        Ok(String::from_utf8_lossy(&res.stdout).trim().to_owned())
+    };
+    let res = (|| -> Result<_> {
+        git(&["clone", "--quiet", url, crate_path])?;
+        if let Some(rev) = rev {
+            git(&["-C", crate_path, "checkout", "--quiet", rev])?;
+        }
+        let commit = git(&["-C", crate_path, "rev-parse", "HEAD"])?;
+        let packages = container_analyze_crate_path(sandbox, crate_path.as_ref()).context("failed to analyze crate")?;
+        let is_workspace = packages.len() > 1;
+        Ok(packages.into_iter().map(|package| {
+            let identity = if is_workspace {
+                format!("git+{}#{}:{}", url, commit, package.crate_name)
+            } else {
+                format!("git+{}#{}", url, commit)
+            };
+            (identity, package.crate_version, package.res)
+        }).collect())
+    })();
+    drop(clone_dir);
+    res
+}
+
+fn container_analyze_crate(sandbox: &SandboxConfig, crate_source: &CrateSource, crate_name: &str, crate_version: &str) -> Result<Either<Vec<FnDetail>, String>> {
+    let crate_tar_path = match crate_source {
+        CrateSource::Panamax(path) => crate_to_tar_path(path, crate_name, crate_version),
+        CrateSource::Sparse(index) => index.download(crate_name, crate_version)?,
+    };
+    container_analyze_crate_tar(sandbox, &crate_tar_path, crate_name, crate_version)
+}
+
+fn container_analyze_crate_tar(sandbox: &SandboxConfig, crate_tar_path: &Path, crate_name: &str, crate_version: &str) -> Result<Either<Vec<FnDetail>, String>> {
+    let crate_tar_path = crate_tar_path.to_str().unwrap(); // where the crate tar currently is
+    let extract_dir = TempWorkDir::new(&sandbox.work_dir, "extract");
+    let crate_path = extract_dir.path().join(format!("{}-{}", crate_name, crate_version)); // where it will get extracted to
 
     let res = Command::new("tar")
-        .args(&["-C", CRATE_WORK_DIR, "-xzf", crate_tar_path])
+        .args(&["-C", extract_dir.path().to_str().unwrap(), "-xzf", crate_tar_path])
         .status().unwrap();
     if !res.success() {
         bail!("failed to create extracted crate")
     }
 
-    let res = container_analyze_crate_path(crate_path.as_ref());
-    fs::remove_dir_all(crate_path).unwrap();
+    let res = container_analyze_crate_path(sandbox, &crate_path);
+    drop(extract_dir);
 
-    let res = res.context("failed to analyze crate")?;
-    assert_eq!((crate_name, crate_version), (res.crate_name.as_str(), res.crate_version.as_str()));
+    let res = match res {
+        Ok(res) => res,
+        // Record this like any other analysis error so bulk runs move on rather than retrying forever
+        Err(e) => match e.downcast_ref::<AnalysisTimeout>() {
+            Some(timeout) => return Ok(Either::Right(timeout.to_string())),
+            None => return Err(e.context("failed to analyze crate")),
+        },
+    };
+    // Crates from the registry are packaged on their own, so are the only package
+    let res = match res.into_iter().find(|res| res.crate_name == crate_name) {
+        Some(res) => res,
+        None => bail!("analysis of {}-{} didn't find its package", crate_name, crate_version),
+    };
+    assert_eq!(crate_version, res.crate_version.as_str());
 
     Ok(res.res)
 }
 
-fn container_analyze_crate_path(path: &Path) -> Result<AnalyzeAndPrintOutput> {
+fn container_analyze_crate_path(sandbox: &SandboxConfig, path: &Path) -> Result<Vec<AnalyzeAndPrintOutput>> {
     const OUTPUT_LIMIT: usize = 500;
     fn snip_output(mut s: &[u8]) -> String {
         let mut didsnip = false;
@@ -332,49 +1933,80 @@ fn container_analyze_crate_path(path: &Path) -> Result<AnalyzeAndPrintOutput> {
         out
     }
 
-    let cwd = env::current_dir().unwrap();
-    let cwd = cwd.to_str().unwrap();
+    // Containers are named so they can be removed on timeout - killing the runtime client may not stop them
+    static CONTAINER_COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let container_name = format!("reeves-{}-{}", process::id(), CONTAINER_COUNTER.fetch_add(1, Ordering::SeqCst));
+
+    let (mut prep_cmd, mut analyze_cmd) = match sandbox.kind {
+        Sandbox::Container => container_analysis_cmds(sandbox, path, &container_name),
+        Sandbox::Bwrap => bwrap_analysis_cmds(path),
+        Sandbox::None => host_analysis_cmds(path),
+    };
+
+    let run = |cmd: &mut Command, deadline: Option<Instant>| -> Result<Output> {
+        match output_with_deadline(cmd, deadline).unwrap() {
+            Some(output) => Ok(output),
+            None => {
+                if sandbox.kind == Sandbox::Container {
+                    let _ = Command::new(&sandbox.runtime).args(&["rm", "-f", &container_name])
+                        .stdout(Stdio::null()).stderr(Stdio::null()).status();
+                }
+                Err(AnalysisTimeout(sandbox.timeout.unwrap()).into())
+            },
+        }
+    };
+
+    // With a log dir, output goes in full to a log per crate (named after the crate dir) rather than being snipped
+    // into errors
+    let mut log = match &sandbox.log_dir {
+        Some(log_dir) => {
+            fs::create_dir_all(log_dir).unwrap();
+            let log_path = log_dir.join(format!("{}.log", path.file_name().unwrap().to_str().unwrap()));
+            let file = fs::File::create(&log_path).with_context(|| format!("failed to create log {}", log_path.display()))?;
+            Some((log_path, file))
+        },
+        None => None,
+    };
+    let mut describe_output = |stage: &str, res: &Output| -> String {
+        match &mut log {
+            Some((log_path, file)) => {
+                write!(file, "==== {} ({}) stdout ====\n", stage, res.status).unwrap();
+                file.write_all(&res.stdout).unwrap();
+                write!(file, "\n==== {} ({}) stderr ====\n", stage, res.status).unwrap();
+                file.write_all(&res.stderr).unwrap();
+                file.write_all(b"\n").unwrap();
+                format!(", output in {}", log_path.display())
+            },
+            None => format!(":\n====\n{}\n====\n{}\n====", snip_output(&res.stdout), snip_output(&res.stderr)),
+        }
+    };
 
     // We need to do these so when we actually invoke the crate build scripts etc via rust-analyzer, everything is
     // already downloaded so we can isolate network access
-    let res = Command::new("podman").args(&["run", "--rm"])
-        // Basics
-        .args(&["-v", &format!("{}/container-state:/work", cwd), "-v", &format!("{}:/crate", path.display())])
-        .args(&["-e=RUSTUP_HOME=/work/rustup", "-e=CARGO_HOME=/work/cargo"])
-        // Custom
-        .args(&["-w=/crate", "--net=host"])
-        // Command
-        .args(&["ubuntu:20.04", "bash", "-c"])
-        // TODO: ideally generate-lockfile would use --offline, but it seems to have an issue with a replaced registry
-        // when attempting to generate a lockfile for serde-1.0.127
-        .arg("/work/cargo/bin/cargo generate-lockfile && /work/cargo/bin/cargo metadata >/dev/null")
-        .output().unwrap();
-
+    let prep_slot = sandbox.stages.as_ref().map(|stages| stages.prep.acquire());
+    let mut deadline = sandbox.timeout.map(|timeout| Instant::now() + timeout);
+    let res = run(&mut prep_cmd, deadline)?;
+    drop(prep_slot);
+    let output = describe_output("prep", &res);
     if !res.status.success() {
-        bail!("failed to prep for analysis {}:\n====\n{}\n====\n{}\n====", path.display(), snip_output(&res.stdout), snip_output(&res.stderr))
+        bail!("failed to prep for analysis {}{}", path.display(), output)
     }
 
-    let res = Command::new("podman").args(&["run", "--rm"])
-        // Basics
-        // NOTE: these are read-only
-        .args(&["-v", &format!("{}/container-state:/work:ro", cwd), "-v", &format!("{}:/crate:ro", path.display())])
-        .args(&["-e=RUSTUP_HOME=/work/rustup", "-e=CARGO_HOME=/work/cargo"])
-        // Custom
-        .args(&["-w=/work", "--net=none"])
-        .args(&["-v", &format!("{}:/reeves:ro", &env::current_exe().unwrap().to_str().unwrap())])
-        // Command
-        .args(&["ubuntu:20.04", "bash", "-c"])
-        .arg(format!("PATH=$PATH:/work/cargo/bin /reeves --rust-analyzer /work/rust-analyzer {} /crate", ANALYZE_AND_PRINT_COMMAND))
-        .output().unwrap();
-
+    // Waiting for a turn isn't the crate's fault, so doesn't count towards the timeout
+    let waiting_since = Instant::now();
+    let _analyze_slot = sandbox.stages.as_ref().map(|stages| stages.analyze.acquire());
+    deadline = deadline.map(|deadline| deadline + waiting_since.elapsed());
+    let res = run(&mut analyze_cmd, deadline)?;
+    let output = describe_output("analyze", &res);
     if !res.status.success() {
-        bail!("failed to analyze {}:\n====\n{}\n====\n{}\n====", path.display(), snip_output(&res.stdout), snip_output(&res.stderr))
+        bail!("failed to analyze {}{}", path.display(), output)
     }
 
     match serde_json::from_slice(&res.stdout) {
         Ok(r) => Ok(r),
+        Err(e) if log.is_some() => bail!("failed to deserialize output from sandboxed analysis: {}{}", e, output),
         Err(e) => {
-            bail!("failed to deserialize output from analysis in container: {}\n====\n{}\n====",
+            bail!("failed to deserialize output from sandboxed analysis: {}\n====\n{}\n====",
                    e, String::from_utf8_lossy(&res.stdout[..cmp::min(res.stdout.len(), OUTPUT_LIMIT)]))
         },
     }
@@ -397,3 +2029,137 @@ fn crate_to_tar_path(panamax_mirror_path: &Path, name: &str, version: &str) -> P
 
     panamax_mirror_path.join("crates").join(crate_path).join(version_path)
 }
+
+// TODO: ideally generate-lockfile would use --offline, but it seems to have an issue with a replaced registry
+// when attempting to generate a lockfile for serde-1.0.127
+const PREP_SCRIPT: &str = "cargo generate-lockfile && cargo metadata >/dev/null";
+
+fn container_analysis_cmds(sandbox: &SandboxConfig, path: &Path, name: &str) -> (Command, Command) {
+    let cwd = env::current_dir().unwrap();
+    let cwd = cwd.to_str().unwrap();
+
+    let mut prep_cmd = Command::new(&sandbox.runtime);
+    prep_cmd.args(&["run", "--rm", "--name", name])
+        // Basics
+        .args(&["-v", &format!("{}/container-state:/work", cwd), "-v", &format!("{}:/crate", path.display())])
+        .args(&["-e=RUSTUP_HOME=/work/rustup", "-e=CARGO_HOME=/work/cargo"])
+        // Custom
+        .args(&["-w=/crate", "--net=host"])
+        // Command
+        .args(&[sandbox.image.as_str(), "bash", "-c"])
+        .arg(format!("PATH=$PATH:/work/cargo/bin && {}", PREP_SCRIPT));
+
+    let mut analyze_cmd = Command::new(&sandbox.runtime);
+    analyze_cmd.args(&["run", "--rm", "--name", name])
+        // Basics
+        // NOTE: these are read-only
+        .args(&["-v", &format!("{}/container-state:/work:ro", cwd), "-v", &format!("{}:/crate:ro", path.display())])
+        .args(&["-e=RUSTUP_HOME=/work/rustup", "-e=CARGO_HOME=/work/cargo"])
+        // Custom
+        .args(&["-w=/work", "--net=none"])
+        .args(&["-v", &format!("{}:/reeves:ro", &env::current_exe().unwrap().to_str().unwrap())])
+        // Command
+        .args(&[sandbox.image.as_str(), "bash", "-c"])
+        .arg(format!("PATH=$PATH:/work/cargo/bin /reeves --rust-analyzer /work/rust-analyzer {} /crate", ANALYZE_AND_PRINT_COMMAND));
+
+    (prep_cmd, analyze_cmd)
+}
+
+// The host filesystem is visible read-only, apart from the crate during prep (to write the lockfile) and cargo home
+// (to download dependencies)
+fn bwrap_analysis_cmds(path: &Path) -> (Command, Command) {
+    fn bwrap() -> Command {
+        let mut cmd = Command::new("bwrap");
+        cmd.args(&["--ro-bind", "/", "/", "--dev", "/dev", "--proc", "/proc", "--tmpfs", "/tmp"])
+            .args(&["--unshare-all", "--die-with-parent"]);
+        clear_reexec_env(&mut cmd);
+        cmd
+    }
+    let cargo_home = env::var_os("CARGO_HOME").map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from(env::var_os("HOME").unwrap()).join(".cargo"));
+
+    let mut prep_cmd = bwrap();
+    prep_cmd.arg("--share-net")
+        .arg("--bind").arg(&cargo_home).arg(&cargo_home)
+        .arg("--bind").arg(path).arg("/crate")
+        .args(&["--chdir", "/crate", "bash", "-c", PREP_SCRIPT]);
+
+    let mut analyze_cmd = bwrap();
+    analyze_cmd.arg("--ro-bind").arg(path).arg("/crate")
+        .args(&["--chdir", "/crate"])
+        .arg(env::current_exe().unwrap())
+        .arg("--rust-analyzer").arg(rust_analyzer_binary())
+        .args(&[ANALYZE_AND_PRINT_COMMAND, "/crate"]);
+
+    (prep_cmd, analyze_cmd)
+}
+
+fn host_analysis_cmds(path: &Path) -> (Command, Command) {
+    let mut prep_cmd = Command::new("bash");
+    prep_cmd.args(&["-c", PREP_SCRIPT]).current_dir(path);
+
+    let mut analyze_cmd = Command::new(env::current_exe().unwrap());
+    analyze_cmd.arg("--rust-analyzer").arg(rust_analyzer_binary())
+        .arg(ANALYZE_AND_PRINT_COMMAND).arg(path);
+    clear_reexec_env(&mut analyze_cmd);
+
+    (prep_cmd, analyze_cmd)
+}
+
+// The child must start as reeves rather than rust-analyzer, see ENV_RUST_ANALYZER_EXEC
+fn clear_reexec_env(cmd: &mut Command) {
+    cmd.env_remove(ENV_RUST_ANALYZER_EXEC).env_remove(ENV_RUST_ANALYZER_BINARY);
+}
+
+// Absolute, as sandboxed commands may run from a different directory
+fn rust_analyzer_binary() -> PathBuf {
+    let binary = PathBuf::from(env::var_os(ENV_RUST_ANALYZER_BINARY).unwrap());
+    fs::canonicalize(&binary).unwrap_or(binary)
+}
+
+#[derive(Debug)]
+struct AnalysisTimeout(Duration);
+
+impl std::fmt::Display for AnalysisTimeout {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Timeout: analysis did not finish within {}s", self.0.as_secs())
+    }
+}
+
+impl std::error::Error for AnalysisTimeout {}
+
+// Like Command::output, but kills the process and returns None if it's still running at the deadline
+fn output_with_deadline(cmd: &mut Command, deadline: Option<Instant>) -> io::Result<Option<Output>> {
+    let deadline = match deadline {
+        Some(deadline) => deadline,
+        None => return cmd.output().map(Some),
+    };
+    let mut child = cmd.stdin(Stdio::null()).stdout(Stdio::piped()).stderr(Stdio::piped()).spawn()?;
+
+    // Drain the pipes as we go so the child doesn't block on a full pipe
+    fn drain(mut pipe: impl io::Read + Send + 'static) -> thread::JoinHandle<Vec<u8>> {
+        thread::spawn(move || {
+            let mut buf = vec![];
+            pipe.read_to_end(&mut buf).unwrap();
+            buf
+        })
+    }
+    let stdout = drain(child.stdout.take().unwrap());
+    let stderr = drain(child.stderr.take().unwrap());
+
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status
+        }
+        if Instant::now() >= deadline {
+            warn!("killing process {} after timeout", child.id());
+            child.kill()?;
+            child.wait()?;
+            // Don't wait for the pipes, grandchildren may still be holding them open
+            return Ok(None)
+        }
+        thread::sleep(Duration::from_millis(100));
+    };
+
+    Ok(Some(Output { status, stdout: stdout.join().unwrap(), stderr: stderr.join().unwrap() }))
+}
@@ -0,0 +1,229 @@
+// Small self-contained indexes of just the crates a project depends on, e.g. for searching from a cargo subcommand
+// without the global index. Everything is in memory and types are looked up exactly (in normal form) rather than
+// through the text search, so a search is a few hash lookups
+
+use serde::{Serialize, Deserialize};
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+
+use reeves_types::*;
+
+use crate::{ReevesError, file_error};
+
+#[derive(Deserialize)]
+struct Lockfile {
+    #[serde(default)]
+    package: Vec<LockedPackage>,
+}
+
+#[derive(Deserialize)]
+struct LockedPackage {
+    name: String,
+    version: String,
+    source: Option<String>,
+}
+
+// (name, version) of the crates.io packages in a Cargo.lock. Workspace members, path and git dependencies have no
+// registry source, so can only be analyzed locally
+pub fn lockfile_crates(lockfile_path: &Path) -> Result<Vec<(String, String)>, ReevesError> {
+    let s = fs::read_to_string(lockfile_path).map_err(|e| file_error("read", lockfile_path, e))?;
+    let lockfile: Lockfile = toml::from_str(&s).map_err(|e| file_error("parse", lockfile_path, e))?;
+    Ok(lockfile.package.into_iter()
+        .filter(|package| package.source.as_deref().map_or(false, |source| source.starts_with("registry+")))
+        .map(|package| (package.name, package.version))
+        .collect())
+}
+
+// What's saved - the type maps are rebuilt on load
+#[derive(Deserialize)]
+struct SavedMiniIndex {
+    crates: BTreeSet<(String, String)>,
+    fndetails: Vec<FnDetail>,
+}
+
+// Serializes the same as SavedMiniIndex, without copying the index
+#[derive(Serialize)]
+struct SavedMiniIndexRef<'a> {
+    crates: &'a BTreeSet<(String, String)>,
+    fndetails: &'a [FnDetail],
+}
+
+#[derive(Default)]
+pub struct MiniIndex {
+    // (name, version) - lockfiles often have several versions of a crate, e.g. syn 1 and 2
+    crates: BTreeSet<(String, String)>,
+    fndetails: Vec<FnDetail>,
+    params: HashMap<String, HashSet<usize>>, // type => indexes into fndetails
+    rets: HashMap<String, HashSet<usize>>,
+}
+
+impl MiniIndex {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Take the crates from the main db, returning the index and the crates it didn't have at those versions, which
+    // need analyzing locally and adding with add_crate
+    pub fn from_db(db: &sled::Db, crates: &[(String, String)]) -> Result<(Self, Vec<(String, String)>), ReevesError> {
+        let mut index = Self::new();
+        let mut missing = vec![];
+        for (name, version) in crates {
+            match crate::crate_fns(db, name)? {
+                Some((db_version, fndetails)) if db_version == *version => index.add_crate(name, version, fndetails),
+                _ => missing.push((name.clone(), version.clone())),
+            }
+        }
+        Ok((index, missing))
+    }
+
+    // Replaces any fns already in the index for the crate at that version, other versions are kept
+    pub fn add_crate(&mut self, krate_name: &str, krate_version: &str, fndetails: Vec<FnDetail>) {
+        if !self.crates.insert((krate_name.to_owned(), krate_version.to_owned())) {
+            self.fndetails.retain(|fndetail| fndetail.krate != krate_name || fndetail.version != krate_version);
+            self.fndetails.extend(fndetails);
+            self.reindex();
+        } else {
+            let start = self.fndetails.len();
+            self.fndetails.extend(fndetails);
+            for i in start..self.fndetails.len() {
+                self.index_fn(i)
+            }
+        }
+    }
+
+    fn reindex(&mut self) {
+        self.params.clear();
+        self.rets.clear();
+        for i in 0..self.fndetails.len() {
+            self.index_fn(i)
+        }
+    }
+
+    // Analysis and the main db already have types in normal form
+    fn index_fn(&mut self, i: usize) {
+        let fndetail = &self.fndetails[i];
+        for param in fndetail.params.iter() {
+            self.params.entry(param.clone()).or_default().insert(i);
+        }
+        self.rets.entry(fndetail.ret.clone()).or_default().insert(i);
+    }
+
+    // (name, version) of the crates in the index
+    pub fn crates(&self) -> impl Iterator<Item=(&str, &str)> {
+        self.crates.iter().map(|(name, version)| (name.as_str(), version.as_str()))
+    }
+
+    pub fn num_fns(&self) -> usize {
+        self.fndetails.len()
+    }
+
+    // Fns with all the params (and any others after) and the ret, fewest extra params first. Some empty params means
+    // only fns without params, and None any params, like the main search
+    pub fn search(&self, params_search: Option<&[String]>, ret_search: Option<&str>) -> Vec<&FnDetail> {
+        let mut sets: Vec<&HashSet<usize>> = vec![];
+        let empty = HashSet::new();
+        if let Some(ret) = ret_search {
+            sets.push(self.rets.get(&ty::normalize_type(ret)).unwrap_or(&empty));
+        }
+        for param in params_search.unwrap_or(&[]) {
+            sets.push(self.params.get(&ty::normalize_type(param)).unwrap_or(&empty));
+        }
+        let matches: Vec<usize> = if sets.is_empty() {
+            (0..self.fndetails.len()).collect()
+        } else {
+            // Start from the smallest set, so the fewest lookups are made in the others
+            sets.sort_by_key(|set| set.len());
+            sets[0].iter().copied().filter(|i| sets[1..].iter().all(|set| set.contains(i))).collect()
+        };
+        let mut found: Vec<&FnDetail> = matches.into_iter()
+            .map(|i| &self.fndetails[i])
+            .filter(|fndetail| params_search.map_or(true, |params| !params.is_empty() || fndetail.params.is_empty()))
+            .collect();
+        found.sort_by(|a, b| a.params.len().cmp(&b.params.len()).then_with(|| a.cmp(b)));
+        found
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), ReevesError> {
+        let mut tmp_path = path.as_os_str().to_owned();
+        tmp_path.push(".tmp");
+        let tmp_path = PathBuf::from(tmp_path);
+        let out = BufWriter::new(File::create(&tmp_path).map_err(|e| file_error("create", &tmp_path, e))?);
+        let saved = SavedMiniIndexRef { crates: &self.crates, fndetails: &self.fndetails };
+        bincode::serialize_into(out, &saved).map_err(|e| file_error("write", &tmp_path, e))?;
+        fs::rename(&tmp_path, path).map_err(|e| file_error("replace", path, e))?;
+        Ok(())
+    }
+
+    pub fn load(path: &Path) -> Result<Self, ReevesError> {
+        let input = BufReader::new(File::open(path).map_err(|e| file_error("open", path, e))?);
+        let SavedMiniIndex { crates, fndetails } = bincode::deserialize_from(input)
+            .map_err(|e| file_error("read mini index", path, e))?;
+        let mut index = MiniIndex { crates, fndetails, ..Default::default() };
+        index.reindex();
+        Ok(index)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::fndetail;
+
+    // (path, version) of what's found
+    fn search<'a>(index: &'a MiniIndex, params: Option<Vec<&str>>, ret: Option<&str>) -> Vec<(&'a str, &'a str)> {
+        let params: Option<Vec<String>> = params.map(|params| params.into_iter().map(str::to_owned).collect());
+        index.search(params.as_deref(), ret).into_iter()
+            .map(|fndetail| (fndetail.path.as_str(), fndetail.version.as_str()))
+            .collect()
+    }
+
+    fn index() -> MiniIndex {
+        let mut index = MiniIndex::new();
+        index.add_crate("a", "1.0.0", vec![
+            fndetail("1.0.0", "a::g", ItemKind::Function, &["u8", "u16"], "String"),
+            fndetail("1.0.0", "a::f", ItemKind::Function, &["u8"], "String"),
+        ]);
+        index.add_crate("b", "1.0.0", vec![
+            fndetail("1.0.0", "b::h", ItemKind::Function, &[], "String"),
+            fndetail("1.0.0", "b::k", ItemKind::Function, &["u16"], "bool"),
+        ]);
+        index
+    }
+
+    #[test]
+    fn search_fewest_params_first() {
+        let index = index();
+        assert_eq!(search(&index, None, Some("String")), [("b::h", "1.0.0"), ("a::f", "1.0.0"), ("a::g", "1.0.0")]);
+        assert_eq!(search(&index, Some(vec!["u8"]), Some("String")), [("a::f", "1.0.0"), ("a::g", "1.0.0")]);
+        assert_eq!(search(&index, Some(vec!["u16"]), None), [("b::k", "1.0.0"), ("a::g", "1.0.0")]);
+        assert_eq!(search(&index, Some(vec![]), Some("String")), [("b::h", "1.0.0")]);
+        assert!(search(&index, Some(vec!["u8"]), Some("bool")).is_empty());
+        assert!(search(&index, None, Some("Vec<u8>")).is_empty());
+    }
+
+    #[test]
+    fn crate_versions_kept_apart() {
+        let mut index = index();
+        index.add_crate("b", "2.0.0", vec![fndetail("2.0.0", "b::h", ItemKind::Function, &[], "String")]);
+        assert_eq!(search(&index, Some(vec![]), Some("String")), [("b::h", "1.0.0"), ("b::h", "2.0.0")]);
+        // Re-adding a version only replaces that version
+        index.add_crate("b", "1.0.0", vec![]);
+        assert_eq!(search(&index, Some(vec![]), Some("String")), [("b::h", "2.0.0")]);
+        assert_eq!(index.crates().collect::<Vec<_>>(), [("a", "1.0.0"), ("b", "1.0.0"), ("b", "2.0.0")]);
+    }
+
+    #[test]
+    fn save_load_round_trip() {
+        let index = index();
+        let path = std::env::temp_dir().join(format!("reeves-mini-index-test-{}", std::process::id()));
+        index.save(&path).unwrap();
+        let loaded = MiniIndex::load(&path);
+        fs::remove_file(&path).unwrap();
+        let loaded = loaded.unwrap();
+        assert_eq!(loaded.crates().collect::<Vec<_>>(), index.crates().collect::<Vec<_>>());
+        assert_eq!(loaded.num_fns(), 4);
+        assert_eq!(search(&loaded, Some(vec!["u8"]), Some("String")), [("a::f", "1.0.0"), ("a::g", "1.0.0")]);
+    }
+}
@@ -0,0 +1,322 @@
+// Crate popularity from the crates.io database dump (https://crates.io/data-access), used to rank results and pick
+// which crates to analyze. Only crates.io crates have entries, std and git crates are never in the dump
+
+use isahc::config::RedirectPolicy;
+use isahc::prelude::*;
+use log::{info, warn};
+use serde::{Serialize, Deserialize};
+use std::collections::HashMap;
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader};
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::process::{self, Command};
+use std::time::{Duration, SystemTime};
+
+use crate::{POPULARITY_TREE, ReevesError, decode, decode_name, file_error};
+
+pub const CRATES_IO_DB_DUMP: &str = "https://static.crates.io/db-dump.tar.gz";
+// The dump is regenerated daily
+const DB_DUMP_MAX_AGE: Duration = Duration::from_secs(24 * 60 * 60);
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct CratePopularity {
+    pub downloads: u64, // all time
+    pub recent_downloads: u64, // over the last 90 days, which is all the dump has per-day counts for
+    pub categories: Vec<String>, // slugs, e.g. "parser-implementations"
+    pub max_version: Option<String>, // highest unyanked
+}
+
+impl CratePopularity {
+    // Results are ordered on recent downloads, so a crate that was popular years ago doesn't crowd out what people
+    // use now
+    pub fn rank(&self) -> u64 {
+        self.recent_downloads
+    }
+}
+
+// Download the latest dump into a cache directory, unless there's already one from today
+pub fn fetch_db_dump(cache_dir: &Path, user_agent: &str) -> Result<PathBuf, ReevesError> {
+    fs::create_dir_all(cache_dir).map_err(|e| file_error("create", cache_dir, e))?;
+    let dump_path = cache_dir.join("db-dump.tar.gz");
+    let is_fresh = fs::metadata(&dump_path).and_then(|m| m.modified()).ok()
+        .and_then(|modified| SystemTime::now().duration_since(modified).ok())
+        .map_or(false, |age| age < DB_DUMP_MAX_AGE);
+    if !is_fresh {
+        info!("downloading crates.io database dump");
+        let mut res = isahc::Request::get(CRATES_IO_DB_DUMP)
+            .header("User-Agent", user_agent)
+            .redirect_policy(RedirectPolicy::Follow)
+            .body(())
+            .unwrap() // the request is always valid
+            .send()
+            .map_err(|e| file_error("download", &dump_path, e))?;
+        if !res.status().is_success() {
+            return Err(file_error("download", &dump_path, format!("crates.io returned {}", res.status())))
+        }
+        let tmp_path = dump_path.with_extension("tmp");
+        res.copy_to_file(&tmp_path).map_err(|e| file_error("download", &tmp_path, e))?;
+        fs::rename(&tmp_path, &dump_path).map_err(|e| file_error("rename to", &dump_path, e))?;
+    }
+    Ok(dump_path)
+}
+
+// Replace the popularity of all crates with what's in a dump, returning the number of crates
+pub fn ingest_db_dump(db: &sled::Db, dump_path: &Path) -> Result<usize, ReevesError> {
+    let extract_dir = env::temp_dir().join(format!("reeves-db-dump-{}", process::id()));
+    let _ = fs::remove_dir_all(&extract_dir);
+    fs::create_dir_all(&extract_dir).map_err(|e| file_error("create", &extract_dir, e))?;
+    let res = read_db_dump(dump_path, &extract_dir);
+    if let Err(e) = fs::remove_dir_all(&extract_dir) {
+        warn!("failed to remove {}: {}", extract_dir.display(), e)
+    }
+    let popularity = res?;
+
+    let tree = db.open_tree(POPULARITY_TREE)?;
+    // One batch, so a crash part way leaves the previous popularity rather than none
+    let mut batch = sled::Batch::default();
+    for name in tree.iter().keys() {
+        let name = name?;
+        if !popularity.contains_key(&*String::from_utf8_lossy(&name)) {
+            batch.remove(name)
+        }
+    }
+    for (name, krate_popularity) in popularity.iter() {
+        batch.insert(name.as_bytes(), bincode::serialize(krate_popularity).unwrap());
+    }
+    tree.apply_batch(batch)?;
+    tree.flush()?;
+    Ok(popularity.len())
+}
+
+fn read_db_dump(dump_path: &Path, extract_dir: &Path) -> Result<HashMap<String, CratePopularity>, ReevesError> {
+    const TABLES: &[&str] = &["crates", "crate_downloads", "versions", "version_downloads", "categories", "crates_categories"];
+
+    info!("extracting crates.io database dump {}", dump_path.display());
+    // The dump is inside a dated directory, so the files can't be named exactly. Tar complains about patterns that
+    // don't match anything (crate_downloads is only in newer dumps), so its status is ignored and the files checked
+    // for instead
+    let patterns: Vec<String> = TABLES.iter().map(|table| format!("*/data/{}.csv", table)).collect();
+    let res = Command::new("tar")
+        .args(&["-xzf", dump_path.to_str().unwrap(), "-C", extract_dir.to_str().unwrap(), "--strip-components=2", "--wildcards"])
+        .args(&patterns)
+        .output().map_err(|e| file_error("extract", dump_path, format!("failed to run tar: {}", e)))?;
+    let table_path = |table: &str| extract_dir.join(format!("{}.csv", table));
+    if !table_path("crates").exists() {
+        return Err(file_error("extract", dump_path, format!("no crates table: {}", String::from_utf8_lossy(&res.stderr))))
+    }
+
+    let mut crate_names: HashMap<String, String> = HashMap::new(); // crate id => name
+    let mut popularity: HashMap<String, CratePopularity> = HashMap::new(); // by crate id until the end
+    let mut has_downloads = false;
+    for_each_row(&table_path("crates"), &["id", "name", "?downloads"], |row| {
+        let downloads = row[2].parse().unwrap_or(0);
+        has_downloads |= !row[2].is_empty();
+        crate_names.insert(row[0].to_owned(), row[1].to_owned());
+        popularity.insert(row[0].to_owned(), CratePopularity { downloads, ..Default::default() });
+    })?;
+    // Newer dumps moved download counts out of crates
+    if !has_downloads {
+        for_each_row(&table_path("crate_downloads"), &["crate_id", "downloads"], |row| {
+            if let Some(krate_popularity) = popularity.get_mut(row[0]) {
+                krate_popularity.downloads = row[1].parse().unwrap_or(0)
+            }
+        })?;
+    }
+    info!("read {} crates from database dump", crate_names.len());
+
+    let mut version_crates: HashMap<String, String> = HashMap::new(); // version id => crate id
+    let mut max_versions: HashMap<String, semver::Version> = HashMap::new(); // crate id => highest unyanked
+    for_each_row(&table_path("versions"), &["id", "crate_id", "num", "yanked"], |row| {
+        version_crates.insert(row[0].to_owned(), row[1].to_owned());
+        if row[3] == "t" {
+            return
+        }
+        if let Ok(version) = semver::Version::parse(row[2]) {
+            if max_versions.get(row[1]).map_or(true, |max| &version > max) {
+                max_versions.insert(row[1].to_owned(), version);
+            }
+        }
+    })?;
+    for (krate_id, version) in max_versions {
+        if let Some(krate_popularity) = popularity.get_mut(&krate_id) {
+            krate_popularity.max_version = Some(version.to_string())
+        }
+    }
+
+    // Only the last 90 days of per-day counts are in the dump, so everything here is recent
+    for_each_row(&table_path("version_downloads"), &["version_id", "downloads"], |row| {
+        let krate_popularity = version_crates.get(row[0]).and_then(|krate_id| popularity.get_mut(krate_id));
+        if let Some(krate_popularity) = krate_popularity {
+            krate_popularity.recent_downloads += row[1].parse().unwrap_or(0)
+        }
+    })?;
+
+    let mut categories: HashMap<String, String> = HashMap::new(); // category id => slug
+    for_each_row(&table_path("categories"), &["id", "slug"], |row| {
+        categories.insert(row[0].to_owned(), row[1].to_owned());
+    })?;
+    for_each_row(&table_path("crates_categories"), &["crate_id", "category_id"], |row| {
+        let krate_popularity = popularity.get_mut(row[0]);
+        if let (Some(krate_popularity), Some(category)) = (krate_popularity, categories.get(row[1])) {
+            krate_popularity.categories.push(category.clone())
+        }
+    })?;
+
+    Ok(popularity.into_iter()
+        .filter_map(|(krate_id, mut krate_popularity)| {
+            krate_popularity.categories.sort();
+            crate_names.remove(&krate_id).map(|name| (name, krate_popularity))
+        })
+        .collect())
+}
+
+// Calls f with the given columns of each row, in the order given. Columns starting with ? may be missing, and are
+// then empty
+fn for_each_row(path: &Path, columns: &[&str], mut f: impl FnMut(&[&str])) -> Result<(), ReevesError> {
+    let file = File::open(path).map_err(|e| file_error("open", path, e))?;
+    let mut records = CsvRecords::new(BufReader::new(file));
+    let header = records.next()
+        .ok_or_else(|| file_error("read", path, "table is empty"))?
+        .map_err(|e| file_error("read", path, e))?;
+    let indices = column_indices(&header, columns).map_err(|e| file_error("read", path, e))?;
+    let mut row = vec![];
+    for record in records {
+        let record = record.map_err(|e| file_error("read", path, e))?;
+        row.clear();
+        row.extend(indices.iter().map(|i| i.and_then(|i| record.get(i)).map_or("", String::as_str)));
+        f(&row)
+    }
+    Ok(())
+}
+
+// Where each of the columns is in the header
+fn column_indices(header: &[String], columns: &[&str]) -> Result<Vec<Option<usize>>, String> {
+    columns.iter()
+        .map(|col| match (header.iter().position(|h| h == col.trim_start_matches('?')), col.starts_with('?')) {
+            (Some(i), _) => Ok(Some(i)),
+            (None, true) => Ok(None),
+            (None, false) => Err(format!("no {} column", col)),
+        })
+        .collect()
+}
+
+// Just enough CSV for the database dump - quoted fields may contain commas, newlines and doubled quotes
+pub struct CsvRecords<R> {
+    input: R,
+    line: String,
+}
+
+impl<R: BufRead> CsvRecords<R> {
+    pub fn new(input: R) -> Self {
+        CsvRecords { input, line: String::new() }
+    }
+}
+
+impl<R: BufRead> Iterator for CsvRecords<R> {
+    type Item = io::Result<Vec<String>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut record = vec![];
+        let mut field = String::new();
+        let mut in_quotes = false;
+        loop {
+            self.line.clear();
+            match self.input.read_line(&mut self.line) {
+                Ok(0) if field.is_empty() && record.is_empty() => return None,
+                Ok(0) => {
+                    record.push(field);
+                    return Some(Ok(record))
+                },
+                Ok(_) => (),
+                Err(e) => return Some(Err(e)),
+            }
+            let mut chars = self.line.chars().peekable();
+            while let Some(c) = chars.next() {
+                match (c, in_quotes) {
+                    ('"', true) if chars.peek() == Some(&'"') => { chars.next(); field.push('"') },
+                    ('"', true) => in_quotes = false,
+                    ('"', false) => in_quotes = true,
+                    (',', false) => record.push(mem::take(&mut field)),
+                    ('\n', false) => {
+                        record.push(field);
+                        return Some(Ok(record))
+                    },
+                    ('\r', false) => (),
+                    (c, _) => field.push(c),
+                }
+            }
+        }
+    }
+}
+
+pub fn crate_popularity(db: &sled::Db, krate_name: &str) -> Result<Option<CratePopularity>, ReevesError> {
+    let tree = db.open_tree(POPULARITY_TREE)?;
+    tree.get(krate_name)?
+        .map(|bs| decode(POPULARITY_TREE, krate_name, &bs))
+        .transpose()
+}
+
+// For ordering results. Std is used by everything so comes before any crate, and crates we know nothing about after
+pub(crate) fn crate_rank(tree: &sled::Tree, krate_name: &str) -> Result<u64, ReevesError> {
+    if crate::is_sysroot_crate(krate_name) {
+        return Ok(u64::MAX)
+    }
+    Ok(match tree.get(krate_name)? {
+        Some(bs) => {
+            let krate_popularity: CratePopularity = decode(POPULARITY_TREE, krate_name, &bs)?;
+            krate_popularity.rank()
+        },
+        None => 0,
+    })
+}
+
+// The most downloaded crates (all time, like crates.io's own ordering), most first
+pub fn top_crates(db: &sled::Db, count: usize) -> Result<Vec<(String, CratePopularity)>, ReevesError> {
+    let tree = db.open_tree(POPULARITY_TREE)?;
+    let mut crates = tree.iter()
+        .map(|kv| -> Result<_, ReevesError> {
+            let (name, bs) = kv?;
+            let name = decode_name(POPULARITY_TREE, &name)?;
+            let krate_popularity: CratePopularity = decode(POPULARITY_TREE, &name, &bs)?;
+            Ok((name, krate_popularity))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    crates.sort_by(|(n1, p1), (n2, p2)| p2.downloads.cmp(&p1.downloads).then_with(|| n1.cmp(n2)));
+    crates.truncate(count);
+    Ok(crates)
+}
+
+// Whether a dump has been ingested at all
+pub fn has_popularity(db: &sled::Db) -> Result<bool, ReevesError> {
+    Ok(!db.open_tree(POPULARITY_TREE)?.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn records(csv: &str) -> Vec<Vec<String>> {
+        CsvRecords::new(csv.as_bytes()).collect::<io::Result<_>>().unwrap()
+    }
+
+    #[test]
+    fn csv_records() {
+        assert_eq!(records(""), Vec::<Vec<String>>::new());
+        assert_eq!(records("id,name\n1,serde\n"), [["id", "name"], ["1", "serde"]]);
+        assert_eq!(records("id,name\r\n1,serde"), [["id", "name"], ["1", "serde"]]);
+        assert_eq!(records("1,,\n"), [["1", "", ""]]);
+        assert_eq!(records("1,\"a, b\"\n"), [["1", "a, b"]]);
+        assert_eq!(records("1,\"say \"\"hi\"\"\"\n"), [["1", "say \"hi\""]]);
+        assert_eq!(records("1,\"two\nlines\"\n2,x\n"), [["1", "two\nlines"], ["2", "x"]]);
+    }
+
+    #[test]
+    fn column_lookup() {
+        let header: Vec<String> = ["id", "name", "downloads"].iter().map(|h| h.to_string()).collect();
+        assert_eq!(column_indices(&header, &["name", "id"]), Ok(vec![Some(1), Some(0)]));
+        assert_eq!(column_indices(&header, &["id", "?downloads", "?recent"]), Ok(vec![Some(0), Some(2), None]));
+        assert_eq!(column_indices(&header, &["id", "slug"]), Err("no slug column".to_owned()));
+    }
+}
@@ -1,21 +1,37 @@
-use actix_web::{App, HttpResponse, HttpServer, Responder};
-use actix_web::http::header::{ContentEncoding, ContentType};
+use actix::{Actor, ActorContext, AsyncContext, Handler, Message, StreamHandler};
+use actix_web::{App, FromRequest, HttpRequest, HttpResponse, HttpServer, ResponseError};
+use actix_web::dev::Payload;
+use actix_web::error::BlockingError;
+use actix_web::http::StatusCode;
+use actix_web::http::header::{self, ContentEncoding, ContentType};
 use actix_web::middleware;
 use actix_web::web;
+use actix_web_actors::ws;
+use either::Either;
 use filesystem::{FakeFileSystem, FileSystem};
-use log::{info, trace};
+use isahc::prelude::*;
+use log::{debug, info, trace, warn};
+use serde::{Serialize, Deserialize};
+use subtle::ConstantTimeEq;
+use std::fmt;
 use std::fs;
+use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
 use std::io::{self, BufReader, Read};
 use std::path::{Path, PathBuf};
-use std::rc::Rc;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex, mpsc};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
 
 use reeves_types::*;
+use reeves_types::proto::ApiError;
 
 macro_rules! resp {
     ($status:ident, $mime:expr, $resp:expr) => {{
         let mime: ContentType = $mime;
-        return HttpResponse::$status().set(mime).body($resp)
+        return Ok(HttpResponse::$status().set(mime).body($resp))
     }}
 }
 //macro_rules! resp_uncompressed {
@@ -52,16 +68,345 @@ macro_rules! respbin {
 //    }};
 //}
 
+pub struct AdminConfig {
+    pub token: String,
+    pub sandbox: crate::SandboxConfig,
+    pub crate_source: crate::CrateSource,
+    pub webhook: Option<String>, // notified when queued analyses finish, unless the request gave its own
+    pub index_on_demand: bool, // queue crates that searches filter on but aren't indexed
+}
+
+struct AnalyzeJob {
+    name: String,
+    version: String,
+    webhook: Option<String>,
+    on_demand: bool, // queued by a search rather than the admin API, so its progress is in OnDemandStatuses
+}
+
+// Crates queued by searches, until they're indexed. Failures are kept so a broken crate isn't retried on every search
+type OnDemandStatuses = Arc<Mutex<HashMap<String, proto::IndexStatus>>>;
+
+// Analyses queue up behind each other, so more waiting than this would take too long to be worth telling people about
+const MAX_ON_DEMAND_QUEUED: usize = 64;
+
+struct AdminState {
+    token: String,
+    analyze_queue: Mutex<mpsc::Sender<AnalyzeJob>>,
+    on_demand: Option<OnDemandStatuses>,
+}
+
+pub enum Listen {
+    Tcp(String),
+    Uds(PathBuf),
+}
+
+pub struct ServerConfig {
+    pub listen: Listen,
+    pub static_path: PathBuf,
+    pub admin: Option<AdminConfig>,
+    pub query_log: bool,
+    pub maintenance: Option<MaintenanceConfig>,
+}
+
+// Upkeep for long running servers. Each task is skipped if its interval is unset, and is put off while searches are
+// coming in, so it doesn't slow them down
+pub struct MaintenanceConfig {
+    pub text_search_sync_interval: Option<Duration>, // catch the text search up with crates saved by other processes
+    pub gc_interval: Option<Duration>, // remove types purged crates left with no fns
+    pub flush_interval: Option<Duration>, // flush the db, letting sled reclaim space from rewritten segments
+    pub idle: Duration, // how long since the last search before tasks run
+}
+
 struct InnerData {
     db: sled::Db,
+    admin: Option<AdminState>,
+    query_log: bool,
+    last_search: Mutex<Instant>,
 }
 
 impl InnerData {
-    fn new(db: sled::Db) -> Self {
-        Self { db }
+    fn new(db: sled::Db, admin: Option<AdminConfig>, query_log: bool) -> Self {
+        let admin = admin.map(|AdminConfig { token, sandbox, crate_source, webhook, index_on_demand }| {
+            let on_demand = OnDemandStatuses::default();
+            AdminState {
+                token,
+                analyze_queue: Mutex::new(spawn_analyze_worker(db.clone(), sandbox, crate_source, webhook, on_demand.clone())),
+                on_demand: if index_on_demand { Some(on_demand) } else { None },
+            }
+        });
+        Self { db, admin, query_log, last_search: Mutex::new(Instant::now()) }
+    }
+
+    fn note_search(&self) {
+        *self.last_search.lock().unwrap() = Instant::now()
+    }
+
+    fn idle_for(&self) -> Duration {
+        self.last_search.lock().unwrap().elapsed()
+    }
+
+    // Whether a crate is indexed, and if not, whether it's been queued for analysis. With queue, a crate that hasn't
+    // been is queued if it can be
+    fn crate_index_status(&self, krate: &str, queue: bool) -> Result<proto::IndexStatus, reeves::ReevesError> {
+        if let Some(version) = reeves::crate_version(&self.db, krate)? {
+            return Ok(proto::IndexStatus::Indexed { version })
+        }
+        let (admin, on_demand) = match self.admin.as_ref().and_then(|admin| admin.on_demand.as_ref().map(|on_demand| (admin, on_demand))) {
+            Some(admin) => admin,
+            None => return Ok(proto::IndexStatus::NotIndexed),
+        };
+        // Held until the job is recorded, so the worker can't get to it first
+        let mut statuses = on_demand.lock().unwrap();
+        if let Some(status) = statuses.get(krate) {
+            return Ok(status.clone())
+        }
+        if !queue || !is_valid_crate_name(krate) {
+            return Ok(proto::IndexStatus::NotIndexed)
+        }
+        // Only crates.io crates can be fetched, and the database dump knows their latest version
+        let version = match reeves::popularity::crate_popularity(&self.db, krate)?.and_then(|p| p.max_version) {
+            Some(version) => version,
+            None => return Ok(proto::IndexStatus::NotIndexed),
+        };
+        let num_queued = statuses.values().filter(|status| matches!(status, proto::IndexStatus::Queued { .. })).count();
+        if num_queued >= MAX_ON_DEMAND_QUEUED {
+            return Ok(proto::IndexStatus::QueueFull)
+        }
+        info!("queueing on-demand analysis of {}-{}", krate, version);
+        let job = AnalyzeJob { name: krate.to_owned(), version: version.clone(), webhook: None, on_demand: true };
+        if admin.analyze_queue.lock().unwrap().send(job).is_err() {
+            warn!("analysis worker has stopped, not queueing {}", krate);
+            return Ok(proto::IndexStatus::NotIndexed)
+        }
+        let status = proto::IndexStatus::Queued { version };
+        statuses.insert(krate.to_owned(), status.clone());
+        Ok(status)
+    }
+}
+
+// Link to a docs.rs search within the indexed version of the crate - we can't reliably reconstruct the rustdoc page for
+// an item, as it depends on the item kind and where it's defined rather than where it's exported
+fn docs_url(fndetail: &FnDetail) -> String {
+    // Builder chains are documented through their constructor
+    let path = match fndetail.kind {
+        ItemKind::BuilderChain => fndetail.path.split("().").next().unwrap(),
+        _ => &fndetail.path,
+    };
+    let (import_name, item_path) = path.split_once("::").unwrap_or((path, ""));
+    // Standard library crates are saved as <crate>@<rustc version>, and documented on doc.rust-lang.org
+    if reeves::is_sysroot_crate(&fndetail.krate) {
+        return format!("https://doc.rust-lang.org/{}/{}/?search={}", fndetail.version, import_name, item_path)
+    }
+    // Git crates aren't on docs.rs, so the repo is the best there is
+    if let Some((url, _commit, _package)) = git_crate_source(&fndetail.krate) {
+        return url.to_owned()
+    }
+    format!("https://docs.rs/{}/{}/{}/?search={}", fndetail.krate, fndetail.version, import_name, item_path)
+}
+
+fn crate_facets(db: &sled::Db, fnviews: &[reeves::FnView]) -> Vec<proto::CrateFacet> {
+    let mut counts: HashMap<&str, usize> = HashMap::new();
+    for fnview in fnviews {
+        *counts.entry(fnview.get().krate.as_str()).or_insert(0) += 1
+    }
+    facets_from_counts(db, counts)
+}
+
+fn facets_from_counts(db: &sled::Db, counts: HashMap<&str, usize>) -> Vec<proto::CrateFacet> {
+    let mut facets: Vec<_> = counts.into_iter()
+        .map(|(krate, count)| {
+            // Popularity only helps ordering, so a search shouldn't fail without it
+            let popularity = reeves::popularity::crate_popularity(db, krate)
+                .unwrap_or_else(|e| { warn!("failed to get popularity of {}: {}", krate, e); None });
+            proto::CrateFacet {
+                krate: krate.to_owned(),
+                count,
+                is_std: reeves::is_sysroot_crate(krate),
+                recent_downloads: popularity.map(|p| p.recent_downloads),
+            }
+        })
+        .collect();
+    facets.sort_by(|f1, f2| f2.count.cmp(&f1.count).then_with(|| f1.krate.cmp(&f2.krate)));
+    facets
+}
+
+// Only the hits on the requested page get deserialized. Blocking, see search_result_async
+fn search_result(db: &sled::Db, fnviews: Vec<reeves::FnView>, info: &reeves::SearchInfo, start: Instant, krate: Option<&str>, sort: proto::SortOrder, offset: usize, limit: Option<usize>) -> proto::SearchResult {
+    let _span = tracing::info_span!("search_result", num_fns = fnviews.len(), offset, limit = ?limit).entered();
+    let facets = crate_facets(db, &fnviews);
+    let mut fnviews: Vec<_> = fnviews.into_iter()
+        .filter(|fnview| krate.map_or(true, |krate| fnview.get().krate.as_str() == krate))
+        .collect();
+    sort.sort(&mut fnviews, |fnview| (fnview.get().krate.as_str(), fnview.get().path.as_str()), &facets);
+    let total = fnviews.len();
+    let hits = fnviews.iter()
+        .skip(offset)
+        .take(limit.unwrap_or(usize::MAX))
+        .map(|fnview| {
+            let fndetail = fnview.to_fndetail();
+            proto::SearchHit { docs_url: docs_url(&fndetail), fndetail, partial: fnview.is_partial(), ownership_variant: fnview.is_ownership_variant() }
+        })
+        .collect();
+    proto::SearchResult {
+        hits,
+        offset,
+        total,
+        truncated: info.truncated,
+        timed_out: info.timed_out,
+        crate_status: None,
+        facets,
+        query: info.query.clone(),
+        server_time_ms: start.elapsed().as_millis() as u64,
+    }
+}
+
+// search_result on the blocking pool, as facets read popularity from the db and hits are deserialized
+async fn search_result_async(db: &sled::Db, fnviews: Vec<reeves::FnView>, info: reeves::SearchInfo, start: Instant, krate: Option<String>, sort: proto::SortOrder, offset: usize, limit: Option<usize>) -> Result<proto::SearchResult, ServerError> {
+    let db = db.clone();
+    let res = web::block(move || -> Result<_, ApiError> {
+        Ok(search_result(&db, fnviews, &info, start, krate.as_deref(), sort, offset, limit))
+    }).await?;
+    Ok(res)
+}
+
+// Search, recording the query in the query log if enabled
+async fn search_and_log(db: &sled::Db, query_log: bool, params: Option<Vec<String>>, ret: Option<String>, exact_params: bool) -> Result<(Vec<reeves::FnView>, reeves::SearchInfo), reeves::ReevesError> {
+    let start = Instant::now();
+    let logged_query = if query_log { Some((params.clone(), ret.clone())) } else { None };
+    // Sled reads happen on the blocking pool and the text search is waited on asynchronously, so this doesn't hold up
+    // the event loop
+    let (fnviews, info) = reeves::search_with_info_async(db, params, ret, exact_params).await?;
+    if let Some((params, ret)) = logged_query {
+        let db = db.clone();
+        let num_results = fnviews.len();
+        let elapsed = start.elapsed();
+        // The log is for maintainers, so shouldn't fail the search
+        if let Err(e) = web::block(move || reeves::log_query(&db, params.as_deref(), ret.as_deref(), num_results, elapsed)).await {
+            warn!("failed to log query: {}", e)
+        }
+    }
+    Ok((fnviews, info))
+}
+
+#[derive(Serialize)]
+struct AnalysisNotification<'a> {
+    #[serde(rename = "crate")]
+    krate: &'a str,
+    version: &'a str,
+    status: &'a str, // indexed, errored (analysis reported an error, which is saved) or failed (nothing saved)
+    error: Option<&'a str>,
+}
+
+fn notify_webhook(url: &str, notification: &AnalysisNotification) {
+    let body = serde_json::to_vec(notification).unwrap();
+    let res = isahc::Request::post(url)
+        .header("Content-Type", "application/json")
+        .body(body)
+        .map_err(isahc::Error::from)
+        .and_then(|req| req.send());
+    match res {
+        Ok(resp) if resp.status().is_success() => (),
+        Ok(resp) => warn!("admin: webhook {} returned {}", url, resp.status()),
+        Err(e) => warn!("admin: failed to call webhook {}: {}", url, e),
     }
 }
 
+// Analyses are slow and we don't want to swamp the box, so queued analyses run one at a time
+fn spawn_analyze_worker(db: sled::Db, sandbox: crate::SandboxConfig, crate_source: crate::CrateSource, default_webhook: Option<String>, on_demand: OnDemandStatuses) -> mpsc::Sender<AnalyzeJob> {
+    let (tx, rx) = mpsc::channel::<AnalyzeJob>();
+    thread::spawn(move || {
+        for AnalyzeJob { name, version, webhook, on_demand: is_on_demand } in rx {
+            info!("admin: analyzing crate {}-{}", name, version);
+            if is_on_demand {
+                on_demand.lock().unwrap().insert(name.clone(), proto::IndexStatus::Analyzing { version: version.clone() });
+            }
+            let res = crate::container_analyze_crate(&sandbox, &crate_source, &name, &version);
+            let reported_err = match &res {
+                Ok(Either::Right(err)) => Some(err.clone()),
+                _ => None,
+            };
+            let (status, error) = match crate::save_container_analysis(&db, res, &name, &version) {
+                Ok(()) if reported_err.is_none() => ("indexed", None),
+                Ok(()) => ("errored", reported_err),
+                Err(e) => {
+                    warn!("admin: failed to analyze {}-{}: {:?}", name, version, e);
+                    ("failed", Some(format!("{:?}", e)))
+                },
+            };
+            if is_on_demand {
+                let next_status = match &error {
+                    None => {
+                        // Whoever searched for the crate will be searching its types next
+                        if let Err(e) = reeves::sync_text_search(&db, &[name.clone()]) {
+                            warn!("admin: failed to update text search for {}, run load-text-search --incremental to catch up: {}", name, e)
+                        }
+                        None
+                    },
+                    Some(err) => Some(proto::IndexStatus::Failed { version: version.clone(), err: err.clone() }),
+                };
+                let mut statuses = on_demand.lock().unwrap();
+                match next_status {
+                    Some(status) => statuses.insert(name.clone(), status),
+                    // The db says it's indexed from now on
+                    None => statuses.remove(&name),
+                };
+            }
+            if let Some(url) = webhook.as_ref().or_else(|| default_webhook.as_ref()) {
+                let notification = AnalysisNotification { krate: &name, version: &version, status, error: error.as_deref() };
+                notify_webhook(url, &notification)
+            }
+        }
+    });
+    tx
+}
+
+struct MaintenanceTask {
+    name: &'static str,
+    interval: Duration,
+    last_run: Instant,
+    run: fn(&sled::Db) -> Result<(), reeves::ReevesError>,
+}
+
+// How often to check whether a task is due and the server is idle
+const MAINTENANCE_POLL: Duration = Duration::from_secs(10);
+
+fn spawn_maintenance(s: Arc<InnerData>, config: MaintenanceConfig) {
+    let MaintenanceConfig { text_search_sync_interval, gc_interval, flush_interval, idle } = config;
+    let now = Instant::now();
+    let task = |name, interval: Option<Duration>, run: fn(&sled::Db) -> Result<(), reeves::ReevesError>| interval.map(|interval| MaintenanceTask { name, interval, last_run: now, run });
+    let mut tasks: Vec<MaintenanceTask> = vec![
+        task("text search sync", text_search_sync_interval, |db| reeves::load_text_search_incremental(db)),
+        task("empty type gc", gc_interval, |db| {
+            let num_removed = reeves::remove_empty_types(db)?;
+            info!("maintenance: removed {} empty types", num_removed);
+            Ok(())
+        }),
+        task("db flush", flush_interval, |db| {
+            let bytes = db.flush()?;
+            debug!("maintenance: flushed {} bytes", bytes);
+            Ok(())
+        }),
+    ].into_iter().flatten().collect();
+    if tasks.is_empty() {
+        return
+    }
+    thread::spawn(move || loop {
+        thread::sleep(MAINTENANCE_POLL);
+        for task in tasks.iter_mut() {
+            if task.last_run.elapsed() < task.interval || s.idle_for() < idle {
+                continue
+            }
+            let start = Instant::now();
+            match (task.run)(&s.db) {
+                Ok(()) => info!("maintenance: {} took {:.1}s", task.name, start.elapsed().as_secs_f64()),
+                Err(e) => warn!("maintenance: {} failed: {}", task.name, e),
+            }
+            // A failed task waits for its next interval too, rather than retrying while still broken
+            task.last_run = Instant::now();
+        }
+    });
+}
+
 #[derive(Clone)]
 struct MyServerData {
     s: Arc<InnerData>,
@@ -69,27 +414,516 @@ struct MyServerData {
 
 type ServerData = web::Data<MyServerData>;
 
+// Serialized as an ErrorResponse, so clients can tell errors apart without parsing messages. ApiError is shared with
+// clients, so needs wrapping to be an actix ResponseError
+#[derive(Debug)]
+struct ServerError(ApiError);
+
+impl fmt::Display for ServerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl ResponseError for ServerError {
+    fn status_code(&self) -> StatusCode {
+        StatusCode::from_u16(self.0.status_code()).unwrap()
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let resp = proto::ErrorResponse { err: self.0.clone() };
+        HttpResponse::build(self.status_code())
+            .set(ContentType::octet_stream())
+            .body(bincode::serialize(&resp).unwrap())
+    }
+}
+
+impl From<ApiError> for ServerError {
+    fn from(e: ApiError) -> Self {
+        ServerError(e)
+    }
+}
+
+impl<E: Into<ApiError> + fmt::Debug> From<BlockingError<E>> for ServerError {
+    fn from(e: BlockingError<E>) -> Self {
+        match e {
+            BlockingError::Error(e) => ServerError(e.into()),
+            BlockingError::Canceled => ServerError(ApiError::Internal("blocking operation was cancelled".into())),
+        }
+    }
+}
+
+type ApiResult = Result<HttpResponse, ServerError>;
+
+// Extracting this rejects requests from clients that say they speak a different protocol version, as bincode bodies
+// would otherwise be misread rather than failing. Clients that don't say (e.g. curl) are let through
+struct ProtocolChecked;
+
+impl FromRequest for ProtocolChecked {
+    type Error = ServerError;
+    type Future = futures::future::Ready<Result<Self, ServerError>>;
+    type Config = ();
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        let version = req.headers().get(proto::PROTOCOL_VERSION_HEADER)
+            .map(|v| v.to_str().ok().and_then(|v| v.parse::<u32>().ok()));
+        futures::future::ready(match version {
+            None => Ok(ProtocolChecked),
+            Some(Some(version)) if version == proto::PROTOCOL_VERSION => Ok(ProtocolChecked),
+            Some(Some(version)) => Err(ApiError::ProtocolMismatch { server: proto::PROTOCOL_VERSION, client: version }.into()),
+            Some(None) => Err(ApiError::BadRequest(format!("invalid {} header", proto::PROTOCOL_VERSION_HEADER)).into()),
+        })
+    }
+}
+
 // Handlers
 
-async fn srv_post_reeves_search(state: ServerData, body: web::Bytes) -> impl Responder {
-    let proto::SearchRequest { params, ret } = bincode::deserialize(&body).unwrap();
+#[tracing::instrument(skip_all)]
+async fn srv_post_reeves_search(_: ProtocolChecked, state: ServerData, body: web::Bytes) -> ApiResult {
+    let proto::SearchRequest { params, ret, krate, offset, limit, sort, exact_params } = bincode::deserialize(&body)
+        .map_err(|e| ApiError::BadRequest(format!("invalid search request: {}", e)))?;
     let searchreq_str = format!("{:?} {:?}", params, ret);
-    let fndetails = reeves::search(&state.s.db, params, ret);
-    info!("returning {} results for {}", fndetails.len(), searchreq_str);
-    let ret = proto::SearchResult {
-        fndetails,
+    state.s.note_search();
+    let start = Instant::now();
+    let (fnviews, info) = search_and_log(&state.s.db, state.s.query_log, params, ret, exact_params).await
+        .map_err(|e| { warn!("search failed for {}: {}", searchreq_str, e); ApiError::from(e) })?;
+    info!("returning {} results for {}", fnviews.len(), searchreq_str);
+    let mut ret = search_result_async(&state.s.db, fnviews, info, start, krate.clone(), sort, offset, limit).await?;
+    ret.crate_status = filter_crate_status(&state, krate).await?;
+    respbin!(&ret)
+}
+
+// For a search filtered on a crate that isn't indexed, how its on-demand analysis is going
+async fn filter_crate_status(state: &ServerData, krate: Option<String>) -> Result<Option<proto::IndexStatus>, ServerError> {
+    let krate = match krate {
+        Some(krate) => krate,
+        None => return Ok(None),
+    };
+    let s = state.s.clone();
+    let status = web::block(move || s.crate_index_status(&krate, true)).await?;
+    Ok(match status {
+        proto::IndexStatus::Indexed { .. } => None,
+        status => Some(status),
+    })
+}
+
+async fn srv_get_reeves_index_status(_: ProtocolChecked, state: ServerData, krate: web::Path<String>) -> ApiResult {
+    let krate = path_param_crate(krate.into_inner());
+    let s = state.s.clone();
+    let status = web::block(move || s.crate_index_status(&krate, false)).await?;
+    respbin!(&status)
+}
+
+const MAX_BATCH_SEARCHES: usize = 32;
+
+#[tracing::instrument(skip_all)]
+async fn srv_post_reeves_search_batch(_: ProtocolChecked, state: ServerData, body: web::Bytes) -> ApiResult {
+    let proto::BatchSearchRequest { requests } = bincode::deserialize(&body)
+        .map_err(|e| ApiError::BadRequest(format!("invalid batch search request: {}", e)))?;
+    if requests.len() > MAX_BATCH_SEARCHES {
+        return Err(ApiError::BadRequest(format!("too many searches in batch ({} > {})", requests.len(), MAX_BATCH_SEARCHES)).into())
+    }
+    let num_requests = requests.len();
+    state.s.note_search();
+    let mut results = vec![];
+    for proto::SearchRequest { params, ret, krate, offset, limit, sort, exact_params } in requests {
+        let start = Instant::now();
+        let (fnviews, info) = search_and_log(&state.s.db, state.s.query_log, params, ret, exact_params).await
+            .map_err(|e| { warn!("batch search failed: {}", e); ApiError::from(e) })?;
+        let mut result = search_result_async(&state.s.db, fnviews, info, start, krate.clone(), sort, offset, limit).await?;
+        result.crate_status = filter_crate_status(&state, krate).await?;
+        results.push(result);
+    }
+    info!("returning results for batch of {} searches", num_requests);
+    let ret = proto::BatchSearchResult {
+        results,
     };
     respbin!(&ret)
 }
 
-fn load_static(static_tar: &Path) -> FakeFileSystem {
-    let rdr = BufReader::new(fs::File::open(static_tar).unwrap());
+#[derive(Deserialize)]
+struct SuggestQuery {
+    q: String,
+}
+
+#[tracing::instrument(skip_all)]
+async fn srv_get_reeves_suggest(_: ProtocolChecked, query: web::Query<SuggestQuery>) -> ApiResult {
+    let SuggestQuery { q } = query.into_inner();
+    if q.trim().is_empty() {
+        respbin!(&proto::SuggestResult { types: vec![] })
+    }
+    let types = reeves::suggest_async(&q).await.map_err(ApiError::from)?;
+    respbin!(&proto::SuggestResult { types })
+}
+
+#[tracing::instrument(skip_all)]
+async fn srv_get_reeves_stats(_: ProtocolChecked, state: ServerData) -> ApiResult {
+    let db = state.s.db.clone();
+    let span = tracing::Span::current();
+    let stats = web::block(move || span.in_scope(|| reeves::stats(&db))).await?;
+    respbin!(&stats)
+}
+
+const MAX_SIMILAR_FNS: usize = 10;
+
+// actix leaves `/`, `+` and `%` percent-encoded in path params, which are all in git crates, e.g. git+<url>#<commit>
+fn path_param_crate(krate: String) -> String {
+    decode_component(&krate).unwrap_or(krate)
+}
+
+#[tracing::instrument(skip_all, fields(krate = %path.0, hash = %path.1))]
+async fn srv_get_reeves_fn(_: ProtocolChecked, state: ServerData, path: web::Path<(String, String)>) -> ApiResult {
+    let (krate, hash) = path.into_inner();
+    let krate = path_param_crate(krate);
+    let db = state.s.db.clone();
+    let not_found = format!("no function {} in crate {}", hash, krate);
+    let span = tracing::Span::current();
+    let fndetail = {
+        let db = db.clone();
+        web::block(move || {
+            let _enter = span.enter();
+            reeves::crate_fn(&db, &krate, &hash)
+        }).await?
+    };
+    let fndetail = match fndetail {
+        Some(fndetail) => fndetail,
+        None => return Err(ApiError::NotFound(not_found).into()),
+    };
+    // Similar functions are a nicety, so don't fail the whole page if the text search backend is down. This is the
+    // search the search endpoints make, so is held to the same deadline and memory budget
+    let similar = match reeves::search_with_info_async(&db, Some(fndetail.params.clone()), Some(fndetail.ret.clone()), false).await {
+        Ok((fnviews, _info)) => fnviews.iter()
+            .filter(|other| other.get().krate.as_str() != fndetail.krate || other.get().path.as_str() != fndetail.path)
+            .take(MAX_SIMILAR_FNS)
+            .map(|other| {
+                let other = other.to_fndetail();
+                proto::SearchHit { docs_url: docs_url(&other), fndetail: other, partial: false, ownership_variant: false }
+            })
+            .collect(),
+        Err(e) => {
+            warn!("failed to find functions similar to {}: {}", fndetail.path, e);
+            vec![]
+        },
+    };
+    let hit = proto::SearchHit { docs_url: docs_url(&fndetail), fndetail, partial: false, ownership_variant: false };
+    respbin!(&proto::FnDetailResult { hit, similar })
+}
+
+// An indexed crate's version, popularity and functions
+#[tracing::instrument(skip_all)]
+async fn srv_post_reeves_crate_detail(_: ProtocolChecked, state: ServerData, body: web::Bytes) -> ApiResult {
+    let proto::CrateDetailRequest { krate } = bincode::deserialize(&body)
+        .map_err(|e| ApiError::BadRequest(format!("invalid crate detail request: {}", e)))?;
+    let db = state.s.db.clone();
+    let not_found = format!("crate {} is not indexed", krate);
+    let span = tracing::Span::current();
+    let res = web::block(move || -> Result<_, reeves::ReevesError> {
+        let _enter = span.enter();
+        let (version, mut fndetails) = match reeves::crate_fns(&db, &krate)? {
+            Some(crate_fns) => crate_fns,
+            None => return Ok(None),
+        };
+        fndetails.sort_by(|a, b| a.path.cmp(&b.path));
+        // Popularity is a nicety, so don't fail the whole request without it
+        let popularity = reeves::popularity::crate_popularity(&db, &krate)
+            .unwrap_or_else(|e| { warn!("failed to get popularity of {}: {}", krate, e); None });
+        let fns = fndetails.into_iter()
+            .map(|fndetail| proto::SearchHit { docs_url: docs_url(&fndetail), fndetail, partial: false, ownership_variant: false })
+            .collect();
+        Ok(Some(proto::CrateDetailResult {
+            is_std: reeves::is_sysroot_crate(&krate),
+            krate,
+            version,
+            recent_downloads: popularity.map(|p| p.recent_downloads),
+            fns,
+        }))
+    }).await?;
+    match res {
+        Some(res) => respbin!(&res),
+        None => Err(ApiError::NotFound(not_found).into()),
+    }
+}
+
+// Feedback is logged for maintainers to pick through, and counted towards demoting results reported for a query
+async fn srv_post_reeves_feedback(_: ProtocolChecked, state: ServerData, body: web::Bytes) -> ApiResult {
+    let feedback: proto::FeedbackRequest = bincode::deserialize(&body)
+        .map_err(|e| ApiError::BadRequest(format!("invalid feedback request: {}", e)))?;
+    let proto::FeedbackRequest { params, ret, krate, version, path, s, kind } = &feedback;
+    match kind {
+        proto::FeedbackKind::BadResult => info!("feedback: bad result for {:?} {:?}: {} {} {} ({})", params, ret, krate, version, path, s),
+        proto::FeedbackKind::ClickThrough => debug!("feedback: followed result for {:?} {:?}: {} {} {}", params, ret, krate, version, path),
+    }
+    let db = state.s.db.clone();
+    web::block(move || reeves::record_feedback(&db, &feedback)).await?;
+    respbin!(&())
+}
+
+// Websocket search: the client sends a single SearchRequest, and is sent a SearchResult for each batch of results as
+// it's found, then a final SearchResult with no hits summarising the whole search - its total, whether it was truncated
+// or timed out, facets over all the batches and the crate filter's status - followed by a close (or an ErrorResponse,
+// followed by a close). The crate filter applies to each batch, but sorting and paging can't apply to results that
+// arrive over time, so requests with a sort or offset are rejected and limit is ignored. Results are made on the
+// blocking pool along with the search, so the actor only sends them, and the search stops if the client goes away
+
+struct SearchWs {
+    state: ServerData,
+    searching: bool,
+    stopped: Arc<AtomicBool>,
+}
+
+enum SearchWsMsg {
+    Batch(proto::SearchResult),
+    Done(proto::SearchResult),
+    Error(ApiError),
+}
+
+impl Message for SearchWsMsg {
+    type Result = ();
+}
+
+impl Actor for SearchWs {
+    type Context = ws::WebsocketContext<Self>;
+
+    fn stopped(&mut self, _ctx: &mut Self::Context) {
+        self.stopped.store(true, Ordering::Relaxed)
+    }
+}
+
+impl SearchWs {
+    fn fail(&mut self, err: ApiError, ctx: &mut ws::WebsocketContext<Self>) {
+        let resp = proto::ErrorResponse { err };
+        ctx.binary(bincode::serialize(&resp).unwrap());
+        ctx.close(Some(ws::CloseCode::Error.into()));
+        ctx.stop()
+    }
+}
+
+impl Handler<SearchWsMsg> for SearchWs {
+    type Result = ();
+
+    fn handle(&mut self, msg: SearchWsMsg, ctx: &mut Self::Context) {
+        match msg {
+            SearchWsMsg::Batch(ret) => ctx.binary(bincode::serialize(&ret).unwrap()),
+            SearchWsMsg::Done(ret) => {
+                ctx.binary(bincode::serialize(&ret).unwrap());
+                ctx.close(Some(ws::CloseCode::Normal.into()));
+                ctx.stop()
+            },
+            SearchWsMsg::Error(err) => self.fail(err, ctx),
+        }
+    }
+}
+
+impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for SearchWs {
+    fn handle(&mut self, msg: Result<ws::Message, ws::ProtocolError>, ctx: &mut Self::Context) {
+        let body = match msg {
+            Ok(ws::Message::Binary(body)) => body,
+            Ok(ws::Message::Ping(msg)) => return ctx.pong(&msg),
+            Ok(ws::Message::Close(reason)) => {
+                ctx.close(reason);
+                return ctx.stop()
+            },
+            Ok(_) => return,
+            Err(e) => {
+                warn!("websocket protocol error: {}", e);
+                return ctx.stop()
+            },
+        };
+        if self.searching {
+            return self.fail(ApiError::BadRequest("only one search is permitted per connection".into()), ctx)
+        }
+        let proto::SearchRequest { params, ret, krate, offset, limit: _, sort, exact_params } = match bincode::deserialize(&body) {
+            Ok(sr) => sr,
+            Err(e) => return self.fail(ApiError::BadRequest(format!("invalid search request: {}", e)), ctx),
+        };
+        if sort != proto::SortOrder::Relevance || offset != 0 {
+            return self.fail(ApiError::BadRequest("streamed results can't be sorted or paged".into()), ctx)
+        }
+        self.searching = true;
+        let start = Instant::now();
+        info!("streaming results for {:?} {:?}", params, ret);
+        let state = self.state.clone();
+        let db = state.s.db.clone();
+        let stopped = self.stopped.clone();
+        let addr = ctx.address();
+        let span = tracing::info_span!("search_ws");
+        actix_rt::spawn(async move {
+            let batch_addr = addr.clone();
+            let batch_krate = krate.clone();
+            let res = web::block(move || span.in_scope(|| {
+                // Over all batches sent so far, for the final facets
+                let mut crate_counts: HashMap<String, usize> = HashMap::new();
+                let mut total = 0;
+                let info = reeves::search_batches(&db, params, ret, exact_params, |query, fnviews| {
+                    if stopped.load(Ordering::Relaxed) {
+                        return false
+                    }
+                    for fnview in fnviews.iter() {
+                        *crate_counts.entry(fnview.get().krate.to_string()).or_insert(0) += 1
+                    }
+                    // Each batch is sent as a complete page. Whether there are too many results isn't known until the
+                    // end, so is left to the final result
+                    let info = reeves::SearchInfo { truncated: false, timed_out: false, query: query.clone() };
+                    let ret = search_result(&db, fnviews, &info, start, batch_krate.as_deref(), proto::SortOrder::Relevance, 0, None);
+                    total += ret.total;
+                    batch_addr.do_send(SearchWsMsg::Batch(ret));
+                    true
+                })?;
+                if stopped.load(Ordering::Relaxed) {
+                    debug!("client went away, stopped streaming search");
+                }
+                let counts = crate_counts.iter().map(|(krate, &count)| (krate.as_str(), count)).collect();
+                Ok::<_, reeves::ReevesError>(proto::SearchResult {
+                    hits: vec![],
+                    offset: 0,
+                    total,
+                    truncated: info.truncated,
+                    timed_out: info.timed_out,
+                    crate_status: None,
+                    facets: facets_from_counts(&db, counts),
+                    query: info.query,
+                    server_time_ms: start.elapsed().as_millis() as u64,
+                })
+            })).await;
+            let msg = match res {
+                Ok(mut ret) => match filter_crate_status(&state, krate).await {
+                    Ok(crate_status) => {
+                        ret.crate_status = crate_status;
+                        SearchWsMsg::Done(ret)
+                    },
+                    Err(ServerError(e)) => SearchWsMsg::Error(e),
+                },
+                Err(BlockingError::Error(e)) => SearchWsMsg::Error(ApiError::from(e)),
+                Err(BlockingError::Canceled) => SearchWsMsg::Error(ApiError::Internal("search was cancelled".into())),
+            };
+            addr.do_send(msg)
+        });
+    }
+}
+
+async fn srv_get_reeves_search_ws(_: ProtocolChecked, state: ServerData, req: HttpRequest, stream: web::Payload) -> Result<HttpResponse, actix_web::Error> {
+    state.s.note_search();
+    ws::start(SearchWs { state: state.clone(), searching: false, stopped: Arc::new(AtomicBool::new(false)) }, &req, stream)
+}
+
+fn check_admin<'a>(state: &'a ServerData, req: &HttpRequest) -> Result<&'a AdminState, ApiError> {
+    let admin = state.s.admin.as_ref()
+        .ok_or_else(|| ApiError::NotFound("admin api is not enabled".into()))?;
+    let token = req.headers().get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "));
+    match token {
+        Some(token) if bool::from(token.as_bytes().ct_eq(admin.token.as_bytes())) => Ok(admin),
+        _ => Err(ApiError::Unauthorized),
+    }
+}
+
+const MAX_INGEST_SIZE: usize = 256 * 1024 * 1024;
+
+// Crate names and versions end up in paths, so be strict about what we accept
+fn is_valid_crate_name(s: &str) -> bool {
+    !s.is_empty() && s.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+fn is_valid_crate_version(s: &str) -> bool {
+    !s.is_empty() && !s.contains("..") && s.chars().all(|c| c.is_ascii_alphanumeric() || "-_.+".contains(c))
+}
+
+async fn srv_get_admin_query_log(state: ServerData, req: HttpRequest) -> ApiResult {
+    check_admin(&state, &req)?;
+    let db = state.s.db.clone();
+    let entries = web::block(move || reeves::query_log(&db)).await?;
+    Ok(HttpResponse::Ok().json(entries))
+}
+
+async fn srv_get_admin_feedback(state: ServerData, req: HttpRequest) -> ApiResult {
+    check_admin(&state, &req)?;
+    let db = state.s.db.clone();
+    let entries = web::block(move || reeves::feedback_entries(&db)).await?;
+    Ok(HttpResponse::Ok().json(entries))
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct AdminAnalyzeRequest {
+    #[serde(rename = "crate")]
+    krate: String,
+    version: String,
+    #[serde(default)]
+    webhook: Option<String>,
+}
+
+async fn srv_post_admin_analyze(state: ServerData, req: HttpRequest, body: web::Json<AdminAnalyzeRequest>) -> ApiResult {
+    let admin = check_admin(&state, &req)?;
+    let AdminAnalyzeRequest { krate, version, webhook } = body.into_inner();
+    if !is_valid_crate_name(&krate) || !is_valid_crate_version(&version) {
+        return Err(ApiError::BadRequest(format!("invalid crate {:?} or version {:?}", krate, version)).into())
+    }
+    info!("admin: queueing analysis of {}-{}", krate, version);
+    admin.analyze_queue.lock().unwrap().send(AnalyzeJob { name: krate, version, webhook, on_demand: false })
+        .map_err(|_| ApiError::Internal("analysis worker has stopped".into()))?;
+    Ok(HttpResponse::Accepted().finish())
+}
+
+// Lets analysis machines send results without access to the db
+async fn srv_post_admin_ingest(state: ServerData, req: HttpRequest, body: web::Json<crate::AnalyzeAndPrintOutput>) -> ApiResult {
+    check_admin(&state, &req)?;
+    let crate::AnalyzeAndPrintOutput { crate_name, crate_version, res } = body.into_inner();
+    if !is_valid_crate_name(&crate_name) || !is_valid_crate_version(&crate_version) {
+        return Err(ApiError::BadRequest(format!("invalid crate {:?} or version {:?}", crate_name, crate_version)).into())
+    }
+    info!("admin: ingesting analysis of {}-{}", crate_name, crate_version);
+    let db = state.s.db.clone();
+    web::block(move || crate::save_container_analysis(&db, Ok(res), &crate_name, &crate_version)).await
+        .map_err(|e| ApiError::Internal(format!("failed to save analysis: {:?}", e)))?;
+    Ok(HttpResponse::Ok().finish())
+}
+
+async fn srv_delete_admin_crate(state: ServerData, req: HttpRequest, name: web::Path<String>) -> ApiResult {
+    check_admin(&state, &req)?;
+    let name = path_param_crate(name.into_inner());
+    info!("admin: removing crate {}", name);
+    let db = state.s.db.clone();
+    let removed_name = name.clone();
+    let removed = web::block(move || reeves::remove_crate(&db, &removed_name)).await?;
+    if !removed {
+        return Err(ApiError::NotFound(format!("crate {} is not in the db", name)).into())
+    }
+    Ok(HttpResponse::Ok().finish())
+}
+
+// Static assets are either loaded into memory from a tar (for deployment), or served directly from a directory (for
+// development, so rebuilt assets get picked up without restarting the server)
+#[derive(Clone)]
+enum StaticSource {
+    Tar(Arc<StaticTar>),
+    Dir(PathBuf),
+}
+
+struct StaticTar {
+    fs: FakeFileSystem,
+    etags: HashMap<PathBuf, String>,
+}
+
+// The wasm-pack output doesn't have content-hashed filenames, so clients must always revalidate - the ETag makes
+// that cheap
+const STATIC_CACHE_CONTROL: &str = "no-cache";
+// Precompressed variants that may be present in the tar alongside the original file, in order of preference
+const STATIC_PRECOMPRESSED: &[(&str, &str)] = &[("br", "br"), ("gzip", "gz")];
+
+fn load_static(static_path: &Path) -> StaticSource {
+    if static_path.is_dir() {
+        info!("Serving static files from directory {}", static_path.display());
+        return StaticSource::Dir(static_path.to_owned())
+    }
+    info!("Serving static files from tar {}", static_path.display());
+    let rdr = BufReader::new(fs::File::open(static_path).unwrap());
     let ar = tar::Archive::new(rdr);
-    archive_to_fake_filesystem(ar)
+    StaticSource::Tar(Arc::new(archive_to_static_tar(ar)))
 }
 
-fn archive_to_fake_filesystem(mut ar: tar::Archive<impl Read>) -> FakeFileSystem {
+fn archive_to_static_tar(mut ar: tar::Archive<impl Read>) -> StaticTar {
     let filesystem = FakeFileSystem::new();
+    let mut etags = HashMap::new();
     for entry in ar.entries().unwrap().into_iter() {
         let mut entry = entry.unwrap();
         let path = entry.path().unwrap().into_owned();
@@ -99,6 +933,9 @@ fn archive_to_fake_filesystem(mut ar: tar::Archive<impl Read>) -> FakeFileSystem
             tar::EntryType::Regular => {
                 let mut data = Vec::with_capacity(entry.header().size().unwrap() as usize);
                 entry.read_to_end(&mut data).unwrap();
+                let mut hasher = DefaultHasher::new();
+                data.hash(&mut hasher);
+                etags.insert(path.clone(), format!("\"{:016x}\"", hasher.finish()));
                 filesystem.create_file(path, data).unwrap();
             },
             tar::EntryType::Directory => {
@@ -107,48 +944,145 @@ fn archive_to_fake_filesystem(mut ar: tar::Archive<impl Read>) -> FakeFileSystem
             ft => panic!("{} in tar is {:?}", path.display(), ft),
         }
     }
-    filesystem
+    StaticTar { fs: filesystem, etags }
+}
+
+fn accepts_encoding(accept_encoding: &str, encoding: &str) -> bool {
+    accept_encoding.split(',').any(|part| {
+        let mut part = part.split(';').map(str::trim);
+        part.next() == Some(encoding) && part.all(|param| param.replace(' ', "") != "q=0")
+    })
+}
+
+async fn srv_get_static(static_tar: web::Data<Arc<StaticTar>>, req: HttpRequest) -> ApiResult {
+    let path = match req.path().trim_start_matches('/') {
+        "" => "index.html",
+        path => path,
+    };
+    let path = Path::new(path);
+    if !static_tar.etags.contains_key(path) {
+        return Err(ApiError::NotFound(format!("no static file at {}", path.display())).into())
+    }
+    let content_type = mime_guess::from_path(path).first_or_octet_stream().to_string();
+
+    // Pick the best representation the client will accept
+    let accept_encoding = req.headers().get(header::ACCEPT_ENCODING)
+        .and_then(|v| v.to_str().ok()).unwrap_or("");
+    let mut encoding = None;
+    let mut repr_path = path.to_owned();
+    for &(name, ext) in STATIC_PRECOMPRESSED {
+        let mut candidate = path.as_os_str().to_owned();
+        candidate.push(".");
+        candidate.push(ext);
+        let candidate = PathBuf::from(candidate);
+        if accepts_encoding(accept_encoding, name) && static_tar.etags.contains_key(&candidate) {
+            encoding = Some(name);
+            repr_path = candidate;
+            break
+        }
+    }
+    let etag = &static_tar.etags[&repr_path];
+
+    let not_modified = req.headers().get(header::IF_NONE_MATCH)
+        .and_then(|v| v.to_str().ok())
+        .map_or(false, |v| v.split(',').any(|t| t.trim() == etag || t.trim() == "*"));
+    let mut resp = HttpResponse::build(if not_modified { StatusCode::NOT_MODIFIED } else { StatusCode::OK });
+    resp.set_header(header::CACHE_CONTROL, STATIC_CACHE_CONTROL)
+        .set_header(header::ETAG, etag.as_str())
+        .set_header(header::VARY, "Accept-Encoding")
+        .set_header(header::CONTENT_TYPE, content_type);
+    if not_modified {
+        return Ok(resp.finish())
+    }
+    // Setting this header stops the compression middleware from compressing again
+    if let Some(encoding) = encoding {
+        resp.set_header(header::CONTENT_ENCODING, encoding);
+    }
+    let data = static_tar.fs.read_file(&repr_path)
+        .map_err(|e| ApiError::Internal(format!("failed to read static file {}: {}", repr_path.display(), e)))?;
+    Ok(resp.body(data))
 }
 
 // Main control functions
 
-pub fn serve(db: sled::Db, addr: String, static_tar: PathBuf) {
-    let state = MyServerData { s: Arc::new(InnerData::new(db)) };
+fn configure_search_api(cfg: &mut web::ServiceConfig) {
+    cfg.route(proto::Search::PATH, web::post().to(srv_post_reeves_search));
+    cfg.route(proto::BatchSearch::PATH, web::post().to(srv_post_reeves_search_batch));
+    cfg.route("/search/ws", web::get().to(srv_get_reeves_search_ws));
+    cfg.route(proto::SUGGEST_PATH, web::get().to(srv_get_reeves_suggest));
+    cfg.route(proto::STATS_PATH, web::get().to(srv_get_reeves_stats));
+    cfg.route(&format!("{}/{{krate}}/{{hash}}", proto::FN_PATH), web::get().to(srv_get_reeves_fn));
+    cfg.route(&format!("{}/{{krate}}", proto::INDEX_STATUS_PATH), web::get().to(srv_get_reeves_index_status));
+    cfg.route(proto::CrateDetail::PATH, web::post().to(srv_post_reeves_crate_detail));
+    cfg.route(proto::Feedback::PATH, web::post().to(srv_post_reeves_feedback));
+}
+
+fn configure_admin_api(cfg: &mut web::ServiceConfig) {
+    cfg.route("/analyze", web::post().to(srv_post_admin_analyze));
+    cfg.service(web::resource("/ingest")
+        // Analyses of big crates can be many MB of fndetails
+        .app_data(web::Json::<crate::AnalyzeAndPrintOutput>::configure(|cfg| cfg.limit(MAX_INGEST_SIZE)))
+        .route(web::post().to(srv_post_admin_ingest)));
+    cfg.route("/crate/{name}", web::delete().to(srv_delete_admin_crate));
+    cfg.route("/query-log", web::get().to(srv_get_admin_query_log));
+    cfg.route("/feedback", web::get().to(srv_get_admin_feedback));
+}
+
+fn protocol_version_header() -> middleware::DefaultHeaders {
+    middleware::DefaultHeaders::new().header(proto::PROTOCOL_VERSION_HEADER, proto::PROTOCOL_VERSION.to_string())
+}
+
+pub fn serve(db: sled::Db, config: ServerConfig) {
+    let ServerConfig { listen, static_path, admin, query_log, maintenance } = config;
+    if admin.is_some() {
+        info!("Admin API enabled");
+    }
+    if query_log {
+        info!("Query logging enabled");
+    }
+    let state = MyServerData { s: Arc::new(InnerData::new(db, admin, query_log)) };
+    if let Some(maintenance) = maintenance {
+        spawn_maintenance(state.s.clone(), maintenance);
+    }
 
-    let fake_fs = load_static(&static_tar);
+    let static_source = load_static(&static_path);
 
     let app_factory = move || {
         let app = App::new();
         let app = app.data(state.clone());
         let app = app.wrap(middleware::Logger::default());
         let app = app.wrap(middleware::Compress::new(ContentEncoding::Auto));
-        let app = app.route("/reeves/search", web::post().to(srv_post_reeves_search));
-        let app = app.service(actix_files::Files::new_with_filesystem_and_namedfile_open_and_renderer(
-            fake_fs.clone(),
-            |fs, path| {
-                let ret = fs.read_file(path).and_then(|data| {
-                    let metadata = actix_files::NamedFileMetadata {
-                        modified: None,
-                        len: data.len() as u64,
-                        ino: None,
-                    };
-                    actix_files::NamedFile::from_readseek(io::Cursor::new(data), path, metadata)
-                });
-                trace!("got namedfile request for {} -> {:?}", path.display(), ret.is_ok());
-                ret
-            },
-            Rc::new(|_, _, _| { panic!() }),
-            "/",
-            "".into(),
-        ).index_file("index.html"));
+        let app = app.service(web::scope(proto::API_PREFIX)
+            .configure(configure_search_api)
+            .service(web::scope("/admin").configure(configure_admin_api))
+            .wrap(protocol_version_header()));
+        // Unversioned paths, for compatibility with frontends and clients from before the API was versioned
+        let app = app.service(web::scope("/reeves").configure(configure_search_api).wrap(protocol_version_header()));
+        let app = app.service(web::scope("/admin").configure(configure_admin_api).wrap(protocol_version_header()));
+        let app = match static_source.clone() {
+            StaticSource::Dir(dir) => app.service(actix_files::Files::new("/", dir).index_file("index.html")),
+            StaticSource::Tar(static_tar) => app.data(static_tar).route("/{path:.*}", web::get().to(srv_get_static)),
+        };
         app
     };
 
-    info!("Server starting on {}", addr);
+    let server = HttpServer::new(app_factory);
+    let server = match listen {
+        Listen::Tcp(addr) => {
+            info!("Server starting on {}", addr);
+            server.bind(addr).unwrap()
+        },
+        Listen::Uds(path) => {
+            info!("Server starting on unix socket {}", path.display());
+            // Clear out a socket left behind by a previous run
+            if let Err(e) = fs::remove_file(&path) {
+                if e.kind() != io::ErrorKind::NotFound { panic!("{}", e) }
+            }
+            server.bind_uds(path).unwrap()
+        },
+    };
     actix_rt::System::new("actix server").block_on(async {
-        HttpServer::new(app_factory)
-            .bind(addr)
-            .unwrap()
+        server
             .run()
             .await
     }).unwrap()
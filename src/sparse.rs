@@ -0,0 +1,228 @@
+// Finds and downloads crates using a sparse HTTP index (https://rust-lang.github.io/rfcs/2789-sparse-index.html), so
+// bulk analysis doesn't need a full panamax mirror. Downloaded crates are cached, as they never change once published
+
+use anyhow::{Context, Result, anyhow, bail};
+use futures::executor::ThreadPool;
+use futures::stream::{FuturesUnordered, StreamExt};
+use futures::task::SpawnExt;
+use isahc::config::RedirectPolicy;
+use isahc::prelude::*;
+use log::{info, warn};
+use once_cell::sync::OnceCell;
+use serde::Deserialize;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{self, Command};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use reeves::popularity::CsvRecords;
+
+pub const CRATES_IO_SPARSE_INDEX: &str = "https://index.crates.io";
+
+// Index entries are small, so fetching is dominated by latency
+const FETCH_JOBS: usize = 32;
+
+// Makes each download's temporary file unique within the process
+static DOWNLOAD_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Deserialize)]
+struct IndexConfig {
+    dl: String,
+}
+
+#[derive(Clone, Debug)]
+pub struct SparseIndex {
+    url: String,
+    cache_dir: PathBuf,
+    // The download URL template from the index's config.json, fetched on the first download and shared by clones
+    dl: Arc<OnceCell<String>>,
+}
+
+impl SparseIndex {
+    pub fn new(url: &str, cache_dir: &Path) -> SparseIndex {
+        SparseIndex { url: url.trim_end_matches('/').to_owned(), cache_dir: cache_dir.to_owned(), dl: Arc::new(OnceCell::new()) }
+    }
+
+    fn get(&self, url: &str) -> Result<isahc::Response<isahc::Body>> {
+        let res = isahc::Request::get(url)
+            .header("User-Agent", crate::CRATES_IO_USER_AGENT)
+            .redirect_policy(RedirectPolicy::Follow)
+            .body(())?
+            .send()?;
+        Ok(res)
+    }
+
+    // None if the index doesn't have the crate
+    pub fn krate(&self, name: &str) -> Result<Option<crates_index::Crate>> {
+        let mut res = self.get(&format!("{}/{}", self.url, index_path(name)))?;
+        match res.status().as_u16() {
+            200 => (),
+            // crates.io gives 403 for crates that don't exist, as the index is served from S3
+            403 | 404 | 410 => return Ok(None),
+            status => bail!("sparse index returned {} for crate {}", status, name),
+        }
+        let mut bytes = vec![];
+        res.copy_to(&mut bytes)?;
+        let krate = crates_index::Crate::from_slice(&bytes).with_context(|| format!("failed to parse index entry for {}", name))?;
+        Ok(Some(krate))
+    }
+
+    // Every crate with its highest unyanked version, if any
+    pub fn crates(&self) -> Result<Vec<(String, Option<String>)>> {
+        let names = self.crate_names()?;
+        info!("looking up {} crates in the sparse index", names.len());
+        let pool = ThreadPool::builder().pool_size(FETCH_JOBS).create().unwrap();
+        let mut futs: FuturesUnordered<_> = names.into_iter()
+            .map(|name| {
+                let index = self.clone();
+                pool.spawn_with_handle(futures::future::lazy(move |_| {
+                    let krate = index.krate(&name);
+                    (name, krate)
+                })).unwrap()
+            })
+            .collect();
+        let mut crates = vec![];
+        let mut looked_up = 0;
+        futures::executor::block_on(async {
+            while let Some((name, krate)) = futs.next().await {
+                match krate {
+                    Ok(Some(krate)) => crates.push((name, crate::highest_unyanked_version(&krate))),
+                    // Deleted since the dump was taken
+                    Ok(None) => warn!("crate {} not in sparse index", name),
+                    Err(e) => warn!("failed to look up crate {}: {:?}", name, e),
+                }
+                looked_up += 1;
+                if looked_up % 10000 == 0 {
+                    info!("looked up {} crates", looked_up)
+                }
+            }
+        });
+        crates.sort();
+        Ok(crates)
+    }
+
+    // The sparse index can't list crates, so the crates.io database dump is used to find their names
+    fn crate_names(&self) -> Result<Vec<String>> {
+        let dump_path = reeves::popularity::fetch_db_dump(&self.cache_dir, crate::CRATES_IO_USER_AGENT)?;
+
+        // The dump is inside a dated directory, so the file can't be named exactly
+        let res = Command::new("tar")
+            .args(&["-xzOf", dump_path.to_str().unwrap(), "--wildcards", "*/data/crates.csv"])
+            .output().context("failed to run tar")?;
+        if !res.status.success() {
+            bail!("failed to extract crates from database dump: {}", String::from_utf8_lossy(&res.stderr))
+        }
+        let mut records = CsvRecords::new(&res.stdout[..]);
+        let header = records.next().ok_or_else(|| anyhow!("database dump crates are empty"))?
+            .context("database dump crates aren't utf8")?;
+        let name_col = header.iter().position(|col| col == "name").ok_or_else(|| anyhow!("no name column in database dump crates"))?;
+        let mut names = vec![];
+        for record in records {
+            let mut record = record.context("database dump crates aren't utf8")?;
+            if name_col < record.len() {
+                names.push(record.swap_remove(name_col))
+            }
+        }
+        Ok(names)
+    }
+
+    // Download a crate into the cache, if it isn't already there
+    pub fn download(&self, name: &str, version: &str) -> Result<PathBuf> {
+        let crate_dir = self.cache_dir.join("crates").join(name);
+        let crate_path = crate_dir.join(format!("{}-{}.crate", name, version));
+        if crate_path.exists() {
+            return Ok(crate_path)
+        }
+
+        let dl = self.dl.get_or_try_init(|| -> Result<String> {
+            let mut res = self.get(&format!("{}/config.json", self.url))?;
+            if !res.status().is_success() {
+                bail!("sparse index returned {} for config.json", res.status())
+            }
+            let config: IndexConfig = res.json()?;
+            Ok(config.dl)
+        })?;
+        let url = download_url(dl, name, version)?;
+
+        info!("downloading crate {}-{}", name, version);
+        let mut res = self.get(&url)?;
+        if !res.status().is_success() {
+            bail!("registry returned {} for crate {}-{}", res.status(), name, version)
+        }
+        fs::create_dir_all(&crate_dir).with_context(|| format!("failed to create {}", crate_dir.display()))?;
+        // Concurrent analyses, in this process or others, may download the same crate, so each download has its own
+        // temporary file and never leaves a partial file at the final path
+        let download_id = DOWNLOAD_COUNTER.fetch_add(1, Ordering::Relaxed);
+        let tmp_path = crate_path.with_extension(format!("crate.{}.{}.tmp", process::id(), download_id));
+        let saved = res.copy_to_file(&tmp_path).context("failed to download crate")
+            .and_then(|_| fs::rename(&tmp_path, &crate_path).with_context(|| format!("failed to move download to {}", crate_path.display())));
+        if saved.is_err() {
+            let _ = fs::remove_file(&tmp_path);
+        }
+        saved?;
+        Ok(crate_path)
+    }
+}
+
+// The directories a crate is under in the index
+fn prefix(name: &str) -> String {
+    match name.len() {
+        1 => "1".to_owned(),
+        2 => "2".to_owned(),
+        3 => format!("3/{}", &name[..1]),
+        _ => format!("{}/{}", &name[..2], &name[2..4]),
+    }
+}
+
+// Same layout as the git index, but the sparse index is case sensitive and crates.io serves lowercase names
+fn index_path(name: &str) -> String {
+    let name = name.to_lowercase();
+    format!("{}/{}", prefix(&name), name)
+}
+
+// Fill in the markers of the config's dl template. Without any, the template is a base the crate and version are
+// appended to. The checksum marker needs the index entry, which isn't worth fetching as no known registry uses it, so
+// it's refused along with anything else unknown rather than making a URL that can't be right
+fn download_url(dl: &str, name: &str, version: &str) -> Result<String> {
+    if !dl.contains('{') {
+        return Ok(format!("{}/{}/{}/download", dl.trim_end_matches('/'), name, version))
+    }
+    let url = dl
+        .replace("{crate}", name)
+        .replace("{version}", version)
+        .replace("{prefix}", &prefix(name))
+        .replace("{lowerprefix}", &prefix(&name.to_lowercase()));
+    if url.contains('{') {
+        bail!("unsupported marker in index download template {}", dl)
+    }
+    Ok(url)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn index_paths() {
+        assert_eq!(index_path("a"), "1/a");
+        assert_eq!(index_path("ab"), "2/ab");
+        assert_eq!(index_path("abc"), "3/a/abc");
+        assert_eq!(index_path("serde"), "se/rd/serde");
+        assert_eq!(index_path("Inflector"), "in/fl/inflector");
+        assert_eq!(index_path("FOO"), "3/f/foo");
+    }
+
+    #[test]
+    fn download_urls() {
+        let url = |dl| download_url(dl, "Inflector", "0.11.4").ok();
+        assert_eq!(url("https://static.crates.io/crates").as_deref(), Some("https://static.crates.io/crates/Inflector/0.11.4/download"));
+        assert_eq!(url("https://dl.example/").as_deref(), Some("https://dl.example/Inflector/0.11.4/download"));
+        assert_eq!(url("https://dl.example/{crate}/{version}/download").as_deref(), Some("https://dl.example/Inflector/0.11.4/download"));
+        assert_eq!(url("https://dl.example/{prefix}/{crate}-{version}.crate").as_deref(), Some("https://dl.example/In/fl/Inflector-0.11.4.crate"));
+        assert_eq!(url("https://dl.example/{lowerprefix}/{crate}").as_deref(), Some("https://dl.example/in/fl/Inflector"));
+        assert_eq!(url("https://dl.example/{sha256-checksum}"), None);
+        assert_eq!(url("https://dl.example/{crate}/{checksum}"), None);
+        assert_eq!(download_url("https://dl.example/{prefix}/{crate}", "abc", "1.0.0").unwrap(), "https://dl.example/3/a/abc");
+    }
+}
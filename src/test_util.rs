@@ -0,0 +1,30 @@
+// Fixtures for tests, and for benches, which include this file by path - so it only uses reeves_types
+
+use reeves_types::*;
+
+// A fn at a path from its crate root, e.g. `foo::Foo::new`, with nothing recorded beyond its signature
+pub fn fndetail(version: &str, path: &str, kind: ItemKind, params: &[&str], ret: &str) -> FnDetail {
+    let krate = path.split("::").next().unwrap();
+    let (module, name) = path.rsplit_once("::").unwrap();
+    FnDetail {
+        krate: krate.to_owned(),
+        version: version.to_owned(),
+        path: path.to_owned(),
+        name: name.to_owned(),
+        kind,
+        params: params.iter().map(|p| p.to_string()).collect(),
+        ret: ret.to_owned(),
+        param_tys: params.iter().map(|p| ty::parse_type(p).unwrap_or(ty::Type::Infer)).collect(),
+        ret_ty: ty::parse_type(ret).unwrap_or(ty::Type::Infer),
+        s: format!("fn {}({}) -> {}", path, params.join(", "), ret),
+        doc_summary: None,
+        is_unsafe: false,
+        is_async: false,
+        is_const: false,
+        is_deprecated: false,
+        example: None,
+        reexported_from: None,
+        module: module.to_owned(),
+        source: None,
+    }
+}
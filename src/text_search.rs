@@ -0,0 +1,556 @@
+// The text search backends, which find the known types closest to a (possibly misspelt or partial) type. Each has an
+// index of param types and one of ret types, of documents with the type tokenized for searching and the type itself to
+// return. Which types are in them is tracked in the db, see TEXT_SEARCH_SYNC_TREE
+
+use async_trait::async_trait;
+use futures::io::AsyncReadExt;
+use isahc::prelude::*;
+use log::info;
+#[cfg(feature = "meilisearch")]
+use meilisearch_sdk as meili;
+use once_cell::sync::Lazy;
+use serde::{Serialize, Deserialize};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+use reeves_types::fnv1a;
+use crate::{PARAM_TYPES_INDEX, RET_TYPES_INDEX, ReevesError, blocking};
+
+// Documents are added and removed in chunks of this many, to keep requests a reasonable size
+const UPDATE_CHUNK: usize = 500;
+
+#[async_trait(?Send)]
+pub trait TextSearch {
+    // Create the indexes if needed and make sure they have the right settings
+    async fn prepare(&self) -> Result<(), ReevesError>;
+    // Remove the indexes and everything in them, for a full reload
+    async fn delete(&self) -> Result<(), ReevesError>;
+    async fn add_types(&self, index: &str, tys: &HashSet<String>) -> Result<(), ReevesError>;
+    async fn remove_types(&self, index: &str, tys: &HashSet<String>) -> Result<(), ReevesError>;
+    // Known types matching the query, closest first
+    async fn search(&self, index: &str, query: &str, limit: usize) -> Result<Vec<String>, ReevesError>;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TextSearchBackend {
+    Tantivy, // embedded, with the url being the directory its indexes are in
+    #[cfg(feature = "meilisearch")]
+    Meilisearch,
+    Elasticsearch, // or anything else with its API, e.g. OpenSearch
+}
+
+// Needs nothing else running
+impl Default for TextSearchBackend {
+    fn default() -> Self {
+        TextSearchBackend::Tantivy
+    }
+}
+
+impl TextSearchBackend {
+    // The reeves CLI puts tantivy's indexes next to whichever db it's using instead
+    pub fn default_url(self) -> &'static str {
+        match self {
+            TextSearchBackend::Tantivy => "reeves.db.text-search",
+            #[cfg(feature = "meilisearch")]
+            TextSearchBackend::Meilisearch => "http://localhost:7700",
+            TextSearchBackend::Elasticsearch => "http://localhost:9200",
+        }
+    }
+
+    pub fn connect(self, url: &str) -> Box<dyn TextSearch> {
+        let url = url.trim_end_matches('/').to_owned();
+        match self {
+            TextSearchBackend::Tantivy => Box::new(Tantivy { dir: PathBuf::from(url) }),
+            #[cfg(feature = "meilisearch")]
+            TextSearchBackend::Meilisearch => Box::new(Meilisearch { url }),
+            TextSearchBackend::Elasticsearch => Box::new(Elasticsearch { url }),
+        }
+    }
+}
+
+impl FromStr for TextSearchBackend {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, String> {
+        Ok(match s {
+            "tantivy" => TextSearchBackend::Tantivy,
+            #[cfg(feature = "meilisearch")]
+            "meilisearch" => TextSearchBackend::Meilisearch,
+            #[cfg(not(feature = "meilisearch"))]
+            "meilisearch" => return Err("reeves was built without the meilisearch feature".to_owned()),
+            "elasticsearch" => TextSearchBackend::Elasticsearch,
+            _ => return Err(format!("unknown text search backend {}, expected tantivy, meilisearch or elasticsearch", s)),
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct TypeInFn {
+    id: u64,
+    ty: String,
+    orig_ty: String,
+}
+
+#[cfg(feature = "meilisearch")]
+impl meili::document::Document for TypeInFn {
+    type UIDType = u64;
+
+    fn get_uid(&self) -> &Self::UIDType {
+        &self.id
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct TypeInFnResult {
+    orig_ty: String,
+}
+
+// Spaces around punctuation, so each part of a type is a word to the backend
+fn tokenize_type(s: &str) -> String {
+    let mut s = s
+        .replace('<', " < ")
+        .replace('>', " > ")
+        .replace('[', " [ ")
+        .replace(']', " ] ")
+        .replace('&', " & ");
+    loop {
+        let news = s.replace("  ", " ");
+        if news == s {
+            return s
+        }
+        s = news
+    }
+}
+
+// Document ids are derived from the type so re-adding a type replaces it, which lets the text search be updated
+// incrementally
+fn type_doc(ty: &str) -> TypeInFn {
+    TypeInFn { id: fnv1a(ty), ty: tokenize_type(ty), orig_ty: ty.to_owned() }
+}
+
+fn backend_error(e: impl fmt::Display) -> ReevesError {
+    ReevesError::TextSearch(e.to_string())
+}
+
+#[cfg(feature = "meilisearch")]
+pub struct Meilisearch {
+    url: String,
+}
+
+#[cfg(feature = "meilisearch")]
+impl Meilisearch {
+    fn client(&self) -> meili::client::Client {
+        meili::client::Client::new(&self.url, "no_key")
+    }
+}
+
+#[cfg(feature = "meilisearch")]
+fn meili_settings() -> meili::settings::Settings {
+    meili::settings::Settings {
+        synonyms: None,
+        stop_words: Some(vec![]),
+        ranking_rules: None,
+        distinct_attribute: None,
+        filterable_attributes: Some(vec![]),
+        searchable_attributes: Some(vec!["ty".into()]),
+        displayed_attributes: Some(vec!["orig_ty".into()]),
+    }
+}
+
+#[cfg(feature = "meilisearch")]
+#[async_trait(?Send)]
+impl TextSearch for Meilisearch {
+    async fn prepare(&self) -> Result<(), ReevesError> {
+        let client = self.client();
+        let settings = meili_settings();
+        for index in &[PARAM_TYPES_INDEX, RET_TYPES_INDEX] {
+            let index = client.get_or_create(index).await?;
+            index.set_settings(&settings).await?
+                .wait_for_pending_update(None, None).await.ok_or(ReevesError::TextSearchUpdate)??;
+        }
+        Ok(())
+    }
+
+    async fn delete(&self) -> Result<(), ReevesError> {
+        let client = self.client();
+        client.delete_index_if_exists(PARAM_TYPES_INDEX).await?;
+        client.delete_index_if_exists(RET_TYPES_INDEX).await?;
+        Ok(())
+    }
+
+    async fn add_types(&self, index: &str, tys: &HashSet<String>) -> Result<(), ReevesError> {
+        let client = self.client();
+        let index_handle = client.assume_index(index);
+        let docs: Vec<TypeInFn> = tys.iter().map(|ty| type_doc(ty)).collect();
+        let mut progress = 0;
+        for chunk in docs.chunks(UPDATE_CHUNK) {
+            index_handle.add_documents(chunk, Some("id")).await?
+                .wait_for_pending_update(None, None).await.ok_or(ReevesError::TextSearchUpdate)??;
+            progress += chunk.len();
+            info!("Added {}/{} entries to {}", progress, docs.len(), index);
+        }
+        Ok(())
+    }
+
+    async fn remove_types(&self, index: &str, tys: &HashSet<String>) -> Result<(), ReevesError> {
+        let client = self.client();
+        let index_handle = client.assume_index(index);
+        let ids: Vec<u64> = tys.iter().map(|ty| type_doc(ty).id).collect();
+        for chunk in ids.chunks(UPDATE_CHUNK) {
+            index_handle.delete_documents(chunk).await?
+                .wait_for_pending_update(None, None).await.ok_or(ReevesError::TextSearchUpdate)??;
+        }
+        info!("Removed {} entries from {}", ids.len(), index);
+        Ok(())
+    }
+
+    async fn search(&self, index: &str, query: &str, limit: usize) -> Result<Vec<String>, ReevesError> {
+        let client = self.client();
+        let candidates = client.assume_index(index).search()
+            .with_query(query)
+            .with_limit(limit)
+            .execute::<TypeInFnResult>()
+            .await?;
+        Ok(candidates.hits.into_iter().map(|c| c.result.orig_ty).collect())
+    }
+}
+
+// Indexes in a directory each, searched in process. Tantivy's default tokenizer drops punctuation, so the parts of a
+// type that aren't words are spelt out as words for it. Searches are fuzzy and prefix matching like meilisearch's, with
+// exact words also scored so they come first. Opening an index does disk IO, so each is opened once per process and
+// shared (see TANTIVY_INDEXES), and all of the work is done on the blocking pool
+pub struct Tantivy {
+    dir: PathBuf,
+}
+
+// For tantivy's index writer, which flushes a segment when it's used this much
+const TANTIVY_WRITER_HEAP: usize = 64 * 1024 * 1024;
+
+// Open indexes by path, shared by every Tantivy connected to the same directory
+static TANTIVY_INDEXES: Lazy<Mutex<HashMap<PathBuf, Arc<TantivyIndex>>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+struct TantivyIndex {
+    index: tantivy::Index,
+    reader: tantivy::IndexReader, // reloaded on each commit
+    // Tantivy allows one writer per index, across processes, so one is made for each update and dropped after its
+    // commit - e.g. `reeves watch` can update the index while a server syncs it too. This serializes the updates of
+    // this process, another process updating at the same moment fails to get the writer
+    update: Mutex<()>,
+    ty: tantivy::schema::Field, // tokenized, for searching
+    orig_ty: tantivy::schema::Field, // as is, for deletes and returning
+}
+
+// The words tantivy should see for a type, from the same spacing out as the other backends
+fn tantivy_words(s: &str) -> String {
+    tokenize_type(s).split(' ')
+        .map(|word| match word {
+            "&" => "ref",
+            "*" => "ptr",
+            "[" => "slice",
+            "<" | ">" | "]" => "",
+            word => word,
+        })
+        .filter(|word| !word.is_empty())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+impl Tantivy {
+    // Blocking, as are the rest
+    fn open_index(dir: &Path, index: &str, create: bool) -> Result<Option<Arc<TantivyIndex>>, ReevesError> {
+        use tantivy::schema::{STORED, STRING, TEXT};
+        let path = dir.join(index);
+        // Held while opening, so the index is only opened once
+        let mut indexes = TANTIVY_INDEXES.lock().unwrap();
+        if let Some(tindex) = indexes.get(&path) {
+            return Ok(Some(tindex.clone()))
+        }
+        if !path.exists() {
+            if !create {
+                return Ok(None)
+            }
+            fs::create_dir_all(&path).map_err(backend_error)?;
+        }
+        let mut schema_builder = tantivy::schema::Schema::builder();
+        let ty = schema_builder.add_text_field("ty", TEXT);
+        let orig_ty = schema_builder.add_text_field("orig_ty", STRING | STORED);
+        let mmap_dir = tantivy::directory::MmapDirectory::open(&path).map_err(backend_error)?;
+        let index = tantivy::Index::open_or_create(mmap_dir, schema_builder.build()).map_err(backend_error)?;
+        let reader = index.reader_builder()
+            .reload_policy(tantivy::ReloadPolicy::OnCommit)
+            .try_into()
+            .map_err(backend_error)?;
+        let tindex = Arc::new(TantivyIndex { index, reader, update: Mutex::new(()), ty, orig_ty });
+        indexes.insert(path, tindex.clone());
+        Ok(Some(tindex))
+    }
+
+    // The writer, and so the lock on the index, is held until it's dropped
+    fn writer(tindex: &TantivyIndex, index: &str) -> Result<tantivy::IndexWriter, ReevesError> {
+        tindex.index.writer(TANTIVY_WRITER_HEAP)
+            .map_err(|e| backend_error(format!("failed to get a writer for {}, is another process updating it? {}", index, e)))
+    }
+
+    fn update_index(dir: &Path, index: &str, tys: &HashSet<String>, add: bool) -> Result<(), ReevesError> {
+        let tindex = Tantivy::open_index(dir, index, true)?.unwrap();
+        let _update = tindex.update.lock().unwrap();
+        let mut writer = Tantivy::writer(&tindex, index)?;
+        let (ty, orig_ty) = (tindex.ty, tindex.orig_ty);
+        for s in tys {
+            // Replaces the type if it's already there, as re-adding does for the other backends
+            writer.delete_term(tantivy::Term::from_field_text(orig_ty, s));
+            if add {
+                writer.add_document(tantivy::doc!(ty => tantivy_words(s), orig_ty => s.as_str()));
+            }
+        }
+        writer.commit().map_err(backend_error)?;
+        // The reload policy picks commits up too, but after a delay
+        tindex.reader.reload().map_err(backend_error)?;
+        info!("{} {} entries {} {}", if add { "Added" } else { "Removed" }, tys.len(), if add { "to" } else { "from" }, index);
+        Ok(())
+    }
+
+    fn search_index(dir: &Path, index: &str, query: &str, limit: usize) -> Result<Vec<String>, ReevesError> {
+        use tantivy::query::{BooleanQuery, FuzzyTermQuery, Occur, Query, TermQuery};
+        let tindex = match Tantivy::open_index(dir, index, false)? {
+            Some(tindex) => tindex,
+            None => return Err(backend_error(format!("no {} index in {}, run load-text-search", index, dir.display()))),
+        };
+        // Split up as tantivy's tokenizer splits up what's indexed
+        let words = tantivy_words(query).to_lowercase();
+        let words: Vec<&str> = words.split(|c: char| !c.is_alphanumeric()).filter(|word| !word.is_empty()).collect();
+        let mut clauses: Vec<(Occur, Box<dyn Query>)> = vec![];
+        for (i, word) in words.iter().enumerate() {
+            let term = tantivy::Term::from_field_text(tindex.ty, word);
+            // Like meilisearch, short words have to be exact and the last word may be unfinished
+            let distance = if word.len() < 5 { 0 } else if word.len() < 9 { 1 } else { 2 };
+            let fuzzy = if i == words.len() - 1 {
+                FuzzyTermQuery::new_prefix(term.clone(), distance, true)
+            } else {
+                FuzzyTermQuery::new(term.clone(), distance, true)
+            };
+            clauses.push((Occur::Should, Box::new(fuzzy)));
+            clauses.push((Occur::Should, Box::new(TermQuery::new(term, tantivy::schema::IndexRecordOption::WithFreqs))));
+        }
+        if clauses.is_empty() {
+            return Ok(vec![])
+        }
+        let searcher = tindex.reader.searcher();
+        let hits = searcher.search(&BooleanQuery::new(clauses), &tantivy::collector::TopDocs::with_limit(limit)).map_err(backend_error)?;
+        let mut tys = vec![];
+        for (_score, address) in hits {
+            let doc = searcher.doc(address).map_err(backend_error)?;
+            if let Some(s) = doc.get_first(tindex.orig_ty).and_then(|value| value.text()) {
+                tys.push(s.to_owned())
+            }
+        }
+        Ok(tys)
+    }
+}
+
+#[async_trait(?Send)]
+impl TextSearch for Tantivy {
+    async fn prepare(&self) -> Result<(), ReevesError> {
+        let dir = self.dir.clone();
+        blocking(move || {
+            for index in &[PARAM_TYPES_INDEX, RET_TYPES_INDEX] {
+                Tantivy::open_index(&dir, index, true)?;
+            }
+            Ok(())
+        }).await
+    }
+
+    // The indexes are emptied rather than removed, as other processes (e.g. a server) may have them open
+    async fn delete(&self) -> Result<(), ReevesError> {
+        let dir = self.dir.clone();
+        blocking(move || {
+            for index in &[PARAM_TYPES_INDEX, RET_TYPES_INDEX] {
+                let tindex = match Tantivy::open_index(&dir, index, false)? {
+                    Some(tindex) => tindex,
+                    None => continue,
+                };
+                let _update = tindex.update.lock().unwrap();
+                let mut writer = Tantivy::writer(&tindex, index)?;
+                writer.delete_all_documents().map_err(backend_error)?;
+                writer.commit().map_err(backend_error)?;
+                tindex.reader.reload().map_err(backend_error)?;
+            }
+            Ok(())
+        }).await
+    }
+
+    async fn add_types(&self, index: &str, tys: &HashSet<String>) -> Result<(), ReevesError> {
+        let (dir, index, tys) = (self.dir.clone(), index.to_owned(), tys.clone());
+        blocking(move || Tantivy::update_index(&dir, &index, &tys, true)).await
+    }
+
+    async fn remove_types(&self, index: &str, tys: &HashSet<String>) -> Result<(), ReevesError> {
+        let (dir, index, tys) = (self.dir.clone(), index.to_owned(), tys.clone());
+        blocking(move || Tantivy::update_index(&dir, &index, &tys, false)).await
+    }
+
+    async fn search(&self, index: &str, query: &str, limit: usize) -> Result<Vec<String>, ReevesError> {
+        let (dir, index, query) = (self.dir.clone(), index.to_owned(), query.to_owned());
+        blocking(move || Tantivy::search_index(&dir, &index, &query, limit)).await
+    }
+}
+
+// Tokenization is done the same way as for meilisearch, with the whitespace tokenizer splitting up what tokenize_type
+// produces, and searches are made fuzzy and prefix matching to behave like meilisearch's
+pub struct Elasticsearch {
+    url: String,
+}
+
+#[derive(Deserialize)]
+struct EsSearchResponse {
+    hits: EsHits,
+}
+
+#[derive(Deserialize)]
+struct EsHits {
+    hits: Vec<EsHit>,
+}
+
+#[derive(Deserialize)]
+struct EsHit {
+    #[serde(rename = "_source")]
+    source: TypeInFnResult,
+}
+
+#[derive(Deserialize)]
+struct EsBulkResponse {
+    errors: bool,
+    items: Vec<serde_json::Value>,
+}
+
+impl Elasticsearch {
+    // The status and body of the response. Statuses other than 2xx and those allowed are errors
+    async fn request(&self, method: &str, path: &str, body: Option<(&str, Vec<u8>)>, allowed: &[u16]) -> Result<(u16, Vec<u8>), ReevesError> {
+        let mut request = isahc::Request::builder()
+            .method(method)
+            .uri(format!("{}/{}", self.url, path));
+        if let Some((content_type, _)) = &body {
+            request = request.header("Content-Type", *content_type);
+        }
+        let request = request.body(body.map(|(_, body)| body).unwrap_or_default()).map_err(backend_error)?;
+        let mut res = request.send_async().await.map_err(backend_error)?;
+        let status = res.status().as_u16();
+        let mut body = vec![];
+        res.body_mut().read_to_end(&mut body).await.map_err(backend_error)?;
+        if !(200..300).contains(&status) && !allowed.contains(&status) {
+            return Err(backend_error(format!("{} {} returned {}: {}", method, path, status, String::from_utf8_lossy(&body))))
+        }
+        Ok((status, body))
+    }
+
+    async fn request_json(&self, method: &str, path: &str, body: &serde_json::Value) -> Result<Vec<u8>, ReevesError> {
+        let body = serde_json::to_vec(body).unwrap();
+        Ok(self.request(method, path, Some(("application/json", body)), &[]).await?.1)
+    }
+
+    // Waits for the changes to be searchable, like waiting for meilisearch's pending updates
+    async fn bulk(&self, actions: String) -> Result<(), ReevesError> {
+        let (_, body) = self.request("POST", "_bulk?refresh=wait_for", Some(("application/x-ndjson", actions.into_bytes())), &[]).await?;
+        let res: EsBulkResponse = serde_json::from_slice(&body).map_err(backend_error)?;
+        if res.errors {
+            let failed = res.items.iter()
+                .filter_map(|item| item.as_object()?.values().next()?.get("error"))
+                .next();
+            return Err(backend_error(format!("bulk update failed: {}", failed.map_or_else(|| "unknown error".into(), |e| e.to_string()))))
+        }
+        Ok(())
+    }
+}
+
+#[async_trait(?Send)]
+impl TextSearch for Elasticsearch {
+    async fn prepare(&self) -> Result<(), ReevesError> {
+        let mappings = serde_json::json!({
+            "properties": {
+                "ty": { "type": "text", "analyzer": "type_tokens" },
+                "orig_ty": { "type": "keyword", "index": false },
+            },
+        });
+        for index in &[PARAM_TYPES_INDEX, RET_TYPES_INDEX] {
+            let (status, _) = self.request("HEAD", index, None, &[404]).await?;
+            if status == 404 {
+                let settings = serde_json::json!({
+                    "settings": {
+                        "analysis": {
+                            "analyzer": {
+                                "type_tokens": { "type": "custom", "tokenizer": "whitespace", "filter": ["lowercase"] },
+                            },
+                        },
+                    },
+                    "mappings": mappings,
+                });
+                self.request_json("PUT", index, &settings).await?;
+            } else {
+                // Analyzers can't be changed on an open index, but a full load recreates it
+                self.request_json("PUT", &format!("{}/_mapping", index), &mappings).await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn delete(&self) -> Result<(), ReevesError> {
+        for index in &[PARAM_TYPES_INDEX, RET_TYPES_INDEX] {
+            self.request("DELETE", index, None, &[404]).await?;
+        }
+        Ok(())
+    }
+
+    async fn add_types(&self, index: &str, tys: &HashSet<String>) -> Result<(), ReevesError> {
+        let docs: Vec<TypeInFn> = tys.iter().map(|ty| type_doc(ty)).collect();
+        let mut progress = 0;
+        for chunk in docs.chunks(UPDATE_CHUNK) {
+            let mut actions = String::new();
+            for doc in chunk {
+                actions.push_str(&serde_json::json!({ "index": { "_index": index, "_id": doc.id.to_string() } }).to_string());
+                actions.push('\n');
+                actions.push_str(&serde_json::to_string(doc).unwrap());
+                actions.push('\n');
+            }
+            self.bulk(actions).await?;
+            progress += chunk.len();
+            info!("Added {}/{} entries to {}", progress, docs.len(), index);
+        }
+        Ok(())
+    }
+
+    async fn remove_types(&self, index: &str, tys: &HashSet<String>) -> Result<(), ReevesError> {
+        let ids: Vec<u64> = tys.iter().map(|ty| type_doc(ty).id).collect();
+        for chunk in ids.chunks(UPDATE_CHUNK) {
+            let mut actions = String::new();
+            for id in chunk {
+                actions.push_str(&serde_json::json!({ "delete": { "_index": index, "_id": id.to_string() } }).to_string());
+                actions.push('\n');
+            }
+            self.bulk(actions).await?;
+        }
+        info!("Removed {} entries from {}", ids.len(), index);
+        Ok(())
+    }
+
+    async fn search(&self, index: &str, query: &str, limit: usize) -> Result<Vec<String>, ReevesError> {
+        let query = tokenize_type(query);
+        let body = serde_json::json!({
+            "size": limit,
+            "_source": ["orig_ty"],
+            "query": {
+                "bool": {
+                    "should": [
+                        { "match": { "ty": { "query": query, "fuzziness": "AUTO" } } },
+                        { "match_bool_prefix": { "ty": query } },
+                    ],
+                },
+            },
+        });
+        let res = self.request_json("POST", &format!("{}/_search", index), &body).await?;
+        let res: EsSearchResponse = serde_json::from_slice(&res).map_err(backend_error)?;
+        Ok(res.hits.hits.into_iter().map(|hit| hit.source.orig_ty).collect())
+    }
+}
@@ -0,0 +1,260 @@
+// Usage snippets for analysed functions, found by looking for calls in the crate's examples and tests. This is textual
+// rather than going through rust analyzer, as examples and tests aren't part of the lib crate it loads, so snippets
+// are best-effort: a method call's receiver type is unknown, so `x.name(` is only used for a method when no other method
+// in the crate has that name, and even then may be a call to a dependency's method
+
+use log::debug;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use reeves_types::*;
+
+// Snippets are shown alongside results, so are kept short
+const MAX_SNIPPET_LINES: usize = 4;
+const MAX_SNIPPET_CHARS: usize = 300;
+
+// How a call was made, to tell apart e.g. `Header::new(` from `Builder::new(` and `x.new(`
+#[derive(Debug, PartialEq)]
+enum CallKind<'a> {
+    Method, // x.name(
+    Path(&'a str), // Type::name(, with the segment before the name
+    Bare, // name(
+}
+
+struct CallSite<'a> {
+    kind: CallKind<'a>,
+    file: usize,
+    line: usize,
+}
+
+// Give each fndetail the first call found for it, preferring examples (written to be read) over tests
+pub(crate) fn add_examples(crate_path: &Path, fndetails: &mut [FnDetail]) {
+    let mut files = vec![];
+    for dir in &["examples", "tests"] {
+        rust_files(&crate_path.join(dir), &mut files);
+    }
+    let mut src_files = vec![];
+    rust_files(&crate_path.join("src"), &mut src_files);
+
+    let mut sources: Vec<Vec<String>> = vec![];
+    for path in files {
+        match fs::read_to_string(&path) {
+            Ok(source) => sources.push(source.lines().map(str::to_owned).collect()),
+            Err(e) => debug!("failed to read {} for usage snippets: {}", path.display(), e),
+        }
+    }
+    for path in src_files {
+        match fs::read_to_string(&path) {
+            Ok(source) => sources.push(cfg_test_lines(&source)),
+            Err(e) => debug!("failed to read {} for usage snippets: {}", path.display(), e),
+        }
+    }
+
+    let mut calls: HashMap<&str, Vec<CallSite>> = HashMap::new();
+    for (file, lines) in sources.iter().enumerate() {
+        for (line, text) in lines.iter().enumerate() {
+            for (name, kind) in calls_in_line(text) {
+                calls.entry(name).or_default().push(CallSite { kind, file, line })
+            }
+        }
+    }
+
+    // Method calls can't be matched on their type, so only names belonging to a single method are used
+    let mut method_counts: HashMap<String, usize> = HashMap::new();
+    for fndetail in fndetails.iter().filter(|fndetail| fndetail.kind == ItemKind::Method) {
+        *method_counts.entry(fndetail.name.clone()).or_default() += 1
+    }
+
+    let mut num_found = 0;
+    for fndetail in fndetails.iter_mut() {
+        let name = fndetail.name.clone();
+        let sites = match calls.get(name.as_str()) {
+            Some(sites) => sites,
+            None => continue,
+        };
+        // The type (or module, for free functions) the fn is in, e.g. Header for tar::Header::new_gnu
+        let parent = fndetail.path.rsplit("::").nth(1).unwrap_or("");
+        let site = sites.iter().find(|site| match (&site.kind, fndetail.kind) {
+            (CallKind::Method, ItemKind::Method) => method_counts[name.as_str()] == 1,
+            (CallKind::Path(segment), ItemKind::Method) |
+            (CallKind::Path(segment), ItemKind::AssocFn) |
+            (CallKind::Path(segment), ItemKind::Function) => *segment == parent,
+            (CallKind::Bare, ItemKind::Function) => true,
+            _ => false,
+        });
+        if let Some(site) = site {
+            fndetail.example = Some(snippet(&sources[site.file], site.line));
+            num_found += 1;
+        }
+    }
+    debug!("found usage snippets for {} of {} functions", num_found, fndetails.len());
+}
+
+fn rust_files(dir: &Path, files: &mut Vec<PathBuf>) {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+    let mut paths: Vec<_> = entries.filter_map(|entry| entry.ok()).map(|entry| entry.path()).collect();
+    // So the same snippet is picked on every analysis
+    paths.sort();
+    for path in paths {
+        if path.is_dir() {
+            rust_files(&path, files)
+        } else if path.extension().map_or(false, |ext| ext == "rs") {
+            files.push(path)
+        }
+    }
+}
+
+// Only the lines inside #[cfg(test)] items count as tests, everything else is blanked so line numbers are kept
+fn cfg_test_lines(source: &str) -> Vec<String> {
+    let mut lines = vec![];
+    let mut in_test = false;
+    let mut depth: i64 = 0;
+    let mut seen_brace = false;
+    for line in source.lines() {
+        if !in_test && line.trim_start().starts_with("#[cfg(test)]") {
+            in_test = true;
+            depth = 0;
+            seen_brace = false;
+        }
+        if in_test {
+            lines.push(line.to_owned());
+            // Braces in strings and comments throw this off, but only by picking up a bit more or less of the file
+            for c in line.chars() {
+                match c {
+                    '{' => { depth += 1; seen_brace = true },
+                    '}' => depth -= 1,
+                    _ => (),
+                }
+            }
+            if seen_brace && depth <= 0 {
+                in_test = false
+            }
+        } else {
+            lines.push(String::new());
+        }
+    }
+    lines
+}
+
+fn calls_in_line(line: &str) -> Vec<(&str, CallKind)> {
+    let code = line.split("//").next().unwrap();
+    if code.trim_start().starts_with("fn ") || code.contains(" fn ") {
+        return vec![]
+    }
+    let bytes = code.as_bytes();
+    let is_ident = |b: u8| b.is_ascii_alphanumeric() || b == b'_';
+    let mut calls = vec![];
+    let mut i = 0;
+    while i < bytes.len() {
+        if !is_ident(bytes[i]) || (i > 0 && is_ident(bytes[i - 1])) {
+            i += 1;
+            continue
+        }
+        let start = i;
+        while i < bytes.len() && is_ident(bytes[i]) {
+            i += 1
+        }
+        let name = &code[start..i];
+        // Turbofish calls, e.g. x.parse::<u64>(, have their args after the generics
+        let is_call = code[i..].starts_with('(') || code[i..].starts_with("::<");
+        if !is_call || bytes[start].is_ascii_digit() {
+            continue
+        }
+        let before = &code[..start];
+        let kind = if before.ends_with('.') {
+            CallKind::Method
+        } else if let Some(before) = before.strip_suffix("::") {
+            let segment_start = before.rfind(|c: char| !(c.is_ascii_alphanumeric() || c == '_')).map_or(0, |i| i + 1);
+            CallKind::Path(&before[segment_start..])
+        } else {
+            CallKind::Bare
+        };
+        calls.push((name, kind));
+    }
+    calls
+}
+
+// The call's line and the ones after it until its parens close, dedented
+fn snippet(lines: &[String], line: usize) -> String {
+    let mut snippet_lines = vec![];
+    let mut depth: i64 = 0;
+    for text in lines[line..].iter().take(MAX_SNIPPET_LINES) {
+        snippet_lines.push(text.as_str());
+        for c in text.chars() {
+            match c {
+                '(' | '[' | '{' => depth += 1,
+                ')' | ']' | '}' => depth -= 1,
+                _ => (),
+            }
+        }
+        if depth <= 0 {
+            break
+        }
+    }
+    let indent = snippet_lines.iter()
+        .filter(|l| !l.trim().is_empty())
+        .map(|l| l.len() - l.trim_start().len())
+        .min().unwrap_or(0);
+    let mut snippet = snippet_lines.iter()
+        .map(|l| l.get(indent..).unwrap_or("").trim_end())
+        .collect::<Vec<_>>()
+        .join("\n");
+    if snippet.len() > MAX_SNIPPET_CHARS {
+        let end = (0..=MAX_SNIPPET_CHARS).rev().find(|&i| snippet.is_char_boundary(i)).unwrap();
+        snippet.truncate(end);
+        snippet.push_str("...");
+    }
+    snippet
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines(lines: &[&str]) -> Vec<String> {
+        lines.iter().map(|line| line.to_string()).collect()
+    }
+
+    #[test]
+    fn test_lines_only() {
+        let source = "fn main() {\n    foo();\n}\n#[cfg(test)]\nmod tests {\n    fn t() { foo(); }\n}\nfn after() {}\n";
+        assert_eq!(cfg_test_lines(source), ["", "", "", "#[cfg(test)]", "mod tests {", "    fn t() { foo(); }", "}", ""]);
+        let source = "#[cfg(test)]\nfn helper() { bar() }\nfn after() {}\n";
+        assert_eq!(cfg_test_lines(source), ["#[cfg(test)]", "fn helper() { bar() }", ""]);
+        assert_eq!(cfg_test_lines("fn main() {}\n"), [""]);
+    }
+
+    #[test]
+    fn calls() {
+        let cases: &[(&str, &[(&str, CallKind)])] = &[
+            ("let h = Header::new_gnu();", &[("new_gnu", CallKind::Path("Header"))]),
+            ("let h = tar::Header::new(x);", &[("new", CallKind::Path("Header"))]),
+            ("let n = s.parse::<u64>()?;", &[("parse", CallKind::Method)]),
+            ("foo(bar(1)) // baz(2)", &[("foo", CallKind::Bare), ("bar", CallKind::Bare)]),
+            ("a.b().c(d)", &[("b", CallKind::Method), ("c", CallKind::Method)]),
+            ("fn foo(x: u8) {", &[]),
+            ("pub fn foo(x: u8) {", &[]),
+            ("let x = 1u8(2); vec![1]", &[]),
+            ("// foo()", &[]),
+        ];
+        for (line, expected) in cases {
+            assert_eq!(calls_in_line(line), *expected, "{}", line);
+        }
+    }
+
+    #[test]
+    fn snippets() {
+        assert_eq!(snippet(&lines(&["    let x = foo(1);", "    bar();"]), 0), "let x = foo(1);");
+        assert_eq!(snippet(&lines(&["a();", "    foo(", "        1,", "    );", "    bar();"]), 1), "foo(\n    1,\n);");
+        assert_eq!(snippet(&lines(&["    foo(", "", "        1,", "    );"]), 0), "foo(\n\n    1,\n);");
+        let unclosed = lines(&["foo(", "1,", "2,", "3,", "4,", "5,"]);
+        assert_eq!(snippet(&unclosed, 0), "foo(\n1,\n2,\n3,");
+        // Truncated on a char boundary at or before the limit
+        let long = format!("x{}", "é".repeat(200));
+        assert_eq!(snippet(&[long], 0), format!("x{}...", "é".repeat(149)));
+    }
+}